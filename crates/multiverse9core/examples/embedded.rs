@@ -0,0 +1,33 @@
+//! Runs a node inside the application process and talks to it without going
+//! through TCP, the way an application server would which keeps its data on a
+//! co-located node.
+//!
+//! ```text
+//! cargo run --example embedded --no-default-features
+//! ```
+
+use multiverse9core::prelude::*;
+use std::time::Duration;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut settings = Settings::new("memory://".into())?;
+    settings.addr = "127.0.0.1:0".parse()?;
+    // Clients in this process which connect to the address of the node reach
+    // it through an in-process pipe too, such as the ones of other libraries.
+    settings.loopback = true;
+
+    let node = Node::new(settings);
+    // The node is taken by `start`, so the handle is taken beforehand.
+    let local = node.local();
+    std::thread::spawn(move || node.start(None));
+    while !local.is_running() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut client = local.client()?;
+    let key = client.upload(&b"Hello from the embedding application"[..], 4096)?;
+    for record in client.aggregate(&key)?.records {
+        println!("{}: {}", record.key, String::from_utf8_lossy(&record.value));
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+//! The access log, which records every request a node serves over a connection
+//! once [Settings::access_log](crate::settings::Settings::access_log) is set:
+//! the address of the connection, the identity it acts as, the code of the
+//! request, the size of its payload, the status of the response and how long
+//! the request took.
+//!
+//! Lines are logged at the info level under the [TARGET] target, so that they
+//! can be routed apart from the other logs of the node.
+
+use log::*;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::protocol::Identity;
+
+/// The target lines of the access log are logged under.
+pub(crate) const TARGET: &str = "multiverse9::access";
+
+/// A request which is being served.
+pub(crate) struct Entry {
+    addr: Option<SocketAddr>,
+    code: u16,
+    size: usize,
+    started: Instant,
+}
+
+impl Entry {
+    /// Starts timing a request as soon as it has been read.
+    pub(crate) fn start(addr: Option<SocketAddr>, code: u16, size: usize) -> Self {
+        Self {
+            addr,
+            code,
+            size,
+            started: Instant::now(),
+        }
+    }
+
+    /// Logs the request once it has been answered with the status.
+    pub(crate) fn finish(&self, identity: &Identity, status: Option<u8>) {
+        info!(
+            target: TARGET,
+            "{}",
+            self.line(identity, status, self.started.elapsed())
+        );
+    }
+
+    fn line(&self, identity: &Identity, status: Option<u8>, elapsed: Duration) -> String {
+        let addr = match self.addr {
+            Some(addr) => addr.to_string(),
+            None => "-".into(),
+        };
+        let status = match status {
+            Some(status) => status.to_string(),
+            None => "-".into(),
+        };
+
+        format!(
+            "{} {} {:#06x} {}B {} {}us",
+            addr,
+            identity,
+            self.code,
+            self.size,
+            status,
+            elapsed.as_micros()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let addr = "127.0.0.1:7890".parse().unwrap();
+        let entry = Entry::start(Some(addr), 0x0003, 12);
+        let line = entry.line(
+            &Identity::Subject("alice".into()),
+            Some(0),
+            Duration::from_micros(250),
+        );
+        assert_eq!(line, "127.0.0.1:7890 subject:alice 0x0003 12B 0 250us");
+
+        let entry = Entry::start(None, 0x0010, 0);
+        let line = entry.line(&Identity::Anonymous(addr), None, Duration::ZERO);
+        assert_eq!(line, "- addr:127.0.0.1:7890 0x0010 0B - 0us");
+    }
+}
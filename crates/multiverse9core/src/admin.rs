@@ -0,0 +1,540 @@
+//! The admin listener, which serves the management of a running node on an
+//! address of its own, configured with [Settings::admin]. The requests below are
+//! only answered on the admin listener, and are unknown to the public listener
+//! of the node, so that exposing the public port never exposes the management
+//! of the node. The admin listener is meant to be bound to a loopback address,
+//! since connections to it are not authenticated.
+//!
+//! Requests and responses are framed like on the public listener. Successful
+//! responses carry JSON, and failed responses carry status `1` along with the
+//! error message, which is meant to be shown to the operator.
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::anti_entropy;
+use crate::events::{self, Event};
+use crate::node::Node;
+use crate::protocol::{codec, Identity};
+use crate::settings::{Peer, Settings, Token};
+use crate::shutdown::{self, Shutdown};
+use crate::storage::Storage;
+use crate::{api, crash, outbound};
+use crate::{FrameBuffer, Tcp};
+
+/// Request code for listing the acknowledged nodes, as a JSON array of
+/// [Peer].
+pub const PEERS: u16 = 0x0023;
+
+/// Request code for acknowledging a node. The payload is the node as a JSON
+/// [Peer], which may be its address alone.
+pub const ADD_PEER: u16 = 0x0024;
+
+/// Request code for no longer acknowledging a node. The payload is the
+/// address of the node.
+pub const REMOVE_PEER: u16 = 0x0025;
+
+/// Request code for the counters of the node, as a JSON [crash::Snapshot].
+pub const STATS: u16 = 0x0026;
+
+/// Request code for stopping the node gracefully. See [crate::shutdown].
+pub const SHUTDOWN: u16 = 0x0027;
+
+/// Request code for reloading the settings from the file they were loaded
+/// from. The response lists the settings which changed, but only take effect
+/// once the node is restarted. See [Settings::reload].
+pub const RELOAD: u16 = 0x0028;
+
+/// Request code for listing the tokens of the node, as a JSON array of
+/// [Listed] tokens, which leave out their secrets.
+pub const TOKENS: u16 = 0x0030;
+
+/// Request code for adding a token, which connections may authenticate with
+/// right away. The payload is the token as a JSON [Token], which is given an
+/// ID unless it has one. The response is the ID of the token. The token is
+/// saved into the file the settings were loaded from, if any, and is not added
+/// if it cannot be saved.
+pub const ADD_TOKEN: u16 = 0x0031;
+
+/// Request code for revoking a token, which no connection may authenticate
+/// with from then on. Connections which authenticated with it already keep
+/// their identity until they are closed. The payload is the ID of the token.
+/// Like [ADD_TOKEN], the revocation is only kept if it can be saved.
+pub const REVOKE_TOKEN: u16 = 0x0032;
+
+/// Request code for reconciling the records of the node with the acknowledged
+/// node at the address in the payload right away. The response is the
+/// [Summary](crate::anti_entropy::Summary) of the exchange. See
+/// [crate::anti_entropy].
+pub const SYNC_WITH: u16 = 0x001D;
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .UnknownCommand(u16)
+    .UnknownNode(String)
+    .Acknowledged(SocketAddr)
+    .UnknownToken(String)
+    .Duplicate(&'static str)
+    .Io(std::io::Error) [source]
+    .NoSource(&'static str)
+    .Json(serde_json::Error) [source]
+    .Settings(crate::settings::Error) [source]
+    .Storage(crate::storage::Error) [source]
+    .AntiEntropy(anti_entropy::Error) [source]
+    ~Debug
+}
+
+/// A token as it is listed, without its secret.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Listed {
+    pub id: Option<String>,
+    pub subject: String,
+}
+
+/// What the admin listener works on.
+pub(crate) struct Context {
+    pub node: Arc<RwLock<Node>>,
+    pub shutdown: Shutdown,
+    /// The file the settings are reloaded from.
+    pub source: Option<PathBuf>,
+    pub storage: Arc<Storage>,
+    pub outbound: Arc<outbound::Pool>,
+    /// The subscriptions the records pulled by exchanges are published to.
+    pub events: Arc<events::Bus>,
+}
+
+/// Spawns the thread which accepts admin connections, each of which is served
+/// on a thread of its own. The listener is closed once the node is stopping.
+pub(crate) fn spawn(listener: TcpListener, admin: Context) -> io::Result<()> {
+    // Polling for connections, so that the listener notices the node stopping
+    // without being woken up.
+    listener.set_nonblocking(true)?;
+    let admin = Arc::new(admin);
+    std::thread::spawn(move || {
+        while !admin.shutdown.is_stopping() {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(shutdown::POLL);
+                    continue;
+                }
+                Err(e) => {
+                    error!("Could not accept an admin connection: {}", e);
+                    continue;
+                }
+            };
+
+            let admin = Arc::clone(&admin);
+            std::thread::spawn(move || {
+                if let Err(e) = serve(stream, &admin) {
+                    error!("Admin stream error: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Handles the requests of an admin connection until it is closed.
+fn serve(mut stream: TcpStream, admin: &Context) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let addr = stream.peer_addr()?;
+    let mut frames = FrameBuffer::new(codec::Kind::Request);
+    while let Some(frame) = Tcp::read_frame(&mut stream, &mut frames)? {
+        let (request, _) = codec::decode_request(frame)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let buffer = match handle(admin, addr, request.code, request.payload) {
+            Ok(reply) => codec::encode_response(0, &reply),
+            Err(e) => {
+                warn!(
+                    "Admin request {:#06x} of {} failed: {}",
+                    request.code, addr, e
+                );
+                codec::encode_response(1, e.to_string().as_bytes())
+            }
+        };
+
+        let buffer = buffer.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Tcp::write(&mut stream, &buffer)?;
+    }
+
+    Ok(())
+}
+
+fn handle(admin: &Context, addr: SocketAddr, code: u16, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    match code {
+        PEERS => {
+            let node = admin.node.read().unwrap();
+            serde_json::to_vec(&node.settings.nodes).map_err(Error::Json)
+        }
+
+        ADD_PEER => {
+            let peer: Peer = serde_json::from_slice(payload).map_err(Error::Json)?;
+            let mut node = admin.node.write().unwrap();
+            if node
+                .settings
+                .nodes
+                .iter()
+                .any(|known| known.addr == peer.addr)
+            {
+                return Err(Error::Acknowledged(peer.addr));
+            }
+
+            info!("Acknowledging the node at {}", peer.addr);
+            node.settings.nodes.push(peer);
+            Ok(vec![])
+        }
+
+        REMOVE_PEER => {
+            let addr = String::from_utf8_lossy(payload).to_string();
+            let parsed = addr.parse::<SocketAddr>();
+            let mut node = admin.node.write().unwrap();
+            let before = node.settings.nodes.len();
+            node.settings
+                .nodes
+                .retain(|peer| parsed.as_ref().map_or(true, |addr| peer.addr != *addr));
+            if node.settings.nodes.len() == before {
+                return Err(Error::UnknownNode(addr));
+            }
+
+            info!("No longer acknowledging the node at {}", addr);
+            Ok(vec![])
+        }
+
+        STATS => serde_json::to_vec(&crash::Snapshot::take()).map_err(Error::Json),
+        SHUTDOWN => {
+            admin.shutdown.stop();
+            Ok(vec![])
+        }
+
+        RELOAD => {
+            let path = match &admin.source {
+                Some(path) => path.clone(),
+                None => return Err(Error::NoSource("The settings were not loaded from a file")),
+            };
+
+            let settings = Settings::try_from(path).map_err(Error::Settings)?;
+            let restart = admin.node.write().unwrap().settings.reload(settings);
+            info!("Reloaded the settings");
+            if !restart.is_empty() {
+                warn!(
+                    "Settings which need a restart changed: {}",
+                    restart.join(", ")
+                );
+            }
+
+            serde_json::to_vec(&restart).map_err(Error::Json)
+        }
+
+        TOKENS => {
+            let node = admin.node.read().unwrap();
+            let tokens: Vec<Listed> = node
+                .settings
+                .auth
+                .tokens
+                .iter()
+                .map(|token| Listed {
+                    id: token.id.clone(),
+                    subject: token.subject.clone(),
+                })
+                .collect();
+            serde_json::to_vec(&tokens).map_err(Error::Json)
+        }
+
+        ADD_TOKEN => {
+            let mut token: Token = serde_json::from_slice(payload).map_err(Error::Json)?;
+            let id = token
+                .id
+                .get_or_insert_with(|| ulid::Ulid::new().to_string())
+                .clone();
+            let tokens = {
+                let mut node = admin.node.write().unwrap();
+                let tokens = &mut node.settings.auth.tokens;
+                if tokens.iter().any(|known| known.id.as_ref() == Some(&id)) {
+                    return Err(Error::Duplicate("A token with the same ID exists already"));
+                }
+                if tokens.iter().any(|known| known.secret == token.secret) {
+                    return Err(Error::Duplicate(
+                        "A token with the same secret exists already",
+                    ));
+                }
+
+                info!("Adding token {} of {}", id, token.subject);
+                tokens.push(token);
+                tokens.clone()
+            };
+
+            // The token is taken back if it cannot be saved, so that a failed
+            // request leaves the node as it was.
+            if let Err(e) = persist(admin, &tokens) {
+                let mut node = admin.node.write().unwrap();
+                let tokens = &mut node.settings.auth.tokens;
+                tokens.retain(|token| token.id.as_ref() != Some(&id));
+                return Err(e);
+            }
+
+            serde_json::to_vec(&id).map_err(Error::Json)
+        }
+
+        REVOKE_TOKEN => {
+            let id = String::from_utf8_lossy(payload).to_string();
+            let (revoked, tokens) = {
+                let mut node = admin.node.write().unwrap();
+                let tokens = &mut node.settings.auth.tokens;
+                let (revoked, kept): (Vec<_>, Vec<_>) = std::mem::take(tokens)
+                    .into_iter()
+                    .partition(|token| token.id.as_ref() == Some(&id));
+                *tokens = kept;
+                if revoked.is_empty() {
+                    return Err(Error::UnknownToken(id));
+                }
+
+                info!("Revoked token {}", id);
+                (revoked, tokens.clone())
+            };
+
+            // The token is restored if the revocation cannot be saved.
+            if let Err(e) = persist(admin, &tokens) {
+                let mut node = admin.node.write().unwrap();
+                node.settings.auth.tokens.extend(revoked);
+                return Err(e);
+            }
+
+            Ok(vec![])
+        }
+
+        SYNC_WITH => {
+            let peer = String::from_utf8_lossy(payload).to_string();
+            if !api::internal::is_acknowledged(&admin.node, &peer) {
+                return Err(Error::UnknownNode(peer));
+            }
+
+            // Admin connections are not authenticated, so the records pulled
+            // are attributed to the address of the operator.
+            let identity = Identity::Anonymous(addr);
+            let mut connection = admin.storage.connection().map_err(Error::Storage)?;
+            let summary = anti_entropy::exchange(
+                &admin.outbound,
+                &admin.node,
+                &peer,
+                &mut *connection,
+                admin.storage.keyspace(),
+                |key| {
+                    let event = Event::Created { key: key.into() };
+                    admin.events.publish(&identity, event)
+                },
+            )
+            .map_err(Error::AntiEntropy)?;
+            info!("Reconciled the records with {}", peer);
+            serde_json::to_vec(&summary).map_err(Error::Json)
+        }
+
+        code => Err(Error::UnknownCommand(code)),
+    }
+}
+
+/// Writes the tokens back to the file the settings were loaded from, so that
+/// they survive a restart. Only the tokens of the file are replaced, so that
+/// other changes made at runtime, such as acknowledged nodes, are not written
+/// along with them. The file is replaced as a whole, so that it is never left
+/// half-written. Settings which were not loaded from a file only change until
+/// the node is restarted.
+fn persist(admin: &Context, tokens: &[Token]) -> Result<(), Error> {
+    let path = match &admin.source {
+        Some(path) => path,
+        None => {
+            warn!("The settings were not loaded from a file, so the change is lost on restart");
+            return Ok(());
+        }
+    };
+
+    let mut settings = Settings::try_from(path.clone()).map_err(Error::Settings)?;
+    settings.auth.tokens = tokens.to_vec();
+    let mut partial = path.clone().into_os_string();
+    partial.push(".partial");
+    std::fs::write(&partial, settings.to_string()).map_err(Error::Io)?;
+    std::fs::rename(&partial, path).map_err(Error::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdk;
+    use crate::testing::{FakePeer, Reply};
+    use std::time::Duration;
+
+    #[test]
+    fn test_admin() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        settings.admin = Some(crate::settings::Admin {
+            addr: "127.0.0.1:0".parse().unwrap(),
+        });
+        let node = Node::new(settings);
+        let local = node.local();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || tx.send(node.start(Some(2)).is_ok()));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Admin requests are unknown to the public listener.
+        let mut public = sdk::Client::connect(local.addr().unwrap()).unwrap();
+        assert!(matches!(
+            public.request(PEERS, &[]),
+            Err(sdk::Error::Status(1))
+        ));
+
+        let mut admin = sdk::Admin::connect(local.admin_addr().unwrap()).unwrap();
+        assert_eq!(admin.peers().unwrap(), vec![]);
+        let peer: Peer = serde_json::from_str(r#""127.0.0.1:7000""#).unwrap();
+        admin.add_peer(&peer).unwrap();
+        assert!(matches!(
+            admin.add_peer(&peer),
+            Err(sdk::Error::Rejected(_))
+        ));
+        assert_eq!(admin.peers().unwrap(), vec![peer]);
+        admin.remove_peer("127.0.0.1:7000").unwrap();
+        assert!(matches!(
+            admin.remove_peer("127.0.0.1:7000"),
+            Err(sdk::Error::Rejected(_))
+        ));
+
+        // Exchanges are only run with acknowledged nodes, and only on behalf
+        // of the admin listener.
+        let peer = FakePeer::bind([(anti_entropy::INVENTORY, Reply::ok(""))]).unwrap();
+        assert!(matches!(
+            admin.sync_with(&peer.addr().to_string()),
+            Err(sdk::Error::Rejected(_))
+        ));
+        admin
+            .add_peer(&serde_json::from_str(&format!(r#""{}""#, peer.addr())).unwrap())
+            .unwrap();
+        let summary = admin.sync_with(&peer.addr().to_string()).unwrap();
+        assert_eq!((summary.pulled, summary.pushed), (0, 0));
+        assert!(matches!(
+            public.request(SYNC_WITH, peer.addr().to_string().as_bytes()),
+            Err(sdk::Error::Status(1))
+        ));
+
+        assert!(admin.stats().is_ok());
+        // The settings of the node were not loaded from a file.
+        assert!(matches!(admin.reload(), Err(sdk::Error::Rejected(_))));
+        admin.stop().unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("multiverse9_{}.json", ulid::Ulid::new()));
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        settings.admin = Some(crate::settings::Admin {
+            addr: "127.0.0.1:0".parse().unwrap(),
+        });
+        std::fs::write(&path, settings.to_string()).unwrap();
+
+        let node = Node::new(settings).with_source(&path);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut settings = Settings::try_from(path.clone()).unwrap();
+        settings.name = "multiverse9_renamed".into();
+        settings.nodes = vec![serde_json::from_str(r#""127.0.0.1:7000""#).unwrap()];
+        std::fs::write(&path, settings.to_string()).unwrap();
+
+        let mut admin = sdk::Admin::connect(local.admin_addr().unwrap()).unwrap();
+        assert_eq!(admin.reload().unwrap(), vec!["name".to_string()]);
+        assert_eq!(admin.peers().unwrap(), settings.nodes);
+        admin.stop().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tokens() {
+        let path = std::env::temp_dir().join(format!("multiverse9_{}.json", ulid::Ulid::new()));
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        settings.admin = Some(crate::settings::Admin {
+            addr: "127.0.0.1:0".parse().unwrap(),
+        });
+        settings.auth.tokens = vec![Token {
+            id: Some("old".into()),
+            subject: "frontend".into(),
+            secret: "old-secret".into(),
+        }];
+        std::fs::write(&path, settings.to_string()).unwrap();
+
+        let node = Node::new(settings).with_source(&path);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Rotating the secret by adding its successor before revoking it.
+        let mut admin = sdk::Admin::connect(local.admin_addr().unwrap()).unwrap();
+        let token = Token {
+            id: None,
+            subject: "frontend".into(),
+            secret: "new-secret".into(),
+        };
+        // Nodes acknowledged at runtime are not persisted along with the tokens.
+        let peer: Peer = serde_json::from_str(r#""127.0.0.1:7000""#).unwrap();
+        admin.add_peer(&peer).unwrap();
+        let id = admin.add_token(&token).unwrap();
+        assert!(matches!(
+            admin.add_token(&token),
+            Err(sdk::Error::Rejected(_))
+        ));
+        let mut client = sdk::Client::connect(local.addr().unwrap()).unwrap();
+        assert_eq!(
+            client.authenticate("new-secret").unwrap(),
+            "subject:frontend"
+        );
+
+        admin.revoke_token("old").unwrap();
+        assert!(matches!(
+            admin.revoke_token("old"),
+            Err(sdk::Error::Rejected(_))
+        ));
+        let mut client = sdk::Client::connect(local.addr().unwrap()).unwrap();
+        assert!(matches!(
+            client.authenticate("old-secret"),
+            Err(sdk::Error::Status(crate::auth::UNAUTHORIZED))
+        ));
+
+        let listed = Listed {
+            id: Some(id),
+            subject: "frontend".into(),
+        };
+        assert_eq!(admin.tokens().unwrap(), vec![listed]);
+        // The tokens survive a restart.
+        let persisted = Settings::try_from(path.clone()).unwrap();
+        assert_eq!(persisted.auth.tokens.len(), 1);
+        assert_eq!(persisted.auth.tokens[0].secret, "new-secret");
+        assert!(persisted.nodes.is_empty());
+
+        // Tokens which cannot be saved are not added either.
+        std::fs::remove_file(&path).unwrap();
+        let token = Token {
+            id: None,
+            subject: "frontend".into(),
+            secret: "unsaved-secret".into(),
+        };
+        assert!(matches!(
+            admin.add_token(&token),
+            Err(sdk::Error::Rejected(_))
+        ));
+        assert_eq!(admin.tokens().unwrap().len(), 1);
+        admin.stop().unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+//! Anti-entropy reconciles the records of current node with those of an
+//! acknowledged node, so that records which one of them missed, for example
+//! while it was down, end up on both.
+//!
+//! An exchange compares the IDs of the records on both nodes. The records
+//! missing locally are aggregated from the remote node and stored under the
+//! same keys, along with their owners and signatures. The records missing on
+//! the remote node are sent to it with a [REPLICATE] request, whose payload
+//! holds them in the format of aggregated entries. Records which exist on both
+//! nodes are left alone, so an exchange never overwrites anything.
+//!
+//! Operators run an exchange on demand with a [crate::admin::SYNC_WITH]
+//! request on the admin listener, whose payload is the address of the
+//! acknowledged node. Its response is a [Summary] encoded as JSON.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use crate::node::Node;
+use crate::sdk::response::Record;
+use crate::storage::{self, Connection, Keyspace};
+use crate::{api, outbound, sdk};
+
+/// Request code for listing the IDs of the records of a node, separated by
+/// null bytes.
+pub const INVENTORY: u16 = 0x001B;
+
+/// Request code for storing the records of another node which are missing on
+/// a node. Its response is the number of records stored.
+pub const REPLICATE: u16 = 0x001C;
+
+/// The most records aggregated from or replicated to the remote node in a
+/// single request.
+const CHUNK_LEN: usize = 64;
+
+/// Who the content-addressed records stored by an exchange are referenced by,
+/// unless they are owned by a user.
+const REFERRER: &str = "anti-entropy";
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .Storage(storage::Error) [source]
+    .Sdk(sdk::Error) [source]
+    ~Debug
+}
+
+/// The outcome of an exchange with a remote node.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Summary {
+    /// Number of records stored on current node.
+    pub pulled: usize,
+    /// Number of records the remote node stored.
+    pub pushed: usize,
+    /// Number of bytes of the entries received from and sent to the remote
+    /// node.
+    pub bytes: usize,
+    /// Duration of the exchange in milliseconds.
+    pub duration: u64,
+}
+
+/// Returns the IDs of the records of the instance, in no particular order.
+pub(crate) fn inventory(
+    connection: &mut dyn Connection,
+    keyspace: &Keyspace,
+) -> Result<Vec<String>, storage::Error> {
+    Ok(connection
+        .keys(keyspace)?
+        .into_iter()
+        .filter(|key| is_record(key))
+        .collect())
+}
+
+/// Parses the records out of aggregated entries. Entries which are malformed,
+/// or which stand for a key the remote node does not know, are left out. The
+/// interactions carried by the entries are ignored, since they are counted by
+/// every node on its own.
+pub(crate) fn records(entries: &[u8]) -> Vec<Record> {
+    entries
+        .split(|c| *c == 00)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(Record::decode)
+        .filter(|record| !record.is_unknown())
+        .collect()
+}
+
+/// Stores a record of another node, unless a record with the same key exists
+/// already. Content-addressed records whose value does not match their key are
+/// rejected.
+///
+/// # Returns
+///
+/// Whether the record was stored.
+pub(crate) fn store(
+    connection: &mut dyn Connection,
+    keyspace: &Keyspace,
+    record: &Record,
+) -> Result<bool, storage::Error> {
+    if !is_record(&record.key) || connection.exists(keyspace, &record.key)? {
+        return Ok(false);
+    }
+
+    let owner = record.owner.as_deref();
+    let signature = record.signature.as_deref();
+    match ulid::Ulid::from_string(&record.key) {
+        Ok(id) => {
+            connection.create(keyspace, &id, &record.value, signature, owner)?;
+            Ok(true)
+        }
+        Err(_) if crate::content::verify(&record.key, &record.value) => connection.create_content(
+            keyspace,
+            &record.key,
+            &record.value,
+            signature,
+            owner,
+            owner.unwrap_or(REFERRER),
+        ),
+        Err(_) => {
+            log::warn!("Record {} does not match its content", record.key);
+            Ok(false)
+        }
+    }
+}
+
+/// Runs an exchange with the acknowledged node at the given address over the
+/// pool, calling `pulled` with the key of every record stored on current node.
+pub(crate) fn exchange(
+    pool: &outbound::Pool,
+    node: &Arc<RwLock<Node>>,
+    addr: &str,
+    connection: &mut dyn Connection,
+    keyspace: &Keyspace,
+    mut pulled: impl FnMut(&str),
+) -> Result<Summary, Error> {
+    let started = Instant::now();
+    let remote: HashSet<String> = pool
+        .with(node, addr, |client, _| client.inventory())
+        .map_err(Error::Sdk)?
+        .into_iter()
+        .collect();
+    let local: HashSet<String> = inventory(connection, keyspace)
+        .map_err(Error::Storage)?
+        .into_iter()
+        .collect();
+
+    let mut summary = Summary::default();
+    let mut missing: Vec<&String> = remote.difference(&local).collect();
+    missing.sort();
+    for chunk in missing.chunks(CHUNK_LEN) {
+        let keys: Vec<&str> = chunk.iter().map(|key| key.as_str()).collect();
+        let entries = pool
+            .with(node, addr, |client, _| client.aggregate_entries(&keys))
+            .map_err(Error::Sdk)?;
+        summary.bytes += entries.len();
+        for record in records(&entries) {
+            if store(connection, keyspace, &record).map_err(Error::Storage)? {
+                summary.pulled += 1;
+                pulled(&record.key);
+            }
+        }
+    }
+
+    let mut extra: Vec<&String> = local.difference(&remote).collect();
+    extra.sort();
+    for chunk in extra.chunks(CHUNK_LEN) {
+        let mut entries = vec![];
+        for key in chunk {
+            api::internal::push_record(connection, keyspace, &mut entries, key)
+                .map_err(Error::Storage)?;
+        }
+
+        if entries.is_empty() {
+            continue;
+        }
+
+        summary.bytes += entries.len();
+        summary.pushed += pool
+            .with(node, addr, |client, _| client.replicate(&entries))
+            .map_err(Error::Sdk)?;
+    }
+
+    summary.duration = started.elapsed().as_millis() as u64;
+    Ok(summary)
+}
+
+/// Returns whether the key relative to the namespace is the key of a record.
+pub(crate) fn is_record(key: &str) -> bool {
+    crate::content::is_key(key)
+        || (key.len() == ulid::ULID_LEN && ulid::Ulid::from_string(key).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{Partition, Settings};
+    use crate::storage::memory::Memory;
+    use crate::storage::Backend;
+    use crate::testing::{FakePeer, Reply};
+
+    #[test]
+    fn test_records() {
+        let entries = b"01ARZ3NDEKTSV4RRFFQ69G5FAV~alice+2,1#abcd:value1\x00\
+                        01ARZ3NDEKTSV4RRFFQ69G5FAW:Unknown key\x00\
+                        01ARZ3NDEKTSV4RRFFQ69G5FAX#zz:value3\x00\
+                        01ARZ3NDEKTSV4RRFFQ69G5FAY:value4\x00";
+        assert_eq!(
+            records(entries),
+            vec![
+                Record {
+                    key: "01ARZ3NDEKTSV4RRFFQ69G5FAV".into(),
+                    owner: Some("alice".into()),
+                    likes: 2,
+                    replies: 1,
+                    signature: Some(vec![0xab, 0xcd]),
+                    value: b"value1".to_vec(),
+                },
+                Record {
+                    key: "01ARZ3NDEKTSV4RRFFQ69G5FAY".into(),
+                    owner: None,
+                    likes: 0,
+                    replies: 0,
+                    signature: None,
+                    value: b"value4".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exchange() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Hour,
+        };
+        let mut connection = Memory::default().connection().unwrap();
+        let (shared, local) = (ulid::Ulid::from_parts(1, 1), ulid::Ulid::from_parts(1, 2));
+        for id in [shared, local] {
+            connection
+                .create(&keyspace, &id, b"local", None, Some("alice"))
+                .unwrap();
+        }
+
+        let content = crate::content::key(b"remote");
+        let remote = ulid::Ulid::from_parts(1, 3);
+        let inventory = format!("{}\x00{}\x00{}", shared, remote, content);
+        let entries = format!(
+            "{}~bob#abcd:remote\x00{}:forged\x00",
+            remote,
+            crate::content::key(b"other")
+        );
+        let peer = FakePeer::bind([
+            (INVENTORY, Reply::ok(inventory)),
+            (0x0003, Reply::ok(entries)),
+            (REPLICATE, Reply::ok("1")),
+        ])
+        .unwrap();
+
+        let node = Arc::new(RwLock::new(Node::new(
+            Settings::new("memory://".into()).unwrap(),
+        )));
+        let pool = outbound::Pool::new(sdk::Connector::Plain, 1);
+        let mut keys = vec![];
+        let summary = exchange(
+            &pool,
+            &node,
+            &peer.addr().to_string(),
+            connection.as_mut(),
+            &keyspace,
+            |key| keys.push(key.to_string()),
+        )
+        .unwrap();
+
+        assert_eq!(summary.pulled, 1);
+        assert_eq!(summary.pushed, 1);
+        assert_eq!(keys, vec![remote.to_string()]);
+        let key = remote.to_string();
+        assert_eq!(connection.get(&keyspace, &key).unwrap().unwrap(), b"remote");
+        assert_eq!(connection.owner(&keyspace, &key).unwrap().unwrap(), "bob");
+        assert_eq!(
+            connection.signature(&keyspace, &key).unwrap().unwrap(),
+            vec![0xab, 0xcd]
+        );
+
+        let requests = peer.requests();
+        assert_eq!(requests[0], (INVENTORY, vec![]));
+        assert_eq!(requests[1].0, 0x0003);
+        assert_eq!(
+            requests[2],
+            (REPLICATE, format!("{}~alice:local\x00", local).into_bytes())
+        );
+    }
+}
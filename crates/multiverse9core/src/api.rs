@@ -1,11 +1,23 @@
-use crate::protocol::Packet;
-use crate::{sdk, Tcp};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
-use redis::Commands;
-use std::io;
+use crate::capabilities::Capabilities;
+use crate::envelope::{self, Envelope};
+use crate::events::Event;
+use crate::keys::Keypair;
+use crate::pagination::{self, Paging};
+use crate::protocol::{codec, Identity, Metadata, Packet};
+use crate::roles::Role;
+use crate::sdk::response::Record;
+use crate::settings::Addressing;
+use crate::storage::{Append, Relation};
+use crate::{
+    anti_entropy, bulk, deadline, feed, health, journal, listing, placement, quorum, sdk, search,
+    sharding, storage, tags, users, views,
+};
 
 /// This module contains private helper functions used within [api](crate::api).
-mod internal {
+pub(crate) mod internal {
     /// Extracts target keys from the provided buffer.
     ///
     /// # Arguments
@@ -43,6 +55,224 @@ mod internal {
         targets
     }
 
+    /// Returns whether the node at the given address is acknowledged by current
+    /// node.
+    pub fn is_acknowledged(
+        node: &std::sync::Arc<std::sync::RwLock<crate::node::Node>>,
+        addr: &str,
+    ) -> bool {
+        match addr.parse::<std::net::SocketAddr>() {
+            Ok(addr) => {
+                let node = node.read().unwrap();
+                node.settings.nodes.iter().any(|peer| peer.addr == addr)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Returns whether the acknowledged node with the given public key is at the
+    /// given address. Nodes relaying requests on behalf of their users may only
+    /// speak for the users registered on themselves.
+    pub fn is_node_at(
+        node: &std::sync::Arc<std::sync::RwLock<crate::node::Node>>,
+        public: &str,
+        addr: &str,
+    ) -> bool {
+        match addr.parse::<std::net::SocketAddr>() {
+            Ok(addr) => {
+                let node = node.read().unwrap();
+                node.settings.nodes.iter().any(|peer| {
+                    peer.addr == addr
+                        && peer
+                            .key
+                            .as_ref()
+                            .is_some_and(|key| key.eq_ignore_ascii_case(public))
+                })
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Appends an entry of a local record to the reply, i.e. `key:value`, with
+    /// `~owner` after the key for records created by users, `+likes,replies`
+    /// after that for records which have been interacted with, and `#signature`
+    /// last for signed records, followed by a null byte.
+    pub fn push_entry(
+        reply: &mut Vec<u8>,
+        key: &str,
+        owner: Option<&str>,
+        interactions: crate::storage::Interactions,
+        value: &[u8],
+        signature: Option<&[u8]>,
+    ) {
+        reply.extend(key.as_bytes());
+        if let Some(owner) = owner {
+            reply.push(super::OWNER_DELIMITER);
+            reply.extend(owner.as_bytes());
+        }
+        if interactions != Default::default() {
+            reply.push(super::INTERACTIONS_DELIMITER);
+            reply.extend(format!("{},{}", interactions.likes, interactions.replies).as_bytes());
+        }
+        if let Some(signature) = signature {
+            reply.push(super::SIGNATURE_DELIMITER);
+            reply.extend(crate::keys::hex(signature).as_bytes());
+        }
+
+        reply.push(b':');
+        reply.extend(value);
+        reply.push(00);
+    }
+
+    /// Appends the entry of the local record with the given key to the reply.
+    ///
+    /// # Returns
+    ///
+    /// `false` without appending anything if the record does not exist.
+    pub fn push_record(
+        storage: &mut dyn crate::storage::Connection,
+        keyspace: &crate::storage::Keyspace,
+        reply: &mut Vec<u8>,
+        key: &str,
+    ) -> Result<bool, crate::storage::Error> {
+        let value = match storage.get(keyspace, key)? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+
+        let owner = storage.owner(keyspace, key)?;
+        let interactions = storage.interactions(keyspace, key)?;
+        let signature = storage.signature(keyspace, key)?;
+        push_entry(
+            reply,
+            key,
+            owner.as_deref(),
+            interactions,
+            &value,
+            signature.as_deref(),
+        );
+        Ok(true)
+    }
+
+    /// Verifies the signatures of the entries aggregated from a remote node,
+    /// by checking the signature of every signed entry with `verify`, which is
+    /// given the key, the owner, the value and the signature of the entry.
+    ///
+    /// # Returns
+    ///
+    /// The entries without their signatures. The values of entries whose
+    /// signature does not match are replaced, so that tampered content is
+    /// never passed on. Unsigned entries are passed on as they are.
+    pub fn verify_entries(
+        reply: &[u8],
+        verify: impl Fn(&str, Option<&str>, &[u8], &[u8]) -> bool,
+    ) -> Vec<u8> {
+        let mut verified = vec![];
+        for entry in buf_extract_targets(reply) {
+            let split = entry.iter().position(|c| *c == b':').unwrap_or(entry.len());
+            let (head, value) = (&entry[..split], entry.get(split + 1..).unwrap_or(&[]));
+            let mut head = head.splitn(2, |c| *c == super::SIGNATURE_DELIMITER);
+            let attributed = String::from_utf8_lossy(head.next().unwrap_or(&[])).to_string();
+            // The interactions are not signed, since they change after the record
+            // has been created.
+            let signed = match attributed.split_once(super::INTERACTIONS_DELIMITER as char) {
+                Some((signed, _)) => signed,
+                None => attributed.as_str(),
+            };
+            let (key, owner) = match signed.split_once(super::OWNER_DELIMITER as char) {
+                Some((key, owner)) => (key, Some(owner)),
+                None => (signed, None),
+            };
+
+            let intact = match head.next() {
+                Some(signature) => crate::keys::unhex(&String::from_utf8_lossy(signature))
+                    .map(|signature| verify(key, owner, value, &signature))
+                    .unwrap_or(false),
+                None => true,
+            };
+
+            verified.extend(attributed.as_bytes());
+            verified.push(b':');
+            if intact {
+                verified.extend(value);
+            } else {
+                log::warn!("Record {} has an invalid signature", key);
+                verified.extend(super::INVALID_SIGNATURE);
+            }
+
+            verified.push(00);
+        }
+
+        verified
+    }
+
+    /// Re-hashes the values of the content-addressed entries aggregated from a
+    /// remote node, whose signatures have been stripped already.
+    ///
+    /// # Returns
+    ///
+    /// The entries, with the values of content-addressed entries which do not
+    /// match their key replaced. Entries keyed by ULIDs are passed on as they
+    /// are.
+    pub fn verify_content(reply: &[u8]) -> Vec<u8> {
+        let mut verified = vec![];
+        for entry in buf_extract_targets(reply) {
+            let split = entry.iter().position(|c| *c == b':').unwrap_or(entry.len());
+            let (head, value) = (&entry[..split], entry.get(split + 1..).unwrap_or(&[]));
+            let key = entry_key(head);
+
+            verified.extend(head);
+            verified.push(b':');
+            if !crate::content::is_key(&key) || crate::content::verify(&key, value) {
+                verified.extend(value);
+            } else {
+                log::warn!("Record {} does not match its content", key);
+                verified.extend(super::INVALID_CONTENT);
+            }
+
+            verified.push(00);
+        }
+
+        verified
+    }
+
+    /// Transforms the values of the entries with the view. Signatures are
+    /// dropped, since they do not cover the transformed values.
+    pub fn apply_view(reply: &[u8], view: &dyn crate::views::View) -> Vec<u8> {
+        let mut transformed = vec![];
+        for entry in buf_extract_targets(reply) {
+            let split = entry.iter().position(|c| *c == b':').unwrap_or(entry.len());
+            let (head, value) = (&entry[..split], entry.get(split + 1..).unwrap_or(&[]));
+            let head = match head.iter().position(|c| *c == super::SIGNATURE_DELIMITER) {
+                Some(end) => &head[..end],
+                None => head,
+            };
+
+            transformed.extend(head);
+            transformed.push(b':');
+            transformed.extend(view.apply(&entry_key(head), value));
+            transformed.push(00);
+        }
+
+        transformed
+    }
+
+    /// Returns the key from the part of an entry before its value.
+    fn entry_key(head: &[u8]) -> String {
+        let end = head
+            .iter()
+            .position(|c| {
+                [
+                    super::OWNER_DELIMITER,
+                    super::INTERACTIONS_DELIMITER,
+                    super::SIGNATURE_DELIMITER,
+                ]
+                .contains(c)
+            })
+            .unwrap_or(head.len());
+        String::from_utf8_lossy(&head[..end]).to_string()
+    }
+
     #[cfg(test)]
     mod tests {
         #[test]
@@ -57,81 +287,647 @@ mod internal {
             let buffer = b"key1@addr1\x00key2\x00key3@addr3\x00";
             assert_eq!(super::buf_extract_targets(buffer), expected);
         }
+
+        #[test]
+        fn test_push_entry() {
+            let mut reply = vec![];
+            let interactions = crate::storage::Interactions {
+                likes: 2,
+                replies: 1,
+            };
+            super::push_entry(
+                &mut reply,
+                "key1",
+                None,
+                Default::default(),
+                b"value1",
+                None,
+            );
+            super::push_entry(
+                &mut reply,
+                "key2",
+                Some("alice"),
+                interactions,
+                b"value2",
+                Some(&[0xAB]),
+            );
+            assert_eq!(reply, b"key1:value1\x00key2~alice+2,1#ab:value2\x00");
+        }
+
+        #[test]
+        fn test_verify_entries() {
+            let keypair = crate::keys::Keypair::generate();
+            let signature = crate::keys::hex(&keypair.sign_record("key1", None, b"value1"));
+            let owned = crate::keys::hex(&keypair.sign_record("key4", Some("alice"), b"value4"));
+            let reply = format!(
+                "key1#{0}:value1\x00key2#{0}:value2\x00key3:value3\x00\
+                 key4~alice+2,1#{1}:value4\x00key4~bob#{1}:value4\x00",
+                signature, owned
+            );
+
+            assert_eq!(
+                super::verify_entries(reply.as_bytes(), |key, owner, value, signature| {
+                    crate::keys::verify_record(&keypair.public(), key, owner, value, signature)
+                }),
+                b"key1:value1\x00key2:Invalid signature\x00key3:value3\x00\
+                  key4~alice+2,1:value4\x00key4~bob:Invalid signature\x00"
+            );
+        }
+
+        #[test]
+        fn test_apply_view() {
+            let view = |key: &str, value: &[u8]| format!("{}={}", key, value.len()).into_bytes();
+            assert_eq!(
+                super::apply_view(b"key1~alice+2,1#abcd:value1\x00key2:v\x00", &view),
+                b"key1~alice+2,1:key1=6\x00key2:key2=1\x00"
+            );
+        }
+
+        #[test]
+        fn test_verify_content() {
+            let key = crate::content::key(b"value1");
+            let ulid = ulid::Ulid::new();
+            let reply = format!(
+                "{0}~alice+2,1:value1\x00{0}:value2\x00{1}:value3\x00",
+                key, ulid
+            );
+
+            assert_eq!(
+                super::verify_content(reply.as_bytes()),
+                format!(
+                    "{0}~alice+2,1:value1\x00{0}:Invalid content\x00{1}:value3\x00",
+                    key, ulid
+                )
+                .into_bytes()
+            );
+        }
     }
 }
 
-crate::enum_with_impl_to_string! {
+crate::enum_with_impl_error! {
     pub Error,
-    .Sdk(sdk::Error)
+    .Sdk(sdk::Error) [source]
     .InvalidKey(String)
     .EmptyKeys(&'static str)
-    .Redis(redis::RedisError)
+    .Storage(storage::Error) [source]
     .EmptyBuffer(&'static str)
+    .Forbidden(&'static str)
+    .Keys(crate::keys::Error) [source]
+    .InvalidFeed(String)
+    .InvalidCount(String)
+    .UnknownNode(String)
+    .InvalidHandle(String)
+    .InvalidSignature(&'static str)
+    .AlreadyRegistered(String)
+    .UnknownUser(String)
+    .InvalidActor(String)
+    .UnknownUpload(String)
+    .UploadTooLarge(usize)
+    .UnknownView(String)
+    .InvalidQuorum(String)
+    .NoQuorum(String)
+    .Forwarded(u8)
+    .Streaming(std::io::Error) [source]
+    .InvalidTag(String)
+    .InvalidQuery(String)
+    .NoSearch(&'static str)
+    .TooManyRecords(usize)
+    .NoChangelog(&'static str)
+    .InvalidCursor(String)
+    .Changelog(std::io::Error) [source]
+    .Envelope(envelope::Error) [source]
     ~Debug
 }
 
+/// Separates the key of an aggregated entry from the hex-encoded signature of
+/// the record, i.e. `key#signature:value`. Entries of unsigned records carry no
+/// signature.
+pub const SIGNATURE_DELIMITER: u8 = b'#';
+
+/// Separates the key of an aggregated entry from the handle of the user who
+/// created the record, i.e. `key~owner:value`. It precedes the signature of
+/// signed records.
+pub const OWNER_DELIMITER: u8 = b'~';
+
+/// Separates the key and owner of an aggregated entry from the number of likes
+/// of and replies to the record, i.e. `key+likes,replies:value`. Entries of
+/// records without any interactions carry no counts.
+pub const INTERACTIONS_DELIMITER: u8 = b'+';
+
+/// The value of aggregated entries standing for a key which current node does
+/// not know.
+pub const UNKNOWN_KEY: &[u8] = b"Unknown key";
+
+/// The value of entries aggregated from remote nodes whose signature does not
+/// match, in place of the tampered value.
+pub const INVALID_SIGNATURE: &[u8] = b"Invalid signature";
+
+/// The value of content-addressed entries aggregated from remote nodes which
+/// do not match their key, in place of the tampered value.
+pub const INVALID_CONTENT: &[u8] = b"Invalid content";
+
 pub type HandlerOutputCodes = (u8, u8);
 pub type HandlerResult = Result<Vec<u8>, Error>;
 pub type HandlerFn = fn(Packet) -> HandlerResult;
 
 #[inline(always)]
-/// Sends an error response `(1, 1)` if an unknown command is received.
-pub fn unknown_command(p: Packet) -> io::Result<()> {
-    Tcp::write(p.stream, &[1, 1])
+/// Encodes the error response with status `1` which is sent if an unknown
+/// command is received.
+pub fn unknown_command(p: Packet) -> Result<Vec<u8>, codec::Error> {
+    codec::encode_response(1, &p.code.to_be_bytes())
 }
 
-/// A lookup table mapping request codes to handler functions. Used to determine
-/// the appropriate handler function to call for a given request.
-pub const HANDLER_LOOKUP_TABLE: phf::Map<u8, HandlerFn> = phf::phf_map! {
-    0x0001u8 => create,
-    0x0002u8 => remove,
-    0x0003u8 => aggregate,
-};
+/// How requests with a given code are handled.
+pub struct Route {
+    /// The handler function the request is executed by.
+    pub handler: HandlerFn,
+    /// The response statuses sent when the handler succeeds and fails.
+    pub codes: HandlerOutputCodes,
+    /// The role a connection needs to issue the request. See [crate::roles].
+    pub role: Role,
+}
 
-/// A lookup table mapping request codes to response codes. Used to determine
-/// the appropriate response for a given request.
-pub const CODE_LOOKUP_TABLE: phf::Map<u8, HandlerOutputCodes> = phf::phf_map! {
-    0x0001u8 => (0, 1),
-    0x0002u8 => (0, 1),
-    0x0003u8 => (0, 1),
-};
+impl Route {
+    /// Routes requests to the handler, replying with status `0` on success and
+    /// `1` on failure.
+    const fn new(handler: HandlerFn, role: Role) -> Self {
+        Self {
+            handler,
+            codes: (0, 1),
+            role,
+        }
+    }
+}
 
-/// Compile-time length equality assertion for the lookup tables.
-const _: () = assert!(CODE_LOOKUP_TABLE.len() == HANDLER_LOOKUP_TABLE.len());
+/// A lookup table mapping request codes to the way they are handled. Codes are
+/// taken from the ranges of [codec::CORE] and [codec::FEDERATION], while codes of
+/// [codec::EXTENSION] are never routed here.
+pub const HANDLER_LOOKUP_TABLE: phf::Map<u16, Route> = phf::phf_map! {
+    // Records, feeds and users.
+    0x0001u16 => Route::new(create, Role::Write),
+    0x0002u16 => Route::new(remove, Role::Delete),
+    0x0003u16 => Route::new(aggregate, Role::Read),
+    0x0005u16 => Route::new(metadata, Role::Read),
+    0x0007u16 => Route::new(post, Role::Write),
+    0x0008u16 => Route::new(latest, Role::Read),
+    0x000Au16 => Route::new(register, Role::Write),
+    0x000Bu16 => Route::new(profile, Role::Read),
+    0x000Cu16 => Route::new(follow, Role::Write),
+    0x000Du16 => Route::new(unfollow, Role::Write),
+    0x000Eu16 => Route::new(followers, Role::Read),
+    0x000Fu16 => Route::new(following, Role::Read),
+    0x0011u16 => Route::new(like, Role::Write),
+    0x0012u16 => Route::new(reply, Role::Write),
+    0x0013u16 => Route::new(replies, Role::Read),
+    0x0015u16 => Route::new(begin, Role::Write),
+    0x0016u16 => Route::new(chunk, Role::Write),
+    0x0017u16 => Route::new(commit, Role::Write),
+    0x0019u16 => Route::new(changes, Role::Read),
+    0x0021u16 => Route::new(create_envelope, Role::Write),
+    0x0029u16 => Route::new(ring, Role::Read),
+    0x002Au16 => Route::new(create_many, Role::Write),
+    0x002Cu16 => Route::new(list, Role::Read),
+    0x002Du16 => Route::new(create_tagged, Role::Write),
+    0x002Eu16 => Route::new(tagged, Role::Read),
+    0x002Fu16 => Route::new(find, Role::Read),
+    // Requests between nodes, which predate the federation range.
+    0x0014u16 => Route::new(peer_health, Role::Federate),
+    0x001Bu16 => Route::new(inventory, Role::Federate),
+    0x001Cu16 => Route::new(replicate, Role::Federate),
+    // Requests between nodes.
+    0x1000u16 => Route::new(store_forwarded, Role::Federate),
+};
 
-fn create(p: Packet) -> HandlerResult {
+fn create(mut p: Packet) -> HandlerResult {
     // The buffer cannot be empty when creating data
     if p.buffer.is_empty() {
         return Err(Error::EmptyBuffer(""));
     }
 
+    let buffer = p.buffer.clone();
+    Ok(place(&mut p, &buffer)?.into_bytes())
+}
+
+/// Creates a record wrapped in an envelope, which records the content type of
+/// the body along with when and by whom the record was created.
+fn create_envelope(mut p: Packet) -> HandlerResult {
+    let (content_type, body) = match envelope::decode_create(&p.buffer) {
+        Some(decoded) => decoded,
+        None => return Err(Error::EmptyBuffer("content type")),
+    };
+
+    let mut envelope = Envelope::new(&content_type, body);
+    envelope.author = owner(p.identity).map(String::from);
+    let value = envelope.encode().map_err(Error::Envelope)?;
+    Ok(place(&mut p, &value)?.into_bytes())
+}
+
+/// Creates several records at once, and returns their keys in the order of
+/// their values. See [bulk].
+fn create_many(mut p: Packet) -> HandlerResult {
+    let payload = p.buffer.clone();
+    let values = match bulk::decode(&payload) {
+        Some(values) if !values.is_empty() => values,
+        _ => return Err(Error::EmptyBuffer("records")),
+    };
+    if values.len() > bulk::MAX_RECORDS {
+        return Err(Error::TooManyRecords(values.len()));
+    }
+    if values.iter().any(|value| value.is_empty()) {
+        return Err(Error::EmptyBuffer("record"));
+    }
+
+    let addressing = p.node.read().unwrap().settings.addressing;
+    let keys = match addressing {
+        Addressing::Ulid => store_many(&mut p, &values)?,
+        Addressing::Content => values
+            .iter()
+            .map(|value| store_content(&mut p, value))
+            .collect::<Result<_, _>>()?,
+    };
+
+    let mut buffer = vec![];
+    for key in keys {
+        buffer.extend(key.into_bytes());
+        buffer.push(00);
+    }
+
+    Ok(buffer)
+}
+
+/// Stores the values as new records in a single transaction, the way [store]
+/// stores each of them, and returns their keys.
+fn store_many(p: &mut Packet, values: &[&[u8]]) -> Result<Vec<String>, Error> {
+    let mut records = Vec::with_capacity(values.len());
+    for value in values {
+        let id = sharding::draw(&p.node.read().unwrap());
+        let (owner, signature) = attribute(p, &id.to_string(), value)?;
+        records.push(storage::NewRecord {
+            id,
+            value,
+            signature,
+            owner,
+        });
+    }
+
+    p.storage
+        .create_many(p.keyspace, &records)
+        .map_err(Error::Storage)?;
+    let keys: Vec<String> = records.iter().map(|record| record.id.to_string()).collect();
+    for (key, value) in keys.iter().zip(values) {
+        index(p, key, value)?;
+        p.events
+            .publish(p.identity, Event::Created { key: key.clone() });
+    }
+
+    Ok(keys)
+}
+
+/// Stores the value as a new record on the node which owns its key, which is
+/// current node unless it shards its records. See [sharding].
+fn place(p: &mut Packet, value: &[u8]) -> Result<String, Error> {
+    let destinations = sharding::destinations(&p.node.read().unwrap(), p.identity, value);
+    for addr in destinations.into_iter().flatten() {
+        match p.outbound.with(&p.node, &addr.to_string(), |client, _| {
+            client.forward(value)
+        }) {
+            Ok(key) => return Ok(key),
+            Err(e) if sharding::is_failover(&e) && !deadline::exceeded() => {
+                log::warn!("Could not forward a record to {}: {}", addr, e);
+            }
+            // The node which owns the record refused it, so the client gets the
+            // same answer it would have gotten from the node itself.
+            Err(sdk::Error::Status(status)) => return Err(Error::Forwarded(status)),
+            Err(e) => return Err(Error::Sdk(e)),
+        }
+    }
+
+    store_keyed(p, value)
+}
+
+/// Stores a record forwarded by the node it was created on. See
+/// [sharding::STORE].
+fn store_forwarded(mut p: Packet) -> HandlerResult {
+    if p.buffer.is_empty() {
+        return Err(Error::EmptyBuffer(""));
+    }
+
+    let buffer = p.buffer.clone();
+    Ok(store_keyed(&mut p, &buffer)?.into_bytes())
+}
+
+/// Stores the value as a new record, keyed the way current node keys its
+/// records, and returns its key.
+fn store_keyed(p: &mut Packet, value: &[u8]) -> Result<String, Error> {
+    let addressing = p.node.read().unwrap().settings.addressing;
+    match addressing {
+        Addressing::Ulid => Ok(store(p, value)?.to_string()),
+        Addressing::Content => store_content(p, value),
+    }
+}
+
+/// Stores the value as a new record, attributed to the user the request comes
+/// from and signed if current node signs its records.
+fn store(p: &mut Packet, value: &[u8]) -> Result<ulid::Ulid, Error> {
     // Generating a unique ID for the data
-    let id = ulid::Ulid::new().to_string();
-    p.storage.set(&id, p.buffer).map_err(Error::Redis)?;
-    Ok(id.as_bytes().to_vec())
+    let id = sharding::draw(&p.node.read().unwrap());
+    let (owner, signature) = attribute(p, &id.to_string(), value)?;
+    p.storage
+        .create(p.keyspace, &id, value, signature.as_deref(), owner)
+        .map_err(Error::Storage)?;
+    index(p, &id.to_string(), value)?;
+    p.events.publish(
+        p.identity,
+        Event::Created {
+            key: id.to_string(),
+        },
+    );
+    Ok(id)
+}
+
+/// Stores the value under its content key, unless it is stored already, and
+/// references the record on behalf of the identity. The existing record keeps
+/// its owner and signature.
+fn store_content(p: &mut Packet, value: &[u8]) -> Result<String, Error> {
+    let key = crate::content::key(value);
+    let (owner, signature) = attribute(p, &key, value)?;
+    let referrer = referrer(p.identity);
+    let created = p
+        .storage
+        .create_content(
+            p.keyspace,
+            &key,
+            value,
+            signature.as_deref(),
+            owner,
+            &referrer,
+        )
+        .map_err(Error::Storage)?;
+    if created {
+        index(p, &key, value)?;
+        p.events
+            .publish(p.identity, Event::Created { key: key.clone() });
+    }
+
+    Ok(key)
+}
+
+/// Indexes the words of a record created on current node, if it keeps an
+/// index of them. See [search].
+fn index(p: &mut Packet, key: &str, value: &[u8]) -> Result<(), Error> {
+    if !p.node.read().unwrap().settings.search {
+        return Ok(());
+    }
+
+    p.storage
+        .index(p.keyspace, key, &search::terms(value))
+        .map_err(Error::Storage)
 }
 
-fn remove(p: Packet) -> HandlerResult {
-    // As of right now, only local removals are supported. However,
-    // remote removals might also become supported.
-    let keys: Vec<_> = internal::buf_extract_targets(p.buffer);
+/// Returns the owner of a record with the given key and value, and its
+/// signature if current node signs its records.
+fn attribute<'a>(
+    p: &Packet<'a>,
+    key: &str,
+    value: &[u8],
+) -> Result<(Option<&'a str>, Option<Vec<u8>>), Error> {
+    let owner = owner(p.identity);
+    let node = p.node.read().unwrap();
+    let signature = match (&node.settings.key, node.settings.sign) {
+        (Some(secret), true) => Some(
+            Keypair::from_hex(secret)
+                .map_err(Error::Keys)?
+                .sign_record(key, owner, value),
+        ),
+        _ => None,
+    };
+
+    Ok((owner, signature))
+}
+
+/// Returns the owner of the records created by the identity. Records created
+/// by users are attributed to them.
+fn owner(identity: &Identity) -> Option<&str> {
+    match identity {
+        Identity::User(handle) => Some(handle.as_str()),
+        _ => None,
+    }
+}
+
+/// Returns who the content-addressed records created by the identity are
+/// referenced by. Users reference records by their handle, the same way they
+/// own them, and anonymous connections share a single reference, since their
+/// addresses do not identify them across connections.
+fn referrer(identity: &Identity) -> String {
+    match identity {
+        Identity::User(handle) => handle.clone(),
+        Identity::Anonymous(_) => "anonymous".into(),
+        identity => identity.key(),
+    }
+}
+
+fn remove(mut p: Packet) -> HandlerResult {
+    let targets: Vec<String> = internal::buf_extract_targets(&p.buffer)
+        .iter()
+        .map(|key| String::from_utf8_lossy(key).to_string())
+        .collect();
     // If the keys vector is empty after extraction, then this operation
     // is invalid.
-    if keys.is_empty() {
+    if targets.is_empty() {
         return Err(Error::EmptyKeys(""));
     }
 
-    p.storage.del(keys).map_err(Error::Redis)?;
-    Ok(Vec::with_capacity(0))
+    // Keys with an address are removed from the node at that address, as are
+    // the keys owned by another node if current node shards its records. The
+    // other keys are removed from current node.
+    let (mut keys, mut remote) = (vec![], BTreeMap::<String, Vec<String>>::new());
+    for target in &targets {
+        let (key, addr) = match target.split_once('@') {
+            Some((key, addr)) => (key.to_string(), Some(addr.to_string())),
+            None => (target.clone(), locate(&mut p, target)?),
+        };
+
+        match addr {
+            Some(addr) => remote.entry(addr).or_default().push(key),
+            None => keys.push(key),
+        }
+    }
+
+    remove_local(&mut p, keys)?;
+
+    // Remote keys are reported one by one, since the nodes they are removed
+    // from may fail independently of each other.
+    let mut failed: HashMap<String, String> = HashMap::new();
+    for (addr, keys) in remote {
+        let result = if matches!(p.identity, Identity::User(_)) {
+            // Remote nodes cannot tell whether the user owns the records.
+            Err(Error::Forbidden(
+                "Users may only remove records of current node",
+            ))
+        } else if !internal::is_acknowledged(&p.node, &addr) {
+            Err(Error::UnknownNode(addr.clone()))
+        } else {
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            p.outbound
+                .with(&p.node, &addr, |client, _| client.remove(&keys))
+                .map_err(Error::Sdk)
+        };
+
+        match result {
+            Ok(removals) => {
+                for removal in removals.into_iter().filter(|removal| !removal.removed) {
+                    let error = removal.error.unwrap_or_default();
+                    failed.insert(format!("{}@{}", removal.key, addr), error);
+                }
+            }
+            Err(e) => {
+                log::warn!("Could not remove {} keys from {}: {}", keys.len(), addr, e);
+                for key in keys {
+                    failed.insert(format!("{}@{}", key, addr), e.to_string());
+                }
+            }
+        }
+    }
+
+    let report: Vec<sdk::Removal> = targets
+        .into_iter()
+        .map(|key| {
+            let error = failed.remove(&key);
+            sdk::Removal {
+                removed: error.is_none(),
+                key,
+                error,
+            }
+        })
+        .collect();
+    Ok(serde_json::to_vec(&report).unwrap())
+}
+
+/// Removes the records with the given keys from current node, or none of them
+/// if any of them cannot be removed.
+fn remove_local(p: &mut Packet, keys: Vec<String>) -> Result<(), Error> {
+    // Users may only remove the records they have created themselves, which
+    // for shared content includes the records they reference.
+    if let Identity::User(handle) = p.identity {
+        for key in &keys {
+            if crate::content::is_key(key)
+                && p.storage
+                    .references(p.keyspace, key)
+                    .map_err(Error::Storage)?
+                    .contains(handle)
+            {
+                continue;
+            }
+
+            let owner = p.storage.owner(p.keyspace, key).map_err(Error::Storage)?;
+            if owner.as_ref() != Some(handle) {
+                return Err(Error::Forbidden("Record is owned by another user"));
+            }
+        }
+    }
+
+    // Shared content only loses the reference of the identity, and is removed
+    // along with the last reference. Content the identity does not reference
+    // is removed regardless of its references.
+    let referrer = referrer(p.identity);
+    let (mut removed, mut deleted) = (vec![], vec![]);
+    for key in keys {
+        if crate::content::is_key(&key) {
+            match p
+                .storage
+                .dereference(p.keyspace, &key, &referrer)
+                .map_err(Error::Storage)?
+            {
+                Some(0) => removed.push(key),
+                Some(_) => {}
+                None => deleted.push(key),
+            }
+        } else {
+            deleted.push(key);
+        }
+    }
+
+    if !deleted.is_empty() {
+        p.storage
+            .del(p.keyspace, &deleted)
+            .map_err(Error::Storage)?;
+    }
+    for key in removed.into_iter().chain(deleted) {
+        p.events.publish(p.identity, Event::Removed { key });
+    }
+
+    Ok(())
 }
 
 fn aggregate(p: Packet) -> HandlerResult {
-    let targets = internal::buf_extract_targets(p.buffer);
+    let mut aggregated: Vec<u8> = vec![];
+    let footer = aggregate_each(p, &mut |entries| {
+        aggregated.extend(entries);
+        Ok(())
+    })?;
+    aggregated.extend(footer.unwrap_or_default());
+    Ok(aggregated)
+}
+
+/// Aggregates the targets of the request the way [aggregate] does, except that
+/// the entries of every key are handed to `emit` as soon as they are read,
+/// instead of being collected into a single response. See [crate::streaming].
+///
+/// # Returns
+///
+/// The footer of the page if the request is a scan. See [pagination].
+pub(crate) fn aggregate_each(
+    mut p: Packet,
+    emit: &mut dyn FnMut(&[u8]) -> Result<(), Error>,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut targets = internal::buf_extract_targets(&p.buffer);
+    // The first targets may name the view the entries are returned in, the
+    // number of replicas every key is read from, and the page of a scan.
+    // Entries of remote nodes are verified before the view is applied to them.
+    let mut view = None;
+    let mut reads = 1;
+    let mut paging: Option<Paging> = None;
+    while let Some(first) = targets.first() {
+        let first = String::from_utf8_lossy(first).to_string();
+        if let Some(name) = first.strip_prefix(views::PREFIX) {
+            let found = p.node.read().unwrap().views.get(name);
+            view = match found {
+                Some(_) if name == views::RAW => None,
+                Some(view) if feed::is_valid_name(name) => Some(view),
+                _ => return Err(Error::UnknownView(name.to_string())),
+            };
+        } else if let Some(count) = first.strip_prefix(quorum::PREFIX) {
+            reads = quorum::parse(count).ok_or_else(|| Error::InvalidQuorum(count.to_string()))?;
+        } else if !parse_paging(&first, &mut paging)? {
+            break;
+        }
+
+        targets.remove(0);
+    }
+
+    let mut emit = |entries: &[u8]| match &view {
+        Some(view) => emit(&internal::apply_view(entries, &**view)),
+        None => emit(entries),
+    };
+
+    if let Some(paging) = paging {
+        // Scans have no keys, since they read the keys of current node.
+        if !targets.is_empty() {
+            return Err(Error::InvalidCursor("Keys cannot be paged".into()));
+        }
+
+        let keys = pagination::scan(p.storage, p.keyspace, &paging).map_err(Error::Storage)?;
+        for key in &keys {
+            emit(&read_replica(&mut p, key, None)?)?;
+        }
+
+        return Ok(Some(paging.footer(&keys)));
+    }
+
     if targets.is_empty() {
         return Err(Error::EmptyKeys(""));
     }
 
-    let mut aggregated: Vec<u8> = vec![];
     for target in targets {
         if target.is_empty() {
             dbg!(target);
@@ -143,39 +939,1052 @@ fn aggregate(p: Packet) -> HandlerResult {
         // default instance where the key is going to be looked for is the current
         // node.
         let key: String = String::from_utf8_lossy(target.first().unwrap()).to_string();
-        if key.len() != ulid::ULID_LEN {
+        if key.len() != ulid::ULID_LEN && !crate::content::is_key(&key) {
             return Err(Error::InvalidKey(key));
         }
 
-        // Attempts to extract the address of the key and convert it to a String.
-        match target
-            .get(1)
-            .map(|chunks| String::from_utf8_lossy(chunks).to_string())
-        {
-            Some(addr) => {
-                // If the key came with an address, then we are going to make an external
-                // request to the remote node via the SDK and push the aggregated resposne
-                // bytes to the reply.
-                let reply = sdk::aggregate(addr, key).map_err(Error::Sdk)?;
-                aggregated.extend(reply);
-                Ok(())
-
-                // TODO: Implement a HashMap, which would collect all the keys which are
-                // registered under one address. This is used to send bulk read requests
-                // instead of separate smaller requests. This would also require sdk::aggregate
-                // to be changed accordingly.
+        // Attempts to extract the address of the key and convert it to a String,
+        // falling back to the node which owns the key if the records are sharded.
+        let addr = match target.get(1) {
+            Some(chunks) => Some(String::from_utf8_lossy(chunks).to_string()),
+            None => locate(&mut p, &key)?,
+        };
+        let read = match reads {
+            1 => read_replica(&mut p, &key, addr.as_deref()),
+            reads => read_quorum(&mut p, &key, addr.as_deref(), reads),
+        };
+        let entries = match read {
+            Ok(entries) => entries,
+            // The keys of nodes which did not reply before the deadline of the
+            // request are left out of the partial response.
+            Err(Error::Sdk(e)) if deadline::exceeded() => {
+                let addr = addr.unwrap_or_default();
+                log::debug!("Leaving out {}@{} past the deadline: {}", key, addr, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        emit(&entries)?;
+
+        // TODO: Implement a HashMap, which would collect all the keys which are
+        // registered under one address. This is used to send bulk read requests
+        // instead of separate smaller requests, see sdk::Client::aggregate_many.
+    }
+
+    Ok(None)
+}
+
+/// Lists the keys of the records of current node which match the query of the
+/// request. See [listing].
+fn list(p: Packet) -> HandlerResult {
+    let (paging, targets) = paged_targets(&p.buffer)?;
+    let query =
+        listing::Query::parse(&targets).ok_or_else(|| Error::InvalidKey(targets.join("..")))?;
+    let keys: Vec<String> = p
+        .storage
+        .scan_keys(p.keyspace, query.prefix())
+        .map_err(Error::Storage)?
+        .into_iter()
+        .filter(|key| anti_entropy::is_record(key) && query.matches(key))
+        .collect();
+    Ok(encode_keys(keys, paging))
+}
+
+/// Creates a record with tags attached to it. See [tags].
+fn create_tagged(mut p: Packet) -> HandlerResult {
+    let payload = p.buffer.clone();
+    let (tags, value) = match tags::decode_create(&payload) {
+        Some(decoded) => decoded,
+        None => return Err(Error::EmptyBuffer("tags")),
+    };
+    if let Some(tag) = tags.iter().find(|tag| !tags::is_valid(tag)) {
+        return Err(Error::InvalidTag(tag.clone()));
+    }
+    if tags.len() > tags::MAX_TAGS {
+        return Err(Error::InvalidTag(tags.join(",")));
+    }
+    if value.is_empty() {
+        return Err(Error::EmptyBuffer(""));
+    }
+
+    let key = store_keyed(&mut p, value)?;
+    p.storage
+        .tag(p.keyspace, &key, &tags)
+        .map_err(Error::Storage)?;
+    Ok(key.into_bytes())
+}
+
+/// Returns the keys of the records tagged with all or any of the tags of the
+/// request. See [tags].
+fn tagged(p: Packet) -> HandlerResult {
+    let (paging, mut targets) = paged_targets(&p.buffer)?;
+    let all = match targets.first().map(String::as_str) {
+        Some(tags::ALL) => true,
+        Some(tags::ANY) => false,
+        _ => return Err(Error::EmptyKeys("all or any")),
+    };
+
+    let tags = targets.split_off(1);
+    if tags.is_empty() {
+        return Err(Error::EmptyKeys("tags"));
+    }
+    if let Some(tag) = tags.iter().find(|tag| !tags::is_valid(tag)) {
+        return Err(Error::InvalidTag(tag.clone()));
+    }
+
+    let keys = p
+        .storage
+        .tagged(p.keyspace, &tags, all)
+        .map_err(Error::Storage)?;
+    Ok(encode_keys(keys, paging))
+}
+
+/// Returns the keys of the records which hold every word of the text of the
+/// request. See [search].
+fn find(p: Packet) -> HandlerResult {
+    if !p.node.read().unwrap().settings.search {
+        return Err(Error::NoSearch(""));
+    }
+
+    let (paging, targets) = paged_targets(&p.buffer)?;
+    let text = targets.join(" ");
+    let terms = search::tokenize(&text);
+    if terms.is_empty() || terms.len() > search::MAX_QUERY_TERMS {
+        return Err(Error::InvalidQuery(text));
+    }
+
+    let keys = p
+        .storage
+        .search(p.keyspace, &terms)
+        .map_err(Error::Storage)?;
+    Ok(encode_keys(keys, paging))
+}
+
+/// Splits the payload of a request into the page it asks for, if any, and the
+/// targets which follow the options of [pagination].
+fn paged_targets(buffer: &[u8]) -> Result<(Option<Paging>, Vec<String>), Error> {
+    let mut targets: Vec<String> = internal::buf_extract_targets(buffer)
+        .iter()
+        .map(|target| String::from_utf8_lossy(target).to_string())
+        .collect();
+    let mut paging = None;
+    while let Some(first) = targets.first() {
+        if !parse_paging(first, &mut paging)? {
+            break;
+        }
+
+        targets.remove(0);
+    }
+
+    Ok((paging, targets))
+}
+
+/// Encodes the keys in lexicographic order, each followed by a null byte. Only
+/// the keys of the page are encoded if a page is asked for, followed by its
+/// footer, and no more than [listing::MAX_KEYS] otherwise.
+fn encode_keys(mut keys: Vec<String>, paging: Option<Paging>) -> Vec<u8> {
+    let (cursor, limit) = match &paging {
+        Some(paging) => (paging.cursor.as_str(), paging.limit),
+        None => ("", listing::MAX_KEYS),
+    };
+
+    keys.retain(|key| key.as_str() > cursor);
+    keys.sort();
+    keys.truncate(limit);
+
+    let mut buffer = vec![];
+    for key in &keys {
+        buffer.extend(key.as_bytes());
+        buffer.push(00);
+    }
+
+    if let Some(paging) = paging {
+        buffer.extend(paging.footer(&keys));
+    }
+
+    buffer
+}
+
+/// Applies the target to the page a request asks for, if it is an option of
+/// [pagination].
+///
+/// # Returns
+///
+/// Whether the target is an option of [pagination].
+fn parse_paging(target: &str, paging: &mut Option<Paging>) -> Result<bool, Error> {
+    if let Some(cursor) = target.strip_prefix(pagination::CURSOR) {
+        if !pagination::is_cursor(cursor) {
+            return Err(Error::InvalidCursor(cursor.to_string()));
+        }
+
+        paging.get_or_insert_with(Paging::default).cursor = cursor.to_string();
+    } else if let Some(limit) = target.strip_prefix(pagination::LIMIT) {
+        let limit =
+            pagination::parse_limit(limit).ok_or_else(|| Error::InvalidCount(limit.into()))?;
+        paging.get_or_insert_with(Paging::default).limit = limit;
+    } else {
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Returns the address of the node which owns the key, unless current node
+/// owns or holds it. See [sharding].
+fn locate(p: &mut Packet, key: &str) -> Result<Option<String>, Error> {
+    let owner = sharding::owner(&p.node.read().unwrap(), key);
+    let owner = match owner {
+        Some(owner) => owner,
+        None => return Ok(None),
+    };
+
+    match p.storage.exists(p.keyspace, key).map_err(Error::Storage)? {
+        true => Ok(None),
+        false => Ok(Some(owner.to_string())),
+    }
+}
+
+/// Reads the entry of the key from the given number of its replicas, and
+/// returns the newest version among them. Replicas which turn out not to hold
+/// the record are repaired along the way. See [quorum].
+///
+/// # Errors
+///
+/// Returns an [Error::NoQuorum] if fewer replicas replied, unless the deadline
+/// of the request has passed, in which case the key is left out of the partial
+/// response.
+fn read_quorum(
+    p: &mut Packet,
+    key: &str,
+    addr: Option<&str>,
+    reads: usize,
+) -> Result<Vec<u8>, Error> {
+    let (mut replicas, mut entries) = (vec![], vec![]);
+    for replica in quorum::replicas(&p.node, addr) {
+        if entries.len() == reads {
+            break;
+        }
+
+        match read_replica(p, key, replica.as_deref()) {
+            Ok(reply) => {
+                if let Some(entry) = internal::buf_extract_targets(&reply).into_iter().next() {
+                    replicas.push(replica);
+                    entries.push(entry);
+                }
             }
+            Err(e) => log::debug!("Replica {:?} of {} did not reply: {}", replica, key, e),
+        }
+    }
+
+    if entries.len() < reads {
+        if deadline::exceeded() {
+            log::debug!("Leaving out {} past the deadline", key);
+            return Ok(vec![]);
+        }
+
+        return Err(Error::NoQuorum(key.to_string()));
+    }
+
+    let mut newest = quorum::newest(&entries).cloned().unwrap_or_default();
+    let mut lagging = vec![];
+    for (replica, entry) in replicas.into_iter().zip(&entries) {
+        if !quorum::is_lagging(entry, &newest) {
+            continue;
+        }
+
+        match replica {
+            Some(addr) => lagging.push(addr),
+            // Current node is repaired right away, since it only takes a write to
+            // the local storage.
             None => {
-                let buffer: Option<Vec<u8>> = p.storage.get(&key).map_err(Error::Redis)?;
-                let buffer = buffer.unwrap_or(b"Unknown key".to_vec());
-                aggregated.extend(key.as_bytes());
-                aggregated.push(b':');
-                aggregated.extend(buffer);
-                aggregated.push(00);
-                Ok(())
+                if let Some(record) = Record::decode(&newest) {
+                    if anti_entropy::store(p.storage, p.keyspace, &record)
+                        .map_err(Error::Storage)?
+                    {
+                        index(p, &record.key, &record.value)?;
+                        p.events
+                            .publish(p.identity, Event::Created { key: record.key });
+                    }
+                }
             }
-        }?;
+        }
     }
 
-    Ok(aggregated)
+    newest.push(00);
+    quorum::repair(
+        Arc::clone(&p.node),
+        Arc::clone(p.outbound),
+        newest.clone(),
+        lagging,
+    );
+    Ok(newest)
+}
+
+/// Reads the entry of the key from the acknowledged node at the given
+/// address, or from current node without an address.
+fn read_replica(p: &mut Packet, key: &str, addr: Option<&str>) -> Result<Vec<u8>, Error> {
+    let addr = match addr {
+        Some(addr) => addr,
+        None => {
+            let mut entry = vec![];
+            if !internal::push_record(p.storage, p.keyspace, &mut entry, key)
+                .map_err(Error::Storage)?
+            {
+                internal::push_entry(&mut entry, key, None, Default::default(), UNKNOWN_KEY, None);
+            }
+
+            return Ok(entry);
+        }
+    };
+
+    // If the key came with an address, then we are going to make an external
+    // request to the remote node via the SDK and return the aggregated entries.
+    let reply = p.outbound.with(&p.node, addr, |client, pinned| {
+        // Signatures can only be verified against a pinned key, while
+        // content-addressed entries can always be re-hashed.
+        let reply = client.aggregate_entries(&[key])?;
+        Ok(match pinned {
+            Some(pinned) => p.outbound.verify(pinned, &reply),
+            None => reply,
+        })
+    });
+
+    reply
+        .map(|reply| internal::verify_content(&reply))
+        .map_err(Error::Sdk)
+}
+
+fn metadata(p: Packet) -> HandlerResult {
+    let node = p.node.read().unwrap();
+    let settings = &node.settings;
+    // Anonymous connections may only read the metadata if it is open.
+    if !settings.perms.open_metadata && matches!(p.identity, Identity::Anonymous(_)) {
+        return Err(Error::Forbidden("Metadata is not open"));
+    }
+
+    let key = match &settings.key {
+        Some(secret) => Some(Keypair::from_hex(secret).map_err(Error::Keys)?.public()),
+        None => None,
+    };
+
+    let metadata = Metadata {
+        name: settings.name.clone(),
+        version: settings.version.clone(),
+        key,
+        time: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .ok(),
+        peers: settings.nodes.iter().map(|peer| peer.addr).collect(),
+        capabilities: Some(Capabilities::of(settings)),
+    };
+
+    Ok(serde_json::to_vec(&metadata).unwrap())
+}
+
+fn post(p: Packet) -> HandlerResult {
+    // The first target is the name of the feed, and the rest are the IDs of the
+    // records which are posted to it.
+    let targets = internal::buf_extract_targets(&p.buffer);
+    let (name, ids) = match targets.split_first() {
+        Some(split) => split,
+        None => return Err(Error::EmptyBuffer("")),
+    };
+
+    let name = String::from_utf8_lossy(name).to_string();
+    if !feed::is_valid_name(&name) {
+        return Err(Error::InvalidFeed(name));
+    }
+
+    let ids = ids
+        .iter()
+        .map(|id| {
+            let id = String::from_utf8_lossy(id).to_string();
+            ulid::Ulid::from_string(&id).map_err(|_| Error::InvalidKey(id))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if ids.is_empty() {
+        return Err(Error::EmptyKeys(""));
+    }
+
+    p.storage
+        .post(p.keyspace, &name, &ids)
+        .map_err(Error::Storage)?;
+    for id in ids {
+        p.events.publish(
+            p.identity,
+            Event::Posted {
+                feed: name.clone(),
+                key: id.to_string(),
+            },
+        );
+    }
+
+    Ok(Vec::with_capacity(0))
+}
+
+fn latest(p: Packet) -> HandlerResult {
+    // The payload is the name of the feed, optionally followed by the address of
+    // the node it is on, and by the number of entries to return. The first
+    // targets may ask for a page of the feed instead, see [pagination].
+    let mut targets = internal::buf_extract_targets(&p.buffer);
+    let mut paging = None;
+    while let Some(first) = targets.first() {
+        if !parse_paging(&String::from_utf8_lossy(first), &mut paging)? {
+            break;
+        }
+
+        targets.remove(0);
+    }
+
+    let target = match targets.first() {
+        Some(target) => String::from_utf8_lossy(target).to_string(),
+        None => return Err(Error::EmptyBuffer("")),
+    };
+
+    let count = match targets.get(1) {
+        Some(count) => {
+            let count = String::from_utf8_lossy(count).to_string();
+            count
+                .parse::<usize>()
+                .map_err(|_| Error::InvalidCount(count))?
+        }
+        None => feed::DEFAULT_COUNT,
+    }
+    .min(feed::MAX_COUNT);
+
+    let (name, addr) = match target.split_once('@') {
+        Some((name, addr)) => (name.to_string(), Some(addr.to_string())),
+        None => (target, None),
+    };
+
+    if !feed::is_valid_name(&name) {
+        return Err(Error::InvalidFeed(name));
+    }
+
+    match addr {
+        Some(addr) => {
+            // Only the feeds of acknowledged nodes are relayed.
+            if !internal::is_acknowledged(&p.node, &addr) {
+                return Err(Error::UnknownNode(addr));
+            }
+
+            p.outbound
+                .with(&p.node, &addr, |client, pinned| {
+                    let reply = match &paging {
+                        Some(paging) => client.feed_page_entries(&name, paging)?,
+                        None => client.feed_entries(&name, count)?,
+                    };
+                    // The footer of a page is passed on as it is.
+                    let (entries, _) = pagination::split(&reply);
+                    let mut verified = match pinned {
+                        Some(pinned) => p.outbound.verify(pinned, entries),
+                        None => entries.to_vec(),
+                    };
+                    verified.extend(&reply[entries.len()..]);
+                    Ok(verified)
+                })
+                .map_err(Error::Sdk)
+        }
+
+        None => {
+            let ids = match &paging {
+                Some(Paging { cursor, limit }) if !cursor.is_empty() => {
+                    p.storage.latest_before(p.keyspace, &name, cursor, *limit)
+                }
+                Some(Paging { limit, .. }) => p.storage.latest(p.keyspace, &name, *limit),
+                None => p.storage.latest(p.keyspace, &name, count),
+            }
+            .map_err(Error::Storage)?;
+
+            let mut entries = vec![];
+            for id in &ids {
+                // Records which have been removed since they were posted are skipped.
+                internal::push_record(p.storage, p.keyspace, &mut entries, id)
+                    .map_err(Error::Storage)?;
+            }
+
+            if let Some(paging) = paging {
+                entries.extend(paging.footer(&ids));
+            }
+
+            Ok(entries)
+        }
+    }
+}
+
+fn register(p: Packet) -> HandlerResult {
+    // The payload is the handle, followed by the hex-encoded public key of the
+    // user and its hex-encoded signature over the handle.
+    let targets: Vec<String> = internal::buf_extract_targets(&p.buffer)
+        .iter()
+        .map(|target| String::from_utf8_lossy(target).to_string())
+        .collect();
+    let (handle, public, signature) = match targets.as_slice() {
+        [handle, public, signature] => (handle, public.to_ascii_lowercase(), signature),
+        _ => {
+            return Err(Error::EmptyBuffer(
+                "Expected a handle, a key and a signature",
+            ))
+        }
+    };
+
+    if !users::is_valid_handle(handle) {
+        return Err(Error::InvalidHandle(handle.clone()));
+    }
+
+    let signed = crate::keys::unhex(signature)
+        .map(|signature| crate::keys::verify_registration(&public, handle, &signature))
+        .unwrap_or(false);
+    if !signed {
+        return Err(Error::InvalidSignature("Handle is not signed with the key"));
+    }
+
+    let profile = users::Profile {
+        handle: handle.clone(),
+        key: public,
+        registered: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default(),
+    };
+
+    let buffer = serde_json::to_vec(&profile).unwrap();
+    if !p
+        .storage
+        .register(p.keyspace, &profile.handle, &profile.key, &buffer)
+        .map_err(Error::Storage)?
+    {
+        return Err(Error::AlreadyRegistered(profile.handle));
+    }
+
+    Ok(buffer)
+}
+
+fn profile(p: Packet) -> HandlerResult {
+    let handle = String::from_utf8_lossy(&p.buffer).to_string();
+    if !users::is_valid_handle(&handle) {
+        return Err(Error::InvalidHandle(handle));
+    }
+
+    p.storage
+        .profile(p.keyspace, &handle)
+        .map_err(Error::Storage)?
+        .ok_or(Error::UnknownUser(handle))
+}
+
+fn follow(p: Packet) -> HandlerResult {
+    relate(p, true)
+}
+
+fn unfollow(p: Packet) -> HandlerResult {
+    relate(p, false)
+}
+
+/// Follows or unfollows an actor. The payload is the followed actor, either a
+/// local handle or `handle@addr` for users of acknowledged nodes.
+///
+/// # Functionality
+///
+/// Users follow on their own behalf. When the followed actor is on a remote
+/// node, the request is relayed to that node, with the follower as `handle@addr`
+/// after the followed handle, so that the remote node keeps track of its remote
+/// followers. Relayed requests are only accepted from acknowledged nodes which
+/// have proven their identity, and only for followers at their own address.
+fn relate(p: Packet, follow: bool) -> HandlerResult {
+    let targets: Vec<String> = internal::buf_extract_targets(&p.buffer)
+        .iter()
+        .map(|target| String::from_utf8_lossy(target).to_string())
+        .collect();
+    let target = targets.first().ok_or(Error::EmptyBuffer(""))?;
+    let (handle, addr) =
+        users::split_actor(target).ok_or_else(|| Error::InvalidActor(target.clone()))?;
+
+    let follower = match (p.identity, targets.get(1)) {
+        (Identity::User(follower), None) => follower.clone(),
+        (Identity::Node(public), Some(follower)) => {
+            let valid = match users::split_actor(follower) {
+                Some((_, Some(from))) => internal::is_node_at(&p.node, public, from),
+                _ => false,
+            };
+
+            if !valid || addr.is_some() {
+                return Err(Error::InvalidActor(follower.clone()));
+            }
+
+            follower.clone()
+        }
+        _ => return Err(Error::Forbidden("Only users can follow")),
+    };
+
+    match addr {
+        Some(addr) => {
+            if !internal::is_acknowledged(&p.node, addr) {
+                return Err(Error::UnknownNode(addr.to_string()));
+            }
+
+            let on_behalf = format!("{}@{}", follower, p.node.read().unwrap().settings.addr);
+            p.outbound
+                .with(&p.node, addr, |client, _| {
+                    client.relate(handle, Some(&on_behalf), follow)
+                })
+                .map_err(Error::Sdk)?;
+        }
+
+        None => {
+            if follow
+                && p.storage
+                    .profile(p.keyspace, handle)
+                    .map_err(Error::Storage)?
+                    .is_none()
+            {
+                return Err(Error::UnknownUser(handle.to_string()));
+            }
+
+            if handle == follower {
+                return Err(Error::InvalidActor(follower));
+            }
+
+            apply(
+                p.storage,
+                p.keyspace,
+                Relation::Followers,
+                handle,
+                &follower,
+                follow,
+            )?;
+        }
+    }
+
+    // Remote followers only have a following set on their own node.
+    if !follower.contains('@') {
+        apply(
+            p.storage,
+            p.keyspace,
+            Relation::Following,
+            &follower,
+            target,
+            follow,
+        )?;
+    }
+
+    Ok(Vec::with_capacity(0))
+}
+
+/// Adds the actor to or removes it from a set of the follow graph of the user.
+fn apply(
+    storage: &mut dyn storage::Connection,
+    keyspace: &storage::Keyspace,
+    relation: Relation,
+    handle: &str,
+    actor: &str,
+    follow: bool,
+) -> Result<(), Error> {
+    match follow {
+        true => storage.link(keyspace, relation, handle, actor),
+        false => storage.unlink(keyspace, relation, handle, actor),
+    }
+    .map(|_| ())
+    .map_err(Error::Storage)
+}
+
+fn followers(p: Packet) -> HandlerResult {
+    members(p, Relation::Followers)
+}
+
+fn following(p: Packet) -> HandlerResult {
+    members(p, Relation::Following)
+}
+
+/// Lists the actors in a set of the follow graph of a local user, in
+/// lexicographic order, each followed by a null byte.
+fn members(p: Packet, relation: Relation) -> HandlerResult {
+    let handle = String::from_utf8_lossy(&p.buffer).to_string();
+    if !users::is_valid_handle(&handle) {
+        return Err(Error::InvalidHandle(handle));
+    }
+
+    if p.storage
+        .profile(p.keyspace, &handle)
+        .map_err(Error::Storage)?
+        .is_none()
+    {
+        return Err(Error::UnknownUser(handle));
+    }
+
+    let mut members = p
+        .storage
+        .members(p.keyspace, relation, &handle)
+        .map_err(Error::Storage)?;
+    members.sort_unstable();
+
+    let mut reply = vec![];
+    for member in members {
+        reply.extend(member.as_bytes());
+        reply.push(00);
+    }
+
+    Ok(reply)
+}
+
+/// Reads the ID of an existing local record from the start of the payload.
+fn existing_record(p: &mut Packet, key: &[u8]) -> Result<String, Error> {
+    let key = String::from_utf8_lossy(key).to_string();
+    if ulid::Ulid::from_string(&key).is_err() && !crate::content::is_key(&key) {
+        return Err(Error::InvalidKey(key));
+    }
+
+    match p.storage.get(p.keyspace, &key).map_err(Error::Storage)? {
+        Some(_) => Ok(key),
+        None => Err(Error::InvalidKey(key)),
+    }
+}
+
+fn like(mut p: Packet) -> HandlerResult {
+    // Likes are counted per identity, which anonymous connections lack.
+    if matches!(p.identity, Identity::Anonymous(_)) {
+        return Err(Error::Forbidden("Anonymous connections cannot like"));
+    }
+
+    let buffer = p.buffer.clone();
+    let key = existing_record(&mut p, &buffer)?;
+    p.storage
+        .like(p.keyspace, &key, &p.identity.key())
+        .map_err(Error::Storage)?;
+    Ok(Vec::with_capacity(0))
+}
+
+fn reply(mut p: Packet) -> HandlerResult {
+    // The payload is the key of the record which is replied to, followed by the
+    // value of the reply. The value may contain null bytes itself.
+    let buffer = p.buffer.clone();
+    let split = buffer.iter().position(|c| *c == 00);
+    let (parent, value) = match split {
+        Some(split) if split + 1 < buffer.len() => (&buffer[..split], &buffer[split + 1..]),
+        _ => return Err(Error::EmptyBuffer("Expected a key and a value")),
+    };
+
+    let parent = existing_record(&mut p, parent)?;
+    let id = store(&mut p, value)?;
+    p.storage
+        .reply(p.keyspace, &parent, &id)
+        .map_err(Error::Storage)?;
+    Ok(id.to_string().into_bytes())
+}
+
+fn replies(p: Packet) -> HandlerResult {
+    // The payload is the key of the record, optionally followed by the number
+    // of replies to return.
+    let targets = internal::buf_extract_targets(&p.buffer);
+    let key = match targets.first() {
+        Some(key) => String::from_utf8_lossy(key).to_string(),
+        None => return Err(Error::EmptyBuffer("")),
+    };
+
+    let count = match targets.get(1) {
+        Some(count) => {
+            let count = String::from_utf8_lossy(count).to_string();
+            count
+                .parse::<usize>()
+                .map_err(|_| Error::InvalidCount(count))?
+        }
+        None => feed::DEFAULT_COUNT,
+    }
+    .min(feed::MAX_COUNT);
+
+    let mut entries = vec![];
+    for id in p
+        .storage
+        .replies(p.keyspace, &key, count)
+        .map_err(Error::Storage)?
+    {
+        internal::push_record(p.storage, p.keyspace, &mut entries, &id).map_err(Error::Storage)?;
+    }
+
+    Ok(entries)
+}
+
+fn changes(p: Packet) -> HandlerResult {
+    // The payload is the cursor to read from, optionally followed by the number
+    // of changes to return.
+    let changelog = p.events.changelog().ok_or(Error::NoChangelog(""))?;
+    let targets = internal::buf_extract_targets(&p.buffer);
+    let cursor = match targets.first() {
+        Some(cursor) => {
+            let cursor = String::from_utf8_lossy(cursor).to_string();
+            cursor
+                .parse::<u64>()
+                .map_err(|_| Error::InvalidCursor(cursor))?
+        }
+        None => 0,
+    };
+
+    let count = match targets.get(1) {
+        Some(count) => {
+            let count = String::from_utf8_lossy(count).to_string();
+            count
+                .parse::<usize>()
+                .map_err(|_| Error::InvalidCount(count))?
+        }
+        None => feed::DEFAULT_COUNT,
+    }
+    .min(feed::MAX_COUNT);
+
+    let page = changelog.read(cursor, count).map_err(|e| match e.kind() {
+        std::io::ErrorKind::InvalidInput => Error::InvalidCursor(cursor.to_string()),
+        _ => Error::Changelog(e),
+    })?;
+    Ok(serde_json::to_vec(&page).unwrap())
+}
+
+fn inventory(p: Packet) -> HandlerResult {
+    // Only nodes and operators exchange records, see [anti_entropy].
+    if !matches!(p.identity, Identity::Node(_) | Identity::Subject(_)) {
+        return Err(Error::Forbidden("Only nodes may list the records"));
+    }
+
+    let ids = anti_entropy::inventory(p.storage, p.keyspace).map_err(Error::Storage)?;
+    Ok(ids.join("\x00").into_bytes())
+}
+
+fn replicate(mut p: Packet) -> HandlerResult {
+    // The payload holds the records in the format of aggregated entries. The
+    // records which exist already are skipped.
+    if !matches!(p.identity, Identity::Node(_) | Identity::Subject(_)) {
+        return Err(Error::Forbidden("Only nodes may replicate records"));
+    }
+
+    let mut stored = 0;
+    for record in anti_entropy::records(&p.buffer) {
+        if anti_entropy::store(p.storage, p.keyspace, &record).map_err(Error::Storage)? {
+            index(&mut p, &record.key, &record.value)?;
+            p.events
+                .publish(p.identity, Event::Created { key: record.key });
+            stored += 1;
+        }
+    }
+
+    Ok(stored.to_string().into_bytes())
+}
+
+fn ring(p: Packet) -> HandlerResult {
+    let ring = placement::Ring::of(&p.node.read().unwrap());
+    Ok(serde_json::to_vec(ring.nodes()).unwrap())
+}
+
+fn peer_health(p: Packet) -> HandlerResult {
+    // The payload is optionally the address of a single acknowledged node.
+    let addr = match p.buffer.is_empty() {
+        true => None,
+        false => {
+            let addr = String::from_utf8_lossy(&p.buffer).to_string();
+            Some(
+                addr.parse::<std::net::SocketAddr>()
+                    .map_err(|_| Error::UnknownNode(addr))?,
+            )
+        }
+    };
+
+    let peers: Vec<std::net::SocketAddr> = {
+        let node = p.node.read().unwrap();
+        node.settings
+            .nodes
+            .iter()
+            .map(|peer| peer.addr)
+            .filter(|peer| addr.is_none_or(|addr| addr == *peer))
+            .collect()
+    };
+
+    if let (Some(addr), true) = (addr, peers.is_empty()) {
+        return Err(Error::UnknownNode(addr.to_string()));
+    }
+
+    let mut reports = vec![];
+    for addr in peers {
+        let samples = p
+            .storage
+            .samples(p.keyspace, &addr.to_string())
+            .map_err(Error::Storage)?
+            .iter()
+            .filter_map(|sample| serde_json::from_slice(sample).ok())
+            .collect();
+        reports.push(health::Report { addr, samples });
+    }
+
+    Ok(serde_json::to_vec(&reports).unwrap())
+}
+
+fn begin(p: Packet) -> HandlerResult {
+    let ttl = p.node.read().unwrap().settings.uploads.ttl;
+    let id = ulid::Ulid::new().to_string();
+    // Uploads are keyed by client like journal entries, so that an upload can
+    // be continued over a new connection.
+    p.storage
+        .begin_upload(
+            p.keyspace,
+            &journal::client(p.identity),
+            &id,
+            std::time::Duration::from_secs(ttl),
+        )
+        .map_err(Error::Storage)?;
+    Ok(id.into_bytes())
+}
+
+fn chunk(p: Packet) -> HandlerResult {
+    // The payload is the ID of the upload, followed by the chunk. The chunk may
+    // contain null bytes itself.
+    let split = p.buffer.iter().position(|c| *c == 00);
+    let (id, chunk) = match split {
+        Some(split) if split + 1 < p.buffer.len() => (&p.buffer[..split], &p.buffer[split + 1..]),
+        _ => return Err(Error::EmptyBuffer("Expected an upload ID and a chunk")),
+    };
+
+    let id = String::from_utf8_lossy(id).to_string();
+    let uploads = p.node.read().unwrap().settings.uploads;
+    let appended = p
+        .storage
+        .append_upload(
+            p.keyspace,
+            &journal::client(p.identity),
+            &id,
+            chunk,
+            uploads.max_size,
+            std::time::Duration::from_secs(uploads.ttl),
+        )
+        .map_err(Error::Storage)?;
+    match appended {
+        Append::Appended(len) => Ok(len.to_string().into_bytes()),
+        Append::Unknown => Err(Error::UnknownUpload(id)),
+        Append::Oversized => Err(Error::UploadTooLarge(uploads.max_size)),
+    }
+}
+
+fn commit(mut p: Packet) -> HandlerResult {
+    let id = String::from_utf8_lossy(&p.buffer).to_string();
+    let client = journal::client(p.identity);
+    let (addressing, signs) = {
+        let node = p.node.read().unwrap();
+        let settings = &node.settings;
+        (settings.addressing, settings.sign && settings.key.is_some())
+    };
+
+    // Unless the key or the signature of the record depends on its value, the
+    // upload is moved into the record without being read.
+    if addressing == Addressing::Ulid && !signs {
+        let record = sharding::draw(&p.node.read().unwrap());
+        let committed = p
+            .storage
+            .commit_upload(p.keyspace, &client, &id, &record, owner(p.identity))
+            .map_err(Error::Storage)?;
+        if !committed {
+            return Err(Error::UnknownUpload(id));
+        }
+
+        p.events.publish(
+            p.identity,
+            Event::Created {
+                key: record.to_string(),
+            },
+        );
+        return Ok(record.to_string().into_bytes());
+    }
+
+    let value = match p
+        .storage
+        .read_upload(p.keyspace, &client, &id)
+        .map_err(Error::Storage)?
+    {
+        Some(value) if !value.is_empty() => value,
+        _ => return Err(Error::UnknownUpload(id)),
+    };
+
+    let key = match addressing {
+        Addressing::Ulid => store(&mut p, &value)?.to_string(),
+        Addressing::Content => store_content(&mut p, &value)?,
+    };
+
+    p.storage
+        .discard_upload(p.keyspace, &client, &id)
+        .map_err(Error::Storage)?;
+    Ok(key.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::settings::Settings;
+    use crate::testing::{FakePeer, Reply};
+    use std::time::Duration;
+
+    #[test]
+    fn test_routes() {
+        for code in HANDLER_LOOKUP_TABLE.keys() {
+            assert!(
+                codec::CORE.contains(code) || codec::FEDERATION.contains(code),
+                "{:#06x} is outside of the ranges of the protocol",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        settings.perms.open_metadata = true;
+        let peer = serde_json::from_str(r#""127.0.0.1:1""#).unwrap();
+        settings.nodes.push(peer);
+        let capabilities = Capabilities::of(&settings);
+
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let metadata = local.client().unwrap().metadata().unwrap();
+        assert_eq!(metadata.peers, vec!["127.0.0.1:1".parse().unwrap()]);
+        assert_eq!(metadata.capabilities, Some(capabilities));
+        assert!(metadata.key.is_some());
+    }
+
+    #[test]
+    fn test_remove() {
+        let removing = FakePeer::bind([(0x0002, Reply::ok(""))]).unwrap();
+        let failing = FakePeer::bind([(0x0002, Reply::status(1))]).unwrap();
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        for peer in [&removing, &failing] {
+            let peer = serde_json::from_str(&format!(r#""{}""#, peer.addr())).unwrap();
+            settings.nodes.push(peer);
+        }
+
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        let key = client.create(b"value").unwrap().id;
+        let targets = [
+            key.clone(),
+            format!("remote@{}", removing.addr()),
+            format!("remote@{}", failing.addr()),
+            "remote@127.0.0.1:1".into(),
+        ];
+        let targets: Vec<&str> = targets.iter().map(String::as_str).collect();
+        let removals = client.remove(&targets).unwrap();
+        let removed: Vec<bool> = removals.iter().map(|removal| removal.removed).collect();
+        assert_eq!(removed, vec![true, true, false, false]);
+        assert!(removals[2].error.is_some());
+        assert_eq!(client.aggregate(&key).unwrap().unknown, vec![key]);
+        assert!(removing
+            .requests()
+            .contains(&(0x0002, b"remote\x00".to_vec())));
+    }
 }
@@ -0,0 +1,185 @@
+use crate::keys::{self, Challenge, Keypair};
+use crate::protocol::Identity;
+use crate::settings::{Auth, Settings};
+
+/// Request code of the authentication frame. The payload of the frame is the
+/// secret of a token. Authentication is handled by the connection handler
+/// itself rather than by the handler functions, since it changes the state of
+/// the connection.
+pub const AUTHENTICATE: u16 = 0x0004;
+
+/// Request code of the handshake frames, with which nodes prove their identity
+/// by signing with their keypair. The handshake takes two frames: the first
+/// carries a nonce of the client and is answered with the challenge of the
+/// node, and the second carries the public key of the client along with its
+/// signature over the challenge. See [keys] for the layout of the payloads.
+pub const HANDSHAKE: u16 = 0x0006;
+
+/// Response status sent when authentication fails, or when the connection is
+/// not allowed to issue a request before authenticating.
+pub const UNAUTHORIZED: u8 = 0x0002;
+
+/// Looks up the token with the given secret.
+///
+/// # Returns
+///
+/// The identity requests on the connection are attributed to from now on, or
+/// [None] if no token matches the secret.
+pub(crate) fn authenticate(auth: &Auth, secret: &[u8]) -> Option<Identity> {
+    auth.tokens
+        .iter()
+        .find(|token| constant_time_eq(token.secret.as_bytes(), secret))
+        .map(|token| Identity::Subject(token.subject.clone()))
+}
+
+/// The outcome of a handshake frame which the node accepted.
+pub(crate) enum Step {
+    /// The opening frame was answered with the given challenge.
+    Challenge(Vec<u8>),
+    /// The client has proven to be the acknowledged node or the registered
+    /// user with the given identity.
+    Proven(Identity),
+}
+
+/// Advances the handshake of a connection by one frame.
+///
+/// # Arguments
+///
+/// * `settings` - The settings holding the keypair of current node, and the
+///   public keys of the acknowledged nodes.
+/// * `challenge` - The challenge of the connection which is waiting for a proof.
+/// * `payload` - The payload of the handshake frame.
+/// * `user` - Looks up the handle of the registered user with the given public
+///   key, for clients which are not acknowledged nodes.
+///
+/// # Returns
+///
+/// [None] if current node has no keypair, if the frame is malformed, or if the
+/// client signed with a key which belongs to neither an acknowledged node nor a
+/// registered user.
+pub(crate) fn handshake<F>(
+    settings: &Settings,
+    challenge: &mut Option<Challenge>,
+    payload: &[u8],
+    user: F,
+) -> Option<Step>
+where
+    F: FnOnce(&str) -> Option<String>,
+{
+    let keypair = Keypair::from_hex(settings.key.as_ref()?).ok()?;
+    match challenge.take() {
+        None => {
+            let (reply, opened) = keys::respond(&keypair, payload)?;
+            *challenge = Some(opened);
+            Some(Step::Challenge(reply))
+        }
+
+        Some(opened) => {
+            let public = keys::verify_proof(&keypair, &opened, payload)?;
+            let acknowledged = settings
+                .nodes
+                .iter()
+                .filter_map(|peer| peer.key.as_ref())
+                .any(|key| key.eq_ignore_ascii_case(&public));
+            if acknowledged {
+                return Some(Step::Proven(Identity::Node(public)));
+            }
+
+            user(&public).map(|handle| Step::Proven(Identity::User(handle)))
+        }
+    }
+}
+
+/// Returns whether a connection with the given identity may issue a request
+/// with the given code. Unauthenticated connections are limited to the codes
+/// listed in [Auth::public], unless no tokens are configured at all.
+#[inline(always)]
+pub(crate) fn authorized(auth: &Auth, identity: &Identity, code: u16) -> bool {
+    auth.tokens.is_empty()
+        || !matches!(identity, Identity::Anonymous(_))
+        || auth.public.contains(&code)
+}
+
+/// Compares the secrets in time which only depends on their length, so that
+/// a secret cannot be guessed byte by byte from response timings.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Token;
+
+    fn auth() -> Auth {
+        Auth {
+            tokens: vec![Token {
+                id: None,
+                subject: "frontend".into(),
+                secret: "s3cr3t".into(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let auth = auth();
+        assert_eq!(
+            authenticate(&auth, b"s3cr3t"),
+            Some(Identity::Subject("frontend".into()))
+        );
+        assert_eq!(authenticate(&auth, b"s3cr3"), None);
+        assert_eq!(authenticate(&auth, b""), None);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (server, client) = (Keypair::generate(), Keypair::generate());
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.key = Some(server.secret());
+        settings.nodes = vec![serde_json::from_str(&format!(
+            r#"{{"addr": "127.0.0.1:1", "key": "{}"}}"#,
+            client.public()
+        ))
+        .unwrap()];
+
+        let user = Keypair::generate();
+        let lookup = |public: &str| (public == user.public()).then(|| "alice".to_string());
+        let run = |settings: &Settings, client: &Keypair| {
+            let (nonce, mut challenge) = (keys::nonce(), None);
+            let reply = match handshake(settings, &mut challenge, &nonce, lookup) {
+                Some(Step::Challenge(reply)) => reply,
+                _ => panic!("The handshake was not opened"),
+            };
+
+            let (_, proof) = keys::prove(client, &nonce, &reply).unwrap();
+            match handshake(settings, &mut challenge, &proof, lookup) {
+                Some(Step::Proven(identity)) => Some(identity),
+                _ => None,
+            }
+        };
+
+        assert_eq!(
+            run(&settings, &client),
+            Some(Identity::Node(client.public()))
+        );
+        assert_eq!(run(&settings, &user), Some(Identity::User("alice".into())));
+        // Keys which belong to neither an acknowledged node nor a user are rejected.
+        assert_eq!(run(&settings, &Keypair::generate()), None);
+    }
+
+    #[test]
+    fn test_authorized() {
+        let anonymous = Identity::Anonymous("127.0.0.1:1".parse().unwrap());
+        let subject = Identity::Subject("frontend".into());
+
+        let auth = auth();
+        assert!(!authorized(&auth, &anonymous, 0x0001));
+        assert!(authorized(&auth, &anonymous, 0x0003));
+        assert!(authorized(&auth, &subject, 0x0001));
+
+        // Without any tokens, authentication is disabled.
+        assert!(authorized(&Auth::default(), &anonymous, 0x0001));
+    }
+}
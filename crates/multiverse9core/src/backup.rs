@@ -0,0 +1,467 @@
+//! Backups are portable archives of every key of a node, which do not depend
+//! on the storage backend the keys were read from. Keys are stored without the
+//! namespace of the node, so an archive can be loaded into a node with another
+//! name.
+//!
+//! An archive starts with [MAGIC], the version of its format and a [Header]
+//! encoded as JSON, prefixed with its length. Every key follows as an entry,
+//! and a trailer with the number of entries closes the archive, so that
+//! archives which were cut short are noticed. All numbers are big-endian.
+//!
+//! ```text
+//! +-------+--------+-----------------+-------------+-----+-----------------+
+//! | MAGIC | FORMAT | header len: u32 | header JSON | ... | 0: u8 | n: u64  |
+//! +-------+--------+-----------------+-------------+-----+-----------------+
+//!
+//! entry:  | kind: u8 | ttl ms: u64 | key len: u32 | key | count: u32 | items |
+//! item:   | len: u32 | bytes | (score: f64, for sorted sets only)
+//! ```
+//!
+//! Keys which do not expire have a time to live of `0`. The keys are read one
+//! after another while the node keeps running, so keys which are changed while
+//! the backup is taken are archived either as they were before the change or as
+//! they are after it.
+
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use crate::settings::Partition;
+use crate::storage::{self, Entry, Keyspace, Value};
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .Io(std::io::Error) [source]
+    .Json(serde_json::Error) [source]
+    .Storage(crate::storage::Error) [source]
+    .Invalid(String)
+    ~Debug
+}
+
+/// The bytes every archive starts with.
+pub const MAGIC: &[u8; 4] = b"M9BK";
+
+/// The version of the format of the archives written by this version.
+pub const FORMAT: u8 = 1;
+
+/// Describes the node an archive was taken from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    /// The name of the node, which its keys were namespaced with.
+    pub name: String,
+    /// The version of the node.
+    pub version: String,
+    /// Granularity of the index buckets, which the keys of the index are named
+    /// after.
+    pub partition: Partition,
+    /// When the backup was started, in milliseconds since the Unix epoch.
+    pub started: u64,
+}
+
+/// The outcome of taking a backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Report {
+    /// The number of keys archived.
+    pub keys: usize,
+    /// The length of the archive in bytes.
+    pub bytes: u64,
+}
+
+/// How keys which exist already are treated when an archive is restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Existing {
+    /// Keeps the existing key, and leaves the archived one out.
+    #[default]
+    Skip,
+    /// Replaces the existing key with the archived one.
+    Overwrite,
+}
+
+/// The outcome of restoring an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Restored {
+    /// The number of keys read from the archive.
+    pub read: usize,
+    /// The number of keys written, or which would be written on a dry run.
+    pub restored: usize,
+    /// The number of keys left out, since they exist already.
+    pub skipped: usize,
+}
+
+const STRING: u8 = 1;
+const LIST: u8 = 2;
+const SET: u8 = 3;
+const SORTED_SET: u8 = 4;
+/// Marks the end of the entries.
+const END: u8 = 0;
+
+/// Archives every key of the instance into the writer.
+pub(crate) fn backup(
+    connection: &mut dyn storage::Connection,
+    keyspace: &Keyspace,
+    header: &Header,
+    out: impl Write,
+) -> Result<Report, Error> {
+    let mut keys = connection.keys(keyspace).map_err(Error::Storage)?;
+    keys.sort_unstable();
+
+    let mut writer = Writer::new(out, header)?;
+    for key in keys {
+        // Keys which went away since they were listed are left out.
+        if let Some(entry) = connection.export(keyspace, &key).map_err(Error::Storage)? {
+            writer.entry(&entry)?;
+        }
+    }
+
+    writer.finish()
+}
+
+/// Loads the keys of the archive into the instance. Keys are written as they
+/// are read, so an archive which turns out to be malformed leaves the keys
+/// before the malformed entry written. Dry runs read the whole archive without
+/// writing anything, which makes sure that it is intact.
+///
+/// # Errors
+///
+/// Returns [Error::Invalid] if the archive is malformed, or if it was taken
+/// from a node whose index is partitioned differently.
+pub(crate) fn restore(
+    connection: &mut dyn storage::Connection,
+    keyspace: &Keyspace,
+    input: impl Read,
+    existing: Existing,
+    dry_run: bool,
+) -> Result<Restored, Error> {
+    let (mut reader, header) = Reader::new(input)?;
+    if header.partition != keyspace.partition {
+        return Err(Error::Invalid(format!(
+            "The archive is partitioned by {}, but the node by {}",
+            header.partition, keyspace.partition
+        )));
+    }
+
+    let mut restored = Restored::default();
+    while let Some(entry) = reader.entry()? {
+        restored.read += 1;
+        if existing == Existing::Skip
+            && connection
+                .exists(keyspace, &entry.key)
+                .map_err(Error::Storage)?
+        {
+            restored.skipped += 1;
+            continue;
+        }
+
+        if !dry_run {
+            connection
+                .import(keyspace, &entry)
+                .map_err(Error::Storage)?;
+        }
+        restored.restored += 1;
+    }
+
+    Ok(restored)
+}
+
+/// Writes the entries of an archive.
+pub(crate) struct Writer<W: Write> {
+    out: W,
+    report: Report,
+}
+
+impl<W: Write> Writer<W> {
+    /// Starts the archive by writing its header.
+    pub(crate) fn new(out: W, header: &Header) -> Result<Self, Error> {
+        let header = serde_json::to_vec(header).map_err(Error::Json)?;
+        let mut writer = Self {
+            out,
+            report: Report::default(),
+        };
+
+        writer.write(MAGIC)?;
+        writer.write(&[FORMAT])?;
+        writer.bytes(&header)?;
+        Ok(writer)
+    }
+
+    pub(crate) fn entry(&mut self, entry: &Entry) -> Result<(), Error> {
+        let (kind, count) = match &entry.value {
+            Value::String(_) => (STRING, 1),
+            Value::List(items) => (LIST, items.len()),
+            Value::Set(items) => (SET, items.len()),
+            Value::SortedSet(members) => (SORTED_SET, members.len()),
+        };
+
+        let ttl = entry.ttl.map_or(0, |ttl| (ttl.as_millis() as u64).max(1));
+        self.write(&[kind])?;
+        self.write(&ttl.to_be_bytes())?;
+        self.bytes(entry.key.as_bytes())?;
+        self.write(&(count as u32).to_be_bytes())?;
+        match &entry.value {
+            Value::String(value) => self.bytes(value)?,
+            Value::List(items) | Value::Set(items) => {
+                for item in items {
+                    self.bytes(item)?;
+                }
+            }
+            Value::SortedSet(members) => {
+                for (member, score) in members {
+                    self.bytes(member)?;
+                    self.write(&score.to_be_bytes())?;
+                }
+            }
+        }
+
+        self.report.keys += 1;
+        Ok(())
+    }
+
+    /// Closes the archive with its trailer.
+    pub(crate) fn finish(mut self) -> Result<Report, Error> {
+        self.write(&[END])?;
+        self.write(&(self.report.keys as u64).to_be_bytes())?;
+        self.out.flush().map_err(Error::Io)?;
+        Ok(self.report)
+    }
+
+    /// Writes the bytes prefixed with their length.
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let len = u32::try_from(bytes.len())
+            .map_err(|_| Error::Invalid(format!("{} bytes do not fit an entry", bytes.len())))?;
+        self.write(&len.to_be_bytes())?;
+        self.write(bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.out.write_all(bytes).map_err(Error::Io)?;
+        self.report.bytes += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+/// Reads the entries of an archive, after checking that it is one.
+pub(crate) struct Reader<R: Read> {
+    input: R,
+    /// The number of entries read so far.
+    read: u64,
+    done: bool,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the header of the archive.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Invalid] if the input is not an archive, or if it is in
+    /// a format this version cannot read.
+    pub(crate) fn new(mut input: R) -> Result<(Self, Header), Error> {
+        let mut magic = [0; 5];
+        input.read_exact(&mut magic).map_err(Error::Io)?;
+        if &magic[..4] != MAGIC {
+            return Err(Error::Invalid("Not a backup archive".into()));
+        }
+        if magic[4] != FORMAT {
+            return Err(Error::Invalid(format!(
+                "Unsupported archive format {}",
+                magic[4]
+            )));
+        }
+
+        let mut reader = Self {
+            input,
+            read: 0,
+            done: false,
+        };
+        let header = serde_json::from_slice(&reader.bytes()?).map_err(Error::Json)?;
+        Ok((reader, header))
+    }
+
+    /// Reads the next entry, or [None] once the trailer is reached.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Invalid] if the archive is malformed, or if it holds
+    /// another number of entries than its trailer states.
+    pub(crate) fn entry(&mut self) -> Result<Option<Entry>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let [kind] = self.array()?;
+        if kind == END {
+            let count = u64::from_be_bytes(self.array()?);
+            if count != self.read {
+                return Err(Error::Invalid(format!(
+                    "The archive holds {} keys, but {} were read",
+                    count, self.read
+                )));
+            }
+
+            self.done = true;
+            return Ok(None);
+        }
+
+        let ttl = u64::from_be_bytes(self.array()?);
+        let key = String::from_utf8(self.bytes()?)
+            .map_err(|_| Error::Invalid("Key is not valid UTF-8".into()))?;
+        let count = u32::from_be_bytes(self.array()?) as usize;
+        let value = match kind {
+            STRING if count == 1 => Value::String(self.bytes()?),
+            LIST => Value::List(self.items(count)?),
+            SET => Value::Set(self.items(count)?),
+            SORTED_SET => Value::SortedSet(
+                (0..count)
+                    .map(|_| Ok((self.bytes()?, f64::from_be_bytes(self.array()?))))
+                    .collect::<Result<_, Error>>()?,
+            ),
+            _ => return Err(Error::Invalid(format!("Unknown entry of {}", key))),
+        };
+
+        self.read += 1;
+        Ok(Some(Entry {
+            key,
+            value,
+            ttl: match ttl {
+                0 => None,
+                ttl => Some(Duration::from_millis(ttl)),
+            },
+        }))
+    }
+
+    fn items(&mut self, count: usize) -> Result<Vec<Vec<u8>>, Error> {
+        (0..count).map(|_| self.bytes()).collect()
+    }
+
+    /// Reads bytes prefixed with their length.
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = u32::from_be_bytes(self.array()?) as u64;
+        let mut bytes = vec![];
+        (&mut self.input)
+            .take(len)
+            .read_to_end(&mut bytes)
+            .map_err(Error::Io)?;
+        match bytes.len() as u64 == len {
+            true => Ok(bytes),
+            false => Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        self.input.read_exact(&mut array).map_err(Error::Io)?;
+        Ok(array)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::Memory;
+    use crate::storage::Backend;
+
+    #[test]
+    fn test_backup() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Hour,
+        };
+        let mut connection = Memory::default().connection().unwrap();
+        let id = ulid::Ulid::from_parts(1, 1);
+        connection
+            .create(&keyspace, &id, b"value", None, Some("alice"))
+            .unwrap();
+        connection.post(&keyspace, "news", &[id]).unwrap();
+        connection
+            .push_sample(&keyspace, "127.0.0.1:1", b"sample", 2)
+            .unwrap();
+
+        let header = Header {
+            name: keyspace.namespace.clone(),
+            version: "0.1.0".into(),
+            partition: keyspace.partition,
+            started: 1,
+        };
+        let mut archive = vec![];
+        let report = backup(connection.as_mut(), &keyspace, &header, &mut archive).unwrap();
+        assert_eq!(report.keys, 5);
+        assert_eq!(report.bytes, archive.len() as u64);
+
+        let header = serde_json::to_vec(&header).unwrap();
+        assert_eq!(&archive[..5], b"M9BK\x01");
+        assert_eq!(&archive[9..9 + header.len()], header);
+        assert_eq!(archive[archive.len() - 9..], [0, 0, 0, 0, 0, 0, 0, 0, 5]);
+
+        // The keys are archived in order, starting with the record.
+        let id = id.to_string();
+        let record = [&[STRING][..], &[0; 8], &26u32.to_be_bytes(), id.as_bytes()];
+        assert!(archive[9 + header.len()..].starts_with(&record.concat()));
+    }
+
+    #[test]
+    fn test_restore() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Hour,
+        };
+        let mut connection = Memory::default().connection().unwrap();
+        let id = ulid::Ulid::from_parts(1, 1);
+        connection
+            .create(&keyspace, &id, b"value", None, None)
+            .unwrap();
+        connection.post(&keyspace, "news", &[id]).unwrap();
+
+        let header = Header {
+            name: keyspace.namespace.clone(),
+            version: "0.1.0".into(),
+            partition: keyspace.partition,
+            started: 1,
+        };
+        let mut archive = vec![];
+        backup(connection.as_mut(), &keyspace, &header, &mut archive).unwrap();
+
+        // Archives can be restored into another namespace.
+        let copy = Keyspace {
+            namespace: "multiverse9_copy".into(),
+            ..keyspace.clone()
+        };
+        let mut restore = |existing, dry_run| {
+            super::restore(connection.as_mut(), &copy, &archive[..], existing, dry_run)
+        };
+
+        let restored = restore(Existing::Skip, true).unwrap();
+        assert_eq!((restored.read, restored.restored), (3, 3));
+        let restored = restore(Existing::Skip, false).unwrap();
+        assert_eq!((restored.restored, restored.skipped), (3, 0));
+        let restored = restore(Existing::Skip, false).unwrap();
+        assert_eq!((restored.restored, restored.skipped), (0, 3));
+        let restored = restore(Existing::Overwrite, false).unwrap();
+        assert_eq!((restored.restored, restored.skipped), (3, 0));
+
+        let key = id.to_string();
+        assert_eq!(
+            connection.get(&copy, &key).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(connection.latest(&copy, "news", 1).unwrap(), vec![key]);
+
+        // Archives which were cut short, or are partitioned differently, are
+        // rejected.
+        let truncated = &archive[..archive.len() - 1];
+        assert!(
+            super::restore(connection.as_mut(), &copy, truncated, Existing::Skip, true).is_err()
+        );
+        let daily = Keyspace {
+            partition: Partition::Day,
+            ..copy.clone()
+        };
+        assert!(super::restore(
+            connection.as_mut(),
+            &daily,
+            &archive[..],
+            Existing::Skip,
+            true
+        )
+        .is_err());
+        assert!(Reader::new(&b"M9BK\x02"[..]).is_err());
+    }
+}
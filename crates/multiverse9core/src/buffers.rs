@@ -0,0 +1,54 @@
+//! Byte buffers which are reused across connections and requests, so that
+//! serving a request does not allocate a buffer for encoding its response.
+//! Response buffers are handed back once they are written. Requests are read
+//! into the receive buffer of their connection, see [crate::FrameBuffer].
+//!
+//! Buffers which grew past [MAX_LEN] are dropped rather than kept, so that a
+//! single large frame does not hold on to its memory for the lifetime of the
+//! process, and no more than [MAX_FREE] buffers are kept at once.
+
+use std::sync::Mutex;
+
+/// The largest buffer kept for reuse, in bytes of capacity.
+pub(crate) const MAX_LEN: usize = 64 * 1024;
+
+/// The most buffers kept for reuse at once.
+pub(crate) const MAX_FREE: usize = 1024;
+
+/// Buffers which are waiting to be reused.
+static FREE: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// Takes a buffer of `len` zeroed bytes, reusing one which was handed back if
+/// there is one.
+pub(crate) fn take(len: usize) -> Vec<u8> {
+    let mut buffer = FREE.lock().unwrap().pop().unwrap_or_default();
+    buffer.resize(len, 0);
+    buffer
+}
+
+/// Hands the buffer back for reuse.
+pub(crate) fn give(mut buffer: Vec<u8>) {
+    if buffer.capacity() == 0 || buffer.capacity() > MAX_LEN {
+        return;
+    }
+
+    buffer.clear();
+    let mut free = FREE.lock().unwrap();
+    if free.len() < MAX_FREE {
+        free.push(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        give(vec![0xAB; 64]);
+        let buffer = take(16);
+        assert_eq!(buffer, vec![0; 16]);
+        give(buffer);
+        assert!(take(0).is_empty());
+    }
+}
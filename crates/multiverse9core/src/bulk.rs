@@ -0,0 +1,106 @@
+//! Bulk creation lets ingestion pipelines create many records with a single
+//! [CREATE_MANY] request, instead of paying a round trip per record. The
+//! payload of the request is the values of the records, each preceded by its
+//! length as a big-endian `u32`, and its response is the keys of the records
+//! in the order of their values, each followed by a null byte.
+//!
+//! Nodes keying their records by ULIDs store every record of a request in a
+//! single transaction, so that either all of them are created or none is.
+//! Content-addressed records are stored one after another, since each of them
+//! is deduplicated against the records stored already.
+//!
+//! Records created in bulk are stored on current node even if it shards its
+//! records, which still draws IDs it owns itself. See [crate::sharding].
+
+/// Request code for creating several records at once.
+pub const CREATE_MANY: u16 = 0x002A;
+
+/// The most records created by a single request.
+pub const MAX_RECORDS: usize = 1024;
+
+/// Encodes the values of the records for a [CREATE_MANY] request.
+pub fn encode(values: &[&[u8]]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(values.iter().map(|value| value.len() + 4).sum());
+    for value in values {
+        buffer.extend((value.len() as u32).to_be_bytes());
+        buffer.extend(*value);
+    }
+
+    buffer
+}
+
+/// Splits the payload of a [CREATE_MANY] request into the values of the
+/// records.
+///
+/// # Returns
+///
+/// [None] if a length runs past the end of the payload.
+pub fn decode(payload: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut values = vec![];
+    let mut rest = payload;
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return None;
+        }
+
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        rest = &rest[4..];
+        if rest.len() < len {
+            return None;
+        }
+
+        values.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    Some(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::sdk;
+    use crate::settings::Settings;
+    use std::time::Duration;
+
+    #[test]
+    fn test_decode() {
+        let values: [&[u8]; 3] = [b"first", b"", b"third"];
+        assert_eq!(decode(&encode(&values)), Some(values.to_vec()));
+        assert_eq!(decode(&[]), Some(vec![]));
+        assert_eq!(decode(&[0, 0, 0, 2, b'a']), None);
+        assert_eq!(decode(&[0, 0]), None);
+    }
+
+    #[test]
+    fn test_create_many() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        let values: Vec<Vec<u8>> = (0..100).map(|i| format!("value{}", i).into()).collect();
+        let values: Vec<&[u8]> = values.iter().map(Vec::as_slice).collect();
+        let created = client.create_many(&values).unwrap();
+        assert_eq!(created.len(), values.len());
+        for (created, value) in created.iter().zip(&values) {
+            let records = client.aggregate(&created.id).unwrap().records;
+            assert_eq!(records[0].value, *value);
+        }
+
+        // Requests without records, with empty records or with too many records
+        // are refused as a whole.
+        for values in [vec![], vec![&b"value"[..], b""], vec![&b"value"[..]; 1025]] {
+            assert!(matches!(
+                client.create_many(&values),
+                Err(sdk::Error::Status(1))
+            ));
+        }
+    }
+}
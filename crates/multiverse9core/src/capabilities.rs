@@ -0,0 +1,167 @@
+//! Capabilities let peers find out which features a node supports without
+//! probing its request codes and telling `unknown_command` replies apart from
+//! genuine errors. A [HELLO] request carries the [Capabilities] of the client
+//! encoded as a big-endian u32, and the node replies with its own, encoded the
+//! same way. Like a [crate::health::PING], it is answered without
+//! authenticating first.
+//!
+//! Nodes which predate capabilities answer [HELLO] with status `1`, which
+//! clients treat as a node without any of the capabilities below. Bits which
+//! are not known to a peer are ignored, so that capabilities can be added
+//! without breaking older peers.
+
+use serde::{Deserialize, Serialize};
+use std::ops::BitOr;
+
+use crate::settings::Settings;
+
+/// Request code for exchanging capabilities with a node.
+pub const HELLO: u16 = 0x0022;
+
+/// Length of the encoded capabilities.
+pub const LEN: usize = 4;
+
+/// The features supported by a node or a client, as a bitmap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// No capabilities at all, as with nodes which predate them.
+    pub const NONE: Self = Self(0);
+    /// Compressed payloads. Reserved, since no node compresses payloads yet.
+    pub const COMPRESSION: Self = Self(1 << 0);
+    /// Replication of records through anti-entropy. See
+    /// [crate::anti_entropy].
+    pub const REPLICATION: Self = Self(1 << 1);
+    /// Subscriptions to changes. See [crate::events].
+    pub const PUBSUB: Self = Self(1 << 2);
+    /// Named feeds. See [crate::feed].
+    pub const FEEDS: Self = Self(1 << 3);
+    /// Multiplexed connections. See [crate::multiplex]. Connections whose
+    /// transport cannot be multiplexed, such as TLS, are still refused.
+    pub const MULTIPLEX: Self = Self(1 << 4);
+    /// The changelog of the node. See [crate::changelog].
+    pub const CHANGELOG: Self = Self(1 << 5);
+    /// Structured records. See [crate::envelope].
+    pub const ENVELOPES: Self = Self(1 << 6);
+    /// Requests bounded by a deadline. See [crate::deadline].
+    pub const DEADLINES: Self = Self(1 << 7);
+    /// The signed handshake which proves the identity of a node. See
+    /// [crate::keys].
+    pub const HANDSHAKE: Self = Self(1 << 8);
+
+    /// Returns the capabilities with the given bits.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the bits of the capabilities.
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns whether every capability in `other` is supported.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the capabilities supported by both sides.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Encodes the capabilities into the payload of a [HELLO] request or
+    /// its reply.
+    pub fn encode(self) -> [u8; LEN] {
+        self.0.to_be_bytes()
+    }
+
+    /// Decodes the payload of a [HELLO] request or its reply.
+    ///
+    /// # Returns
+    ///
+    /// [None] if the payload is not [LEN] bytes long.
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        match payload {
+            [a, b, c, d] => Some(Self(u32::from_be_bytes([*a, *b, *c, *d]))),
+            _ => None,
+        }
+    }
+
+    /// Returns the capabilities of a node running with the given settings.
+    pub fn of(settings: &Settings) -> Self {
+        let mut capabilities =
+            Self::REPLICATION | Self::PUBSUB | Self::FEEDS | Self::ENVELOPES | Self::DEADLINES;
+        if settings.max_in_flight > 0 {
+            capabilities = capabilities | Self::MULTIPLEX;
+        }
+
+        if settings.changelog.is_some() {
+            capabilities = capabilities | Self::CHANGELOG;
+        }
+
+        if settings.key.is_some() {
+            capabilities = capabilities | Self::HANDSHAKE;
+        }
+
+        capabilities
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#010x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use std::time::Duration;
+
+    #[test]
+    fn test_roundtrip() {
+        let capabilities = Capabilities::PUBSUB | Capabilities::FEEDS;
+        assert_eq!(capabilities.encode(), [0, 0, 0, 0b1100]);
+        assert_eq!(
+            Capabilities::decode(&capabilities.encode()),
+            Some(capabilities)
+        );
+        assert_eq!(Capabilities::decode(&[0, 0, 0]), None);
+
+        assert!(capabilities.contains(Capabilities::FEEDS));
+        assert!(!capabilities.contains(Capabilities::FEEDS | Capabilities::MULTIPLEX));
+        assert_eq!(
+            capabilities.intersection(Capabilities::FEEDS | Capabilities::MULTIPLEX),
+            Capabilities::FEEDS
+        );
+    }
+
+    #[test]
+    fn test_hello() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.max_in_flight = 0;
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        let capabilities = client.hello(Capabilities::PUBSUB).unwrap();
+        assert!(capabilities.contains(Capabilities::PUBSUB | Capabilities::ENVELOPES));
+        assert!(!capabilities.contains(Capabilities::MULTIPLEX));
+        assert!(!capabilities.contains(Capabilities::COMPRESSION));
+    }
+}
@@ -0,0 +1,178 @@
+//! The changelog is an append-only file of the changes made to the records of
+//! a node, which is kept when [Settings::changelog](crate::settings::Settings::changelog)
+//! is set. Every [Event] is appended to it before it is handed to the
+//! subscriptions, so that peers which were down can replay the changes they
+//! missed, and operators can audit who changed what.
+//!
+//! Every change is a line of JSON, and its cursor is the offset in bytes the
+//! line starts at. Cursors only grow, and stay valid for as long as the file is
+//! kept. The changes are read with a [CHANGES] request, whose payload is the
+//! cursor to read from, optionally followed by a null byte and the number of
+//! changes to read. Its response is a [Page] encoded as JSON.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::events::Event;
+use crate::protocol::Identity;
+
+/// Request code for reading the changelog.
+pub const CHANGES: u16 = 0x0019;
+
+/// A change made to the records of a node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// The offset in bytes the change starts at in the changelog.
+    pub cursor: u64,
+    /// Time of the change, in milliseconds since the Unix epoch.
+    pub at: u64,
+    /// The key of the identity which made the change.
+    pub by: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// The changes read from a cursor.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Page {
+    pub changes: Vec<Change>,
+    /// The cursor the next changes are read from.
+    pub next: u64,
+}
+
+/// The changelog file of a node.
+pub(crate) struct Changelog {
+    path: PathBuf,
+    /// The file opened for appending, along with its length.
+    file: Mutex<(File, u64)>,
+}
+
+impl Changelog {
+    /// Opens the changelog at the given path, creating it if it does not exist.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new((file, len)),
+        })
+    }
+
+    /// Appends the change, and waits for it to reach the disk.
+    ///
+    /// # Returns
+    ///
+    /// The cursor of the change.
+    pub(crate) fn append(&self, identity: &Identity, event: &Event) -> io::Result<u64> {
+        let mut file = self.file.lock().unwrap();
+        let change = Change {
+            cursor: file.1,
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default(),
+            by: identity.key(),
+            event: event.clone(),
+        };
+
+        let mut line = serde_json::to_vec(&change)?;
+        line.push(b'\n');
+        file.0.write_all(&line)?;
+        file.0.sync_data()?;
+        file.1 += line.len() as u64;
+        Ok(change.cursor)
+    }
+
+    /// Reads at most `count` changes starting at the cursor.
+    ///
+    /// # Errors
+    ///
+    /// Returns [io::ErrorKind::InvalidInput] if the cursor is not at the start
+    /// of a change.
+    pub(crate) fn read(&self, cursor: u64, count: usize) -> io::Result<Page> {
+        let len = self.file.lock().unwrap().1;
+        if cursor > len {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        let mut file = File::open(&self.path)?;
+        if cursor > 0 {
+            let mut previous = [0; 1];
+            file.seek(SeekFrom::Start(cursor - 1))?;
+            file.read_exact(&mut previous)?;
+            if previous[0] != b'\n' {
+                return Err(io::ErrorKind::InvalidInput.into());
+            }
+        }
+
+        // Only the changes which were appended whole by the time the length was
+        // taken are read.
+        file.seek(SeekFrom::Start(cursor))?;
+        let mut lines = BufReader::new(file.take(len - cursor));
+        let mut page = Page {
+            changes: vec![],
+            next: cursor,
+        };
+
+        let mut line = vec![];
+        while page.changes.len() < count {
+            line.clear();
+            if lines.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+
+            page.changes.push(serde_json::from_slice(&line)?);
+            page.next += line.len() as u64;
+        }
+
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changelog() {
+        let path = std::env::temp_dir().join(format!("multiverse9_{}.log", ulid::Ulid::new()));
+        let identity = Identity::User("alice".into());
+        let events = [
+            Event::Created { key: "a".into() },
+            Event::Removed { key: "a".into() },
+            Event::Created { key: "b".into() },
+        ];
+
+        let changelog = Changelog::open(&path).unwrap();
+        let cursors: Vec<u64> = events
+            .iter()
+            .map(|event| changelog.append(&identity, event).unwrap())
+            .collect();
+        assert_eq!(cursors[0], 0);
+
+        let page = changelog.read(0, 2).unwrap();
+        assert_eq!(page.next, cursors[2]);
+        assert_eq!(
+            page.changes
+                .iter()
+                .map(|change| change.event.clone())
+                .collect::<Vec<_>>(),
+            events[..2]
+        );
+        assert_eq!(page.changes[1].by, "user:alice");
+
+        // Reopening continues where the changelog left off.
+        drop(changelog);
+        let changelog = Changelog::open(&path).unwrap();
+        let page = changelog.read(page.next, 10).unwrap();
+        assert_eq!(page.changes.len(), 1);
+        assert_eq!(changelog.read(page.next, 10).unwrap().changes, vec![]);
+        assert!(changelog.read(1, 10).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+//! Blocks of IP addresses in CIDR notation, such as `10.0.0.0/8`, for the
+//! allow and deny lists of a node. See [crate::settings::Firewall].
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .Malformed(String)
+    ~Debug
+}
+
+/// A block of IP addresses, which are written in settings files as strings.
+/// Bare addresses are blocks of a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Returns whether the address lies within the block. IPv4 addresses
+    /// mapped onto IPv6, as accepted by listeners bound to both, are matched
+    /// as IPv4 addresses.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(block), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(block) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(block), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(block) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(block: &str) -> Result<Self, Self::Err> {
+        let malformed = || Error::Malformed(block.into());
+        let (addr, prefix) = match block.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (block, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| malformed())?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| malformed())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(malformed());
+        }
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = Error;
+
+    fn try_from(block: String) -> Result<Self, Self::Error> {
+        block.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        let block: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(block.contains("10.1.255.3".parse().unwrap()));
+        assert!(!block.contains("10.2.0.1".parse().unwrap()));
+        assert!(block.contains("::ffff:10.1.0.1".parse().unwrap()));
+
+        let block: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(block.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!block.contains("10.1.0.1".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.0.2.1".parse().unwrap()));
+        let single: Cidr = "192.0.2.1".parse().unwrap();
+        assert_eq!(single.to_string(), "192.0.2.1/32");
+        assert!(!single.contains("192.0.2.2".parse().unwrap()));
+
+        for malformed in ["10.0.0.0/33", "10.0.0/8", "::/129", "10.0.0.0/"] {
+            assert!(malformed.parse::<Cidr>().is_err());
+        }
+    }
+}
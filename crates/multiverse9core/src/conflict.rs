@@ -0,0 +1,251 @@
+//! Replicas of a record which are updated concurrently on different nodes
+//! diverge until they are synced. This module contains what replicas need to
+//! converge once they sync, regardless of the order in which updates arrive
+//! and how often they are delivered:
+//!
+//! - [Clock], a hybrid logical clock which stamps every update with a
+//!   [Timestamp]. Timestamps follow the physical time of the nodes, but unlike
+//!   it never go backwards, and an update is always stamped after every update
+//!   the node has seen from its peers.
+//! - [Merge], the strategy which resolves two versions of a record into one.
+//!   Strategies have to be commutative, associative and idempotent, as
+//!   state-based CRDTs are.
+//! - [LastWriterWins], the default strategy, which keeps the version with the
+//!   later timestamp.
+
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .Drift(u64)
+    .InvalidTimestamp(String)
+    ~Debug
+}
+
+/// The furthest in milliseconds the timestamps of peers may be ahead of the
+/// physical time of current node. Timestamps further ahead are rejected, since
+/// observing them would drag the clock of current node along.
+pub const MAX_DRIFT: u64 = 60 * 1000;
+
+/// A timestamp of a hybrid logical clock. Timestamps are ordered by their
+/// physical part first, then by their logical counter, and then by the node
+/// which issued them, so that no two updates are ever ordered ambiguously.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp {
+    /// Physical time in milliseconds since the Unix epoch.
+    pub wall: u64,
+    /// Orders the timestamps which share their physical time.
+    pub counter: u32,
+    /// The name of the node which issued the timestamp.
+    pub node: String,
+}
+
+/// Timestamps are displayed as `wall.counter@node`, with the numbers padded so
+/// that the textual form sorts in the same order as the timestamps do.
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:020}.{:010}@{}", self.wall, self.counter, self.node)
+    }
+}
+
+impl std::str::FromStr for Timestamp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidTimestamp(s.to_string());
+        let (time, node) = s.split_once('@').ok_or_else(invalid)?;
+        let (wall, counter) = time.split_once('.').ok_or_else(invalid)?;
+        Ok(Self {
+            wall: wall.parse().map_err(|_| invalid())?,
+            counter: counter.parse().map_err(|_| invalid())?,
+            node: node.to_string(),
+        })
+    }
+}
+
+/// A hybrid logical clock, shared by the threads of a node.
+pub struct Clock {
+    node: String,
+    /// The physical and logical parts of the last timestamp issued.
+    last: Mutex<(u64, u32)>,
+    /// Returns the physical time in milliseconds since the Unix epoch.
+    time: fn() -> u64,
+}
+
+impl Clock {
+    /// Creates the clock of the node with the given name.
+    pub fn new(node: impl Into<String>) -> Self {
+        Self::with_time(node, || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default()
+        })
+    }
+
+    /// Creates a clock which reads the physical time from the given function.
+    pub fn with_time(node: impl Into<String>, time: fn() -> u64) -> Self {
+        Self {
+            node: node.into(),
+            last: Mutex::new((0, 0)),
+            time,
+        }
+    }
+
+    /// Issues a timestamp for an update made on current node.
+    pub fn now(&self) -> Timestamp {
+        let physical = (self.time)();
+        let mut last = self.last.lock().unwrap();
+        *last = match physical > last.0 {
+            true => (physical, 0),
+            false => (last.0, last.1 + 1),
+        };
+
+        self.timestamp(*last)
+    }
+
+    /// Advances the clock past a timestamp received from a peer, and issues a
+    /// timestamp for applying the update it came with.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Drift] if the timestamp is more than [MAX_DRIFT]
+    /// milliseconds ahead of the physical time of current node.
+    pub fn observe(&self, remote: &Timestamp) -> Result<Timestamp, Error> {
+        let physical = (self.time)();
+        if remote.wall > physical + MAX_DRIFT {
+            return Err(Error::Drift(remote.wall - physical));
+        }
+
+        let mut last = self.last.lock().unwrap();
+        let wall = physical.max(last.0).max(remote.wall);
+        let counter = match (wall == last.0, wall == remote.wall) {
+            (true, true) => last.1.max(remote.counter) + 1,
+            (true, false) => last.1 + 1,
+            (false, true) => remote.counter + 1,
+            (false, false) => 0,
+        };
+
+        *last = (wall, counter);
+        Ok(self.timestamp(*last))
+    }
+
+    fn timestamp(&self, (wall, counter): (u64, u32)) -> Timestamp {
+        Timestamp {
+            wall,
+            counter,
+            node: self.node.clone(),
+        }
+    }
+}
+
+/// A version of a replicated record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioned {
+    pub value: Vec<u8>,
+    /// When the version was written.
+    pub at: Timestamp,
+    /// Whether the record was removed in this version. Removals are kept as
+    /// tombstones, so that replicas which have not seen the removal yet do not
+    /// bring the record back when they sync.
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+/// Resolves two versions of the same record into the version replicas keep.
+/// Strategies must be commutative, associative and idempotent, so that
+/// replicas converge no matter in which order and how often they sync.
+pub trait Merge: Send + Sync {
+    fn merge(&self, local: &Versioned, remote: &Versioned) -> Versioned;
+}
+
+/// Keeps the version which was written last, including removals.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastWriterWins;
+
+impl Merge for LastWriterWins {
+    fn merge(&self, local: &Versioned, remote: &Versioned) -> Versioned {
+        match remote.at > local.at {
+            true => remote.clone(),
+            false => local.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frozen() -> u64 {
+        1_000
+    }
+
+    #[test]
+    fn test_clock() {
+        let clock = Clock::with_time("a", frozen);
+        let first = clock.now();
+        let second = clock.now();
+        assert!(second > first);
+        assert_eq!((second.wall, second.counter), (1_000, 1));
+
+        // Observed timestamps ahead of the clock move it forward.
+        let remote = Timestamp {
+            wall: 2_000,
+            counter: 7,
+            node: "b".into(),
+        };
+        let observed = clock.observe(&remote).unwrap();
+        assert_eq!((observed.wall, observed.counter), (2_000, 8));
+        assert!(clock.now() > observed);
+
+        let remote = Timestamp {
+            wall: frozen() + MAX_DRIFT + 1,
+            counter: 0,
+            node: "b".into(),
+        };
+        assert!(matches!(clock.observe(&remote), Err(Error::Drift(_))));
+    }
+
+    #[test]
+    fn test_timestamp_text() {
+        let timestamp = Timestamp {
+            wall: 1_000,
+            counter: 2,
+            node: "a".into(),
+        };
+        let later = Timestamp {
+            wall: 10_000,
+            ..timestamp.clone()
+        };
+
+        assert_eq!(
+            timestamp.to_string().parse::<Timestamp>().unwrap(),
+            timestamp
+        );
+        assert!(timestamp.to_string() < later.to_string());
+        assert!("1000@a".parse::<Timestamp>().is_err());
+    }
+
+    #[test]
+    fn test_last_writer_wins() {
+        let clock = Clock::with_time("a", frozen);
+        let version = |value: &[u8], deleted| Versioned {
+            value: value.to_vec(),
+            at: clock.now(),
+            deleted,
+        };
+
+        let (first, second, removed) = (
+            version(b"1", false),
+            version(b"2", false),
+            version(b"", true),
+        );
+        let strategy = LastWriterWins;
+        assert_eq!(strategy.merge(&first, &second), second);
+        assert_eq!(strategy.merge(&second, &first), second);
+        assert_eq!(strategy.merge(&second, &second), second);
+        assert_eq!(strategy.merge(&second, &removed), removed);
+    }
+}
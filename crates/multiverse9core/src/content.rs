@@ -0,0 +1,53 @@
+//! Nodes with [Addressing::Content](crate::settings::Addressing::Content) key
+//! the records they create by the BLAKE3 hash of their values, encoded as
+//! lowercase hex. Creating a record with a value which is stored already
+//! yields the key of the existing record, so identical content is stored once.
+//!
+//! Since the key is derived from the value, any node aggregating a
+//! content-addressed record can check that the value it received is the one
+//! the key was derived from, whether or not it has pinned the key of the node
+//! the record comes from. Unlike ULIDs, content keys do not carry a creation
+//! time, so content-addressed records are not added to the index buckets.
+//!
+//! Every identity creating a content-addressed record references it, and
+//! removing the record only drops the reference of the identity. The record is
+//! removed once its last reference is dropped, so content shared by several
+//! users stays available to each of them until they all remove it. Users
+//! reference records by their handle, and anonymous connections share a single
+//! reference.
+
+/// Length of a content key, i.e. a hex-encoded BLAKE3 hash.
+pub const KEY_LEN: usize = 64;
+
+/// Derives the content key of the value.
+pub fn key(value: &[u8]) -> String {
+    blake3::hash(value).to_hex().to_string()
+}
+
+/// Returns whether the key has the shape of a content key.
+pub fn is_key(key: &str) -> bool {
+    key.len() == KEY_LEN
+        && key
+            .bytes()
+            .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c))
+}
+
+/// Returns whether the value is the one the content key was derived from.
+pub fn verify(key: &str, value: &[u8]) -> bool {
+    is_key(key) && self::key(value) == key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        let key = key(b"Hello, world!");
+        assert!(is_key(&key));
+        assert!(verify(&key, b"Hello, world!"));
+        assert!(!verify(&key, b"Hello, world?"));
+        assert!(!is_key(&key.to_uppercase()));
+        assert!(!is_key(&ulid::Ulid::new().to_string()));
+    }
+}
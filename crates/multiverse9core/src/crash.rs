@@ -0,0 +1,257 @@
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Once;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::protocol::Identity;
+
+/// Counters of the work done by the node, which are included in crash reports.
+struct Stats {
+    /// Time the node started, in milliseconds since the Unix epoch.
+    started: AtomicU64,
+    connections: AtomicU64,
+    active: AtomicU64,
+    queued: AtomicU64,
+    requests: AtomicU64,
+    failures: AtomicU64,
+    accept_errors: AtomicU64,
+}
+
+static STATS: Stats = Stats {
+    started: AtomicU64::new(0),
+    connections: AtomicU64::new(0),
+    active: AtomicU64::new(0),
+    queued: AtomicU64::new(0),
+    requests: AtomicU64::new(0),
+    failures: AtomicU64::new(0),
+    accept_errors: AtomicU64::new(0),
+};
+
+/// A snapshot of the counters of the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Seconds since the node started.
+    pub uptime: u64,
+    /// The number of connections accepted so far.
+    pub connections: u64,
+    /// The number of connections currently open.
+    pub active: u64,
+    /// The number of accepted connections which wait for a worker.
+    #[serde(default)]
+    pub queued: u64,
+    /// The number of requests handled so far.
+    pub requests: u64,
+    /// The number of requests whose handler failed.
+    pub failures: u64,
+    /// The number of times accepting a connection failed.
+    #[serde(default)]
+    pub accept_errors: u64,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the counters of the node.
+    pub fn take() -> Self {
+        Self {
+            uptime: match STATS.started.load(Ordering::Relaxed) {
+                0 => 0,
+                started => now().saturating_sub(started) / 1000,
+            },
+            connections: STATS.connections.load(Ordering::Relaxed),
+            active: STATS.active.load(Ordering::Relaxed),
+            queued: STATS.queued.load(Ordering::Relaxed),
+            requests: STATS.requests.load(Ordering::Relaxed),
+            failures: STATS.failures.load(Ordering::Relaxed),
+            accept_errors: STATS.accept_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What the thread was working on, as far as the node knows.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    /// The identity of the connection the thread is serving, which is the
+    /// address of the connection unless it authenticated.
+    pub peer: Option<String>,
+    /// The code of the request the thread is handling.
+    pub code: Option<u16>,
+    /// The ID of the journaled request the thread is handling.
+    pub request: Option<String>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+/// The report logged when a thread of the node panics.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    /// Time of the panic, in milliseconds since the Unix epoch.
+    pub at: u64,
+    /// The name of the node.
+    pub node: String,
+    /// The name of the thread which panicked, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+    /// The panic message.
+    pub message: String,
+    /// Where in the source the panic occurred.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// The identity of the connection the thread was serving.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    /// The code of the request the thread was handling.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    /// The ID of the journaled request the thread was handling.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<String>,
+    pub stats: Snapshot,
+}
+
+/// Installs the panic hook which logs a [Report] for every panic, and appends
+/// it to the given file if there is one. The default hook still runs
+/// afterwards. Only the first call has any effect.
+pub(crate) fn install(node: String, file: Option<PathBuf>) {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(move || {
+        STATS.started.store(now(), Ordering::Relaxed);
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let message = match info.payload().downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => match info.payload().downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => "Box<dyn Any>".into(),
+                },
+            };
+
+            let context = context();
+            let report = Report {
+                at: now(),
+                node: node.clone(),
+                thread: std::thread::current().name().map(String::from),
+                message,
+                location: info.location().map(|location| location.to_string()),
+                peer: context.peer,
+                code: context.code,
+                request: context.request,
+                stats: Snapshot::take(),
+            };
+
+            let report = serde_json::to_string(&report).unwrap();
+            error!("Crash report: {}", report);
+            if let Some(file) = &file {
+                let written = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(file)
+                    .and_then(|mut file| writeln!(file, "{}", report));
+                if let Err(e) = written {
+                    error!("Could not write the crash report to {:?}: {}", file, e);
+                }
+            }
+
+            previous(info);
+        }));
+    });
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Records that a connection has been accepted.
+#[inline(always)]
+pub(crate) fn accepted() {
+    STATS.connections.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records that accepting a connection has failed.
+#[inline(always)]
+pub(crate) fn accept_failed() {
+    STATS.accept_errors.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records that a job has been queued for a worker of the pool.
+#[inline(always)]
+pub(crate) fn enqueued() {
+    STATS.queued.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records that a worker of the pool has picked up a queued job.
+#[inline(always)]
+pub(crate) fn dequeued() {
+    STATS.queued.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Marks the current thread as serving a connection of the given identity
+/// until the returned guard is dropped.
+pub(crate) fn serve(identity: &Identity) -> Serving {
+    STATS.active.fetch_add(1, Ordering::Relaxed);
+    track(identity, None, None);
+    Serving(())
+}
+
+/// Returns what the current thread is working on, for annotating the lines it
+/// logs. See [Context].
+pub fn context() -> Context {
+    CONTEXT.with(|context| context.borrow().clone())
+}
+
+/// Records the request the current thread is handling, and on behalf of whom.
+pub(crate) fn track(identity: &Identity, code: Option<u16>, request: Option<&str>) {
+    CONTEXT.with(|context| {
+        *context.borrow_mut() = Context {
+            peer: Some(identity.key()),
+            code,
+            request: request.map(String::from),
+        }
+    });
+}
+
+/// Records that a request has been handled, and whether its handler failed.
+#[inline(always)]
+pub(crate) fn handled(failed: bool) {
+    STATS.requests.fetch_add(1, Ordering::Relaxed);
+    if failed {
+        STATS.failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Clears the context of the current thread once it stops serving a
+/// connection, including when it unwinds.
+pub(crate) struct Serving(());
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        STATS.active.fetch_sub(1, Ordering::Relaxed);
+        CONTEXT.with(|context| *context.borrow_mut() = Context::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let identity = Identity::User("alice".into());
+        let serving = serve(&identity);
+        track(&identity, Some(0x0010), Some("01GQ"));
+        let tracked = context();
+        assert_eq!(tracked.peer.as_deref(), Some("user:alice"));
+        assert_eq!(tracked.code, Some(0x0010));
+        assert_eq!(tracked.request.as_deref(), Some("01GQ"));
+
+        drop(serving);
+        assert_eq!(context(), Context::default());
+    }
+}
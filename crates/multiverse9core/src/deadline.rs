@@ -0,0 +1,206 @@
+//! Deadlines let clients bound the time a node spends on a request. A
+//! [DEADLINE] request carries the budget of the request in milliseconds,
+//! followed by another request frame:
+//!
+//! ```text
+//! +---------------+---------------+
+//! | budget (u32)  | request frame |
+//! +---------------+---------------+
+//! ```
+//!
+//! The budget is encoded in big-endian byte order, and is counted from when the
+//! request was received, including the time it waited for a worker. Remote
+//! calls the node would make past the deadline are not made, and remote calls
+//! in flight are cut short once the deadline passes. Handlers which fan out to
+//! remote nodes, such as aggregating keys of other nodes, return the entries
+//! they collected in time instead of failing.
+//!
+//! The inner request cannot wrap another request, such as a timed or a
+//! journaled one, so that wrapped requests are never nested.
+//!
+//! The response is the response frame of the inner request followed by a
+//! single byte of flags, of which [EXPIRED] tells that the deadline passed and
+//! that the response may be partial:
+//!
+//! ```text
+//! +----------------+-------------+
+//! | response frame | flags (u8)  |
+//! +----------------+-------------+
+//! ```
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::protocol::codec;
+
+/// Request code of a request with a deadline.
+pub const DEADLINE: u16 = 0x001F;
+
+/// Flag telling that the deadline of the request passed before the node was
+/// done with it, so that its response may be partial.
+pub const EXPIRED: u8 = 0b0000_0001;
+
+thread_local! {
+    /// The deadline of the request the current thread is handling, and whether
+    /// the deadline has cut a remote call short.
+    static CURRENT: Cell<(Option<Instant>, bool)> = const { Cell::new((None, false)) };
+}
+
+/// Encodes a request with the given budget into the payload of a [DEADLINE]
+/// request. Budgets above [u32::MAX] milliseconds are capped.
+pub fn encode(budget: Duration, code: u16, payload: &[u8]) -> Result<Vec<u8>, codec::Error> {
+    let budget = budget.as_millis().min(u32::MAX as u128) as u32;
+    let mut buffer = budget.to_be_bytes().to_vec();
+    buffer.extend(codec::encode_request(code, payload)?);
+    Ok(buffer)
+}
+
+/// Decodes the payload of a [DEADLINE] request into the budget and the inner
+/// request.
+///
+/// # Returns
+///
+/// [None] if the payload is not a budget followed by exactly one request frame.
+pub fn decode(payload: &[u8]) -> Option<(Duration, codec::Request<'_>)> {
+    if payload.len() < 4 {
+        return None;
+    }
+
+    let (budget, frame) = payload.split_at(4);
+    let (request, len) = codec::decode_request(frame).ok()?;
+    (len == frame.len()).then(|| {
+        let budget = u32::from_be_bytes([budget[0], budget[1], budget[2], budget[3]]);
+        (Duration::from_millis(budget as u64), request)
+    })
+}
+
+/// Decodes the payload of the response to a [DEADLINE] request into the
+/// response to the inner request, and whether the deadline passed.
+pub fn decode_response(payload: &[u8]) -> Result<(codec::Response<'_>, bool), codec::Error> {
+    let (response, len) = codec::decode_response(payload)?;
+    let flags = payload.get(len).copied().unwrap_or_default();
+    Ok((response, flags & EXPIRED != 0))
+}
+
+/// Sets the deadline of the request the current thread is handling, until the
+/// returned guard is dropped.
+pub(crate) fn start(deadline: Instant) -> Guard {
+    let previous = CURRENT.with(|current| current.replace((Some(deadline), false)));
+    Guard(previous)
+}
+
+/// Returns the time left until the deadline of the request the current thread
+/// is handling, or [None] if the request has no deadline.
+pub(crate) fn remaining() -> Option<Duration> {
+    CURRENT
+        .with(|current| current.get().0)
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Returns whether the deadline of the request the current thread is handling
+/// has passed.
+pub(crate) fn exceeded() -> bool {
+    remaining() == Some(Duration::ZERO)
+}
+
+/// Records that the deadline has cut the request short, so that its response
+/// may be partial.
+pub(crate) fn expire() {
+    CURRENT.with(|current| {
+        let (deadline, _) = current.get();
+        current.set((deadline, true));
+    });
+}
+
+/// Restores the deadline of the enclosing request once the request with a
+/// deadline has been handled.
+pub(crate) struct Guard((Option<Instant>, bool));
+
+impl Guard {
+    /// Returns whether the deadline passed, or cut a remote call short, while
+    /// the request was being handled.
+    pub(crate) fn expired(&self) -> bool {
+        let (_, expired) = CURRENT.with(|current| current.get());
+        expired || exceeded()
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::settings::Settings;
+    use crate::{sdk, timing};
+
+    #[test]
+    fn test_roundtrip() {
+        let payload = encode(Duration::from_millis(1500), 0x03, b"key\x00").unwrap();
+        let (budget, request) = decode(&payload).unwrap();
+        assert_eq!(budget, Duration::from_millis(1500));
+        assert_eq!((request.code, request.payload), (0x03, &b"key\x00"[..]));
+
+        assert_eq!(decode(&payload[..3]), None);
+        assert_eq!(decode(&payload[..payload.len() - 1]), None);
+        assert_eq!(decode(&[payload.clone(), vec![0]].concat()), None);
+
+        let mut reply = codec::encode_response(0, b"entries").unwrap();
+        assert!(!decode_response(&reply).unwrap().1);
+        reply.push(EXPIRED);
+        let (response, expired) = decode_response(&reply).unwrap();
+        assert_eq!((response.payload, expired), (&b"entries"[..], true));
+    }
+
+    #[test]
+    fn test_guard() {
+        assert_eq!(remaining(), None);
+        {
+            let guard = start(Instant::now() + Duration::from_secs(60));
+            assert!(remaining().unwrap() > Duration::from_secs(59));
+            assert!(!guard.expired());
+            expire();
+            assert!(guard.expired());
+        }
+
+        assert_eq!(remaining(), None);
+        let guard = start(Instant::now());
+        assert!(exceeded() && guard.expired());
+    }
+
+    #[test]
+    fn test_nested() {
+        let node = Node::new(Settings::new("memory://".into()).unwrap());
+        let (shutdown, local) = (node.shutdown(), node.local());
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Wrapped requests nested in each other are rejected before they are
+        // unwrapped any further, however deep they are nested.
+        let budget = Duration::from_secs(5);
+        let (mut code, mut payload) = (0x0005, vec![]);
+        for level in 0..20000 {
+            (code, payload) = match level % 2 {
+                0 => (DEADLINE, encode(budget, code, &payload).unwrap()),
+                _ => (
+                    timing::TIMED,
+                    codec::encode_request(code, &payload).unwrap(),
+                ),
+            };
+        }
+
+        let mut client = local.client().unwrap();
+        assert!(matches!(
+            client.within(budget, code, &payload),
+            Err(sdk::Error::Status(1))
+        ));
+        client.ping().unwrap();
+        shutdown.stop();
+    }
+}
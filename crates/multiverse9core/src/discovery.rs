@@ -0,0 +1,259 @@
+use log::*;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::keys::Keypair;
+use crate::node::Node;
+use crate::sdk;
+use crate::seeds;
+use crate::settings::Settings;
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .Mdns(mdns_sd::Error) [source]
+    .Keys(crate::keys::Error) [source]
+    ~Debug
+}
+
+/// The DNS-SD service type nodes are advertised under.
+pub const SERVICE_TYPE: &str = "_multiverse9._tcp.local.";
+
+/// A node advertised on the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovered {
+    /// The name of the node.
+    pub name: String,
+    /// The addresses the node can be reached at.
+    pub addrs: Vec<SocketAddr>,
+    /// The version of the node.
+    pub version: Option<String>,
+    /// Hex-encoded Ed25519 public key of the node, for pinning it as a peer.
+    /// Since advertisements are not authenticated, the key has to be verified
+    /// with a handshake before it is trusted.
+    pub key: Option<String>,
+}
+
+impl Discovered {
+    /// Connects to the first address of the node which accepts the connection.
+    pub fn connect(&self, connector: &sdk::Connector) -> Result<sdk::Client, sdk::Error> {
+        let mut last_error = None;
+        for addr in &self.addrs {
+            match connector.connect(addr) {
+                Ok(client) => return Ok(client),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| sdk::Error::Io(std::io::ErrorKind::AddrNotAvailable.into())))
+    }
+}
+
+impl From<&ServiceInfo> for Discovered {
+    fn from(info: &ServiceInfo) -> Self {
+        let name = info
+            .get_fullname()
+            .strip_suffix(SERVICE_TYPE)
+            .map(|name| name.trim_end_matches('.'))
+            .unwrap_or(info.get_fullname());
+        let mut addrs: Vec<SocketAddr> = info
+            .get_addresses()
+            .iter()
+            .map(|ip| SocketAddr::new((*ip).into(), info.get_port()))
+            .collect();
+        addrs.sort();
+
+        Self {
+            name: name.to_string(),
+            addrs,
+            version: info.get_property_val_str("version").map(String::from),
+            key: info.get_property_val_str("key").map(String::from),
+        }
+    }
+}
+
+/// Browses the local network for nodes for the given duration.
+///
+/// # Returns
+///
+/// The nodes which were found, in the order they were found in.
+pub fn discover(timeout: Duration) -> Result<Vec<Discovered>, Error> {
+    let daemon = ServiceDaemon::new().map_err(Error::Mdns)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(Error::Mdns)?;
+    let deadline = Instant::now() + timeout;
+
+    let mut discovered: Vec<Discovered> = vec![];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        let info = match events.recv_timeout(left) {
+            Ok(ServiceEvent::ServiceResolved(info)) => info,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+
+        let node = Discovered::from(&info);
+        debug!("Discovered {} at {:?}", node.name, node.addrs);
+        // Nodes which are resolved again replace what was found before.
+        match discovered.iter_mut().find(|found| found.name == node.name) {
+            Some(found) => *found = node,
+            None => discovered.push(node),
+        }
+    }
+
+    if let Err(e) = daemon.shutdown() {
+        warn!("Could not shut down the mDNS daemon: {}", e);
+    }
+
+    Ok(discovered)
+}
+
+/// Keeps a node advertised on the local network until it is dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+/// Advertises the node with the given settings on the local network, at the
+/// port it is listening on. Nodes bound to an unspecified address are
+/// advertised at all addresses of the host.
+pub fn advertise(settings: &Settings, port: u16) -> Result<Advertisement, Error> {
+    let mut properties = HashMap::new();
+    properties.insert("version".to_string(), settings.version.clone());
+    if let Some(secret) = &settings.key {
+        let keypair = Keypair::from_hex(secret).map_err(Error::Keys)?;
+        properties.insert("key".to_string(), keypair.public());
+    }
+
+    // Without addresses, the addresses of the host are advertised.
+    let ip = settings.addr.ip();
+    let ips = match ip.is_unspecified() {
+        true => String::new(),
+        false => ip.to_string(),
+    };
+
+    let host = format!("{}.local.", settings.name);
+    let mut info = ServiceInfo::new(SERVICE_TYPE, &settings.name, &host, &*ips, port, properties)
+        .map_err(Error::Mdns)?;
+    if ips.is_empty() {
+        info = info.enable_addr_auto();
+    }
+
+    let fullname = info.get_fullname().to_string();
+    let daemon = ServiceDaemon::new().map_err(Error::Mdns)?;
+    daemon.register(info).map_err(Error::Mdns)?;
+    info!("Advertising {} on the local network", fullname);
+    Ok(Advertisement { daemon, fullname })
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            warn!("Could not stop advertising {}: {}", self.fullname, e);
+        }
+
+        if let Err(e) = self.daemon.shutdown() {
+            warn!("Could not shut down the mDNS daemon: {}", e);
+        }
+    }
+}
+
+/// Keeps browsing the local network for nodes until it is dropped.
+pub struct Browser {
+    daemon: ServiceDaemon,
+}
+
+/// Browses the local network for nodes in the background, and acknowledges
+/// the nodes which are found as peers of the node listening at the given
+/// address, once they have completed the handshake. Nodes are pinned by the
+/// key they proved, which has to be the key they advertised. See
+/// [seeds::acknowledge].
+pub(crate) fn browse(
+    node: Arc<RwLock<Node>>,
+    connector: Arc<sdk::Connector>,
+    addr: SocketAddr,
+) -> Result<Browser, Error> {
+    let daemon = ServiceDaemon::new().map_err(Error::Mdns)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(Error::Mdns)?;
+    let (name, own, timeout) = {
+        let node = node.read().unwrap();
+        let settings = &node.settings;
+        let own = seeds::own_keypair(settings);
+        (
+            settings.name.clone(),
+            own,
+            Duration::from_secs(settings.health.timeout),
+        )
+    };
+
+    info!("Browsing the local network for nodes");
+    std::thread::spawn(move || {
+        // The events stop once the daemon is shut down.
+        while let Ok(event) = events.recv() {
+            let found = match event {
+                ServiceEvent::ServiceResolved(info) => Discovered::from(&info),
+                _ => continue,
+            };
+
+            if found.name == name {
+                continue;
+            }
+
+            for candidate in found.addrs.iter().filter(|candidate| **candidate != addr) {
+                let expected = found.key.as_deref();
+                match seeds::acknowledge(&node, &connector, candidate, &own, expected, timeout) {
+                    Ok(Some(_)) => break,
+                    Ok(None) => {}
+                    Err(e) => debug!("{} at {} did not respond: {}", found.name, candidate, e),
+                }
+            }
+        }
+    });
+
+    Ok(Browser { daemon })
+}
+
+impl Drop for Browser {
+    fn drop(&mut self) {
+        if let Err(e) = self.daemon.stop_browse(SERVICE_TYPE) {
+            warn!("Could not stop browsing the local network: {}", e);
+        }
+
+        if let Err(e) = self.daemon.shutdown() {
+            warn!("Could not shut down the mDNS daemon: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discovered() {
+        let properties = HashMap::from([
+            ("version".to_string(), "0.1.0".to_string()),
+            ("key".to_string(), "abcd".to_string()),
+        ]);
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "multiverse9_test",
+            "multiverse9_test.local.",
+            "192.168.1.2",
+            4000,
+            properties,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Discovered::from(&info),
+            Discovered {
+                name: "multiverse9_test".into(),
+                addrs: vec!["192.168.1.2:4000".parse().unwrap()],
+                version: Some("0.1.0".into()),
+                key: Some("abcd".into()),
+            }
+        );
+    }
+}
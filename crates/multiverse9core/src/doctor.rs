@@ -0,0 +1,386 @@
+//! Diagnoses common misconfigurations of a node before it is started: storage
+//! which cannot be reached, addresses which cannot be bound, files which
+//! cannot be opened, and acknowledged nodes which cannot be reached, which are
+//! current node itself, or whose clocks are too far apart from the clock of
+//! current node. See `multiverse9ctl doctor`.
+
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::keys::Keypair;
+use crate::loopback;
+use crate::sdk;
+use crate::settings::{Peer, Settings};
+use crate::storage::Storage;
+
+/// How long connecting to an acknowledged node, and every request to it, may
+/// take before the node is considered unreachable.
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The largest difference in milliseconds between the clocks of current node
+/// and an acknowledged node which is not reported.
+pub const MAX_CLOCK_SKEW: u64 = 1000;
+
+/// How serious a finding is, most serious first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The node does not start, or does not work as configured.
+    Error,
+    /// The node works, but likely not as intended.
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Error => f.write_str("error"),
+            Self::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+/// A problem with the configuration of a node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The name of the check which found the problem.
+    pub check: String,
+    /// What is wrong.
+    pub message: String,
+    /// How the problem is fixed.
+    pub hint: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, check: &str, message: String, hint: &str) -> Self {
+        Self {
+            severity,
+            check: check.into(),
+            message,
+            hint: hint.into(),
+        }
+    }
+}
+
+/// Runs every check against the settings.
+///
+/// # Returns
+///
+/// The findings, most serious first. No findings means that no problem was
+/// found.
+pub fn diagnose(settings: &Settings) -> Vec<Finding> {
+    let mut findings = vec![];
+    check_storage(settings, &mut findings);
+    check_bind(settings, &mut findings);
+    check_files(settings, &mut findings);
+    check_peers(settings, &mut findings);
+    findings.sort_by_key(|finding| finding.severity);
+    findings
+}
+
+fn check_storage(settings: &Settings, findings: &mut Vec<Finding>) {
+    let result = Storage::new(settings).and_then(|storage| {
+        storage
+            .connection()?
+            .exists(storage.keyspace(), crate::metrics::KEY)
+    });
+
+    if let Err(e) = result {
+        findings.push(Finding::new(
+            Severity::Error,
+            "storage",
+            format!("Could not reach the storage: {}", e),
+            "Check that the storage backend is running, and that `redis_uri` and `sentinel` point to it",
+        ));
+    }
+}
+
+fn check_bind(settings: &Settings, findings: &mut Vec<Finding>) {
+    let addr = settings.addr;
+    if let Err(e) = TcpListener::bind(addr) {
+        let (severity, hint) = match e.kind() {
+            ErrorKind::AddrInUse => (
+                Severity::Warning,
+                "Stop the process using the port, unless it is this node running already",
+            ),
+            ErrorKind::PermissionDenied => (
+                Severity::Error,
+                "Choose a port above 1023, or allow the node to bind privileged ports",
+            ),
+            _ => (
+                Severity::Error,
+                "Choose an address which is assigned to an interface of this host",
+            ),
+        };
+
+        findings.push(Finding::new(
+            severity,
+            "bind",
+            format!("Could not bind {}: {}", addr, e),
+            hint,
+        ));
+    }
+
+    let remote = settings
+        .nodes
+        .iter()
+        .any(|peer| !peer.addr.ip().is_loopback());
+    if addr.ip().is_loopback() && (remote || settings.advertise) {
+        findings.push(Finding::new(
+            Severity::Warning,
+            "bind",
+            format!(
+                "The node binds to {}, which other hosts cannot connect to",
+                addr
+            ),
+            "Bind to the address of an interface other hosts reach, or to 0.0.0.0",
+        ));
+    }
+
+    if let Some(admin) = &settings.admin {
+        if admin.addr == addr && addr.port() != 0 {
+            findings.push(Finding::new(
+                Severity::Error,
+                "bind",
+                format!("The admin listener binds to {}, like the node", addr),
+                "Bind the admin listener to another port",
+            ));
+        } else if !admin.addr.ip().is_loopback() {
+            findings.push(Finding::new(
+                Severity::Warning,
+                "bind",
+                format!(
+                    "The admin listener binds to {}, which other hosts can connect to",
+                    admin.addr
+                ),
+                "Bind the admin listener to 127.0.0.1, since admin connections are not authenticated",
+            ));
+        }
+    }
+}
+
+fn check_files(settings: &Settings, findings: &mut Vec<Finding>) {
+    let mut writable: Vec<(&str, &Path)> = vec![];
+    if let Some(path) = &settings.crash_file {
+        writable.push(("crash_file", path));
+    }
+    if let Some(path) = &settings.changelog {
+        writable.push(("changelog", path));
+    }
+
+    for (name, path) in writable {
+        // Leaving files which do not exist yet alone, and only checking that
+        // they can be created.
+        let result = match path.exists() {
+            true => OpenOptions::new().append(true).open(path).map(|_| ()),
+            false => match path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                Some(parent) if !parent.is_dir() => Err(ErrorKind::NotFound.into()),
+                Some(parent) => match parent.metadata() {
+                    Ok(metadata) if metadata.permissions().readonly() => {
+                        Err(ErrorKind::PermissionDenied.into())
+                    }
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e),
+                },
+                None => Ok(()),
+            },
+        };
+
+        if let Err(e) = result {
+            findings.push(Finding::new(
+                Severity::Error,
+                "files",
+                format!("`{}` cannot be written to {:?}: {}", name, path, e),
+                "Create the directory, or give the user of the node write access to it",
+            ));
+        }
+    }
+
+    if let Some(tls) = &settings.tls {
+        let mut readable = vec![("certificate", &tls.certificate), ("key", &tls.key)];
+        if let Some(ca) = &tls.ca {
+            readable.push(("ca", ca));
+        }
+
+        for (name, path) in readable {
+            if let Err(e) = OpenOptions::new().read(true).open(path) {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    "files",
+                    format!("TLS `{}` cannot be read from {:?}: {}", name, path, e),
+                    "Check the path, and give the user of the node read access to the file",
+                ));
+            }
+        }
+    }
+}
+
+fn check_peers(settings: &Settings, findings: &mut Vec<Finding>) {
+    let public = settings
+        .key
+        .as_deref()
+        .and_then(|secret| Keypair::from_hex(secret).ok())
+        .map(|keypair| keypair.public());
+    let connector = match connector(settings) {
+        Ok(connector) => connector,
+        Err(e) => {
+            findings.push(Finding::new(
+                Severity::Error,
+                "tls",
+                format!("Could not load the TLS configuration: {}", e),
+                "Check the files in `tls`, and that the `tls` feature is enabled",
+            ));
+            return;
+        }
+    };
+
+    for peer in &settings.nodes {
+        if loopback::is_bound_to(&settings.addr, &peer.addr)
+            || (public.is_some() && peer.key == public)
+        {
+            findings.push(Finding::new(
+                Severity::Error,
+                "peers",
+                format!("Acknowledged node {} is current node itself", peer.addr),
+                "Remove the node from `nodes`",
+            ));
+            continue;
+        }
+
+        if let Some(finding) = check_peer(&connector, peer, public.as_deref()) {
+            findings.push(finding);
+        }
+    }
+}
+
+/// Asks the acknowledged node for its metadata, and compares its key and its
+/// clock with the ones of current node.
+fn check_peer(connector: &sdk::Connector, peer: &Peer, public: Option<&str>) -> Option<Finding> {
+    let sent = now();
+    let result = connector
+        .connect_timeout(&peer.addr, TIMEOUT)
+        .and_then(|mut client| {
+            if let Some(token) = &peer.token {
+                client.authenticate(token)?;
+            }
+
+            client.metadata()
+        });
+
+    let metadata = match result {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            return Some(Finding::new(
+                Severity::Warning,
+                "peers",
+                format!("Could not reach acknowledged node {}: {}", peer.addr, e),
+                "Check that the node is running, and that no firewall blocks its port",
+            ))
+        }
+    };
+
+    if metadata.key.is_some() && metadata.key.as_deref() == public {
+        return Some(Finding::new(
+            Severity::Error,
+            "peers",
+            format!("Acknowledged node {} is current node itself", peer.addr),
+            "Remove the node from `nodes`",
+        ));
+    }
+
+    if let (Some(pinned), Some(key)) = (&peer.key, &metadata.key) {
+        if !pinned.eq_ignore_ascii_case(key) {
+            return Some(Finding::new(
+                Severity::Error,
+                "peers",
+                format!(
+                    "Acknowledged node {} holds key {}, not the pinned one",
+                    peer.addr, key
+                ),
+                "Update the `key` of the node, or check that the address still belongs to it",
+            ));
+        }
+    }
+
+    // The time of the remote node is compared with the middle of the request.
+    let received = now();
+    let skew = metadata
+        .time
+        .map(|time| time.abs_diff((sent + received) / 2))?;
+    (skew > MAX_CLOCK_SKEW).then(|| {
+        Finding::new(
+            Severity::Warning,
+            "clock",
+            format!(
+                "The clock of acknowledged node {} is {}ms apart from the clock of current node",
+                peer.addr, skew
+            ),
+            "Synchronize the clocks of both hosts, e.g. with NTP",
+        )
+    })
+}
+
+fn connector(settings: &Settings) -> Result<sdk::Connector, String> {
+    match &settings.tls {
+        None => Ok(sdk::Connector::Plain),
+        #[cfg(feature = "tls")]
+        Some(tls) => crate::tls::connector(tls, &settings.nodes)
+            .map(sdk::Connector::Tls)
+            .map_err(|e| e.to_string()),
+        #[cfg(not(feature = "tls"))]
+        Some(_) => Err("The `tls` feature is disabled".into()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePeer, Reply};
+
+    #[test]
+    fn test_diagnose() {
+        let skewed = format!(
+            r#"{{"name": "skewed", "version": "0.1.0", "time": {}}}"#,
+            now() + 60_000
+        );
+        let peer = FakePeer::bind([(0x0005, Reply::ok(skewed))]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = listener.local_addr().unwrap();
+        settings.changelog = Some("/nonexistent/multiverse9/changelog".into());
+        settings.nodes = [settings.addr, peer.addr()]
+            .into_iter()
+            .map(|addr| serde_json::from_str(&format!(r#"{{"addr": "{}"}}"#, addr)).unwrap())
+            .collect();
+
+        let findings: Vec<_> = diagnose(&settings)
+            .into_iter()
+            .map(|finding| (finding.severity, finding.check))
+            .collect();
+        assert_eq!(
+            findings,
+            vec![
+                (Severity::Error, "files".into()),
+                (Severity::Error, "peers".into()),
+                (Severity::Warning, "bind".into()),
+                (Severity::Warning, "clock".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Structured records. Records are opaque byte blobs to the node, which
+//! leaves clients to agree on what the bytes mean. An [Envelope] carries the
+//! body of a record along with its content type, timestamps and author, so
+//! that the meaning of records can evolve without breaking the clients which
+//! read them.
+//!
+//! Envelopes are stored as the value of a record, encoded with MessagePack
+//! after the [MAGIC] prefix. The fields are encoded by name, so that fields can
+//! be added later without breaking older readers. Since aggregated entries are
+//! separated by null bytes, null bytes in the encoding are escaped as
+//! `[ESCAPE, 0x01]`, and [ESCAPE] itself as `[ESCAPE, 0x02]`. Values without the
+//! prefix are raw blobs, which nodes keep storing and serving as before.
+//!
+//! A [CREATE] request carries the content type of the record, followed by a
+//! null byte and the body. The node fills in the timestamp and the author, and
+//! replies with the key of the record like a regular create.
+
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Request code for creating a record wrapped in an envelope.
+pub const CREATE: u16 = 0x0021;
+
+/// Prefix of encoded envelopes. The first byte is never used by MessagePack,
+/// so no encoded value can be mistaken for the start of one.
+pub const MAGIC: &[u8] = b"\xC1m9e";
+
+/// Byte which starts an escaped byte in encoded envelopes.
+pub const ESCAPE: u8 = 0xFF;
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .Encode(rmp_serde::encode::Error) [source]
+    .Decode(rmp_serde::decode::Error) [source]
+    .Escape(&'static str)
+    ~Debug
+}
+
+/// A record along with what it is and who created it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    /// The media type of the body, such as `text/plain`.
+    pub content_type: String,
+    /// When the record was created, in milliseconds since the Unix epoch.
+    pub created: u64,
+    /// When the record was last changed, in milliseconds since the Unix
+    /// epoch, unless it never was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<u64>,
+    /// The handle of the user who created the record, unless it was created
+    /// anonymously or by another node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+impl Envelope {
+    /// Wraps the body in an envelope created now, without an author.
+    pub fn new(content_type: &str, body: &[u8]) -> Self {
+        Self {
+            content_type: content_type.to_string(),
+            created: now(),
+            updated: None,
+            author: None,
+            body: body.to_vec(),
+        }
+    }
+
+    /// Encodes the envelope into the value of a record.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut buffer = MAGIC.to_vec();
+        for byte in rmp_serde::to_vec_named(self).map_err(Error::Encode)? {
+            match byte {
+                00 => buffer.extend([ESCAPE, 0x01]),
+                ESCAPE => buffer.extend([ESCAPE, 0x02]),
+                byte => buffer.push(byte),
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    /// Decodes the value of a record into its envelope.
+    ///
+    /// # Returns
+    ///
+    /// [None] if the value is a raw blob.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Escape] or [Error::Decode] if the value carries the
+    /// prefix of an envelope, but not a valid envelope after it.
+    pub fn decode(value: &[u8]) -> Result<Option<Self>, Error> {
+        let escaped = match value.strip_prefix(MAGIC) {
+            Some(escaped) => escaped,
+            None => return Ok(None),
+        };
+
+        let mut encoded = Vec::with_capacity(escaped.len());
+        let mut bytes = escaped.iter().copied();
+        while let Some(byte) = bytes.next() {
+            encoded.push(match byte {
+                ESCAPE => match bytes.next() {
+                    Some(0x01) => 00,
+                    Some(0x02) => ESCAPE,
+                    _ => return Err(Error::Escape("Invalid escape sequence")),
+                },
+                byte => byte,
+            });
+        }
+
+        rmp_serde::from_slice(&encoded)
+            .map(Some)
+            .map_err(Error::Decode)
+    }
+}
+
+/// Splits the payload of a [CREATE] request into the content type and the
+/// body of the record.
+///
+/// # Returns
+///
+/// [None] if the payload has no content type.
+pub(crate) fn decode_create(payload: &[u8]) -> Option<(String, &[u8])> {
+    let split = payload.iter().position(|c| *c == 00)?;
+    let content_type = std::str::from_utf8(&payload[..split]).ok()?;
+    (!content_type.is_empty()).then(|| (content_type.to_string(), &payload[split + 1..]))
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut envelope = Envelope::new("text/plain", b"Hello, world!");
+        envelope.author = Some("alice".into());
+        let value = envelope.encode().unwrap();
+        assert!(value.starts_with(MAGIC));
+        assert_eq!(Envelope::decode(&value).unwrap(), Some(envelope));
+
+        // Raw blobs are left alone, while corrupted envelopes are reported.
+        assert_eq!(Envelope::decode(b"Hello, world!").unwrap(), None);
+        assert!(Envelope::decode(&value[..value.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_escape() {
+        let envelope = Envelope::new("application/octet-stream", &[0x00, 0xFF, 0x01, 0x02]);
+        let value = envelope.encode().unwrap();
+        assert!(!value.contains(&00));
+        assert_eq!(Envelope::decode(&value).unwrap(), Some(envelope));
+
+        let mut truncated = MAGIC.to_vec();
+        truncated.push(ESCAPE);
+        assert!(matches!(
+            Envelope::decode(&truncated),
+            Err(Error::Escape(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_create() {
+        assert_eq!(
+            decode_create(b"text/plain\x00body"),
+            Some(("text/plain".to_string(), &b"body"[..]))
+        );
+        assert_eq!(
+            decode_create(b"text/plain\x00"),
+            Some(("text/plain".to_string(), &b""[..]))
+        );
+        assert_eq!(decode_create(b"\x00body"), None);
+        assert_eq!(decode_create(b"body"), None);
+    }
+}
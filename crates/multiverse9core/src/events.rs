@@ -0,0 +1,228 @@
+//! Subscriptions let clients follow the changes to the records of a node as
+//! they happen, instead of polling for them. A connection subscribes with a
+//! [SUBSCRIBE] request, whose payload lists the topics it is interested in,
+//! separated by null bytes:
+//!
+//! - `created`, for records created on the node, including replies and
+//!   committed uploads.
+//! - `removed`, for removed records.
+//! - `posted`, for records posted to any feed, or `posted:<feed>` for the
+//!   records posted to the given feed only.
+//!
+//! An empty payload subscribes to every topic. The node acknowledges the
+//! subscription with an empty successful response, and from then on only
+//! pushes events over the connection, each in a successful response frame
+//! whose payload is the [Event] encoded as JSON. While there are no events,
+//! frames with an empty payload are pushed every [KEEPALIVE], so that
+//! connections which went away are noticed. Further requests on the
+//! connection are not read, so a subscription ends by closing the connection.
+//!
+//! Events are pushed at most once. Subscribers which fall more than [BACKLOG]
+//! events behind miss the events published in the meantime.
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::changelog::Changelog;
+use crate::feed;
+use crate::protocol::Identity;
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .InvalidTopic(String)
+    ~Debug
+}
+
+/// Request code of a subscription.
+pub const SUBSCRIBE: u16 = 0x0018;
+
+/// How long a subscribed connection may go without a frame.
+pub const KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// The most events queued for a single subscriber.
+pub const BACKLOG: usize = 1024;
+
+/// A change to the records of a node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Event {
+    /// A record was created under the given key.
+    Created { key: String },
+    /// The record with the given key was removed.
+    Removed { key: String },
+    /// The record with the given key was posted to a feed.
+    Posted { feed: String, key: String },
+}
+
+/// The topics a subscription is interested in. See [crate::events].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Filter {
+    created: bool,
+    removed: bool,
+    /// Whether records posted to any feed are of interest.
+    posted: bool,
+    /// The feeds whose records are of interest.
+    feeds: Vec<String>,
+}
+
+impl Filter {
+    /// Parses the topics listed in the payload of a [SUBSCRIBE] request.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::InvalidTopic] for topics which are unknown, or which
+    /// name an invalid feed.
+    pub fn parse(payload: &[u8]) -> Result<Self, Error> {
+        let mut filter = Self::default();
+        let topics: Vec<&[u8]> = payload
+            .split(|c| *c == 00)
+            .filter(|topic| !topic.is_empty())
+            .collect();
+        if topics.is_empty() {
+            return Ok(Self {
+                created: true,
+                removed: true,
+                posted: true,
+                feeds: vec![],
+            });
+        }
+
+        for topic in topics {
+            let topic = String::from_utf8_lossy(topic).to_string();
+            match topic.as_str() {
+                "created" => filter.created = true,
+                "removed" => filter.removed = true,
+                "posted" => filter.posted = true,
+                _ => match topic.strip_prefix("posted:") {
+                    Some(name) if feed::is_valid_name(name) => filter.feeds.push(name.into()),
+                    _ => return Err(Error::InvalidTopic(topic)),
+                },
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /// Returns whether the event belongs to any of the topics.
+    pub fn matches(&self, event: &Event) -> bool {
+        match event {
+            Event::Created { .. } => self.created,
+            Event::Removed { .. } => self.removed,
+            Event::Posted { feed, .. } => self.posted || self.feeds.contains(feed),
+        }
+    }
+}
+
+/// Hands the events published by the handlers to the subscribed connections,
+/// after appending them to the changelog if the node keeps one.
+#[derive(Default)]
+pub(crate) struct Bus {
+    subscribers: Mutex<Vec<(Filter, SyncSender<Event>)>>,
+    changelog: Option<Changelog>,
+}
+
+impl Bus {
+    pub(crate) fn new(changelog: Option<Changelog>) -> Self {
+        Self {
+            subscribers: Default::default(),
+            changelog,
+        }
+    }
+
+    /// Returns the changelog of the node, if it keeps one.
+    #[inline(always)]
+    pub(crate) fn changelog(&self) -> Option<&Changelog> {
+        self.changelog.as_ref()
+    }
+
+    /// Subscribes to the events matching the filter, which are received until
+    /// the receiver is dropped.
+    pub(crate) fn subscribe(&self, filter: Filter) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::sync_channel(BACKLOG);
+        self.subscribers.lock().unwrap().push((filter, sender));
+        receiver
+    }
+
+    /// Appends the event made by the identity to the changelog, and hands it
+    /// to the subscribers interested in it without waiting for any of them.
+    /// Subscribers which went away are dropped along the way.
+    pub(crate) fn publish(&self, identity: &Identity, event: Event) {
+        if let Some(changelog) = &self.changelog {
+            // The change has been made already, so it is published regardless.
+            if let Err(e) = changelog.append(identity, &event) {
+                error!("Could not append {:?} to the changelog: {}", event, e);
+            }
+        }
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|(filter, sender)| match filter.matches(&event) {
+                false => true,
+                true => match sender.try_send(event.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        warn!("Dropped {:?} for a subscriber which fell behind", event);
+                        true
+                    }
+                    Err(TrySendError::Disconnected(_)) => false,
+                },
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn created() -> Event {
+        Event::Created { key: "key".into() }
+    }
+
+    fn posted(feed: &str) -> Event {
+        Event::Posted {
+            feed: feed.into(),
+            key: "key".into(),
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let all = Filter::parse(b"").unwrap();
+        assert!(all.matches(&created()) && all.matches(&posted("news")));
+
+        let filter = Filter::parse(b"removed\x00posted:news").unwrap();
+        assert!(!filter.matches(&created()));
+        assert!(filter.matches(&posted("news")));
+        assert!(!filter.matches(&posted("sports")));
+
+        assert!(Filter::parse(b"updated").is_err());
+        assert!(Filter::parse(b"posted:a b").is_err());
+    }
+
+    #[test]
+    fn test_bus() {
+        let bus = Bus::default();
+        let news = bus.subscribe(Filter::parse(b"posted:news").unwrap());
+        let gone = bus.subscribe(Filter::parse(b"").unwrap());
+        drop(gone);
+
+        let identity = Identity::User("alice".into());
+        bus.publish(&identity, created());
+        bus.publish(&identity, posted("news"));
+        assert_eq!(news.try_recv().unwrap(), posted("news"));
+        assert!(news.try_recv().is_err());
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_event_json() {
+        assert_eq!(
+            serde_json::to_string(&posted("news")).unwrap(),
+            r#"{"kind":"posted","feed":"news","key":"key"}"#
+        );
+    }
+}
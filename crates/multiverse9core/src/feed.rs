@@ -0,0 +1,32 @@
+/// Number of entries returned when a request does not specify one.
+pub const DEFAULT_COUNT: usize = 20;
+/// The largest number of entries a single request may return.
+pub const MAX_COUNT: usize = 100;
+/// The longest name a feed may have.
+const MAX_NAME_LEN: usize = 64;
+
+/// Returns whether the name can be used for a feed. Names are limited to
+/// alphanumeric characters, `-` and `_`, so that they never collide with the
+/// delimiters of the payloads and keys they are part of.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("announcements"));
+        assert!(is_valid_name("eu-west_1"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("news@127.0.0.1:8000"));
+        assert!(!is_valid_name("a:b"));
+        assert!(!is_valid_name(&"a".repeat(MAX_NAME_LEN + 1)));
+    }
+}
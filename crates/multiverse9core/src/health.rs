@@ -0,0 +1,294 @@
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::crash;
+use crate::node::Node;
+use crate::sdk;
+use crate::storage::Storage;
+
+/// Request code of a liveness probe, which is answered right away without
+/// touching the storage, and without authenticating first. The payload of the
+/// reply starts with [PONG], followed by a null byte and the [Load] of the node
+/// encoded as JSON, so that load balancers which only match the start of the
+/// reply can probe nodes too.
+pub const PING: u16 = 0x001E;
+
+/// The fixed start of the reply to a [PING].
+pub const PONG: &[u8] = b"pong";
+
+/// The load of a node at the time it answered a [PING].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Load {
+    /// The number of connections which are being served.
+    pub active: u64,
+    /// The number of accepted connections which wait for a worker.
+    pub queued: u64,
+}
+
+impl Load {
+    /// Returns the current load of the node.
+    pub fn current() -> Self {
+        let snapshot = crash::Snapshot::take();
+        Self {
+            active: snapshot.active,
+            queued: snapshot.queued,
+        }
+    }
+
+    /// Decodes the reply to a [PING].
+    ///
+    /// # Returns
+    ///
+    /// [None] if the reply does not start with [PONG], or if the load cannot
+    /// be decoded.
+    pub fn decode(reply: &[u8]) -> Option<Self> {
+        let load = reply.strip_prefix(PONG)?.strip_prefix(&[00])?;
+        serde_json::from_slice(load).ok()
+    }
+}
+
+/// Encodes the reply to a [PING] with the current load of the node.
+pub(crate) fn pong() -> Vec<u8> {
+    let mut reply = PONG.to_vec();
+    reply.push(00);
+    reply.extend(serde_json::to_vec(&Load::current()).unwrap());
+    reply
+}
+
+/// The outcome of probing an acknowledged node once.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    /// Time of the probe, in milliseconds since the Unix epoch.
+    pub at: u64,
+    /// Whether a connection to the node could be established.
+    pub up: bool,
+    /// Round-trip time of the probe request in milliseconds, if the node
+    /// replied to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt: Option<u64>,
+    /// Why the probe failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The health history of an acknowledged node.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    /// Address of the node.
+    pub addr: SocketAddr,
+    /// The samples in the window, starting with the most recent one.
+    pub samples: Vec<Sample>,
+}
+
+impl Report {
+    /// Returns whether the node was up when it was last probed, or [None] if it
+    /// has not been probed yet.
+    pub fn is_up(&self) -> Option<bool> {
+        self.samples.first().map(|sample| sample.up)
+    }
+
+    /// Returns the share of the samples in which the node was up.
+    pub fn uptime(&self) -> f64 {
+        self.share(|sample| sample.up)
+    }
+
+    /// Returns the share of the samples in which the probe failed, whether
+    /// because the node was down or because it did not reply properly.
+    pub fn error_rate(&self) -> f64 {
+        self.share(|sample| sample.error.is_some())
+    }
+
+    /// Returns the mean round-trip time of the successful probes.
+    pub fn rtt(&self) -> Option<u64> {
+        let rtts: Vec<u64> = self
+            .samples
+            .iter()
+            .filter_map(|sample| sample.rtt)
+            .collect();
+        (!rtts.is_empty()).then(|| rtts.iter().sum::<u64>() / rtts.len() as u64)
+    }
+
+    fn share(&self, predicate: impl Fn(&Sample) -> bool) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+
+        let matching = self
+            .samples
+            .iter()
+            .filter(|sample| predicate(sample))
+            .count();
+        matching as f64 / self.samples.len() as f64
+    }
+}
+
+/// Probes the node at the given address with a [PING]. Any well-formed reply
+/// counts as a success, even from nodes which predate probes.
+pub(crate) fn probe(connector: &sdk::Connector, addr: &SocketAddr, timeout: Duration) -> Sample {
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default();
+
+    let mut client = match connector.connect_timeout(addr, timeout) {
+        Ok(client) => client,
+        Err(e) => {
+            return Sample {
+                at,
+                up: false,
+                rtt: None,
+                error: Some(e.to_string()),
+            }
+        }
+    };
+
+    let started = Instant::now();
+    match client.ping_timeout(Some(timeout)) {
+        Ok(pong) => Sample {
+            at,
+            up: true,
+            rtt: Some(pong.rtt.as_millis() as u64),
+            error: None,
+        },
+        // Nodes which predate probes reply to them all the same.
+        Err(sdk::Error::Status(_)) | Err(sdk::Error::Malformed(_)) => Sample {
+            at,
+            up: true,
+            rtt: Some(started.elapsed().as_millis() as u64),
+            error: None,
+        },
+        Err(e) => Sample {
+            at,
+            up: true,
+            rtt: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Spawns the thread which probes the acknowledged nodes every
+/// [crate::settings::Health::interval] seconds, and stores the samples in a
+/// rolling window per node. Nothing is spawned if probing is disabled.
+pub(crate) fn spawn(
+    node: Arc<RwLock<Node>>,
+    storage: Arc<Storage>,
+    connector: Arc<sdk::Connector>,
+) {
+    let settings = node.read().unwrap().settings.health;
+    if settings.interval == 0 {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let mut connection = None;
+        loop {
+            let peers: Vec<SocketAddr> = {
+                let node = node.read().unwrap();
+                node.settings.nodes.iter().map(|peer| peer.addr).collect()
+            };
+
+            for addr in peers {
+                let sample = probe(&connector, &addr, Duration::from_secs(settings.timeout));
+                if let Some(error) = &sample.error {
+                    debug!("Probing {} failed: {}", addr, error);
+                }
+
+                if connection.is_none() {
+                    match storage.connection() {
+                        Ok(opened) => connection = Some(opened),
+                        Err(e) => {
+                            error!("Could not store the health of {}: {}", addr, e);
+                            continue;
+                        }
+                    }
+                }
+
+                let connection = connection.as_mut().unwrap();
+                let buffer = serde_json::to_vec(&sample).unwrap();
+                let result = connection.push_sample(
+                    storage.keyspace(),
+                    &addr.to_string(),
+                    &buffer,
+                    settings.window,
+                );
+                if let Err(e) = result {
+                    error!("Could not store the health of {}: {}", addr, e);
+                    if let Err(e) = storage.recover(connection, &e) {
+                        error!("Could not reconnect to the storage: {}", e);
+                    }
+                }
+            }
+
+            std::thread::sleep(Duration::from_secs(settings.interval));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePeer, Reply};
+
+    fn sample(up: bool, rtt: Option<u64>) -> Sample {
+        Sample {
+            at: 0,
+            up,
+            rtt,
+            error: rtt.is_none().then(|| "failed".into()),
+        }
+    }
+
+    #[test]
+    fn test_pong() {
+        // The load itself changes with the tests which run alongside.
+        assert!(Load::decode(&pong()).is_some());
+
+        assert_eq!(Load::decode(b"pong"), None);
+        assert_eq!(Load::decode(b"ping\x00{}"), None);
+        assert_eq!(Load::decode(b"pong\x00{}"), None);
+    }
+
+    #[test]
+    fn test_report() {
+        let report = Report {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            samples: vec![
+                sample(true, Some(10)),
+                sample(true, None),
+                sample(false, None),
+                sample(true, Some(30)),
+            ],
+        };
+
+        assert_eq!(report.is_up(), Some(true));
+        assert_eq!(report.uptime(), 0.75);
+        assert_eq!(report.error_rate(), 0.5);
+        assert_eq!(report.rtt(), Some(20));
+    }
+
+    #[test]
+    fn test_probe() {
+        let timeout = Duration::from_secs(1);
+        let pong = Reply::ok(&b"pong\x00{\"active\": 1, \"queued\": 0}"[..]);
+        let peer = FakePeer::bind([(PING, pong)]).unwrap();
+        let sample = probe(&sdk::Connector::Plain, &peer.addr(), timeout);
+        assert!(sample.up && sample.rtt.is_some() && sample.error.is_none());
+
+        let peer = FakePeer::bind([(PING, Reply::status(1))]).unwrap();
+        let sample = probe(&sdk::Connector::Plain, &peer.addr(), timeout);
+        assert!(sample.up && sample.rtt.is_some() && sample.error.is_none());
+
+        let peer = FakePeer::bind([(PING, Reply::Disconnect)]).unwrap();
+        let sample = probe(&sdk::Connector::Plain, &peer.addr(), timeout);
+        assert!(sample.up && sample.error.is_some());
+
+        // Nothing listens on the address once the peer is dropped.
+        let addr = peer.addr();
+        drop(peer);
+        let sample = probe(&sdk::Connector::Plain, &addr, timeout);
+        assert!(!sample.up && sample.error.is_some());
+    }
+}
@@ -0,0 +1,107 @@
+//! Journaled requests give clients exactly-once semantics for requests they
+//! may have to retry, such as after a connection was lost before the response
+//! arrived. A journaled request carries an ID chosen by the client, and the
+//! response to it is kept in the storage of the node for
+//! [Journal::retention](crate::settings::Journal::retention). Delivering the
+//! request again replays the kept response instead of executing it again, on
+//! any connection and across restarts of the node.
+//!
+//! The payload of a [JOURNALED] request is laid out as follows:
+//!
+//! ```text
+//! +----------------+----------------------+---------------+
+//! | id length (u8) | request id (n bytes) | request frame |
+//! +----------------+----------------------+---------------+
+//! ```
+//!
+//! The response is the response to the request frame itself. Only successful
+//! responses are kept, so that requests which failed can be retried.
+
+use crate::protocol::{codec, Identity};
+
+/// Request code of a journaled request.
+pub const JOURNALED: u16 = 0x0010;
+
+/// Response status sent when a request with the same ID is still being
+/// executed. Clients should retry after a while.
+pub const IN_PROGRESS: u8 = 0x0003;
+
+/// The longest request ID a client may choose. ULIDs are a good choice.
+pub const MAX_ID_LEN: usize = 64;
+
+/// Encodes a request into the payload of a [JOURNALED] request.
+///
+/// # Errors
+///
+/// Returns [codec::Error::Oversized] if the ID is longer than [MAX_ID_LEN], or
+/// if the payload of the request is too large.
+pub fn encode(id: &str, code: u16, payload: &[u8]) -> Result<Vec<u8>, codec::Error> {
+    if id.is_empty() || id.len() > MAX_ID_LEN {
+        return Err(codec::Error::Oversized(id.len()));
+    }
+
+    let mut buffer = vec![id.len() as u8];
+    buffer.extend(id.as_bytes());
+    buffer.extend(codec::encode_request(code, payload)?);
+    Ok(buffer)
+}
+
+/// Decodes the payload of a [JOURNALED] request into the ID and the request.
+///
+/// # Returns
+///
+/// [None] if the payload is malformed, or if anything follows the request.
+pub fn decode(payload: &[u8]) -> Option<(&str, codec::Request<'_>)> {
+    let (len, rest) = payload.split_first()?;
+    let len = *len as usize;
+    if len == 0 || len > MAX_ID_LEN || rest.len() < len {
+        return None;
+    }
+
+    let id = std::str::from_utf8(&rest[..len]).ok()?;
+    let (request, consumed) = codec::decode_request(&rest[len..]).ok()?;
+    (consumed == rest.len() - len).then_some((id, request))
+}
+
+/// Returns the client the journal entries of a connection are kept for.
+/// Anonymous connections are keyed by their IP address only, since a client
+/// retrying over a new connection comes from a different port.
+pub(crate) fn client(identity: &Identity) -> String {
+    match identity {
+        Identity::Anonymous(addr) => format!("ip:{}", addr.ip()),
+        identity => identity.key(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let payload = encode("01GQ", 0x0001, b"value").unwrap();
+        let (id, request) = decode(&payload).unwrap();
+        assert_eq!(id, "01GQ");
+        assert_eq!(request.code, 0x0001);
+        assert_eq!(request.payload, b"value");
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(encode("", 0x0001, b"value").is_err());
+        assert!(encode(&"a".repeat(MAX_ID_LEN + 1), 0x0001, b"value").is_err());
+
+        let payload = encode("01GQ", 0x0001, b"value").unwrap();
+        assert_eq!(decode(&payload[..payload.len() - 1]), None);
+        assert_eq!(decode(&[payload.clone(), vec![0]].concat()), None);
+        assert_eq!(decode(&[]), None);
+    }
+
+    #[test]
+    fn test_client() {
+        let anonymous = Identity::Anonymous("127.0.0.1:4000".parse().unwrap());
+        let reconnected = Identity::Anonymous("127.0.0.1:4001".parse().unwrap());
+        assert_eq!(client(&anonymous), client(&reconnected));
+        assert_eq!(client(&Identity::User("alice".into())), "user:alice");
+    }
+}
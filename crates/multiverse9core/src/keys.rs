@@ -0,0 +1,352 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+
+/// Length of the nonces exchanged during the handshake.
+pub const NONCE_LEN: usize = 32;
+const PUBLIC_LEN: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
+const SIGNATURE_LEN: usize = ed25519_dalek::SIGNATURE_LENGTH;
+/// Prefixed to every message signed during the handshake, so that these
+/// signatures cannot be reused for anything else.
+const CONTEXT: &[u8] = b"multiverse9/handshake";
+/// Prefixed to every signed record, for the same reason.
+const RECORD_CONTEXT: &[u8] = b"multiverse9/record";
+/// Prefixed to every signed record which is owned by a user. Kept apart from
+/// [RECORD_CONTEXT] so that an owned record cannot pass as an unowned one.
+const OWNED_RECORD_CONTEXT: &[u8] = b"multiverse9/owned-record";
+/// Prefixed to the handles users sign when registering.
+const REGISTRATION_CONTEXT: &[u8] = b"multiverse9/registration";
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .InvalidKey(String)
+    .Handshake(&'static str)
+    ~Debug
+}
+
+/// The Ed25519 keypair of a node. Nodes are identified by their public key,
+/// which unlike their address stays the same when they move, and cannot be
+/// claimed without the secret key.
+pub struct Keypair {
+    signing: SigningKey,
+}
+
+impl Keypair {
+    /// Generates a new keypair from the randomness of the operating system.
+    pub fn generate() -> Self {
+        Self {
+            signing: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Restores the keypair from its hex-encoded secret key.
+    pub fn from_hex(secret: &str) -> Result<Self, Error> {
+        let bytes: [u8; 32] = unhex(secret)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| Error::InvalidKey("Expected 64 hex digits".into()))?;
+        Ok(Self {
+            signing: SigningKey::from_bytes(&bytes),
+        })
+    }
+
+    /// Returns the hex-encoded secret key, which is how it is kept in the
+    /// settings.
+    pub fn secret(&self) -> String {
+        hex(&self.signing.to_bytes())
+    }
+
+    /// Returns the hex-encoded public key, which is how nodes are pinned by
+    /// their peers.
+    pub fn public(&self) -> String {
+        hex(self.signing.verifying_key().as_bytes())
+    }
+
+    /// Signs the value of a record along with its ID and owner, so that the
+    /// signature can neither be moved over to another record nor be kept when
+    /// the record is attributed to someone else.
+    pub fn sign_record(&self, id: &str, owner: Option<&str>, value: &[u8]) -> Vec<u8> {
+        let signature = self.signing.sign(&record(id, owner, value));
+        signature.to_bytes().to_vec()
+    }
+
+    /// Signs the handle a user registers with, which proves that the user
+    /// holds the secret key of the profile.
+    pub fn sign_registration(&self, handle: &str) -> Vec<u8> {
+        let signature = self
+            .signing
+            .sign(&[REGISTRATION_CONTEXT, handle.as_bytes()].concat());
+        signature.to_bytes().to_vec()
+    }
+}
+
+/// Returns whether the signature of a record was made by the node with the
+/// given hex-encoded public key.
+pub fn verify_record(
+    public: &str,
+    id: &str,
+    owner: Option<&str>,
+    value: &[u8],
+    signature: &[u8],
+) -> bool {
+    match unhex(public) {
+        Some(public) => verify(&public, &record(id, owner, value), signature),
+        None => false,
+    }
+}
+
+/// Returns whether the handle was signed with the given hex-encoded public key.
+pub fn verify_registration(public: &str, handle: &str, signature: &[u8]) -> bool {
+    match unhex(public) {
+        Some(public) => verify(
+            &public,
+            &[REGISTRATION_CONTEXT, handle.as_bytes()].concat(),
+            signature,
+        ),
+        None => false,
+    }
+}
+
+/// Builds the message signed for a record. Since record IDs have a fixed
+/// length and handles cannot contain null bytes, the parts cannot be shifted
+/// into one another.
+fn record(id: &str, owner: Option<&str>, value: &[u8]) -> Vec<u8> {
+    match owner {
+        Some(owner) => [
+            OWNED_RECORD_CONTEXT,
+            id.as_bytes(),
+            owner.as_bytes(),
+            &[0],
+            value,
+        ]
+        .concat(),
+        None => [RECORD_CONTEXT, id.as_bytes(), value].concat(),
+    }
+}
+
+/// The nonces of a handshake which is waiting for the proof of the client.
+pub(crate) struct Challenge {
+    client: [u8; NONCE_LEN],
+    server: [u8; NONCE_LEN],
+}
+
+/// Generates a random nonce for opening a handshake.
+pub(crate) fn nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Replies to the opening frame of a handshake, whose payload is the nonce of
+/// the client. The reply consists of the public key of the node, a nonce of
+/// its own, and a signature over both nonces.
+///
+/// # Returns
+///
+/// The reply along with the challenge the client has to answer, or [None] if
+/// the payload is not a nonce.
+pub(crate) fn respond(keypair: &Keypair, payload: &[u8]) -> Option<(Vec<u8>, Challenge)> {
+    let client: [u8; NONCE_LEN] = payload.try_into().ok()?;
+    let server = nonce();
+    let public = keypair.signing.verifying_key().to_bytes();
+    let signature = keypair
+        .signing
+        .sign(&transcript(b"server", &client, &server, &public));
+
+    let reply = [&public[..], &server, &signature.to_bytes()].concat();
+    Some((reply, Challenge { client, server }))
+}
+
+/// Verifies the reply of the node to the opening frame, and signs both nonces
+/// in return.
+///
+/// # Returns
+///
+/// The hex-encoded public key of the node, along with the payload of the frame
+/// which proves the identity of the client.
+pub(crate) fn prove(
+    keypair: &Keypair,
+    client: &[u8; NONCE_LEN],
+    reply: &[u8],
+) -> Result<(String, Vec<u8>), Error> {
+    if reply.len() != PUBLIC_LEN + NONCE_LEN + SIGNATURE_LEN {
+        return Err(Error::Handshake("Malformed challenge"));
+    }
+
+    let (public, rest) = reply.split_at(PUBLIC_LEN);
+    let (server, signature) = rest.split_at(NONCE_LEN);
+    if !verify(
+        public,
+        &transcript(b"server", client, server, public),
+        signature,
+    ) {
+        return Err(Error::Handshake("Invalid signature of the node"));
+    }
+
+    let own = keypair.signing.verifying_key().to_bytes();
+    let signature = keypair
+        .signing
+        .sign(&transcript(b"client", client, server, public));
+    Ok((hex(public), [&own[..], &signature.to_bytes()].concat()))
+}
+
+/// Verifies the proof of the client for the challenge.
+///
+/// # Returns
+///
+/// The hex-encoded public key of the client, or [None] if the signature does
+/// not match it.
+pub(crate) fn verify_proof(
+    keypair: &Keypair,
+    challenge: &Challenge,
+    payload: &[u8],
+) -> Option<String> {
+    if payload.len() != PUBLIC_LEN + SIGNATURE_LEN {
+        return None;
+    }
+
+    let (client, signature) = payload.split_at(PUBLIC_LEN);
+    let public = keypair.signing.verifying_key().to_bytes();
+    let message = transcript(b"client", &challenge.client, &challenge.server, &public);
+    verify(client, &message, signature).then(|| hex(client))
+}
+
+/// Builds the message signed by either side. Both nonces are included so that
+/// neither side can replay an old signature, and the public key of the node so
+/// that a proof made for one node cannot be relayed to another.
+fn transcript(role: &[u8], client: &[u8], server: &[u8], public: &[u8]) -> Vec<u8> {
+    [CONTEXT, role, client, server, public].concat()
+}
+
+fn verify(public: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let (public, signature): ([u8; PUBLIC_LEN], [u8; SIGNATURE_LEN]) =
+        match (public.try_into(), signature.try_into()) {
+            (Ok(public), Ok(signature)) => (public, signature),
+            _ => return false,
+        };
+
+    VerifyingKey::from_bytes(&public)
+        .map(|key| {
+            key.verify_strict(message, &Signature::from_bytes(&signature))
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn unhex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let keypair = Keypair::generate();
+        let restored = Keypair::from_hex(&keypair.secret()).unwrap();
+        assert_eq!(restored.public(), keypair.public());
+        assert!(Keypair::from_hex("abc").is_err());
+        assert!(Keypair::from_hex(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_sign_record() {
+        let keypair = Keypair::generate();
+        let public = keypair.public();
+        let signature = keypair.sign_record("01GQ", None, b"value");
+        assert!(verify_record(&public, "01GQ", None, b"value", &signature));
+        assert!(!verify_record(
+            &public,
+            "01GQ",
+            None,
+            b"tampered",
+            &signature
+        ));
+        assert!(!verify_record(&public, "01GR", None, b"value", &signature));
+        assert!(!verify_record(
+            &Keypair::generate().public(),
+            "01GQ",
+            None,
+            b"value",
+            &signature
+        ));
+
+        // The signature of an unowned record cannot be claimed by a user.
+        assert!(!verify_record(
+            &public,
+            "01GQ",
+            Some("alice"),
+            b"value",
+            &signature
+        ));
+        let signature = keypair.sign_record("01GQ", Some("alice"), b"value");
+        assert!(verify_record(
+            &public,
+            "01GQ",
+            Some("alice"),
+            b"value",
+            &signature
+        ));
+        assert!(!verify_record(
+            &public,
+            "01GQ",
+            Some("bob"),
+            b"value",
+            &signature
+        ));
+    }
+
+    #[test]
+    fn test_sign_registration() {
+        let keypair = Keypair::generate();
+        let signature = keypair.sign_registration("alice");
+        assert!(verify_registration(&keypair.public(), "alice", &signature));
+        assert!(!verify_registration(&keypair.public(), "bob", &signature));
+        assert!(!verify_registration(
+            &Keypair::generate().public(),
+            "alice",
+            &signature
+        ));
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (server, client) = (Keypair::generate(), Keypair::generate());
+        let nonce = nonce();
+
+        let (reply, challenge) = respond(&server, &nonce).unwrap();
+        let (public, proof) = prove(&client, &nonce, &reply).unwrap();
+        assert_eq!(public, server.public());
+        assert_eq!(
+            verify_proof(&server, &challenge, &proof),
+            Some(client.public())
+        );
+    }
+
+    #[test]
+    fn test_handshake_tampered() {
+        let (server, client) = (Keypair::generate(), Keypair::generate());
+        let nonce = nonce();
+
+        // The reply was signed for another nonce of the client.
+        let (reply, challenge) = respond(&server, &[0; NONCE_LEN]).unwrap();
+        assert!(prove(&client, &nonce, &reply).is_err());
+
+        // The proof was made for another challenge.
+        let (reply, _) = respond(&server, &nonce).unwrap();
+        let (_, proof) = prove(&client, &nonce, &reply).unwrap();
+        assert_eq!(verify_proof(&server, &challenge, &proof), None);
+
+        assert!(respond(&server, b"short").is_none());
+    }
+}
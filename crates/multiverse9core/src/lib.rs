@@ -1,35 +1,208 @@
 #![forbid(unsafe_code)]
 
+/// Contains the admin listener, which serves the management of a node apart from
+/// its public address.
+pub mod admin;
+/// Contains anti-entropy, which reconciles the records of a node with those of
+/// an acknowledged node.
+pub mod anti_entropy;
+/// Contains the token authentication of incoming connections.
+pub mod auth;
+/// Contains backups, which are portable archives of every key of a node.
+pub mod backup;
+/// Contains bulk creation, which creates many records with a single request.
+pub mod bulk;
+/// Contains the capabilities nodes and clients exchange, so that they can detect
+/// the features supported by the other side.
+pub mod capabilities;
+/// Contains the changelog, an append-only file of the changes made to the records
+/// of a node.
+pub mod changelog;
+/// Contains blocks of IP addresses, for the allow and deny lists of a node.
+pub mod cidr;
+/// Contains the hybrid logical clock and the merge strategies which resolve
+/// conflicting versions of replicated records.
+pub mod conflict;
+/// Contains the keys of content-addressed records, which are derived from the
+/// values of the records.
+pub mod content;
+/// Contains the crash reports logged when a thread of the node panics, and
+/// the counters included in them.
+pub mod crash;
+/// Contains deadlines, which bound the time a node spends on a request, and
+/// the partial responses of requests which exceed them.
+pub mod deadline;
+/// Contains the discovery of nodes advertised on the local network over mDNS.
+#[cfg(feature = "discovery")]
+pub mod discovery;
+/// Contains the diagnosis of common misconfigurations of a node.
+pub mod doctor;
+/// Contains structured records, which carry their content type, timestamps and
+/// author along with their body.
+pub mod envelope;
+/// Contains subscriptions, over which nodes push changes to their records to
+/// clients as they happen.
+pub mod events;
+/// Contains the named feeds records can be posted to, which list the latest
+/// records posted to them.
+pub mod feed;
+/// Contains the probing of acknowledged nodes, and the history of their health
+/// which operators can inspect.
+pub mod health;
+/// Contains the journal of processed requests, which gives clients exactly-once
+/// semantics for requests they retry.
+pub mod journal;
+/// Contains the Ed25519 keypairs nodes are identified by, and the signed
+/// handshake which proves the identity of a node to its peers.
+pub mod keys;
+/// Contains listings, which return the keys of the records of a node matching a
+/// prefix or a range.
+pub mod listing;
+/// Contains loopback connections, which reach a node running in the same
+/// process through an in-process pipe instead of TCP.
+pub mod loopback;
+/// Contains the counters of the requests handled by the node, which are saved
+/// to storage so that they survive restarts.
+pub mod metrics;
+/// Contains the migration of records stored before keys were namespaced.
+pub mod migration;
+/// Contains multiplexing, which lets clients have many requests outstanding on a
+/// single connection, told apart by correlation IDs.
+pub mod multiplex;
 /// Contains the main node implementation which handles incoming TCP connections
 /// and delegates the requests to the appropriate handler functions.
 pub mod node;
+/// Contains pagination, which returns feeds and scans of the records of a node
+/// in pages.
+pub mod pagination;
+/// Contains placement, which maps keys onto the nodes of a federation with a
+/// consistent-hash ring.
+pub mod placement;
 /// Contains the protocol implementation for communicating between nodes. Defines
 /// the request and response codes, as well as the handler functions for each request.
 pub mod protocol;
+/// Contains quorum reads, which read a key from several of its replicas and
+/// return the newest version among them.
+pub mod quorum;
+/// Contains the retention of records, which removes the oldest records of a node
+/// once they exceed the configured limits.
+pub mod retention;
+/// Contains the roles which govern what a connection may do on current node.
+pub mod roles;
+/// Contains tags for describing the topology of the federation, and the
+/// policies used for picking peers based on those tags.
+pub mod routing;
 /// Contains the SDK for interacting with the multiverse9 network.
 pub mod sdk;
+/// Contains full-text search, which finds the records of a node by the words
+/// they hold.
+pub mod search;
+/// Contains the seeds, DNS names which resolve to the nodes a node acknowledges
+/// once it has started.
+pub mod seeds;
 /// Contains the settings struct which holds configuration for a node instance.
 pub mod settings;
+/// Contains sharding, which stores every record on the node which owns its key,
+/// turning a set of nodes into one logical keyspace.
+pub mod sharding;
+/// Contains the graceful shutdown of a node, which drains the requests in flight
+/// before the node stops.
+pub mod shutdown;
+/// Contains streaming aggregations, which return every record in a frame of its
+/// own as soon as it is read.
+pub mod streaming;
+/// Contains tags, which index records in sets that can be intersected and
+/// united.
+pub mod tags;
+/// Contains test doubles which speak the wire protocol, for reproducing the
+/// behavior of remote nodes in tests.
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+/// Contains timed requests, whose responses carry a breakdown of the time the
+/// node spent on them.
+pub mod timing;
+/// Contains mutual TLS for incoming and outgoing connections, with certificates
+/// verified against a CA or pinned for acknowledged nodes.
+#[cfg(feature = "tls")]
+pub mod tls;
+/// Contains transfers, which copy every key of a node from one storage backend
+/// to another.
+pub mod transfer;
+/// Contains the profiles of the users registered on a node, to whom the
+/// records they create are attributed.
+pub mod users;
+/// Contains the views which transform the values of records as they are
+/// aggregated.
+pub mod views;
 pub mod prelude {
     pub use super::node::Node;
     pub use super::sdk;
     pub use super::settings::Settings;
 }
 
+/// Contains the access log, which records every request a node serves.
+pub(crate) mod access;
 /// Contains the protocol implementation for communicating between nodes. Defines
 /// the request and response codes, as well as the handler functions for each request.
 pub(crate) mod api;
+/// Contains the byte buffers which are reused across connections and requests.
+pub(crate) mod buffers;
+/// Contains the pool of connections to acknowledged nodes, which are kept open
+/// between requests.
+pub(crate) mod outbound;
 /// Contains a thread pool implementation. The thread pool spawns a fixed number
 /// of threads on initialization. Jobs can then be submitted to the pool, and will
 /// be executed on the next available thread.
 pub(crate) mod pooling;
+/// Contains the reactor, which parks idle connections until they become readable
+/// so that they do not hold a worker of the pool.
+pub(crate) mod reactor;
+/// Contains the storage backends of a node: Redis, including the resolution of
+/// the master through Redis Sentinel, and an in-memory backend for nodes which
+/// run without Redis.
+pub(crate) mod storage;
+/// Contains the supervision of nodes by systemd, through readiness
+/// notifications, the watchdog and socket activation.
+pub(crate) mod systemd;
+/// Contains the abstraction over the byte streams frames are exchanged over.
+pub(crate) mod transport;
+/// Contains the verification of signed records aggregated from remote nodes,
+/// which runs on workers of its own.
+pub(crate) mod verification;
 
 /// Contains utility functions for interacting with TCP streams.
 pub(crate) struct Tcp;
 
 impl Tcp {
-    /// This indicates how many bytes will be read at once when reading from an io stream.
-    const MAX_READ_BYTES: usize = 16;
+    /// Binds a listener to the address, with the options of the socket.
+    pub(crate) fn bind(
+        addr: std::net::SocketAddr,
+        socket: &settings::Socket,
+    ) -> std::io::Result<std::net::TcpListener> {
+        let domain = socket2::Domain::for_address(addr);
+        let listener = socket2::Socket::new(domain, socket2::Type::STREAM, None)?;
+        listener.set_reuse_address(socket.reuseaddr)?;
+        listener.bind(&addr.into())?;
+        listener.listen(1024)?;
+        Ok(listener.into())
+    }
+
+    /// Applies the options of the socket to the stream, whether it has been
+    /// accepted or connected.
+    pub(crate) fn configure(
+        stream: &std::net::TcpStream,
+        socket: &settings::Socket,
+    ) -> std::io::Result<()> {
+        stream.set_nodelay(socket.nodelay)?;
+        let sock = socket2::SockRef::from(stream);
+        match socket.keepalive {
+            Some(secs) => {
+                let time = std::time::Duration::from_secs(secs);
+                sock.set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(time))
+            }
+            None => sock.set_keepalive(false),
+        }
+    }
 
     /// Writes the given buffer to the stream.
     ///
@@ -46,115 +219,294 @@ impl Tcp {
         stream.flush()
     }
 
-    /// Reads data from the given stream into a buffer. Reads up to
-    /// [Self::MAX_READ_BYTES] at a time from the stream until there is no more data to read.
+    /// Reads a single frame from the given stream. Until the header of the frame
+    /// has arrived, data is read into the spare capacity of the buffer. Once the
+    /// length of the frame is known, the buffer is grown to fit the whole frame,
+    /// so that the rest of it can be read at once. Any bytes received after the
+    /// end of the frame are kept in the buffer and are used when reading the
+    /// next frame.
     ///
     /// # Arguments
     ///
     /// * `stream` - The stream to read from.
+    /// * `frames` - The buffer of the connection, which is reused for every frame.
     ///
     /// # Returns
     ///
-    /// The data read from the stream.
-    pub(crate) fn read<T: std::io::Read + std::io::Write>(
+    /// The bytes of the frame including its header, or [None] if the stream was
+    /// closed before a whole frame was received.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidData] wrapping
+    /// [protocol::codec::Error::Oversized] if the header announces a payload
+    /// larger than the limit of the buffer, before any of the payload is read.
+    pub(crate) fn read_frame<T: std::io::Read>(
         mut stream: T,
-    ) -> std::io::Result<Vec<u8>> {
-        let mut buffer: Vec<u8> = vec![];
-        let mut rx_bytes = [0u8; Self::MAX_READ_BYTES];
+        frames: &mut FrameBuffer,
+    ) -> std::io::Result<Option<&[u8]>> {
+        frames.discard();
         loop {
-            let bytes_read = stream.read(&mut rx_bytes)?;
-            buffer.extend_from_slice(&rx_bytes[..bytes_read]);
-            if bytes_read < Self::MAX_READ_BYTES {
-                // Stopping if all data was read from the stream
-                break;
+            let header = &frames.buffer[..frames.filled];
+            let wanted =
+                match protocol::codec::frame_len_within(frames.kind, header, frames.max_payload) {
+                    Ok(len) if frames.filled >= len => {
+                        frames.consumed = len;
+                        return Ok(Some(&frames.buffer[..len]));
+                    }
+
+                    Ok(len) => len,
+                    Err(protocol::codec::Error::Incomplete(_)) => frames.kind.header_len(),
+                    Err(e) => {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+                    }
+                };
+
+            // Reading at least the size of the buffer at once. Growing the buffer
+            // reclaims the space of the frames split off before, once they are
+            // dropped, rather than allocating.
+            let wanted = wanted.max(frames.len);
+            if frames.buffer.len() < wanted {
+                frames.buffer.resize(wanted, 0);
             }
+
+            let bytes_read = stream.read(&mut frames.buffer[frames.filled..])?;
+            if bytes_read == 0 {
+                return Ok(None);
+            }
+
+            frames.filled += bytes_read;
         }
+    }
+}
+
+/// The receive buffer of a connection, which frames are read into with
+/// [Tcp::read_frame]. The buffer keeps its size between frames, so a connection
+/// only reallocates when a frame larger than any before it arrives, or when a
+/// frame split off with [FrameBuffer::split] is still held onto.
+pub(crate) struct FrameBuffer {
+    buffer: bytes::BytesMut,
+    /// Size the buffer is created with, which is also the least bytes read at
+    /// once.
+    len: usize,
+    /// Number of bytes at the start of the buffer which have been received.
+    filled: usize,
+    /// Length of the frame returned last, which is dropped from the buffer
+    /// before the next frame is read.
+    consumed: usize,
+    /// The largest payload accepted in a frame.
+    max_payload: usize,
+    /// Whether requests or responses are read into the buffer.
+    kind: protocol::codec::Kind,
+}
+
+impl FrameBuffer {
+    /// Initial size of the buffer, which fits the header along with the
+    /// payload of most requests.
+    pub(crate) const INITIAL_LEN: usize = 512;
+
+    /// Creates a buffer which frames of the given kind are read into.
+    pub(crate) fn new(kind: protocol::codec::Kind) -> Self {
+        Self::with_limit(kind, protocol::codec::MAX_PAYLOAD_LEN)
+    }
 
-        stream.flush()?;
-        Ok(buffer)
+    /// Creates a buffer which rejects frames carrying a payload larger than
+    /// `max_payload` bytes.
+    pub(crate) fn with_limit(kind: protocol::codec::Kind, max_payload: usize) -> Self {
+        Self::sized(kind, max_payload, Self::INITIAL_LEN)
+    }
+
+    /// Same as [FrameBuffer::with_limit], with a buffer of `len` bytes.
+    pub(crate) fn sized(kind: protocol::codec::Kind, max_payload: usize, len: usize) -> Self {
+        // Reads into an empty buffer would never make progress.
+        let len = len.max(kind.header_len());
+        let mut buffer = bytes::BytesMut::with_capacity(len);
+        buffer.resize(len, 0);
+        Self {
+            buffer,
+            len,
+            filled: 0,
+            consumed: 0,
+            max_payload,
+            kind,
+        }
+    }
+
+    /// Returns whether there are no received bytes left besides the frame
+    /// returned last, i.e. whether no frame has been partially received.
+    #[inline(always)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.filled == self.consumed
+    }
+
+    /// Frees the buffer until the next frame is read, unless a frame has been
+    /// partially received.
+    pub(crate) fn release(&mut self) {
+        if self.is_empty() {
+            self.discard();
+            self.buffer = bytes::BytesMut::new();
+        }
+    }
+
+    /// Splits the frame returned last off the buffer, without copying it. The
+    /// frame is no longer in the buffer, and its space is reused once it is
+    /// dropped.
+    pub(crate) fn split(&mut self) -> bytes::Bytes {
+        let frame = self.buffer.split_to(self.consumed).freeze();
+        self.filled -= self.consumed;
+        self.consumed = 0;
+        frame
+    }
+
+    /// Drops the frame returned last from the start of the buffer.
+    fn discard(&mut self) {
+        drop(self.buffer.split_to(self.consumed));
+        self.filled -= self.consumed;
+        self.consumed = 0;
     }
 }
 
-/// Defines a macro that generates an enum with a ToString implementation and optional derives.
+/// Defines a macro that generates an error enum with [std::fmt::Display] and
+/// [std::error::Error] implementations and optional derives.
 ///
 /// # Arguments
 ///
 /// * `$enum_name` - The name of the enum to generate.
 /// * `$variant_name` - The name of a variant in the enum.
 /// * `$variant_type` - The type of the variant.
+/// * `[source]` - Optional marker for variants wrapping another error, which is
+///   then returned from [std::error::Error::source].
+/// * `#[cfg(...)]` - Optional condition for variants which only exist when a
+///   feature is enabled.
 /// * `$derive_name` - Optional derives to apply to the enum.
 ///
 /// # Example
 ///
 /// ```rs
-/// enum_with_impl_to_string!(
-///     Color,  
-///     .Red(String),
-///     .Green(u8),
-///     .Blue(f64),
+/// enum_with_impl_error!(
+///     Error,
+///     .Io(std::io::Error) [source]
+///     .InvalidKey(String)
 ///     ~Debug
 /// );
 ///
-/// let c = Color::Red("Crimson".to_string());
-/// println!("{:?}", c); // Prints "Color::Red(Crimson)"
-/// println!("{}", c.to_string()); // Prints "Crimson"
+/// let e = Error::InvalidKey("abc".to_string());
+/// println!("{:?}", e); // Prints "InvalidKey("abc")"
+/// println!("{}", e); // Prints "InvalidKey: abc"
 /// ```
 ///
 /// This will generate an enum like:
 ///
 /// ```rs
 /// #[derive(Debug)]
-/// pub enum Color {
-///     Red(String),  
-///     Green(u8),
-///     Blue(f64)
+/// pub enum Error {
+///     Io(std::io::Error),
+///     InvalidKey(String),
 /// }
 ///
-/// impl std::string::ToString for Color {
-///     fn to_string(&self) -> String {
+/// impl std::fmt::Display for Error {
+///     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 ///         match self {
-///             Self::Red(val) => val.to_string(),  
-///             Self::Green(val) => val.to_string(),
-///             Self::Blue(val) => val.to_string()
+///             Self::Io(val) => write!(f, "Io: {}", val),
+///             Self::InvalidKey(val) => write!(f, "InvalidKey: {}", val),
+///         }
+///     }
+/// }
+///
+/// impl std::error::Error for Error {
+///     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+///         match self {
+///             Self::Io(val) => Some(val),
+///             Self::InvalidKey(_) => None,
 ///         }
 ///     }
 /// }
 /// ```
-macro_rules! enum_with_impl_to_string {
+///
+/// Variants whose value displays as an empty string are displayed by their
+/// name only.
+macro_rules! enum_with_impl_error {
+    (@source $val:ident) => {{
+        let _ = $val;
+        None
+    }};
+    (@source $val:ident, source) => {
+        Some($val)
+    };
     (
         $(#[doc = $doc:expr])*
         $visibility:vis $enum_name:ident,
-        $(.$variant_name:ident($variant_type:ty))*
+        $($(#[cfg($cfg:meta)])? .$variant_name:ident($variant_type:ty) $([$source:ident])?)*
         $(~$derive_name:ident)*
     ) => {
         $(#[doc = $doc])*
         #[derive($($derive_name),*)]
         $visibility enum $enum_name {
-            $($variant_name($variant_type)),*
+            $($(#[cfg($cfg)])? $variant_name($variant_type)),*
+        }
+
+        #[automatically_derived]
+        impl std::fmt::Display for $enum_name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    $($(#[cfg($cfg)])? Self::$variant_name(val) => {
+                        let val = val.to_string();
+                        if val.is_empty() {
+                            f.write_str(stringify!($variant_name))
+                        } else {
+                            write!(f, "{}: {}", stringify!($variant_name), val)
+                        }
+                    })*
+                }
+            }
         }
 
         #[automatically_derived]
-        impl std::string::ToString for $enum_name {
-            fn to_string(&self) -> String {
+        impl std::error::Error for $enum_name {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
                 match self {
-                    $(Self::$variant_name(val) => val.to_string(),)*
+                    $($(#[cfg($cfg)])? Self::$variant_name(val) => {
+                        $crate::enum_with_impl_error!(@source val $(, $source)?)
+                    })*
                 }
             }
         }
     };
 }
 
-pub(crate) use enum_with_impl_to_string;
+pub(crate) use enum_with_impl_error;
 
 #[cfg(test)]
 mod tests {
     #[cfg(test)]
     mod tests_tcp_rw {
-        use crate::Tcp;
+        use crate::protocol::codec::Kind;
+        use crate::{FrameBuffer, Tcp};
 
         use std::net::{TcpListener, TcpStream};
         use std::thread;
 
+        #[test]
+        fn test_tcp_configure() -> std::io::Result<()> {
+            let mut socket = crate::settings::Socket {
+                nodelay: true,
+                keepalive: Some(60),
+                reuseaddr: true,
+            };
+            let listener = Tcp::bind("127.0.0.1:0".parse().unwrap(), &socket)?;
+            let stream = TcpStream::connect(listener.local_addr()?)?;
+            Tcp::configure(&stream, &socket)?;
+            assert!(stream.nodelay()?);
+            assert!(socket2::SockRef::from(&stream).keepalive()?);
+
+            socket.nodelay = false;
+            socket.keepalive = None;
+            Tcp::configure(&stream, &socket)?;
+            assert!(!stream.nodelay()?);
+            assert!(!socket2::SockRef::from(&stream).keepalive()?);
+            Ok(())
+        }
+
         #[test]
         fn test_tcp_write() -> std::io::Result<()> {
             let listener = TcpListener::bind("127.0.0.1:0")?;
@@ -168,32 +520,142 @@ mod tests {
 
             let stream = TcpStream::connect(addr)?;
             Tcp::write(&stream, buffer)?;
-            handle.join().map_err(|e| {
-                std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e))
-            })??;
+            handle
+                .join()
+                .map_err(|e| std::io::Error::other(format!("{:?}", e)))??;
 
             Ok(())
         }
 
         #[test]
-        fn test_tcp_read() -> std::io::Result<()> {
+        fn test_tcp_read_frame() -> std::io::Result<()> {
             let listener = TcpListener::bind("127.0.0.1:0")?;
             let addr = listener.local_addr()?;
-            let buffer = b"Hello, world!";
+            // A small frame, followed by a frame larger than the initial buffer.
+            let first = crate::protocol::codec::encode_request(0x01, b"Hello, world!").unwrap();
+            let second = crate::protocol::codec::encode_request(0x02, &[0xAB; 4096]).unwrap();
 
+            let frames = [first.clone(), second.clone()].concat();
             let handle = thread::spawn(move || -> std::io::Result<()> {
                 let (stream, _) = listener.accept()?;
-                Tcp::write(&stream, buffer)
+                Tcp::write(&stream, &frames)
+            });
+
+            let stream = TcpStream::connect(addr)?;
+            let mut buffer = FrameBuffer::new(Kind::Request);
+            assert_eq!(Tcp::read_frame(&stream, &mut buffer)?, Some(&first[..]));
+            assert_eq!(Tcp::read_frame(&stream, &mut buffer)?, Some(&second[..]));
+            handle
+                .join()
+                .map_err(|e| std::io::Error::other(format!("{:?}", e)))??;
+
+            // The peer has closed the connection after writing both frames.
+            assert_eq!(Tcp::read_frame(&stream, &mut buffer)?, None);
+            assert!(buffer.is_empty());
+            Ok(())
+        }
+
+        #[test]
+        fn test_tcp_read_released() -> std::io::Result<()> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            let first = crate::protocol::codec::encode_request(0x01, b"Hello, world!").unwrap();
+            let second = crate::protocol::codec::encode_request(0x02, b"Hello again").unwrap();
+
+            let frames = [first.clone(), second.clone()].concat();
+            let handle = thread::spawn(move || -> std::io::Result<()> {
+                let (stream, _) = listener.accept()?;
+                Tcp::write(&stream, &frames)
+            });
+
+            // A buffer smaller than the header still reads whole frames.
+            let stream = TcpStream::connect(addr)?;
+            let max_payload = crate::protocol::codec::MAX_PAYLOAD_LEN;
+            let mut buffer = FrameBuffer::sized(Kind::Request, max_payload, 1);
+            assert_eq!(Tcp::read_frame(&stream, &mut buffer)?, Some(&first[..]));
+            // A released buffer is allocated again for the next frame.
+            buffer.release();
+            assert_eq!(Tcp::read_frame(&stream, &mut buffer)?, Some(&second[..]));
+            buffer.release();
+            handle
+                .join()
+                .map_err(|e| std::io::Error::other(format!("{:?}", e)))??;
+
+            assert_eq!(Tcp::read_frame(&stream, &mut buffer)?, None);
+            Ok(())
+        }
+
+        #[test]
+        fn test_tcp_split() -> std::io::Result<()> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            let first = crate::protocol::codec::encode_request(0x01, b"Hello, world!").unwrap();
+            let second = crate::protocol::codec::encode_request(0x02, b"Hello again").unwrap();
+
+            let frames = [first.clone(), second.clone()].concat();
+            let handle = thread::spawn(move || -> std::io::Result<()> {
+                let (stream, _) = listener.accept()?;
+                Tcp::write(&stream, &frames)
+            });
+
+            let stream = TcpStream::connect(addr)?;
+            let mut buffer = FrameBuffer::new(Kind::Request);
+            assert_eq!(Tcp::read_frame(&stream, &mut buffer)?, Some(&first[..]));
+            // Frames which are split off outlive the frames read after them.
+            let split = buffer.split();
+            assert_eq!(Tcp::read_frame(&stream, &mut buffer)?, Some(&second[..]));
+            assert_eq!(split, first);
+            handle
+                .join()
+                .map_err(|e| std::io::Error::other(format!("{:?}", e)))??;
+
+            assert_eq!(Tcp::read_frame(&stream, &mut buffer)?, None);
+            Ok(())
+        }
+
+        #[test]
+        fn test_tcp_read_oversized() -> std::io::Result<()> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            let handle = thread::spawn(move || -> std::io::Result<()> {
+                let (stream, _) = listener.accept()?;
+                Tcp::write(&stream, &[0x01, 0xFF, 0xFF, 0xFF, 0xFF])
+            });
+
+            let stream = TcpStream::connect(addr)?;
+            let mut buffer = FrameBuffer::new(Kind::Response);
+            let result = Tcp::read_frame(&stream, &mut buffer);
+            assert_eq!(
+                result.map_err(|e| e.kind()),
+                Err(std::io::ErrorKind::InvalidData)
+            );
+            handle
+                .join()
+                .map_err(|e| std::io::Error::other(format!("{:?}", e)))??;
+            Ok(())
+        }
+
+        #[test]
+        fn test_tcp_read_over_limit() -> std::io::Result<()> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            let handle = thread::spawn(move || -> std::io::Result<()> {
+                let (stream, _) = listener.accept()?;
+                Tcp::write(&stream, &[0x01, 0x00, 0x00, 0x00, 0x09])
             });
 
             let stream = TcpStream::connect(addr)?;
-            let mut expected_buffer = Vec::new();
-            expected_buffer.extend_from_slice(buffer);
-            let actual_buffer = Tcp::read(&stream)?;
-            handle.join().map_err(|e| {
-                std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e))
-            })??;
-            assert_eq!(actual_buffer, expected_buffer);
+            let mut buffer = FrameBuffer::with_limit(Kind::Response, 8);
+            let error = Tcp::read_frame(&stream, &mut buffer).unwrap_err();
+            assert_eq!(
+                error
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<crate::protocol::codec::Error>()),
+                Some(&crate::protocol::codec::Error::Oversized(9))
+            );
+            handle
+                .join()
+                .map_err(|e| std::io::Error::other(format!("{:?}", e)))??;
             Ok(())
         }
     }
@@ -0,0 +1,151 @@
+//! Listings return the keys of the records of current node which start with a
+//! prefix, or which fall into a lexicographic range, without reading the
+//! records themselves or every key of the node. Since ULIDs start with the
+//! time they were drawn at, a prefix or range of ULIDs lists the records
+//! created within a span of time.
+//!
+//! The payload of a [LIST] request is either the prefix, or the first key of
+//! the range followed by a null byte and the key the range ends before. Both
+//! may be preceded by the options of [crate::pagination]. Its response is the
+//! keys in lexicographic order, each followed by a null byte, and by the
+//! footer of the page if the request is paginated. Listings which are not
+//! paginated hold no more than [MAX_KEYS] keys.
+//!
+//! Keys are matched by the storage backend, with Redis matching the longest
+//! prefix the keys share with `SCAN`, so a prefix may only hold the digits
+//! and letters keys are made of.
+
+/// Request code for listing the keys of the records of a node.
+pub const LIST: u16 = 0x002C;
+
+/// The most keys a listing which is not paginated holds.
+pub const MAX_KEYS: usize = 10_000;
+
+/// The keys a listing asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// Every key which starts with the prefix.
+    Prefix(String),
+    /// Every key from the first key up to the key the range ends before.
+    Range { start: String, end: String },
+}
+
+impl Query {
+    /// Parses the query out of the targets of a [LIST] request.
+    ///
+    /// # Returns
+    ///
+    /// [None] if there are no targets or more than two of them, or if any of
+    /// them holds characters keys are not made of.
+    pub fn parse(targets: &[String]) -> Option<Self> {
+        if !targets.iter().all(|target| is_valid(target)) {
+            return None;
+        }
+
+        match targets {
+            [prefix] => Some(Self::Prefix(prefix.clone())),
+            [start, end] => Some(Self::Range {
+                start: start.clone(),
+                end: end.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Encodes the query as the payload of a [LIST] request.
+    pub fn encode(&self) -> String {
+        match self {
+            Self::Prefix(prefix) => format!("{}\x00", prefix),
+            Self::Range { start, end } => format!("{}\x00{}\x00", start, end),
+        }
+    }
+
+    /// Returns the prefix every key the query matches starts with.
+    pub fn prefix(&self) -> &str {
+        match self {
+            Self::Prefix(prefix) => prefix,
+            Self::Range { start, end } => {
+                let len = start
+                    .bytes()
+                    .zip(end.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                &start[..len]
+            }
+        }
+    }
+
+    /// Returns whether the query matches the key.
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            Self::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            Self::Range { start, end } => start.as_str() <= key && key < end.as_str(),
+        }
+    }
+}
+
+/// Returns whether the prefix or key of a query only holds the characters keys
+/// are made of.
+fn is_valid(target: &str) -> bool {
+    target.bytes().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::settings::Settings;
+    use std::time::Duration;
+
+    #[test]
+    fn test_query() {
+        let range = Query::parse(&["01HA".into(), "01HC".into()]).unwrap();
+        assert_eq!(range.prefix(), "01H");
+        assert!(range.matches("01HA0"));
+        assert!(range.matches("01HBZ"));
+        assert!(!range.matches("01HC"));
+        assert!(!range.matches("01G"));
+        assert_eq!(Query::parse(&["01H*".into()]), None);
+        assert_eq!(Query::parse(&[]), None);
+        assert_eq!(
+            Query::parse(&["".into()]).unwrap(),
+            Query::Prefix(String::new())
+        );
+    }
+
+    #[test]
+    fn test_list() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        let mut keys: Vec<String> = (0..4)
+            .map(|i| client.create(format!("value{}", i).as_bytes()).unwrap().id)
+            .collect();
+        keys.sort();
+        client.post("news", &[&keys[0]]).unwrap();
+
+        // Keys other than those of records, such as of feeds, are left out.
+        assert_eq!(client.list(&Query::Prefix(String::new())).unwrap(), keys);
+        let prefix = Query::Prefix(keys[1].clone());
+        assert_eq!(client.list(&prefix).unwrap(), vec![keys[1].clone()]);
+        let range = Query::Range {
+            start: keys[1].clone(),
+            end: keys[3].clone(),
+        };
+        assert_eq!(client.list(&range).unwrap(), keys[1..3].to_vec());
+
+        let (page, next) = client.list_page(&range, None, 1).unwrap();
+        assert_eq!(page, vec![keys[1].clone()]);
+        let (page, next) = client.list_page(&range, next.as_deref(), 1).unwrap();
+        assert_eq!(page, vec![keys[2].clone()]);
+        let (page, next) = client.list_page(&range, next.as_deref(), 1).unwrap();
+        assert!(page.is_empty() && next.is_none());
+    }
+}
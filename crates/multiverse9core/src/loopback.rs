@@ -0,0 +1,404 @@
+//! Loopback connections reach a node running in the same process through an
+//! in-process pipe instead of TCP, for applications which embed a node and
+//! talk to it through the SDK. Requests sent over a loopback connection are
+//! handled exactly like the ones received over TCP, on the thread pool of the
+//! node, so that embedding applications only save the round trip through the
+//! network stack. Idle loopback connections are parked like TCP connections,
+//! and are woken up by their client writing to them, see [Signal].
+//!
+//! Every node hands out loopback connections through its [Local] handle. Nodes
+//! which have [Settings::loopback](crate::settings::Settings::loopback) enabled
+//! are also registered under the address they listen on, so that every
+//! [sdk::Client] in the process which connects to that address is given a
+//! loopback connection, whatever the transport it was configured with.
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::protocol::Handler;
+use crate::reactor::Reactor;
+use crate::sdk;
+use crate::transport::{Readiness, Transport};
+
+/// The nodes in this process which are registered for loopback connections,
+/// by the address they listen on.
+static REGISTRY: Mutex<Vec<(SocketAddr, Local)>> = Mutex::new(Vec::new());
+
+/// A handle to a node which hands out loopback connections to it once it has
+/// started. The handle can be cloned, and is taken from the node before the
+/// node is started, since [Node::start] takes the node.
+#[derive(Clone, Default)]
+pub struct Local {
+    running: Arc<Mutex<Option<Running>>>,
+}
+
+/// What a running node shares with the handlers of its connections.
+pub(crate) struct Running {
+    /// The address the node listens on, which loopback connections report as
+    /// the address of their remote end.
+    pub(crate) addr: SocketAddr,
+    /// The address of the admin listener, if the node has one.
+    pub(crate) admin: Option<SocketAddr>,
+    /// Serves the loopback connections like the ones accepted over TCP.
+    pub(crate) reactor: Arc<Reactor>,
+}
+
+impl Local {
+    /// Opens a loopback connection to the node.
+    ///
+    /// # Returns
+    ///
+    /// A client issuing its requests over the connection, or an error of kind
+    /// [io::ErrorKind::NotConnected] if the node is not running.
+    pub fn client(&self) -> Result<sdk::Client, sdk::Error> {
+        self.connect()
+            .map(sdk::Client::over)
+            .map_err(sdk::Error::Io)
+    }
+
+    /// Returns whether the node has started, and accepts loopback connections.
+    pub fn is_running(&self) -> bool {
+        self.running.lock().unwrap().is_some()
+    }
+
+    /// Returns the address the node listens on, once it has started.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.running
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|running| running.addr)
+    }
+
+    /// Returns the address of the admin listener, once the node has started
+    /// with one. See [crate::admin].
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.running
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|running| running.admin)
+    }
+
+    pub(crate) fn start(&self, running: Running) {
+        *self.running.lock().unwrap() = Some(running);
+    }
+
+    /// Stops handing out loopback connections once the node stops.
+    pub(crate) fn stop(&self) {
+        *self.running.lock().unwrap() = None;
+    }
+
+    fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        let running = self.running.lock().unwrap();
+        let running = match running.as_ref() {
+            Some(running) => running,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "The node is not running",
+                ))
+            }
+        };
+
+        let (client, server) = Pipe::pair(running.addr);
+        running
+            .reactor
+            .queue(Handler::new(Box::new(server), Instant::now()));
+        Ok(Box::new(client))
+    }
+}
+
+impl std::fmt::Debug for Local {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Local")
+            .field("running", &self.is_running())
+            .finish()
+    }
+}
+
+/// Keeps a node registered for loopback connections, until it is dropped.
+pub(crate) struct Registration(SocketAddr);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.retain(|(addr, _)| *addr != self.0);
+    }
+}
+
+/// Registers the node listening on the address for loopback connections.
+pub(crate) fn register(addr: SocketAddr, local: Local) -> Registration {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.retain(|(registered, _)| *registered != addr);
+    registry.push((addr, local));
+    Registration(addr)
+}
+
+/// Opens a loopback connection if one of the addresses belongs to a node in
+/// this process which is registered for loopback connections.
+///
+/// # Returns
+///
+/// The connection, or `None` if the addresses belong to no registered node.
+pub(crate) fn connect<A: ToSocketAddrs>(addr: &A) -> io::Result<Option<Box<dyn Transport>>> {
+    let local = {
+        let registry = REGISTRY.lock().unwrap();
+        // Leaving out the resolution of the addresses, which may query DNS, in
+        // the common case of no registered nodes.
+        if registry.is_empty() {
+            return Ok(None);
+        }
+
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        registry
+            .iter()
+            .find(|(registered, _)| addrs.iter().any(|addr| is_bound_to(registered, addr)))
+            .map(|(_, local)| local.clone())
+    };
+
+    match local {
+        Some(local) => local.connect().map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Returns whether connecting to the address reaches a node listening on the
+/// registered address, including nodes listening on every interface.
+pub(crate) fn is_bound_to(registered: &SocketAddr, addr: &SocketAddr) -> bool {
+    registered == addr
+        || (registered.ip().is_unspecified()
+            && registered.port() == addr.port()
+            && addr.ip().is_loopback())
+}
+
+/// A function called once a parked stream becomes readable.
+pub(crate) type Wake = Box<dyn FnOnce() + Send>;
+
+/// Tells whether one end of a pipe has something to read, so that the end can
+/// be parked without a socket to wait on. The signal is raised by the other
+/// end whenever it writes, shuts down its write side, or goes away.
+#[derive(Default)]
+pub(crate) struct Signal {
+    state: Mutex<SignalState>,
+}
+
+#[derive(Default)]
+struct SignalState {
+    /// The chunks written which have not been received yet.
+    unread: usize,
+    /// Whether the other end stopped writing.
+    closed: bool,
+    wake: Option<Wake>,
+}
+
+impl Signal {
+    /// Calls `wake` once the end has something to read, which is right away if
+    /// it has already.
+    pub(crate) fn notify(&self, wake: Wake) {
+        let mut state = self.state.lock().unwrap();
+        if state.unread == 0 && !state.closed {
+            state.wake = Some(wake);
+            return;
+        }
+
+        drop(state);
+        wake();
+    }
+
+    fn raise(&self, update: impl FnOnce(&mut SignalState)) {
+        let wake = {
+            let mut state = self.state.lock().unwrap();
+            update(&mut state);
+            state.wake.take()
+        };
+
+        if let Some(wake) = wake {
+            wake();
+        }
+    }
+
+    fn received(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.unread = state.unread.saturating_sub(1);
+    }
+}
+
+/// One end of an in-process pipe, which reads what the other end writes.
+struct Pipe {
+    /// The sending half, which is dropped once the write side is shut down.
+    tx: Option<Sender<Vec<u8>>>,
+    rx: Receiver<Vec<u8>>,
+    /// Raised for this end by the other end, and for the other end by this end.
+    readable: Arc<Signal>,
+    peer: Arc<Signal>,
+    /// The chunk which is being read, and how much of it has been read.
+    pending: Vec<u8>,
+    offset: usize,
+    /// How long reads wait for the other end to write.
+    timeout: Cell<Option<Duration>>,
+    /// The address reported for the remote end.
+    addr: SocketAddr,
+}
+
+impl Pipe {
+    /// Creates both ends of a pipe to the node listening on the address.
+    fn pair(addr: SocketAddr) -> (Self, Self) {
+        let (client_tx, server_rx) = mpsc::channel();
+        let (server_tx, client_rx) = mpsc::channel();
+        let (client_signal, server_signal) =
+            (Arc::new(Signal::default()), Arc::new(Signal::default()));
+        let end = |tx, rx, readable: &Arc<Signal>, peer: &Arc<Signal>| Self {
+            tx: Some(tx),
+            rx,
+            readable: Arc::clone(readable),
+            peer: Arc::clone(peer),
+            pending: vec![],
+            offset: 0,
+            timeout: Cell::new(None),
+            addr,
+        };
+
+        (
+            end(client_tx, client_rx, &client_signal, &server_signal),
+            end(server_tx, server_rx, &server_signal, &client_signal),
+        )
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset == self.pending.len() {
+            let received = match self.timeout.get() {
+                Some(timeout) => self.rx.recv_timeout(timeout),
+                None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match received {
+                Ok(chunk) => {
+                    self.readable.received();
+                    self.pending = chunk;
+                    self.offset = 0;
+                }
+                Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                // The other end went away, which reads as the end of the stream.
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+
+        let read = (&self.pending[self.offset..]).read(buf)?;
+        self.offset += read;
+        Ok(read)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let tx = match &self.tx {
+            Some(tx) => tx,
+            None => return Err(io::ErrorKind::BrokenPipe.into()),
+        };
+
+        // Counting the chunk before it is sent, so that it is never received
+        // before it is counted.
+        self.peer.raise(|state| state.unread += 1);
+        match tx.send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Pipe {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.tx = None;
+        self.peer.raise(|state| state.closed = true);
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout.set(timeout);
+        Ok(())
+    }
+
+    fn readiness(&self) -> io::Result<Readiness> {
+        Ok(Readiness::Signal(Arc::clone(&self.readable)))
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        self.peer.raise(|state| state.closed = true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipe() {
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let (mut client, mut server) = Pipe::pair(addr);
+        client.write_all(b"hello ").unwrap();
+        client.write_all(b"world").unwrap();
+        client.shutdown_write().unwrap();
+
+        let mut received = String::new();
+        server.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "hello world");
+        assert_eq!(server.peer_addr().unwrap(), addr);
+
+        client
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let error = client.read(&mut [0; 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        drop(server);
+        let error = client.write_all(b"gone").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_local() {
+        let local = Local::default();
+        assert!(!local.is_running());
+        let error = local.connect().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+
+        let registered: SocketAddr = "0.0.0.0:9001".parse().unwrap();
+        assert!(is_bound_to(&registered, &"127.0.0.1:9001".parse().unwrap()));
+        assert!(!is_bound_to(
+            &registered,
+            &"127.0.0.1:9002".parse().unwrap()
+        ));
+        assert!(!is_bound_to(
+            &"127.0.0.1:9001".parse().unwrap(),
+            &"127.0.0.2:9001".parse().unwrap()
+        ));
+
+        // Nodes which are registered before they start refuse the connection
+        // instead of falling back to TCP.
+        let _registration = register(registered, local);
+        let error = connect(&"127.0.0.1:9001").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+        assert!(connect(&"127.0.0.1:9003").unwrap().is_none());
+    }
+}
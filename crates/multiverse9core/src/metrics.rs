@@ -0,0 +1,328 @@
+//! Counters of the requests handled by the node, in total and for every peer,
+//! which survive restarts. Every [Metrics::interval](crate::settings::Metrics)
+//! seconds the counters are saved to storage under [KEY], and once the node
+//! starts they are loaded again and added to the counters of the process, so
+//! that accounting and dashboards do not start over on every deploy.
+//!
+//! Peers are counted by [Identity::key], except for anonymous connections,
+//! which are counted by their IP address alone, since their ports change with
+//! every connection.
+//!
+//! Snapshots also hold the state of the workers of the node, see [Workers],
+//! which is taken as the snapshot is, and is not added to the saved counters.
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::node::Node;
+use crate::pooling::Pool;
+use crate::protocol::Identity;
+use crate::storage::{self, Connection, Entry, Keyspace, Storage, Value};
+
+/// The name of the key the counters are saved under, relative to the
+/// namespace.
+pub const KEY: &str = "metrics";
+
+/// The counters of the process, including the ones loaded from storage.
+static METRICS: Mutex<Snapshot> = Mutex::new(Snapshot {
+    at: 0,
+    total: Usage::ZERO,
+    peers: BTreeMap::new(),
+    workers: Workers::ZERO,
+});
+
+/// The pool whose workers snapshots hold the state of.
+static POOL: Mutex<Option<Weak<Pool>>> = Mutex::new(None);
+
+/// The requests of a set of connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Usage {
+    /// The number of requests handled.
+    pub requests: u64,
+    /// The number of requests whose handler failed.
+    pub failures: u64,
+    /// The number of payload bytes received with the requests.
+    pub received: u64,
+    /// The number of bytes sent with the responses.
+    pub sent: u64,
+}
+
+impl Usage {
+    const ZERO: Self = Self {
+        requests: 0,
+        failures: 0,
+        received: 0,
+        sent: 0,
+    };
+
+    fn add(&mut self, other: &Self) {
+        self.requests += other.requests;
+        self.failures += other.failures;
+        self.received += other.received;
+        self.sent += other.sent;
+    }
+}
+
+/// The state of the workers which run the jobs of the node, such as serving
+/// its connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Workers {
+    /// The number of workers.
+    pub size: u64,
+    /// The number of workers which are running a job.
+    pub busy: u64,
+    /// The number of jobs which wait for a worker.
+    pub queued: u64,
+    /// The number of jobs which have run so far.
+    pub completed: u64,
+    /// The number of jobs which panicked.
+    #[serde(default)]
+    pub panics: u64,
+    /// The number of workers which died and were replaced.
+    #[serde(default)]
+    pub respawned: u64,
+    /// The mean time the completed jobs took from being queued until they
+    /// finished, in microseconds.
+    pub latency: u64,
+}
+
+impl Workers {
+    const ZERO: Self = Self {
+        size: 0,
+        busy: 0,
+        queued: 0,
+        completed: 0,
+        panics: 0,
+        respawned: 0,
+        latency: 0,
+    };
+}
+
+/// The counters at a point in time.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Time the snapshot was taken, in milliseconds since the Unix epoch.
+    pub at: u64,
+    /// The requests of every connection.
+    pub total: Usage,
+    /// The requests of every peer, by its key.
+    #[serde(default)]
+    pub peers: BTreeMap<String, Usage>,
+    /// The state of the workers at the time of the snapshot.
+    #[serde(default)]
+    pub workers: Workers,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the counters of the node.
+    pub fn take() -> Self {
+        let mut snapshot = METRICS.lock().unwrap().clone();
+        snapshot.at = now();
+        if let Some(pool) = POOL.lock().unwrap().as_ref().and_then(Weak::upgrade) {
+            snapshot.workers = pool.stats();
+        }
+
+        snapshot
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.total.add(&other.total);
+        for (peer, usage) in &other.peers {
+            self.peers.entry(peer.clone()).or_default().add(usage);
+        }
+    }
+}
+
+/// Records a request of the identity which has been handled.
+///
+/// # Arguments
+///
+/// * `received` - The length of the payload of the request.
+/// * `sent` - The length of the encoded response.
+pub(crate) fn record(identity: &Identity, received: usize, sent: usize, failed: bool) {
+    let usage = Usage {
+        requests: 1,
+        failures: failed as u64,
+        received: received as u64,
+        sent: sent as u64,
+    };
+
+    let peer = match identity {
+        Identity::Anonymous(addr) => format!("addr:{}", addr.ip()),
+        identity => identity.key(),
+    };
+
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.total.add(&usage);
+    metrics.peers.entry(peer).or_default().add(&usage);
+}
+
+/// Makes the snapshots hold the state of the workers of the pool, for as long
+/// as it exists.
+pub(crate) fn watch(pool: &Arc<Pool>) {
+    *POOL.lock().unwrap() = Some(Arc::downgrade(pool));
+}
+
+/// Adds the counters saved in storage to the counters of the process.
+pub(crate) fn load(
+    connection: &mut dyn Connection,
+    keyspace: &Keyspace,
+) -> Result<(), storage::Error> {
+    let stored = match connection.export(keyspace, KEY)? {
+        Some(Entry {
+            value: Value::String(buffer),
+            ..
+        }) => match serde_json::from_slice::<Snapshot>(&buffer) {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!("Discarding the saved metrics: {}", e);
+                return Ok(());
+            }
+        },
+        _ => return Ok(()),
+    };
+
+    METRICS.lock().unwrap().add(&stored);
+    Ok(())
+}
+
+/// Saves the counters of the process to storage.
+pub(crate) fn save(
+    connection: &mut dyn Connection,
+    keyspace: &Keyspace,
+) -> Result<(), storage::Error> {
+    let buffer = serde_json::to_vec(&Snapshot::take()).unwrap();
+    connection.import(
+        keyspace,
+        &Entry {
+            key: KEY.into(),
+            value: Value::String(buffer),
+            ttl: None,
+        },
+    )
+}
+
+/// Loads the saved counters, and saves them periodically from then on. The
+/// counters are not saved before they have been loaded, so that the saved
+/// counters are never replaced with the ones of the process alone.
+pub(crate) fn spawn(node: Arc<RwLock<Node>>, storage: Arc<Storage>) {
+    let interval = node.read().unwrap().settings.metrics.interval;
+    if interval == 0 {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let mut connection = None;
+        let mut loaded = false;
+        loop {
+            if connection.is_none() {
+                match storage.connection() {
+                    Ok(opened) => connection = Some(opened),
+                    Err(e) => {
+                        error!("Could not save the metrics: {}", e);
+                        std::thread::sleep(Duration::from_secs(interval));
+                        continue;
+                    }
+                }
+            }
+
+            let connection = connection.as_mut().unwrap();
+            let result = match loaded {
+                true => save(connection.as_mut(), storage.keyspace()),
+                false => load(connection.as_mut(), storage.keyspace()),
+            };
+
+            match result {
+                Ok(()) => loaded = true,
+                Err(e) => {
+                    error!("Could not save the metrics: {}", e);
+                    if let Err(e) = storage.recover(connection, &e) {
+                        error!("Could not reconnect to the storage: {}", e);
+                    }
+                }
+            }
+
+            std::thread::sleep(Duration::from_secs(interval));
+        }
+    });
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Partition;
+    use crate::storage::memory::Memory;
+    use crate::storage::Backend;
+
+    #[test]
+    fn test_snapshot() {
+        let mut snapshot = Snapshot::default();
+        snapshot.add(&Snapshot {
+            at: 1,
+            total: Usage {
+                requests: 2,
+                failures: 1,
+                received: 10,
+                sent: 20,
+            },
+            peers: BTreeMap::from([(
+                "user:alice".into(),
+                Usage {
+                    requests: 2,
+                    ..Default::default()
+                },
+            )]),
+            workers: Workers::default(),
+        });
+        snapshot.add(&Snapshot {
+            total: Usage {
+                requests: 1,
+                ..Default::default()
+            },
+            peers: BTreeMap::from([(
+                "user:alice".into(),
+                Usage {
+                    requests: 1,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        });
+
+        assert_eq!(snapshot.total.requests, 3);
+        assert_eq!(snapshot.total.failures, 1);
+        assert_eq!(snapshot.peers["user:alice"].requests, 3);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Hour,
+        };
+        let mut connection = Memory::default().connection().unwrap();
+
+        let identity = Identity::Anonymous("127.0.0.1:4000".parse().unwrap());
+        record(&identity, 3, 5, false);
+        let recorded = Snapshot::take();
+        assert!(recorded.peers["addr:127.0.0.1"].requests >= 1);
+
+        // Loading the saved counters adds them to the counters of the process,
+        // the way they are added to the counters of a restarted node.
+        save(connection.as_mut(), &keyspace).unwrap();
+        load(connection.as_mut(), &keyspace).unwrap();
+        let loaded = Snapshot::take();
+        assert!(loaded.total.requests >= 2 * recorded.total.requests);
+        assert!(loaded.total.received >= 2 * recorded.total.received);
+    }
+}
@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+/// The progress of migrating the records which were stored before keys were
+/// namespaced, under their bare IDs. Records are otherwise only migrated
+/// lazily as they are read, which leaves records which are never read out of
+/// the index and out of the reach of maintenance jobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Report {
+    /// The number of keys looked at so far.
+    pub scanned: usize,
+    /// The number of records moved into the namespace.
+    pub migrated: usize,
+    /// The number of records left in place, since a record with the same ID
+    /// exists in the namespace already.
+    pub skipped: usize,
+    /// Whether the whole keyspace has been walked. Migrations which were
+    /// interrupted resume where they left off the next time they are run.
+    pub done: bool,
+}
@@ -0,0 +1,134 @@
+//! Multiplexing lets a client have many requests outstanding on a single
+//! connection, whose responses arrive in the order the node finishes them
+//! rather than the order they were sent in. A connection switches to
+//! multiplexing with a [MULTIPLEX] request, which the node answers with the
+//! most requests it handles at once on the connection, encoded as a big-endian
+//! u32. From then on the payload of every frame in either direction starts with
+//! a correlation ID chosen by the client, which the response to a request
+//! carries as well:
+//!
+//! ```text
+//! +-----------------+----------------------+----------------------+-------------------+
+//! | header          | payload length (u32) | correlation ID (u32) | payload (n bytes) |
+//! +-----------------+----------------------+----------------------+-------------------+
+//! ```
+//!
+//! The header is the code of a request, or the status of a response. See
+//! [codec].
+//!
+//! Requests which change the state of the connection, and batches, cannot be
+//! issued once the connection is multiplexed. Nodes answer [MULTIPLEX] with
+//! status `1` if multiplexing is disabled, or if the transport of the
+//! connection cannot be read and written from different threads, such as TLS,
+//! in which case the connection carries on as before.
+
+use crate::protocol::codec;
+
+/// Request code which switches the connection to multiplexing.
+pub const MULTIPLEX: u16 = 0x0020;
+
+/// Length of the correlation ID which starts the payload of every frame on a
+/// multiplexed connection.
+pub const ID_LEN: usize = 4;
+
+/// Encodes a request frame with the given correlation ID.
+pub fn encode(id: u32, code: u16, payload: &[u8]) -> Result<Vec<u8>, codec::Error> {
+    codec::encode_request(code, &prefix(id, payload))
+}
+
+/// Encodes a response frame with the correlation ID of its request.
+pub fn encode_response(id: u32, status: u8, payload: &[u8]) -> Result<Vec<u8>, codec::Error> {
+    codec::encode_response(status, &prefix(id, payload))
+}
+
+/// Prepends the correlation ID to the payload.
+fn prefix(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(ID_LEN + payload.len());
+    buffer.extend_from_slice(&id.to_be_bytes());
+    buffer.extend_from_slice(payload);
+    buffer
+}
+
+/// Splits the payload of a frame on a multiplexed connection into its
+/// correlation ID and the payload of the request or response.
+///
+/// # Returns
+///
+/// [None] if the payload is too short to carry a correlation ID.
+pub fn decode(payload: &[u8]) -> Option<(u32, &[u8])> {
+    if payload.len() < ID_LEN {
+        return None;
+    }
+
+    let (id, payload) = payload.split_at(ID_LEN);
+    Some((u32::from_be_bytes([id[0], id[1], id[2], id[3]]), payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::sdk::{Client, Error};
+    use crate::settings::Settings;
+    use crate::{health, shutdown};
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[test]
+    fn test_roundtrip() {
+        let frame = encode(7, 0x03, b"key\x00").unwrap();
+        assert_eq!(frame, b"\x00\x03\x00\x00\x00\x08\x00\x00\x00\x07key\x00");
+
+        let (request, _) = codec::decode_request(&frame).unwrap();
+        assert_eq!(decode(request.payload), Some((7, &b"key\x00"[..])));
+        assert_eq!(decode(&[0, 0, 7]), None);
+        assert_eq!(decode(&[0, 0, 0, 7]), Some((7, &[][..])));
+
+        let frame = encode_response(7, 0, b"key").unwrap();
+        assert_eq!(frame, b"\x00\x00\x00\x00\x07\x00\x00\x00\x07key");
+        let (response, _) = codec::decode_response(&frame).unwrap();
+        assert_eq!(decode(response.payload), Some((7, &b"key"[..])));
+    }
+
+    #[test]
+    fn test_multiplex() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        let node = Node::new(settings);
+        let (shutdown, local) = (node.shutdown(), node.local());
+        std::thread::spawn(move || node.start(Some(4)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Loopback connections cannot be read and written from different
+        // threads, so they are not switched.
+        let client = local.client().unwrap();
+        assert!(matches!(
+            client.multiplex(),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::Unsupported
+        ));
+
+        let client = Client::connect(local.addr().unwrap()).unwrap();
+        let multiplexed = client.multiplex().unwrap();
+        assert_eq!(multiplexed.max_in_flight(), 16);
+
+        let pending: Vec<_> = (0..32)
+            .map(|i| multiplexed.send(0x0001, format!("value {}", i).as_bytes()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let ping = multiplexed.send(health::PING, &[]).unwrap();
+        let batch = multiplexed.send(codec::BATCH, &[]).unwrap();
+
+        let ids: HashSet<_> = pending.into_iter().map(|p| p.wait().unwrap()).collect();
+        assert_eq!(ids.len(), 32);
+        assert!(ping.wait().unwrap().starts_with(health::PONG));
+        assert!(matches!(batch.wait(), Err(Error::Status(1))));
+
+        shutdown.stop();
+        assert!(matches!(
+            multiplexed.request(health::PING, &[]),
+            Err(Error::Status(shutdown::SHUTTING_DOWN))
+        ));
+    }
+}
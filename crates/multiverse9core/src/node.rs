@@ -1,60 +1,631 @@
 use log::*;
-use std::net::TcpListener;
-use std::sync::{Arc, Mutex};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::backup;
+use crate::changelog::Changelog;
+use crate::crash;
+use crate::events;
+use crate::health;
+use crate::loopback::{self, Local};
+use crate::metrics;
+use crate::migration;
+use crate::outbound;
 use crate::pooling;
 use crate::protocol::Handler;
-use crate::settings::Settings;
+use crate::reactor::Reactor;
+use crate::retention;
+use crate::sdk;
+use crate::seeds;
+use crate::settings::{Accept, AcceptPolicy, Priority, Settings};
+use crate::shutdown::Shutdown;
+use crate::storage::Storage;
+use crate::systemd;
+use crate::transfer;
+use crate::transport::Transport;
+use crate::views::{View, Views};
+use crate::Tcp;
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .Io(std::io::Error) [source]
+    .Storage(crate::storage::Error) [source]
+    .Tls(String)
+    .Discovery(String)
+    .Keys(crate::keys::Error) [source]
+    .Backup(crate::backup::Error) [source]
+    .InvalidOwner(String)
+    .SameBackend(String)
+    .VolatileBackend(String)
+    ~Debug
+}
+
+/// Wraps accepted streams into the transport configured in the settings.
+enum Acceptor {
+    Plain,
+    #[cfg(feature = "tls")]
+    Tls(crate::tls::Acceptor),
+}
+
+impl Acceptor {
+    /// Creates the acceptor for incoming connections, along with the connector
+    /// for outgoing connections using the same transport.
+    fn new(settings: &Settings) -> Result<(Self, sdk::Connector), Error> {
+        let (acceptor, connector) = Self::transport(settings)?;
+        Ok((acceptor, connector.with_socket(settings.socket)))
+    }
+
+    fn transport(settings: &Settings) -> Result<(Self, sdk::Connector), Error> {
+        match &settings.tls {
+            None => Ok((Self::Plain, sdk::Connector::Plain)),
+            #[cfg(feature = "tls")]
+            Some(tls) => {
+                let acceptor = crate::tls::Acceptor::new(tls, &settings.nodes)
+                    .map_err(|e| Error::Tls(e.to_string()))?;
+                let connector = crate::tls::connector(tls, &settings.nodes)
+                    .map_err(|e| Error::Tls(e.to_string()))?;
+                Ok((Self::Tls(acceptor), sdk::Connector::Tls(connector)))
+            }
+            #[cfg(not(feature = "tls"))]
+            Some(_) => Err(Error::Tls(
+                "TLS is configured, but the `tls` feature is disabled".into(),
+            )),
+        }
+    }
+
+    fn accept(&self, stream: TcpStream) -> std::io::Result<Box<dyn Transport>> {
+        match self {
+            Self::Plain => Ok(Box::new(stream)),
+            #[cfg(feature = "tls")]
+            Self::Tls(acceptor) => Ok(Box::new(acceptor.accept(stream)?)),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Node {
     /// Contains the settings of current node.
     pub settings: Settings,
+    /// The views aggregated entries can be requested in.
+    pub views: Views,
+    /// Hands out loopback connections once the node has started.
+    local: Local,
+    /// Stops the node once it has started.
+    shutdown: Shutdown,
+    /// The file the settings were loaded from, which the admin listener
+    /// reloads them from.
+    source: Option<PathBuf>,
 }
 
 impl Node {
     /// Creates a new node from the specified [Settings] struct instance. [Settings] must be
     /// initialized separately.
     pub fn new(settings: Settings) -> Self {
-        Self { settings }
+        Self {
+            settings,
+            views: Views::default(),
+            local: Local::default(),
+            shutdown: Shutdown::default(),
+            source: None,
+        }
+    }
+
+    /// Records the file the settings were loaded from, so that they can be
+    /// reloaded through the admin listener. See [crate::admin::RELOAD].
+    pub fn with_source(mut self, path: impl Into<PathBuf>) -> Self {
+        self.source = Some(path.into());
+        self
+    }
+
+    /// Registers a view under the given name, which aggregated entries can then
+    /// be requested in. See [Views::register].
+    pub fn with_view(mut self, name: impl Into<String>, view: impl View + 'static) -> Self {
+        self.views.register(name, view);
+        self
+    }
+
+    /// Returns a handle which hands out in-process connections to the node once
+    /// it has started, for applications embedding the node. See
+    /// [crate::loopback].
+    pub fn local(&self) -> Local {
+        self.local.clone()
+    }
+
+    /// Returns a handle which stops the node once it has started, after the
+    /// requests in flight have been processed. See [crate::shutdown].
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Opens an in-process connection to the node. Since [Node::start] takes
+    /// the node, connections to a running node are opened through the handle
+    /// returned by [Node::local].
+    pub fn local_client(&self) -> Result<sdk::Client, sdk::Error> {
+        self.local.client()
+    }
+
+    /// Migrates the records stored under bare IDs into the namespace of the
+    /// node, resuming an earlier migration which was interrupted. See
+    /// [crate::migration].
+    pub fn migrate(&self) -> Result<migration::Report, Error> {
+        let storage = Storage::new(&self.settings).map_err(Error::Storage)?;
+        migrate(&storage, &self.settings)
+    }
+
+    /// Archives every key of the node into the writer, while the node may keep
+    /// running. See [crate::backup].
+    pub fn backup(&self, out: impl std::io::Write) -> Result<backup::Report, Error> {
+        let storage = Storage::new(&self.settings).map_err(Error::Storage)?;
+        let mut connection = storage.connection().map_err(Error::Storage)?;
+        let header = backup::Header {
+            name: self.settings.name.clone(),
+            version: self.settings.version.clone(),
+            partition: self.settings.partition,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default(),
+        };
+
+        backup::backup(connection.as_mut(), storage.keyspace(), &header, out).map_err(Error::Backup)
+    }
+
+    /// Loads the keys of an archive into the node, treating the keys which
+    /// exist already as given. Dry runs only read the archive. See
+    /// [crate::backup].
+    pub fn restore(
+        &self,
+        input: impl std::io::Read,
+        existing: backup::Existing,
+        dry_run: bool,
+    ) -> Result<backup::Restored, Error> {
+        let storage = Storage::new(&self.settings).map_err(Error::Storage)?;
+        let mut connection = storage.connection().map_err(Error::Storage)?;
+        backup::restore(
+            connection.as_mut(),
+            storage.keyspace(),
+            input,
+            existing,
+            dry_run,
+        )
+        .map_err(Error::Backup)
+    }
+
+    /// Copies every key of the node into the backend with the given connection
+    /// string, resuming an earlier transfer which was interrupted. See
+    /// [crate::transfer].
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::VolatileBackend] if the backend does not keep its data
+    /// once the process exits, such as the in-memory one, since the keys
+    /// copied would be lost right away.
+    pub fn transfer(&self, to: &str) -> Result<transfer::Report, Error> {
+        if to == self.settings.redis_uri {
+            return Err(Error::SameBackend(to.into()));
+        }
+
+        if !crate::storage::is_persistent(to) {
+            return Err(Error::VolatileBackend(to.into()));
+        }
+
+        let storage = Storage::new(&self.settings).map_err(Error::Storage)?;
+        let mut source = storage.connection().map_err(Error::Storage)?;
+        let mut target = Storage::at(&self.settings, to, None)
+            .and_then(|target| target.connection())
+            .map_err(Error::Storage)?;
+
+        info!("Transferring the keys of {} to {}", self.settings.name, to);
+        let report = transfer::transfer(
+            source.as_mut(),
+            target.as_mut(),
+            storage.keyspace(),
+            &mut |report| {
+                info!(
+                    "Copied {} of {} keys, {} copied by an earlier transfer",
+                    report.copied + report.resumed,
+                    report.total,
+                    report.resumed
+                );
+            },
+        )
+        .map_err(Error::Storage)?;
+
+        info!("Transfer of the keys is complete");
+        Ok(report)
     }
 
     /// Binds a [std::net::TcpListener] to the address specified by the [Settings] struct.
     /// [Node] must use [std::sync::Arc], since its configuration will be shared across
     /// threads. The threads, as of right now do not have the option of changing the settings
     /// internally.
-    pub fn start(self, threads: Option<usize>) -> std::io::Result<()> {
-        let node = Arc::new(Mutex::new(self));
-        let pool = pooling::Pool::new(threads.unwrap_or(14) - 1);
-        let listener = TcpListener::bind(node.lock().unwrap().settings.addr)?;
-        info!("TcpListener bound at {}", listener.local_addr()?);
-
-        let redis = Arc::new(
-            redis::Client::open(node.lock().unwrap().settings.redis_uri.clone())
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)))?,
+    ///
+    /// The node runs until it is stopped through the handle returned by
+    /// [Node::shutdown], at which point it stops accepting connections, and
+    /// returns once the requests in flight have been processed.
+    ///
+    /// Nodes supervised by systemd notify it once they accept connections, and
+    /// accept them on the listener passed by systemd (`LISTEN_FDS`) if there is
+    /// one, rather than binding the address of the settings.
+    pub fn start(self, threads: Option<usize>) -> Result<(), Error> {
+        crash::install(self.settings.name.clone(), self.settings.crash_file.clone());
+        let node = Arc::new(RwLock::new(self));
+        let pool = Arc::new(pooling::Pool::new(threads.unwrap_or(14) - 1));
+        metrics::watch(&pool);
+        let listener = match systemd::listener() {
+            Some(listener) => listener,
+            None => {
+                let settings = &node.read().unwrap().settings;
+                Tcp::bind(settings.addr, &settings.socket).map_err(Error::Io)?
+            }
+        };
+        info!(
+            "TcpListener bound at {}",
+            listener.local_addr().map_err(Error::Io)?
         );
+
+        let admin = match &node.read().unwrap().settings.admin {
+            Some(admin) => Some(TcpListener::bind(admin.addr).map_err(Error::Io)?),
+            None => None,
+        };
+
+        // Advertising until the listener stops accepting connections.
+        let _advertisement = advertise(&node.read().unwrap().settings, &listener)?;
+
+        // Catching a malformed key on startup rather than on the first handshake.
+        if let Some(secret) = &node.read().unwrap().settings.key {
+            crate::keys::Keypair::from_hex(secret).map_err(Error::Keys)?;
+        }
+
+        let storage =
+            Arc::new(Storage::new(&node.read().unwrap().settings).map_err(Error::Storage)?);
         info!(
-            "Redis connected at {}",
-            node.lock().unwrap().settings.redis_uri
+            "Storage connected at {}",
+            node.read().unwrap().settings.redis_uri
         );
 
+        // Migrating before accepting connections, so that every record can be
+        // found in the index once the node is up.
+        if node.read().unwrap().settings.migration.startup {
+            migrate(&storage, &node.read().unwrap().settings)?;
+        }
+
+        let (acceptor, connector) = Acceptor::new(&node.read().unwrap().settings)?;
+        let settings = node.read().unwrap().settings.outbound;
+        let outbound = Arc::new(outbound::Pool::new(connector.clone(), settings.max_idle));
+        if settings.warmup {
+            outbound.warmup(Arc::clone(&node), settings.parallelism);
+        }
+
+        let (acceptor, connector) = (Arc::new(acceptor), Arc::new(connector));
+        health::spawn(
+            Arc::clone(&node),
+            Arc::clone(&storage),
+            Arc::clone(&connector),
+        );
+        retention::spawn(Arc::clone(&node), Arc::clone(&storage));
+        metrics::spawn(Arc::clone(&node), Arc::clone(&storage));
+
+        let changelog = match &node.read().unwrap().settings.changelog {
+            Some(path) => Some(Changelog::open(path).map_err(Error::Io)?),
+            None => None,
+        };
+        let events = Arc::new(events::Bus::new(changelog));
+        let reactor = Reactor::spawn(
+            Arc::clone(&node),
+            Arc::clone(&storage),
+            Arc::clone(&outbound),
+            Arc::clone(&events),
+            Arc::clone(&pool),
+        )
+        .map_err(Error::Io)?;
+        let (local, shutdown) = {
+            let node = node.read().unwrap();
+            (node.local(), node.shutdown())
+        };
+        let addr = listener.local_addr().map_err(Error::Io)?;
+        let admin = match admin {
+            Some(admin) => {
+                let addr = admin.local_addr().map_err(Error::Io)?;
+                info!("Admin listener bound at {}", addr);
+                let context = crate::admin::Context {
+                    node: Arc::clone(&node),
+                    shutdown: shutdown.clone(),
+                    source: node.read().unwrap().source.clone(),
+                    storage: Arc::clone(&storage),
+                    outbound: Arc::clone(&outbound),
+                    events: Arc::clone(&events),
+                };
+                crate::admin::spawn(admin, context).map_err(Error::Io)?;
+                Some(addr)
+            }
+            None => None,
+        };
+        seeds::spawn(Arc::clone(&node), Arc::clone(&connector), addr);
+        // Browsing until the listener stops accepting connections.
+        let _browser = browse(&node, &connector, addr)?;
+        local.start(loopback::Running {
+            addr,
+            admin,
+            reactor: Arc::clone(&reactor),
+        });
+        let registration = match node.read().unwrap().settings.loopback {
+            true => Some(loopback::register(addr, local.clone())),
+            false => None,
+        };
+
+        let (accept, socket) = {
+            let settings = &node.read().unwrap().settings;
+            (settings.accept, settings.socket)
+        };
+        let mut failures = 0;
+        shutdown.listening(addr);
+        systemd::ready(local.clone(), shutdown.clone(), Arc::clone(&pool));
         for stream in listener.incoming() {
-            let stream = stream?;
-            let node = Arc::clone(&node);
-            let redis = Arc::clone(&redis);
-
-            // Spawning a separate thread for each incoming connection. Besides a thread,
-            // there will also be an instance of [Handler], which will be the main function
-            // the thread tcp executes.
-            pool.execute(move || {
-                let addr = stream.peer_addr().unwrap();
-                let redis = redis.get_connection().unwrap();
-                if let Err(e) = Handler::new(stream).tcp(node, redis) {
-                    error!("Stream error from {}: {}", addr, e);
+            // Connections accepted once the node is stopping, including the one
+            // waking up the listener, are closed right away.
+            if shutdown.is_stopping() {
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    crash::accept_failed();
+                    failures += 1;
+                    let wait = backoff(&accept, failures, e).map_err(Error::Io)?;
+                    std::thread::sleep(wait);
+                    continue;
                 }
+            };
+
+            failures = 0;
+            // Connections from addresses which are not permitted, or whose
+            // address cannot be told, are closed before anything is read from
+            // them.
+            let addr = match stream.peer_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug!("Refused a connection from an unknown address: {}", e);
+                    continue;
+                }
+            };
+
+            if !node.read().unwrap().settings.firewall.permits(addr.ip()) {
+                debug!("Refused the connection of {}", addr);
+                continue;
+            }
+
+            crash::accepted();
+            if let Err(e) = Tcp::configure(&stream, &socket) {
+                warn!("Could not set the socket options of a connection: {}", e);
+            }
+
+            let acceptor = Arc::clone(&acceptor);
+            let reactor = Arc::clone(&reactor);
+            let accepted = std::time::Instant::now();
+
+            // Queueing each incoming connection for a worker, which serves it with an
+            // instance of [Handler] until the connection is closed or goes idle, at
+            // which point it is parked with the reactor, see [crate::reactor].
+            let priority = priority(&node.read().unwrap().settings, &stream);
+            pool.execute_with(priority, move || match acceptor.accept(stream) {
+                Ok(stream) => reactor.serve(Handler::new(stream, accepted)),
+                Err(e) => error!("Stream error from {}: {}", addr, e),
             });
         }
 
+        // Closing the listener, and handing out no more loopback connections,
+        // before waiting for the connections which are being served.
+        systemd::stopping();
+        drop(listener);
+        drop(registration);
+        local.stop();
+        let drain = Duration::from_secs(node.read().unwrap().settings.drain_timeout);
+        let draining = std::time::Instant::now();
+        if pool.drain(drain) {
+            info!("Stopped after draining the requests in flight");
+        } else {
+            warn!("Stopped before every request in flight was processed");
+        }
+
+        if !outbound
+            .repairs
+            .stop(drain.saturating_sub(draining.elapsed()))
+        {
+            warn!("Stopped before every repair of a lagging replica finished");
+        }
+
         Ok(())
     }
 }
+
+/// Applies the policy to the given number of consecutive failures to accept a
+/// connection, the last of which failed with the error.
+///
+/// # Returns
+///
+/// How long to wait before accepting the next connection, or the error if the
+/// node stops.
+fn backoff(accept: &Accept, failures: u32, e: std::io::Error) -> std::io::Result<Duration> {
+    let wait = match accept.policy {
+        AcceptPolicy::Fail => return Err(e),
+        AcceptPolicy::Log => 0,
+        AcceptPolicy::Backoff => 2u64
+            .saturating_pow(failures.saturating_sub(1))
+            .saturating_mul(accept.backoff)
+            .min(accept.max_backoff),
+    };
+
+    error!(
+        "Could not accept a connection ({} in a row), waiting {}ms: {}",
+        failures, wait, e
+    );
+    Ok(Duration::from_millis(wait))
+}
+
+/// Returns the priority with which the connection is queued for a worker.
+/// Connections from the address of an acknowledged node are queued with
+/// [Accept::peers], regardless of their port, since nodes connect from
+/// ephemeral ports.
+fn priority(settings: &Settings, stream: &TcpStream) -> Priority {
+    let ip = match stream.peer_addr() {
+        Ok(addr) => addr.ip(),
+        Err(_) => return Priority::Normal,
+    };
+
+    match settings.nodes.iter().any(|peer| peer.addr.ip() == ip) {
+        true => settings.accept.peers,
+        false => Priority::Normal,
+    }
+}
+
+/// Advertises the node on the local network if it is configured to.
+#[cfg(feature = "discovery")]
+fn advertise(
+    settings: &Settings,
+    listener: &TcpListener,
+) -> Result<Option<crate::discovery::Advertisement>, Error> {
+    if !settings.advertise {
+        return Ok(None);
+    }
+
+    let port = listener.local_addr().map_err(Error::Io)?.port();
+    crate::discovery::advertise(settings, port)
+        .map(Some)
+        .map_err(|e| Error::Discovery(e.to_string()))
+}
+
+#[cfg(not(feature = "discovery"))]
+fn advertise(settings: &Settings, _: &TcpListener) -> Result<Option<()>, Error> {
+    match settings.advertise {
+        true => Err(Error::Discovery(
+            "Advertising is configured, but the `discovery` feature is disabled".into(),
+        )),
+        false => Ok(None),
+    }
+}
+
+#[cfg(feature = "discovery")]
+fn browse(
+    node: &Arc<RwLock<Node>>,
+    connector: &Arc<sdk::Connector>,
+    addr: std::net::SocketAddr,
+) -> Result<Option<crate::discovery::Browser>, Error> {
+    if !node.read().unwrap().settings.discover {
+        return Ok(None);
+    }
+
+    crate::discovery::browse(Arc::clone(node), Arc::clone(connector), addr)
+        .map(Some)
+        .map_err(|e| Error::Discovery(e.to_string()))
+}
+
+#[cfg(not(feature = "discovery"))]
+fn browse(
+    node: &Arc<RwLock<Node>>,
+    _: &Arc<sdk::Connector>,
+    _: std::net::SocketAddr,
+) -> Result<Option<()>, Error> {
+    match node.read().unwrap().settings.discover {
+        true => Err(Error::Discovery(
+            "Discovery is configured, but the `discovery` feature is disabled".into(),
+        )),
+        false => Ok(None),
+    }
+}
+
+/// Runs the migration of legacy records, logging its progress.
+fn migrate(storage: &Storage, settings: &Settings) -> Result<migration::Report, Error> {
+    let migration = &settings.migration;
+    if let Some(owner) = &migration.owner {
+        if !crate::users::is_valid_handle(owner) {
+            return Err(Error::InvalidOwner(owner.clone()));
+        }
+    }
+
+    info!("Migrating legacy records into {}", settings.name);
+    let report = storage
+        .migrate(migration, &mut |report| {
+            info!(
+                "Migrated {} of {} legacy keys, skipped {}",
+                report.migrated, report.scanned, report.skipped
+            );
+        })
+        .map_err(Error::Storage)?;
+
+    info!("Migration of legacy records is complete");
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let error = || std::io::Error::from_raw_os_error(24);
+        let mut accept = Accept {
+            policy: AcceptPolicy::Backoff,
+            backoff: 10,
+            max_backoff: 50,
+            ..Default::default()
+        };
+        let waits: Vec<_> = [1, 2, 3, 4, 40]
+            .into_iter()
+            .map(|failures| backoff(&accept, failures, error()).unwrap())
+            .map(|wait| wait.as_millis())
+            .collect();
+        assert_eq!(waits, vec![10, 20, 40, 50, 50]);
+
+        accept.policy = AcceptPolicy::Log;
+        assert_eq!(backoff(&accept, 3, error()).unwrap(), Duration::ZERO);
+
+        accept.policy = AcceptPolicy::Fail;
+        assert_eq!(
+            backoff(&accept, 1, error()).unwrap_err().raw_os_error(),
+            Some(24)
+        );
+    }
+
+    #[test]
+    fn test_transfer() {
+        let node = Node::new(Settings::new("memory://".into()).unwrap());
+        assert!(matches!(
+            node.transfer("memory://other"),
+            Err(Error::VolatileBackend(_))
+        ));
+    }
+
+    #[test]
+    fn test_firewall() {
+        use std::io::Read;
+
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        settings.firewall.allow = vec!["127.0.0.0/8".parse().unwrap()];
+        settings.firewall.deny = vec!["127.0.0.1".parse().unwrap()];
+        assert!(settings.firewall.permits("127.0.0.2".parse().unwrap()));
+        assert!(!settings.firewall.permits("10.0.0.1".parse().unwrap()));
+
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // The connection is closed before anything is read from it.
+        let mut stream = TcpStream::connect(local.addr().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0; 1];
+        match stream.read(&mut buffer) {
+            Ok(read) => assert_eq!(read, 0),
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use log::*;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use crate::node::Node;
+use crate::sdk;
+use crate::verification::{self, Verifier};
+use crate::{deadline, quorum, timing};
+
+/// A connection to a remote node which has been introduced to it already.
+struct Introduced {
+    client: sdk::Client,
+    /// The pinned public key of the remote node, if there is one.
+    pinned: Option<Pinned>,
+}
+
+/// The pinned public key of a remote node, along with the session of the
+/// connection it was pinned for, see [crate::verification].
+pub(crate) struct Pinned {
+    pub(crate) public: String,
+    pub(crate) session: u64,
+}
+
+/// Keeps connections to the acknowledged nodes open between requests, so that
+/// requests which reach out to peers only pay for connecting and introducing
+/// current node once per connection rather than once per request.
+pub(crate) struct Pool {
+    connector: sdk::Connector,
+    /// Idle connections by the address of the remote node.
+    idle: Mutex<HashMap<SocketAddr, Vec<Introduced>>>,
+    /// The most idle connections kept per remote node.
+    max_idle: usize,
+    verifier: Verifier,
+    /// The repairs of lagging replicas, see [crate::quorum].
+    pub(crate) repairs: quorum::Repairs,
+}
+
+impl Pool {
+    pub(crate) fn new(connector: sdk::Connector, max_idle: usize) -> Self {
+        Self {
+            connector,
+            idle: Mutex::new(HashMap::new()),
+            max_idle,
+            verifier: Default::default(),
+            repairs: Default::default(),
+        }
+    }
+
+    /// Verifies the signatures of the entries aggregated from a node with a
+    /// pinned key. See [crate::verification].
+    pub(crate) fn verify(&self, pinned: &Pinned, reply: &[u8]) -> Vec<u8> {
+        self.verifier.verify(&pinned.public, pinned.session, reply)
+    }
+
+    /// Calls `f` with a connection to the node at the given address, along with
+    /// the pinned public key of the node if there is one. Connections to
+    /// acknowledged nodes are taken from the pool if possible, and put back once
+    /// `f` returns, unless the connection failed. Since the remote node may have
+    /// closed an idle connection in the meantime, `f` is called once more on a
+    /// new connection if an idle one fails. The time spent is recorded for the
+    /// trailers of timed requests.
+    ///
+    /// Calls past the deadline of the request are not made, and responses are
+    /// not waited for past the deadline, in which case the call fails with an
+    /// error of kind [io::ErrorKind::TimedOut] or [io::ErrorKind::WouldBlock].
+    /// See [crate::deadline].
+    pub(crate) fn with<T>(
+        &self,
+        node: &Arc<RwLock<Node>>,
+        addr: &str,
+        mut f: impl FnMut(&mut sdk::Client, Option<&Pinned>) -> Result<T, sdk::Error>,
+    ) -> Result<T, sdk::Error> {
+        let started = Instant::now();
+        let result = self.call(node, addr, |client, public| {
+            let remaining = match deadline::remaining() {
+                Some(remaining) if remaining.is_zero() => {
+                    return Err(sdk::Error::Io(io::ErrorKind::TimedOut.into()))
+                }
+                remaining => remaining,
+            };
+
+            if remaining.is_none() {
+                return f(client, public);
+            }
+
+            client.set_read_timeout(remaining)?;
+            let result = f(client, public);
+            // Connections which failed are not put back, and the others are put
+            // back without the timeout.
+            match result {
+                Ok(_) => client.set_read_timeout(None).and(result),
+                Err(_) => result,
+            }
+        });
+        timing::remote(started.elapsed());
+        if result.is_err() && deadline::exceeded() {
+            deadline::expire();
+        }
+
+        result
+    }
+
+    fn call<T>(
+        &self,
+        node: &Arc<RwLock<Node>>,
+        addr: &str,
+        mut f: impl FnMut(&mut sdk::Client, Option<&Pinned>) -> Result<T, sdk::Error>,
+    ) -> Result<T, sdk::Error> {
+        let acknowledged = addr
+            .parse::<SocketAddr>()
+            .ok()
+            .filter(|addr| is_acknowledged(node, addr));
+        let acknowledged = match acknowledged {
+            Some(acknowledged) => acknowledged,
+            None => {
+                let mut client = self.connector.connect(addr)?;
+                let pinned = introduce(node, &mut client, addr)?;
+                return f(&mut client, pinned.as_ref());
+            }
+        };
+
+        if let Some(mut introduced) = self.take(&acknowledged) {
+            match f(&mut introduced.client, introduced.pinned.as_ref()) {
+                // Connections which timed out at the deadline did not go idle, and are
+                // not opened again.
+                Err(sdk::Error::Io(e)) if !deadline::exceeded() => {
+                    debug!("Idle connection to {} failed: {}", acknowledged, e);
+                }
+                result => {
+                    self.put(acknowledged, introduced);
+                    return result;
+                }
+            }
+        }
+
+        let mut introduced = self.open(node, &acknowledged)?;
+        let result = f(&mut introduced.client, introduced.pinned.as_ref());
+        if !matches!(result, Err(sdk::Error::Io(_))) {
+            self.put(acknowledged, introduced);
+        }
+
+        result
+    }
+
+    /// Opens and introduces a connection to every acknowledged node, with at
+    /// most `parallelism` connections being established at once, and keeps the
+    /// connections in the pool. The warmup runs in the background, so that
+    /// nodes which cannot be reached do not hold up the startup.
+    pub(crate) fn warmup(self: &Arc<Self>, node: Arc<RwLock<Node>>, parallelism: usize) {
+        let peers: Vec<SocketAddr> = {
+            let node = node.read().unwrap();
+            node.settings.nodes.iter().map(|peer| peer.addr).collect()
+        };
+
+        if peers.is_empty() || self.max_idle == 0 {
+            return;
+        }
+
+        let workers = parallelism.clamp(1, peers.len());
+        let peers = Arc::new(Mutex::new(peers));
+        for _ in 0..workers {
+            let pool = Arc::clone(self);
+            let node = Arc::clone(&node);
+            let peers = Arc::clone(&peers);
+            std::thread::spawn(move || loop {
+                let addr = match peers.lock().unwrap().pop() {
+                    Some(addr) => addr,
+                    None => break,
+                };
+
+                match pool.open(&node, &addr) {
+                    Ok(introduced) => {
+                        debug!("Warmed up a connection to {}", addr);
+                        pool.put(addr, introduced);
+                    }
+                    Err(e) => warn!("Could not warm up a connection to {}: {}", addr, e),
+                }
+            });
+        }
+    }
+
+    fn open(&self, node: &Arc<RwLock<Node>>, addr: &SocketAddr) -> Result<Introduced, sdk::Error> {
+        let mut client = self.connector.connect(addr)?;
+        let pinned = introduce(node, &mut client, &addr.to_string())?;
+        Ok(Introduced { client, pinned })
+    }
+
+    fn take(&self, addr: &SocketAddr) -> Option<Introduced> {
+        self.idle.lock().unwrap().get_mut(addr)?.pop()
+    }
+
+    fn put(&self, addr: SocketAddr, introduced: Introduced) {
+        let mut idle = self.idle.lock().unwrap();
+        let idle = idle.entry(addr).or_default();
+        if idle.len() < self.max_idle {
+            idle.push(introduced);
+        }
+    }
+}
+
+fn is_acknowledged(node: &Arc<RwLock<Node>>, addr: &SocketAddr) -> bool {
+    let node = node.read().unwrap();
+    node.settings.nodes.iter().any(|peer| peer.addr == *addr)
+}
+
+/// Proves the identity of current node to the acknowledged node with the
+/// given address. The signed handshake is used if the key of the remote node
+/// is pinned, and the token of the remote node otherwise. Connections to nodes
+/// which are not acknowledged stay anonymous.
+///
+/// # Returns
+///
+/// The pinned public key of the remote node, if there is one, for a new
+/// session.
+fn introduce(
+    node: &Arc<RwLock<Node>>,
+    client: &mut sdk::Client,
+    addr: &str,
+) -> Result<Option<Pinned>, sdk::Error> {
+    let addr: SocketAddr = match addr.parse() {
+        Ok(addr) => addr,
+        Err(_) => return Ok(None),
+    };
+
+    let (peer, secret) = {
+        let node = node.read().unwrap();
+        let peer = node.settings.nodes.iter().find(|peer| peer.addr == addr);
+        (peer.cloned(), node.settings.key.clone())
+    };
+
+    match (peer, secret) {
+        (Some(peer), Some(secret)) if peer.key.is_some() => {
+            let keypair = crate::keys::Keypair::from_hex(&secret).map_err(sdk::Error::Keys)?;
+            client.handshake(&keypair, peer.key.as_deref())?;
+            Ok(peer.key.map(|public| Pinned {
+                public,
+                session: verification::session(),
+            }))
+        }
+
+        (Some(peer), _) => {
+            if let Some(token) = &peer.token {
+                client.authenticate(token)?;
+            }
+
+            Ok(peer.key.map(|public| Pinned {
+                public,
+                session: verification::session(),
+            }))
+        }
+
+        (None, _) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use crate::testing::{FakePeer, Reply};
+
+    #[test]
+    fn test_with() {
+        let peer = FakePeer::bind([
+            (crate::auth::AUTHENTICATE, Reply::ok("node:peer")),
+            (0x0003, Reply::ok("1")),
+            (0x0003, Reply::Disconnect),
+            (0x0003, Reply::ok("2")),
+        ])
+        .unwrap();
+
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.nodes = vec![serde_json::from_str(&format!(
+            r#"{{"addr": "{}", "token": "secret"}}"#,
+            peer.addr()
+        ))
+        .unwrap()];
+        let node = Arc::new(RwLock::new(Node::new(settings)));
+
+        let pool = Pool::new(sdk::Connector::Plain, 1);
+        let addr = peer.addr().to_string();
+        let aggregate = || pool.with(&node, &addr, |client, _| client.aggregate_entries(&["key"]));
+        assert_eq!(aggregate().unwrap(), b"1");
+        // The idle connection is reused, and replaced once it fails.
+        assert_eq!(aggregate().unwrap(), b"2");
+
+        let authenticate = (crate::auth::AUTHENTICATE, b"secret".to_vec());
+        let aggregate = (0x0003, b"key\x00".to_vec());
+        assert_eq!(
+            peer.requests(),
+            vec![
+                authenticate.clone(),
+                aggregate.clone(),
+                aggregate.clone(),
+                authenticate,
+                aggregate,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deadline() {
+        let peer = FakePeer::bind([(
+            0x0003,
+            Reply::ok("1").delayed(std::time::Duration::from_millis(500)),
+        )])
+        .unwrap();
+        let node = Arc::new(RwLock::new(Node::new(
+            Settings::new("memory://".into()).unwrap(),
+        )));
+
+        let pool = Pool::new(sdk::Connector::Plain, 1);
+        let addr = peer.addr().to_string();
+        let aggregate = || pool.with(&node, &addr, |client, _| client.aggregate_entries(&["key"]));
+        let guard = deadline::start(Instant::now() + std::time::Duration::from_millis(50));
+        assert!(matches!(aggregate(), Err(sdk::Error::Io(_))));
+        assert!(guard.expired());
+
+        // Calls past the deadline are not made at all.
+        assert!(matches!(aggregate(), Err(sdk::Error::Io(_))));
+        assert_eq!(peer.requests().len(), 1);
+    }
+}
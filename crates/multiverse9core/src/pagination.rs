@@ -0,0 +1,240 @@
+//! Pagination returns result sets too large for a single response in pages,
+//! which clients request one after another. It applies to the feeds returned
+//! by `latest` requests, and to scans, which are aggregate requests without
+//! any keys that return the records of current node in the order of their
+//! keys.
+//!
+//! The first targets of a paginated request are [CURSOR] followed by the
+//! cursor the page starts after, which is empty for the first page, and
+//! optionally [LIMIT] followed by the most entries the page holds. Scans may
+//! also take the options of [crate::views]. The response is the entries
+//! of the page followed by a footer, [CURSOR] followed by the cursor of the
+//! next page and a null byte. The cursor is empty once there are no more pages.
+//!
+//! Cursors are the key of the last record of a page, so the pages which
+//! follow are not shifted by records created or removed in the meantime.
+//! Streamed scans carry the footer in the frame which ends the stream, see
+//! [crate::streaming].
+
+use crate::sdk::response::Record;
+use crate::sdk::Error;
+use crate::storage::{self, Connection, Keyspace};
+
+/// Prefix of the target of a paginated request which holds the cursor the
+/// page starts after, i.e. `cursor=01H...`. It also prefixes the footer of
+/// the response.
+pub const CURSOR: &str = "cursor=";
+
+/// Prefix of the target of a paginated request which holds the most entries
+/// the page holds, i.e. `limit=50`.
+pub const LIMIT: &str = "limit=";
+
+/// Number of entries a page holds when the request does not specify one.
+pub const DEFAULT_LIMIT: usize = 100;
+
+/// The most entries a single page holds.
+pub const MAX_LIMIT: usize = 1000;
+
+/// The page a paginated request asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paging {
+    /// The key the page starts after, or an empty string for the first page.
+    pub cursor: String,
+    pub limit: usize,
+}
+
+impl Default for Paging {
+    fn default() -> Self {
+        Self {
+            cursor: String::new(),
+            limit: DEFAULT_LIMIT,
+        }
+    }
+}
+
+impl Paging {
+    /// Returns the footer of the page, given the keys read for it. Pages which
+    /// hold fewer keys than their limit are the last ones.
+    pub fn footer(&self, keys: &[String]) -> Vec<u8> {
+        let next = match keys.last() {
+            Some(last) if keys.len() >= self.limit => last.as_str(),
+            _ => "",
+        };
+
+        format!("{}{}\x00", CURSOR, next).into_bytes()
+    }
+}
+
+/// A page of records, along with the cursor of the next page.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Page {
+    pub records: Vec<Record>,
+    /// The cursor the next page starts after, or [None] once there are no
+    /// more pages.
+    pub next: Option<String>,
+}
+
+impl Page {
+    /// Decodes the entries and footer of a paginated response.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Malformed] if the response has no footer, or if any of
+    /// its entries cannot be decoded.
+    pub fn decode(reply: &[u8]) -> Result<Self, Error> {
+        let (entries, next) = split(reply);
+        let next = next.ok_or_else(|| Error::Malformed(String::from_utf8_lossy(reply).into()))?;
+        let mut page = Self {
+            next: next.map(String::from),
+            ..Default::default()
+        };
+
+        for entry in entries
+            .split(|c| *c == 00)
+            .filter(|entry| !entry.is_empty())
+        {
+            match Record::decode(entry) {
+                Some(record) => page.records.push(record),
+                None => return Err(Error::Malformed(String::from_utf8_lossy(entry).into())),
+            }
+        }
+
+        Ok(page)
+    }
+}
+
+/// Encodes the options of a paginated request which asks for the page after
+/// the cursor, or for the first page without one.
+pub fn encode(cursor: Option<&str>, limit: usize) -> String {
+    format!(
+        "{}{}\x00{}{}\x00",
+        CURSOR,
+        cursor.unwrap_or(""),
+        LIMIT,
+        limit
+    )
+}
+
+/// Parses the most entries of a page out of the value of the [LIMIT] target.
+///
+/// # Returns
+///
+/// [None] unless the limit is between 1 and [MAX_LIMIT].
+pub fn parse_limit(limit: &str) -> Option<usize> {
+    match limit.parse::<usize>() {
+        Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => Some(limit),
+        _ => None,
+    }
+}
+
+/// Returns whether the value of the [CURSOR] target can be a cursor.
+pub fn is_cursor(cursor: &str) -> bool {
+    cursor.is_empty() || cursor.len() == ulid::ULID_LEN || crate::content::is_key(cursor)
+}
+
+/// Splits a paginated response into its entries and the cursor of the next
+/// page, which is [None] within the outer option if the response has no
+/// footer.
+pub fn split(reply: &[u8]) -> (&[u8], Option<Option<&str>>) {
+    let body = reply.strip_suffix(&[00]).unwrap_or(reply);
+    let start = body.iter().rposition(|c| *c == 00).map_or(0, |i| i + 1);
+    let next = std::str::from_utf8(&body[start..])
+        .ok()
+        .and_then(|footer| footer.strip_prefix(CURSOR));
+    match next {
+        Some(next) => (&reply[..start], Some((!next.is_empty()).then_some(next))),
+        None => (reply, None),
+    }
+}
+
+/// Returns the keys of up to `limit` records of current node which follow the
+/// cursor in the order of their keys.
+pub(crate) fn scan(
+    connection: &mut dyn Connection,
+    keyspace: &Keyspace,
+    paging: &Paging,
+) -> Result<Vec<String>, storage::Error> {
+    let mut keys: Vec<String> = crate::anti_entropy::inventory(connection, keyspace)?
+        .into_iter()
+        .filter(|key| key.as_str() > paging.cursor.as_str())
+        .collect();
+    keys.sort();
+    keys.truncate(paging.limit);
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::settings::Settings;
+    use std::time::Duration;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            split(b"a:1\x00cursor=a\x00"),
+            (&b"a:1\x00"[..], Some(Some("a")))
+        );
+        assert_eq!(split(b"a:1\x00cursor=\x00"), (&b"a:1\x00"[..], Some(None)));
+        assert_eq!(split(b"cursor=\x00"), (&b""[..], Some(None)));
+        assert_eq!(split(b"a:1\x00"), (&b"a:1\x00"[..], None));
+        assert_eq!(parse_limit("1000"), Some(MAX_LIMIT));
+        assert_eq!(parse_limit("0"), None);
+        assert!(is_cursor(""));
+        assert!(!is_cursor("latest"));
+    }
+
+    #[test]
+    fn test_pages() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        let mut keys = vec![];
+        for i in 0..5 {
+            keys.push(client.create(format!("value{}", i).as_bytes()).unwrap().id);
+        }
+
+        let targets: Vec<&str> = keys.iter().map(String::as_str).collect();
+        client.post("news", &targets).unwrap();
+
+        // Feeds are paged from the most recent record, and scans in the order
+        // of the keys.
+        keys.sort();
+        let (mut feed, mut scanned) = (vec![], vec![]);
+        let mut cursor = None;
+        loop {
+            let page = client.feed_page("news", cursor.as_deref(), 2).unwrap();
+            assert!(page.records.len() <= 2);
+            feed.extend(page.records.into_iter().map(|record| record.key));
+            cursor = match page.next {
+                Some(next) => Some(next),
+                None => break,
+            };
+        }
+
+        // The scan starts over from the first key.
+        let mut cursor = None;
+        loop {
+            let page = client.scan(cursor.as_deref(), 2).unwrap();
+            scanned.extend(page.records.into_iter().map(|record| record.key));
+            cursor = match page.next {
+                Some(next) => Some(next),
+                None => break,
+            };
+        }
+
+        assert_eq!(scanned, keys);
+        keys.reverse();
+        assert_eq!(feed, keys);
+        assert!(client.scan(Some("latest"), 2).is_err());
+        assert!(client.scan(None, MAX_LIMIT + 1).is_err());
+    }
+}
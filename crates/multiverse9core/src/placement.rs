@@ -0,0 +1,171 @@
+//! Placement maps keys onto the nodes of a federation with a consistent-hash
+//! ring, so that any node, and any client which knows the nodes, can compute
+//! which node owns a key without asking a directory service.
+//!
+//! The ring of a node is made of current node and the nodes it acknowledges.
+//! Every node is placed on the ring [VNODES] times, at the BLAKE3 hashes of its
+//! address followed by the number of the point, and a key is owned by the node
+//! at the first point following the hash of the key. Adding or removing a node
+//! only moves the keys next to its points, so most keys keep their owner as
+//! the federation changes.
+//!
+//! Nodes compute the same ring only if they know each other by the same
+//! addresses, so nodes whose keys are placed should bind the address they are
+//! acknowledged at. A [RING] request returns the nodes of the ring of a node,
+//! for clients to compute placements on their own. See
+//! [crate::sdk::Client::ring].
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use crate::node::Node;
+
+/// Request code for listing the nodes of the ring of a node, as a JSON array
+/// of addresses.
+pub const RING: u16 = 0x0029;
+
+/// Number of points every node is placed at on the ring. More points spread
+/// the keys more evenly across the nodes.
+pub const VNODES: usize = 64;
+
+/// A consistent-hash ring of nodes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ring {
+    /// The nodes of the ring, sorted by their addresses.
+    nodes: Vec<SocketAddr>,
+    points: BTreeMap<u64, SocketAddr>,
+}
+
+impl Ring {
+    /// Places the given nodes on a ring. Duplicate addresses are placed once.
+    pub fn new(nodes: impl IntoIterator<Item = SocketAddr>) -> Self {
+        let mut nodes: Vec<SocketAddr> = nodes.into_iter().collect();
+        nodes.sort();
+        nodes.dedup();
+
+        let mut points = BTreeMap::new();
+        for node in &nodes {
+            for point in 0..VNODES {
+                points.insert(hash(format!("{}#{}", node, point).as_bytes()), *node);
+            }
+        }
+
+        Self { nodes, points }
+    }
+
+    /// Returns the ring of the node, made of the node itself and the nodes it
+    /// acknowledges. The node is placed at the address it listens at once it
+    /// has started, or at the address it binds otherwise.
+    pub fn of(node: &Node) -> Self {
+        let own = own(node);
+        let peers = node.settings.nodes.iter().map(|peer| peer.addr);
+        Self::new(std::iter::once(own).chain(peers))
+    }
+
+    /// Returns the nodes of the ring, sorted by their addresses.
+    pub fn nodes(&self) -> &[SocketAddr] {
+        &self.nodes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns the node which owns the key, or [None] if the ring is empty.
+    pub fn owner(&self, key: &str) -> Option<SocketAddr> {
+        self.owners(key, 1).first().copied()
+    }
+
+    /// Returns up to `count` distinct nodes for the key, starting with its
+    /// owner and followed by the nodes which own it once the nodes before them
+    /// are gone, such as to pick the replicas of the key.
+    pub fn owners(&self, key: &str, count: usize) -> Vec<SocketAddr> {
+        let count = count.min(self.nodes.len());
+        let at = hash(key.as_bytes());
+        let mut owners: Vec<SocketAddr> = Vec::with_capacity(count);
+        for (_, node) in self.points.range(at..).chain(self.points.range(..at)) {
+            if owners.len() == count {
+                break;
+            }
+
+            if !owners.contains(node) {
+                owners.push(*node);
+            }
+        }
+
+        owners
+    }
+}
+
+/// Returns the address current node is placed at on its ring.
+pub(crate) fn own(node: &Node) -> SocketAddr {
+    node.local().addr().unwrap_or(node.settings.addr)
+}
+
+/// Hashes the bytes onto the ring.
+fn hash(bytes: &[u8]) -> u64 {
+    let hash = blake3::hash(bytes);
+    let mut point = [0; 8];
+    point.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_be_bytes(point)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use std::time::Duration;
+
+    fn addrs(ports: std::ops::Range<u16>) -> Vec<SocketAddr> {
+        ports
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect()
+    }
+
+    #[test]
+    fn test_owner() {
+        assert_eq!(Ring::default().owner("key"), None);
+
+        let ring = Ring::new(addrs(7000..7004));
+        let keys: Vec<String> = (0..1000).map(|_| ulid::Ulid::new().to_string()).collect();
+        for key in &keys {
+            let owners = ring.owners(key, 3);
+            assert_eq!(owners.len(), 3);
+            assert_eq!(ring.owner(key), Some(owners[0]));
+            assert!(!owners[1..].contains(&owners[0]));
+        }
+
+        // The order the nodes are given in does not matter.
+        let mut reversed = addrs(7000..7004);
+        reversed.reverse();
+        assert_eq!(Ring::new(reversed), ring);
+
+        // Adding a node only moves the keys it takes over.
+        let grown = Ring::new(addrs(7000..7005));
+        let added = SocketAddr::from(([127, 0, 0, 1], 7004));
+        let moved = keys
+            .iter()
+            .filter(|key| grown.owner(key) != ring.owner(key))
+            .collect::<Vec<_>>();
+        assert!(moved.iter().all(|key| grown.owner(key) == Some(added)));
+        assert!(moved.len() < keys.len() / 2);
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        settings.nodes = vec![serde_json::from_str(r#""127.0.0.1:7000""#).unwrap()];
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let ring = local.client().unwrap().ring().unwrap();
+        let mut nodes = vec![local.addr().unwrap(), "127.0.0.1:7000".parse().unwrap()];
+        nodes.sort();
+        assert_eq!(ring.nodes(), nodes);
+    }
+}
@@ -1,20 +1,167 @@
 use log::*;
-use std::sync::{mpsc, Arc, Mutex};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::metrics::Workers;
+use crate::settings::Priority;
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+crate::enum_with_impl_error! {
+    pub Error,
+    .Cancelled(&'static str)
+    .Dropped(&'static str)
+    .Panicked(String)
+    .Pending(&'static str)
+    ~Debug
+}
+
+/// A handle on the result of a job queued with [Pool::submit]. Dropping the
+/// handle leaves the job queued, and discards its result.
+pub struct Handle<T> {
+    rx: mpsc::Receiver<Result<T, String>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<T> Handle<T> {
+    /// Waits for the job to finish, but no longer than the timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Panicked] with the message of the panic if the job
+    /// panicked, [Error::Cancelled] if it was cancelled before it started, and
+    /// [Error::Dropped] if the pool drained before running it. Returns
+    /// [Error::Pending] if the job did not finish within the timeout, in which
+    /// case it can be waited for again.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<T, Error> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(result) => result.map_err(Error::Panicked),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::Pending("")),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                match self.cancelled.load(Ordering::Relaxed) {
+                    true => Err(Error::Cancelled("")),
+                    false => Err(Error::Dropped("")),
+                }
+            }
+        }
+    }
+
+    /// Keeps the job from running, unless a worker started it already.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A job along with the time it was queued at.
+type Queued = (Instant, Job);
+
+/// The queued jobs, which the workers share. Jobs are dequeued from the lane of
+/// the highest priority which holds any, so jobs of a lower priority only run
+/// once no others wait.
+#[derive(Default)]
+struct Queue {
+    lanes: Mutex<Lanes>,
+    /// Notified whenever a job is queued, or the queue is closed.
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct Lanes {
+    /// The jobs of every [Priority], from the highest to the lowest.
+    jobs: [VecDeque<Queued>; 3],
+    /// Whether the queue no longer accepts jobs, once the pool drains.
+    closed: bool,
+}
+
+impl Lanes {
+    fn lane(priority: Priority) -> usize {
+        match priority {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+
+    fn pop(&mut self) -> Option<Queued> {
+        self.jobs.iter_mut().find_map(VecDeque::pop_front)
+    }
+}
+
+impl Queue {
+    /// Waits for the next job, which is [None] once the queue is closed and
+    /// every job queued before has been dequeued.
+    fn next(&self) -> Option<Queued> {
+        // The lanes are only locked for moving jobs in and out of them, which
+        // never panics, so they are still intact if a worker died while
+        // holding the lock.
+        let mut lanes = self.lanes.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(job) = lanes.pop() {
+                return Some(job);
+            }
+            if lanes.closed {
+                return None;
+            }
+
+            lanes = self
+                .ready
+                .wait(lanes)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn close(&self) {
+        self.lanes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// Counters of the jobs of a pool, shared with its workers.
+#[derive(Default)]
+struct Counters {
+    queued: AtomicU64,
+    busy: AtomicU64,
+    completed: AtomicU64,
+    panics: AtomicU64,
+    respawned: AtomicU64,
+    /// The time the completed jobs took from being queued until they finished,
+    /// in microseconds.
+    latency: AtomicU64,
+}
+
 struct Worker {
     id: usize,
     thread: Option<std::thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, rx: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
-        let thread = std::thread::spawn(move || loop {
-            let rx = rx.lock().unwrap().recv();
-            match rx {
-                Ok(job) => job(),
-                Err(_) => break,
+    fn new(id: usize, queue: Arc<Queue>, counters: Arc<Counters>) -> Self {
+        let thread = std::thread::spawn(move || {
+            while let Some((queued, job)) = queue.next() {
+                crate::crash::dequeued();
+                counters.queued.fetch_sub(1, Ordering::Relaxed);
+                counters.busy.fetch_add(1, Ordering::Relaxed);
+                // The panic hook has reported the panic already. Catching it keeps
+                // the worker alive, so that the pool does not shrink.
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+                let latency = queued.elapsed().as_micros() as u64;
+                counters.busy.fetch_sub(1, Ordering::Relaxed);
+                counters.completed.fetch_add(1, Ordering::Relaxed);
+                counters.latency.fetch_add(latency, Ordering::Relaxed);
+                if let Err(payload) = result {
+                    counters.panics.fetch_add(1, Ordering::Relaxed);
+                    error!("Worker {} recovered from a panic", id);
+                    // Dropping the payload of the panic runs code of the job,
+                    // which may panic in turn and end the worker. Such workers
+                    // are respawned by the pool, see [Pool::respawn].
+                    drop(payload);
+                }
             }
         });
 
@@ -26,18 +173,26 @@ impl Worker {
 }
 
 pub struct Pool {
-    workers: Vec<Worker>,
-    tx: Option<mpsc::Sender<Job>>,
+    workers: Mutex<Vec<Worker>>,
+    queue: Arc<Queue>,
+    counters: Arc<Counters>,
+    size: usize,
 }
 
 impl Drop for Pool {
     fn drop(&mut self) {
-        drop(self.tx.take());
+        self.queue.close();
 
-        for worker in &mut self.workers {
+        for worker in self.workers.lock().unwrap().iter_mut() {
             if let Some(thread) = worker.thread.take() {
+                // A job may drop the last reference to the pool of its own
+                // worker, which finishes once the job returns.
+                if thread.thread().id() == std::thread::current().id() {
+                    continue;
+                }
+
                 trace!("Stopping worker {}...", worker.id);
-                thread.join().unwrap();
+                let _ = thread.join();
             }
         }
     }
@@ -46,22 +201,342 @@ impl Drop for Pool {
 impl Pool {
     pub fn new(size: usize) -> Self {
         assert!(size > 0);
-        let (tx, rx) = mpsc::channel::<Job>();
-        let rx = Arc::new(Mutex::new(rx));
+        let queue = Arc::new(Queue::default());
+        let counters = Arc::new(Counters::default());
         let mut workers = Vec::with_capacity(size);
         for id in 0..size {
             trace!("Starting worker {}...", id);
-            workers.push(Worker::new(id, Arc::clone(&rx)));
+            workers.push(Worker::new(id, Arc::clone(&queue), Arc::clone(&counters)));
         }
 
         Self {
-            workers,
-            tx: Some(tx),
+            workers: Mutex::new(workers),
+            queue,
+            counters,
+            size,
+        }
+    }
+
+    /// Returns the state of the workers and the jobs they ran so far.
+    pub fn stats(&self) -> Workers {
+        let completed = self.counters.completed.load(Ordering::Relaxed);
+        let latency = self.counters.latency.load(Ordering::Relaxed);
+        Workers {
+            size: self.size as u64,
+            busy: self.counters.busy.load(Ordering::Relaxed),
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            completed,
+            panics: self.counters.panics.load(Ordering::Relaxed),
+            respawned: self.counters.respawned.load(Ordering::Relaxed),
+            latency: latency.checked_div(completed).unwrap_or_default(),
         }
     }
 
+    /// Replaces the workers which died with new ones, so that the pool keeps
+    /// its size. Workers die if a job panics in a way the worker cannot
+    /// recover from, since jobs which merely panic are caught.
+    fn respawn(&self) {
+        for worker in self.workers.lock().unwrap().iter_mut() {
+            let finished = worker
+                .thread
+                .as_ref()
+                .is_some_and(|thread| thread.is_finished());
+            if !finished {
+                continue;
+            }
+
+            let _ = worker.thread.take().unwrap().join();
+            error!("Worker {} died, respawning it", worker.id);
+            self.counters.respawned.fetch_add(1, Ordering::Relaxed);
+            *worker = Worker::new(
+                worker.id,
+                Arc::clone(&self.queue),
+                Arc::clone(&self.counters),
+            );
+        }
+    }
+
+    /// Queues the job for the next idle worker, without a handle on when it
+    /// finishes. See [Pool::submit].
     pub fn execute<F: FnOnce() + Send + 'static>(&self, f: F) {
-        let job = Box::new(f);
-        self.tx.as_ref().unwrap().send(job).unwrap();
+        self.execute_with(Priority::Normal, f);
+    }
+
+    /// Same as [Pool::execute], but queues the job with the given priority.
+    pub fn execute_with<F: FnOnce() + Send + 'static>(&self, priority: Priority, f: F) {
+        drop(self.submit_with(priority, f));
+    }
+
+    /// Queues the job for the next idle worker, and returns a handle on its
+    /// result. Jobs which panic are still reported by the worker, and jobs
+    /// queued after the pool has started to drain are dropped without being
+    /// run.
+    pub fn submit<T, F>(&self, f: F) -> Handle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.submit_with(Priority::Normal, f)
+    }
+
+    /// Same as [Pool::submit], but queues the job with the given priority.
+    /// Idle workers pick up the jobs of a higher priority first.
+    pub fn submit_with<T, F>(&self, priority: Priority, f: F) -> Handle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&cancelled);
+        self.queue(
+            priority,
+            Box::new(move || {
+                if flag.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+                    Ok(value) => drop(tx.send(Ok(value))),
+                    Err(payload) => {
+                        drop(tx.send(Err(message(payload.as_ref()))));
+                        std::panic::resume_unwind(payload);
+                    }
+                }
+            }),
+        );
+
+        Handle { rx, cancelled }
+    }
+
+    /// Queues the job, respawning the workers which died first.
+    fn queue(&self, priority: Priority, job: Job) {
+        let mut lanes = self
+            .queue
+            .lanes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if lanes.closed {
+            warn!("Dropping a job queued after the pool started to drain");
+            return;
+        }
+
+        self.respawn();
+        crate::crash::enqueued();
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        lanes.jobs[Lanes::lane(priority)].push_back((Instant::now(), job));
+        self.queue.ready.notify_one();
+    }
+
+    /// Stops accepting jobs, and waits for the workers to finish the jobs which
+    /// are running or queued already.
+    ///
+    /// # Returns
+    ///
+    /// Whether every worker finished within the timeout. Workers which did not
+    /// are left running.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        self.queue.close();
+
+        let deadline = Instant::now() + timeout;
+        let mut workers = self.workers.lock().unwrap();
+        loop {
+            for worker in workers.iter_mut() {
+                if worker
+                    .thread
+                    .as_ref()
+                    .is_some_and(|thread| thread.is_finished())
+                {
+                    trace!("Stopping worker {}...", worker.id);
+                    let _ = worker.thread.take().unwrap().join();
+                }
+            }
+
+            let running = workers
+                .iter()
+                .filter(|worker| worker.thread.is_some())
+                .count();
+            if running == 0 {
+                return true;
+            }
+
+            if Instant::now() >= deadline {
+                warn!("{} workers did not finish within {:?}", running, timeout);
+                return false;
+            }
+
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Returns the message of a panic, given its payload.
+fn message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Box<dyn Any>".into(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panicking_job() {
+        let pool = Pool::new(1);
+        let (tx, rx) = mpsc::channel();
+        pool.execute(|| panic!("The job failed"));
+        pool.execute(move || tx.send(()).unwrap());
+        // The only worker survives the panic and runs the next job.
+        rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_respawn() {
+        struct Bomb;
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                panic!("The payload failed");
+            }
+        }
+
+        let pool = Pool::new(1);
+        pool.execute(|| std::panic::panic_any(Bomb));
+        // Dropping the payload ends the only worker, which is respawned once
+        // the next job is queued.
+        while pool.stats().panics == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        while !pool.workers.lock().unwrap()[0]
+            .thread
+            .as_ref()
+            .unwrap()
+            .is_finished()
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(pool.stats().respawned, 1);
+    }
+
+    #[test]
+    fn test_submit() {
+        let pool = Pool::new(1);
+        let timeout = Duration::from_secs(5);
+        assert_eq!(pool.submit(|| 1 + 1).wait_timeout(timeout).unwrap(), 2);
+        assert!(matches!(
+            pool.submit(|| panic!("The job failed")).wait_timeout(timeout),
+            Err(Error::Panicked(message)) if message == "The job failed"
+        ));
+
+        // Jobs cancelled while they wait for a worker do not run.
+        let (tx, rx) = mpsc::channel::<()>();
+        let blocking = pool.submit(move || {
+            let _ = rx.recv();
+        });
+        let cancelled = pool.submit(|| ());
+        cancelled.cancel();
+        assert!(matches!(
+            cancelled.wait_timeout(Duration::from_millis(10)),
+            Err(Error::Pending(_))
+        ));
+        drop(tx);
+        blocking.wait_timeout(timeout).unwrap();
+        assert!(matches!(
+            cancelled.wait_timeout(timeout),
+            Err(Error::Cancelled(_))
+        ));
+
+        assert!(pool.drain(Duration::from_secs(5)));
+        assert!(matches!(
+            pool.submit(|| ()).wait_timeout(timeout),
+            Err(Error::Dropped(_))
+        ));
+    }
+
+    #[test]
+    fn test_priority() {
+        let pool = Pool::new(1);
+        let (tx, rx) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = rx.recv();
+        });
+        running.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Jobs queued while the only worker is busy run by their priority, and
+        // in the order they were queued within the same priority.
+        let order = Arc::new(Mutex::new(vec![]));
+        for (priority, job) in [
+            (Priority::Low, 1),
+            (Priority::Normal, 2),
+            (Priority::High, 3),
+            (Priority::Normal, 4),
+        ] {
+            let order = Arc::clone(&order);
+            pool.execute_with(priority, move || order.lock().unwrap().push(job));
+        }
+
+        drop(tx);
+        assert!(pool.drain(Duration::from_secs(5)));
+        assert_eq!(*order.lock().unwrap(), vec![3, 2, 4, 1]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let pool = Pool::new(1);
+        let (tx, rx) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = rx.recv();
+        });
+        pool.execute(|| std::thread::sleep(Duration::from_millis(5)));
+        running.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let stats = pool.stats();
+        assert_eq!((stats.size, stats.busy, stats.queued), (1, 1, 1));
+        assert_eq!(stats.completed, 0);
+
+        // Both jobs complete once the first one is released.
+        drop(tx);
+        assert!(pool.drain(Duration::from_secs(5)));
+        let stats = pool.stats();
+        assert_eq!((stats.busy, stats.queued, stats.completed), (0, 0, 2));
+        assert!(stats.latency > 0);
+    }
+
+    #[test]
+    fn test_drain() {
+        let pool = Pool::new(2);
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                tx.send(()).unwrap();
+            });
+        }
+
+        // The queued jobs run before the workers stop, and later ones do not.
+        assert!(pool.drain(Duration::from_secs(5)));
+        pool.execute(move || tx.send(()).unwrap());
+        assert_eq!(rx.try_iter().count(), 4);
+
+        let pool = Pool::new(1);
+        let (tx, rx) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = rx.recv();
+        });
+        assert!(!pool.drain(Duration::from_millis(20)));
+        drop(tx);
     }
 }
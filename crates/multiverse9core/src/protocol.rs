@@ -1,105 +1,1360 @@
+use bytes::Bytes;
 use log::*;
 use std::io;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use crate::api;
+use crate::capabilities::{self, Capabilities};
+use crate::keys::Challenge;
 use crate::node::Node;
-use crate::Tcp;
+use crate::roles::{self, Role};
+use crate::settings::{Auth, Close};
+use crate::shutdown::{self, Shutdown};
+use crate::storage::{self, Claim, Keyspace, Storage};
+use crate::timing;
+use crate::transport::Transport;
+use crate::{
+    access, api, auth, crash, deadline, events, health, journal, metrics, multiplex, outbound,
+    streaming,
+};
+use crate::{buffers, FrameBuffer, Tcp};
+
+/// Contains the functions for encoding and decoding request and response frames.
+pub mod codec;
+
+/// The identity requests on a connection are attributed to. Accounting, such
+/// as logging and rate limiting, should be keyed by [Identity::key] instead of
+/// the remote address, since many clients may share an address behind NAT.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// The connection has not authenticated, so its requests can only be
+    /// attributed to the remote address.
+    Anonymous(std::net::SocketAddr),
+    /// An acknowledged node which has proven its identity, by its node ID.
+    Node(String),
+    /// A client which has authenticated with a token, by the token subject.
+    Subject(String),
+    /// A user registered on current node which has proven its identity with
+    /// the key of its profile, by its handle.
+    User(String),
+}
+
+impl Identity {
+    /// Returns a stable key for the identity which can be used as a label
+    /// or as part of a storage key.
+    pub fn key(&self) -> String {
+        match self {
+            Self::Anonymous(addr) => format!("addr:{}", addr),
+            Self::Node(id) => format!("node:{}", id),
+            Self::Subject(subject) => format!("subject:{}", subject),
+            Self::User(handle) => format!("user:{}", handle),
+        }
+    }
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.key())
+    }
+}
+
+/// The metadata a node publishes about itself, encoded as JSON.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Metadata {
+    /// Human-readable identifier of the node.
+    pub name: String,
+    /// The version of the node.
+    pub version: String,
+    /// Hex-encoded Ed25519 public key of the node, for pinning it as a peer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// The time of the node when it replied, in milliseconds since the Unix
+    /// epoch, for telling how far the clocks of nodes are apart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    /// Addresses of the nodes the node acknowledges as its peers.
+    #[serde(default)]
+    pub peers: Vec<std::net::SocketAddr>,
+    /// The features the node supports, or [None] if the node predates them
+    /// in its metadata. See [crate::capabilities].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<crate::capabilities::Capabilities>,
+}
 
 /// Represents a single request packet.
 pub struct Packet<'a> {
     /// The request code used to lookup the appropriate handler function.
-    pub code: u8,
-    /// The stream the request was received on.
-    pub stream: std::net::TcpStream,
+    pub code: u16,
     /// The request payload. Note that, this buffer does not include the code
-    /// prefix which comes from the request.
-    pub buffer: &'a [u8],
-    pub node: Arc<Mutex<Node>>,
-    pub storage: &'a mut redis::Connection,
+    /// prefix which comes from the request. The payload shares the frame it was
+    /// received in, so that handlers can keep it, or parts of it, without
+    /// copying them.
+    pub buffer: Bytes,
+    /// The node the request is handled by. Handlers only take read locks on
+    /// it, so they never wait for each other, but they do wait while the node
+    /// is written to. The node is written to while it runs by [crate::seeds],
+    /// which acknowledges the nodes behind DNS seeds, and by [crate::admin],
+    /// which changes its settings as the node is managed.
+    pub node: Arc<RwLock<Node>>,
+    pub storage: &'a mut dyn storage::Connection,
+    /// The layout of the keys of current node.
+    pub keyspace: &'a Keyspace,
+    /// The identity the request is attributed to.
+    pub identity: &'a Identity,
+    /// Connections to remote nodes, over the same transport the node itself
+    /// accepts connections over.
+    pub(crate) outbound: &'a Arc<outbound::Pool>,
+    /// The subscriptions the changes made by the request are published to.
+    pub(crate) events: &'a events::Bus,
 }
 
 /// Handles incoming TCP requests.
 pub(crate) struct Handler {
-    /// The TCP stream the request was received on.
-    inner: std::net::TcpStream,
+    /// The stream the request was received on.
+    inner: Box<dyn Transport>,
+    /// When the connection was accepted, or became readable after it was
+    /// parked.
+    accepted: Instant,
+    /// The state of the connection while it is parked, see [crate::reactor].
+    parked: Option<Session>,
+    /// The handshake proof the connection is waiting to have checked, see
+    /// [Handler::prove].
+    proof: Option<Proof>,
+}
+
+/// The state of a connection which outlives the worker serving it, so that
+/// the connection can be parked and resumed on another worker.
+struct Session {
+    identity: Identity,
+    challenge: Option<Challenge>,
+    /// Bytes received past the end of the last frame.
+    frames: FrameBuffer,
+    /// When the last request was received, unless one was received since the
+    /// connection was last idle.
+    idle_since: Option<Instant>,
+}
+
+/// The proof of a handshake which is checked off the worker which received it.
+struct Proof {
+    payload: Vec<u8>,
+    access: Option<access::Entry>,
+}
+
+/// How serving a connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// The connection was closed.
+    Closed,
+    /// The connection was idle, and is waiting to become readable again
+    /// without a worker serving it. See [Handler::tcp].
+    Parked,
+    /// The client sent the proof of its handshake, which has to be checked by
+    /// [Handler::prove] before the connection is served again.
+    Proving,
 }
 
 impl Handler {
     #[inline(always)]
-    pub(crate) fn new(stream: std::net::TcpStream) -> Self {
-        Self { inner: stream }
+    pub(crate) fn new(stream: Box<dyn Transport>, accepted: Instant) -> Self {
+        Self {
+            inner: stream,
+            accepted,
+            parked: None,
+            proof: None,
+        }
+    }
+
+    /// Returns the stream the connection is served over.
+    pub(crate) fn transport(&self) -> &dyn Transport {
+        &*self.inner
+    }
+
+    /// Marks the parked connection as readable, so that the time it waits for a
+    /// worker from now on is accounted to its next request.
+    pub(crate) fn wake(&mut self) {
+        self.accepted = Instant::now();
+    }
+
+    /// Returns how long the parked connection has been idle for.
+    pub(crate) fn idle(&self) -> Duration {
+        let since = self.parked.as_ref().and_then(|session| session.idle_since);
+        shutdown::POLL + since.map_or(Duration::ZERO, |since| since.elapsed())
     }
 
     /// Handles incoming TCP requests.
     ///
     /// # Arguments
     ///
-    /// * `node` - An Arc containing a mutex to the node configuration.
-    /// * `redis` - A mutable reference to the Redis connection.
+    /// * `node` - An Arc containing a read-write lock to the node configuration,
+    ///   which handlers only ever read.
+    /// * `storage` - The storage the backend connection for this stream is taken from.
+    /// * `outbound` - Connections to remote nodes for the handlers.
+    /// * `events` - The subscriptions the handlers publish their changes to.
+    /// * `park` - Whether idle connections are parked instead of being waited on.
     ///
     /// # Returns
     ///
-    /// An io::Result indicating success or failure.
+    /// An io::Result with the [Outcome] of serving the connection.
     ///
     /// # Functionality
     ///
-    /// This function reads from the TCP stream in a loop, separating the request
-    /// code and payload. It then attempts to lookup a handler function for the
-    /// request code in the [api::HANDLER_LOOKUP_TABLE]. If a handler is found, it is
-    /// executed and the response is written to the stream. If no handler is found,
-    /// the [api::unknown_command] function is called.
+    /// This function reads one frame per iteration and splits it into a request
+    /// code and a payload. Bytes received past the end of a frame are kept for
+    /// the next iteration, so that pipelined requests are handled in the order
+    /// they were written in. Requests larger than
+    /// [Settings::max_payload_bytes](crate::settings::Settings::max_payload_bytes)
+    /// are answered with [codec::TOO_LARGE], and the connection is closed.
+    ///
+    /// Authentication, handshake, [health::PING] and [capabilities::HELLO]
+    /// frames are handled directly. Requests the connection is not authorized
+    /// to issue are rejected with [auth::UNAUTHORIZED]. The requests of a
+    /// [codec::BATCH] run one after another, and their responses are written
+    /// back in a single frame. Responses to [journal::JOURNALED] requests are
+    /// replayed from the journal when the request has run before. Any other
+    /// request runs the handler [api::HANDLER_LOOKUP_TABLE] has for its code,
+    /// or [api::unknown_command] if there is none. If a handler fails because
+    /// the storage backend went away, such as on a Redis failover, the backend
+    /// connection is re-established through [Storage] before the next request.
+    ///
+    /// Some requests change how the connection is served from then on.
+    /// Connections which [events::SUBSCRIBE] stop being read, and the events
+    /// they subscribed to are pushed over them until they go away. Connections
+    /// which switch to [multiplex::MULTIPLEX] are handled by
+    /// [Handler::multiplex], and [streaming::AGGREGATE] requests are answered
+    /// by [Handler::stream].
+    ///
+    /// Once the client shuts down its side of the connection, the connection is
+    /// closed as configured by [Close]. Connections which send no request for
+    /// [Settings::idle_timeout](crate::settings::Settings::idle_timeout) are
+    /// closed, which frees the worker serving them. Once the node is stopping,
+    /// the request being processed is finished, later requests are answered
+    /// with [shutdown::SHUTTING_DOWN], and the connection is closed.
+    ///
+    /// If `park` is set, connections which send no request for [shutdown::POLL]
+    /// are parked, which frees their worker until they become readable again.
+    /// Calling this function once more resumes them where they left off. See
+    /// [crate::reactor]. Handshake proofs on such connections are left to
+    /// [Handler::prove], and [Outcome::Proving] is returned for them.
+    ///
+    /// Requests are recorded in the access log once they are answered, if it is
+    /// kept. See [access].
     pub(crate) fn tcp(
-        &self,
-        node: Arc<Mutex<Node>>,
-        mut redis: redis::Connection,
-    ) -> io::Result<()> {
+        &mut self,
+        node: Arc<RwLock<Node>>,
+        storage: Arc<Storage>,
+        outbound: Arc<outbound::Pool>,
+        events: Arc<events::Bus>,
+        park: bool,
+    ) -> io::Result<Outcome> {
+        let mut connection = storage.connection().map_err(into_io)?;
+        let (auth, close, max_payload, read_buffer, idle_timeout, access_log, shutdown) = {
+            let node = node.read().unwrap();
+            let settings = &node.settings;
+            (
+                settings.auth.clone(),
+                settings.close,
+                settings.max_payload_bytes,
+                settings.read_buffer_bytes,
+                Duration::from_secs(settings.idle_timeout),
+                settings.access_log,
+                node.shutdown(),
+            )
+        };
+
+        // Reads wake up periodically while waiting for the next request, so that
+        // neither idle connections nor a stopping node keep the worker serving
+        // the connection forever.
+        self.inner.set_read_timeout(Some(shutdown::POLL))?;
+        let Session {
+            mut identity,
+            mut challenge,
+            mut frames,
+            mut idle_since,
+        } = match self.parked.take() {
+            Some(session) => session,
+            // Transports such as TLS may have established the identity already.
+            None => Session {
+                identity: match self.inner.identity() {
+                    Some(identity) => identity,
+                    None => Identity::Anonymous(self.inner.peer_addr()?),
+                },
+                challenge: None,
+                frames: FrameBuffer::sized(codec::Kind::Request, max_payload, read_buffer),
+                idle_since: None,
+            },
+        };
+        let _serving = crash::serve(&identity);
+        // The first request also waited for the connection to be picked up.
+        let mut waited = self.accepted.elapsed();
         while self.inner.peer_addr().is_ok() {
-            let buffer = Tcp::read(&self.inner)?;
-            if buffer.is_empty() {
+            let frame = match Tcp::read_frame(&mut *self.inner, &mut frames) {
+                Ok(Some(_)) => frames.split(),
+                Err(e) => {
+                    let oversized = e.get_ref().and_then(|e| e.downcast_ref::<codec::Error>());
+                    if let Some(codec::Error::Oversized(len)) = oversized {
+                        // The payload is never read, so the stream cannot be resynchronized
+                        // with the next frame, and the connection is closed.
+                        warn!(
+                            "{} sent a request of {} bytes, above the limit of {}",
+                            identity, len, max_payload
+                        );
+                        let buffer = codec::encode_response(codec::TOO_LARGE, &[]);
+                        Tcp::write(&mut *self.inner, &buffer.map_err(into_io)?)?;
+                        break;
+                    }
+
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) {
+                        if shutdown.is_stopping() {
+                            info!(
+                                "Closing the connection of {}, since the node is stopping",
+                                identity
+                            );
+                            break;
+                        }
+
+                        // The read waited for a whole poll before the connection was
+                        // considered idle.
+                        let idle =
+                            shutdown::POLL + idle_since.get_or_insert_with(Instant::now).elapsed();
+                        if !idle_timeout.is_zero() && idle >= idle_timeout {
+                            info!(
+                                "Closing the connection of {}, which was idle for {}s",
+                                identity,
+                                idle.as_secs()
+                            );
+                            break;
+                        }
+
+                        // Connections are only parked between requests, since
+                        // the rest of a request may already be buffered.
+                        if park && frames.is_empty() {
+                            // Parked connections hold no buffer until they
+                            // become readable again.
+                            frames.release();
+                            self.parked = Some(Session {
+                                identity,
+                                challenge,
+                                frames,
+                                idle_since,
+                            });
+                            return Ok(Outcome::Parked);
+                        }
+
+                        continue;
+                    }
+
+                    return Err(e);
+                }
+                Ok(None) => {
+                    // The client has shut down its side of the connection. Since frames
+                    // are only read once the previous ones have been replied to, every
+                    // complete request received before the shutdown is handled by now.
+                    if !frames.is_empty() {
+                        warn!(
+                            "{} closed the connection in the middle of a request",
+                            identity
+                        );
+                    }
+
+                    if close == Close::Graceful {
+                        self.inner.shutdown_write()?;
+                    }
+
+                    break;
+                }
+            };
+
+            // The frame was read whole, so decoding it cannot fail.
+            let (request, _) = codec::decode_request(&frame).map_err(into_io)?;
+            idle_since = None;
+            let access = access_log.then(|| {
+                let addr = self.inner.peer_addr().ok();
+                access::Entry::start(addr, request.code, request.payload.len())
+            });
+            crash::track(&identity, Some(request.code), None);
+            timing::received(std::mem::take(&mut waited));
+            if shutdown.is_stopping() {
+                info!(
+                    "Closing the connection of {}, since the node is stopping",
+                    identity
+                );
+                let buffer = codec::encode_response(shutdown::SHUTTING_DOWN, &[]);
+                self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
+                if close == Close::Graceful {
+                    self.inner.shutdown_write()?;
+                }
+
+                break;
+            }
+
+            if request.code == health::PING {
+                let buffer = codec::encode_response(0, &health::pong());
+                self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
                 continue;
             }
 
-            // Separating request code (ID) and payload into a separate variable and buffer.
-            let (code, buffer) = (&buffer[0], &buffer[1..]);
-            let packet = Packet {
-                buffer,
-                code: *code,
-                storage: &mut redis,
-                node: Arc::clone(&node),
-                stream: self.inner.try_clone()?,
+            if request.code == capabilities::HELLO {
+                let capabilities = Capabilities::of(&node.read().unwrap().settings);
+                match Capabilities::decode(request.payload) {
+                    Some(theirs) => debug!("{} has capabilities {}", identity, theirs),
+                    None => debug!("{} sent malformed capabilities", identity),
+                }
+
+                let buffer = codec::encode_response(0, &capabilities.encode());
+                self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
+                continue;
+            }
+
+            if request.code == auth::AUTHENTICATE {
+                let buffer = match auth::authenticate(&auth, request.payload) {
+                    Some(authenticated) => {
+                        info!("{} authenticated as {}", identity, authenticated);
+                        identity = authenticated;
+                        codec::encode_response(0, identity.key().as_bytes())
+                    }
+
+                    None => {
+                        warn!("{} failed to authenticate", identity);
+                        codec::encode_response(auth::UNAUTHORIZED, &[])
+                    }
+                };
+
+                self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
+                continue;
+            }
+
+            if request.code == auth::HANDSHAKE {
+                // Checking the proof takes verifying a signature, which connections
+                // that can be parked leave to another worker in the meantime.
+                if park && challenge.is_some() {
+                    self.proof = Some(Proof {
+                        payload: request.payload.to_vec(),
+                        access,
+                    });
+                    self.parked = Some(Session {
+                        identity,
+                        challenge,
+                        frames,
+                        idle_since,
+                    });
+                    return Ok(Outcome::Proving);
+                }
+
+                identity = self.handshake(
+                    &node,
+                    &storage,
+                    &mut *connection,
+                    &access,
+                    identity,
+                    &mut challenge,
+                    request.payload,
+                )?;
+                continue;
+            }
+
+            if request.code == events::SUBSCRIBE {
+                let permitted =
+                    roles::permitted(&node.read().unwrap().settings, &identity, Role::Read);
+                if !auth::authorized(&auth, &identity, request.code) || !permitted {
+                    warn!("{} is not allowed to subscribe", identity);
+                    let buffer = codec::encode_response(auth::UNAUTHORIZED, &[]);
+                    self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
+                    continue;
+                }
+
+                match events::Filter::parse(request.payload) {
+                    Ok(filter) => {
+                        info!("{} subscribed to {:?}", identity, filter);
+                        let subscription = events.subscribe(filter);
+                        let buffer = codec::encode_response(0, &[]);
+                        self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
+                        return self
+                            .push(subscription, &identity, &shutdown)
+                            .map(|()| Outcome::Closed);
+                    }
+
+                    Err(e) => {
+                        warn!("{} sent a malformed subscription: {}", identity, e);
+                        let buffer = codec::encode_response(1, &[]);
+                        self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
+                        continue;
+                    }
+                }
+            }
+
+            if request.code == multiplex::MULTIPLEX {
+                if !auth::authorized(&auth, &identity, request.code) {
+                    warn!("{} is not allowed to multiplex", identity);
+                    let buffer = codec::encode_response(auth::UNAUTHORIZED, &[]);
+                    self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
+                    continue;
+                }
+
+                let max_in_flight = node.read().unwrap().settings.max_in_flight;
+                let writer = match self.inner.try_clone() {
+                    Ok(writer) if max_in_flight > 0 => writer,
+                    Ok(_) => {
+                        warn!("{} cannot multiplex, since it is disabled", identity);
+                        let buffer = codec::encode_response(1, &[]);
+                        self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
+                        continue;
+                    }
+
+                    Err(e) => {
+                        warn!("{} cannot multiplex over its transport: {}", identity, e);
+                        let buffer = codec::encode_response(1, &[]);
+                        self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
+                        continue;
+                    }
+                };
+
+                info!("{} multiplexes up to {} requests", identity, max_in_flight);
+                let limit = (max_in_flight.min(u32::MAX as usize) as u32).to_be_bytes();
+                let buffer = codec::encode_response(0, &limit);
+                self.respond(&access, &identity, &buffer.map_err(into_io)?)?;
+                return self
+                    .multiplex(
+                        writer,
+                        frames,
+                        max_in_flight,
+                        &identity,
+                        &node,
+                        &storage,
+                        &outbound,
+                        &events,
+                    )
+                    .map(|()| Outcome::Closed);
+            }
+
+            if request.code == streaming::AGGREGATE {
+                self.stream(
+                    request,
+                    &frame,
+                    &auth,
+                    &identity,
+                    &node,
+                    &storage,
+                    &mut connection,
+                    &outbound,
+                    &events,
+                )?;
+                if let Some(entry) = &access {
+                    entry.finish(&identity, Some(0));
+                }
+                continue;
+            }
+
+            let buffer = match request.code {
+                codec::BATCH => match codec::decode_batch(request.payload) {
+                    Ok(requests) => {
+                        let mut responses = vec![];
+                        for request in requests {
+                            // Requests which change the state of the connection, and nested
+                            // batches, are only accepted on their own.
+                            if [
+                                auth::AUTHENTICATE,
+                                auth::HANDSHAKE,
+                                capabilities::HELLO,
+                                codec::BATCH,
+                                events::SUBSCRIBE,
+                                health::PING,
+                                multiplex::MULTIPLEX,
+                                streaming::AGGREGATE,
+                            ]
+                            .contains(&request.code)
+                            {
+                                responses.extend(
+                                    codec::encode_response(1, &request.code.to_be_bytes())
+                                        .map_err(into_io)?,
+                                );
+                                continue;
+                            }
+
+                            responses.extend(dispatch(
+                                request,
+                                &frame,
+                                &auth,
+                                &identity,
+                                &node,
+                                &storage,
+                                &mut connection,
+                                &outbound,
+                                &events,
+                            )?);
+                        }
+
+                        codec::encode_response(0, &responses).map_err(into_io)?
+                    }
+
+                    Err(e) => {
+                        warn!("{} sent a malformed batch: {}", identity, e);
+                        codec::encode_response(1, &[]).map_err(into_io)?
+                    }
+                },
+
+                _ => dispatch(
+                    request,
+                    &frame,
+                    &auth,
+                    &identity,
+                    &node,
+                    &storage,
+                    &mut connection,
+                    &outbound,
+                    &events,
+                )?,
             };
 
-            match api::HANDLER_LOOKUP_TABLE.get(code) {
-                Some(handle) => {
-                    // Although this operation is safe, it still is a good practice to handle
-                    // the error if I somehow managed to not include the code in the lookup
-                    // table.
-                    let codes = api::CODE_LOOKUP_TABLE.get(code).unwrap();
-                    match handle(packet) {
-                        Ok(reply) => {
-                            let mut buffer = vec![codes.0];
-                            buffer.extend(&reply);
-                            Tcp::write(&self.inner, &buffer)?;
+            self.respond(&access, &identity, &buffer)?;
+            buffers::give(buffer);
+        }
+
+        Ok(Outcome::Closed)
+    }
+
+    /// Checks the handshake proof which [Handler::tcp] returned
+    /// [Outcome::Proving] for, and answers it. Calling [Handler::tcp] once more
+    /// resumes serving the connection with the identity it has proven.
+    pub(crate) fn prove(&mut self, node: &RwLock<Node>, storage: &Storage) -> io::Result<()> {
+        let (Some(mut session), Some(proof)) = (self.parked.take(), self.proof.take()) else {
+            return Ok(());
+        };
+
+        let mut connection = storage.connection().map_err(into_io)?;
+        session.identity = self.handshake(
+            node,
+            storage,
+            &mut *connection,
+            &proof.access,
+            session.identity,
+            &mut session.challenge,
+            &proof.payload,
+        )?;
+        self.parked = Some(session);
+        Ok(())
+    }
+
+    /// Advances the handshake of the connection by one frame, and answers it.
+    ///
+    /// # Returns
+    ///
+    /// The identity requests on the connection are attributed to from now on.
+    #[allow(clippy::too_many_arguments)]
+    fn handshake(
+        &mut self,
+        node: &RwLock<Node>,
+        storage: &Storage,
+        connection: &mut dyn storage::Connection,
+        access: &Option<access::Entry>,
+        mut identity: Identity,
+        challenge: &mut Option<Challenge>,
+        payload: &[u8],
+    ) -> io::Result<Identity> {
+        let step = auth::handshake(
+            &node.read().unwrap().settings,
+            challenge,
+            payload,
+            |public| match connection.handle(storage.keyspace(), public) {
+                Ok(handle) => handle,
+                Err(e) => {
+                    error!("Could not look up the user with key {}: {}", public, e);
+                    None
+                }
+            },
+        );
+        let buffer = match step {
+            Some(auth::Step::Challenge(reply)) => codec::encode_response(0, &reply),
+            Some(auth::Step::Proven(proven)) => {
+                info!("{} proved to be {}", identity, proven);
+                identity = proven;
+                codec::encode_response(0, identity.key().as_bytes())
+            }
+
+            None => {
+                warn!("{} failed the handshake", identity);
+                codec::encode_response(auth::UNAUTHORIZED, &[])
+            }
+        };
+
+        self.respond(access, &identity, &buffer.map_err(into_io)?)?;
+        Ok(identity)
+    }
+
+    /// Writes the response to a request, and records the request in the access
+    /// log if it is kept. See [access].
+    fn respond(
+        &mut self,
+        access: &Option<access::Entry>,
+        identity: &Identity,
+        buffer: &[u8],
+    ) -> io::Result<()> {
+        Tcp::write(&mut *self.inner, buffer)?;
+        if let Some(entry) = access {
+            entry.finish(identity, buffer.first().copied());
+        }
+
+        Ok(())
+    }
+
+    /// Handles the requests of a multiplexed connection, up to `max_in_flight`
+    /// of them at once, each on a worker with its own storage connection. The
+    /// requests are read on the current thread, which stops reading once every
+    /// worker is busy, and the responses are written through `writer` as soon
+    /// as they are done, prefixed with the correlation ID of their request.
+    /// Once the client shuts down its side of the connection, or the node is
+    /// stopping, the requests in flight are finished before the connection is
+    /// closed.
+    #[allow(clippy::too_many_arguments)]
+    fn multiplex(
+        &mut self,
+        writer: Box<dyn Transport>,
+        mut frames: FrameBuffer,
+        max_in_flight: usize,
+        identity: &Identity,
+        node: &Arc<RwLock<Node>>,
+        storage: &Arc<Storage>,
+        outbound: &Arc<outbound::Pool>,
+        events: &Arc<events::Bus>,
+    ) -> io::Result<()> {
+        let (auth, capabilities, close, idle_timeout, shutdown) = {
+            let node = node.read().unwrap();
+            let settings = &node.settings;
+            (
+                settings.auth.clone(),
+                Capabilities::of(settings),
+                settings.close,
+                Duration::from_secs(settings.idle_timeout),
+                node.shutdown(),
+            )
+        };
+
+        let writer = Mutex::new(writer);
+        let respond = |id: u32, status: u8, payload: &[u8]| -> io::Result<()> {
+            let buffer = multiplex::encode_response(id, status, payload).map_err(into_io)?;
+            Tcp::write(&mut **writer.lock().unwrap(), &buffer)
+        };
+
+        // Requests are handed over to the workers without being queued, so
+        // that no more than `max_in_flight` of them are received at once.
+        let (tx, rx) = std::sync::mpsc::sync_channel::<(u32, u16, Bytes, Instant)>(0);
+        let (rx, in_flight) = (Mutex::new(rx), AtomicUsize::new(0));
+        let closed = std::thread::scope(|scope| {
+            // The workers stop once the sender is dropped along with this closure,
+            // before the scope waits for them.
+            let tx = tx;
+            for _ in 0..max_in_flight {
+                scope.spawn(|| {
+                    let _serving = crash::serve(identity);
+                    let mut connection = None;
+                    loop {
+                        let job = rx.lock().unwrap().recv();
+                        let (id, code, payload, received) = match job {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        };
+
+                        timing::received(received.elapsed());
+                        let request = codec::Request {
+                            code,
+                            payload: &payload,
+                        };
+                        if connection.is_none() {
+                            connection = match storage.connection() {
+                                Ok(connection) => Some(connection),
+                                Err(e) => {
+                                    error!("Could not connect to the storage: {}", e);
+                                    None
+                                }
+                            };
                         }
 
-                        Err(e) => {
-                            // TODO: Implement sending the error as a string with the reply in
-                            // some way.
-                            error!("{:?}", e);
+                        let response = match &mut connection {
+                            Some(connection) => dispatch(
+                                request, &payload, &auth, identity, node, storage, connection,
+                                outbound, events,
+                            ),
+                            None => Err(io::Error::new(
+                                io::ErrorKind::NotConnected,
+                                "No connection to the storage",
+                            )),
+                        };
+
+                        let written = match response {
+                            Ok(response) => match codec::decode_response(&response) {
+                                Ok((response, _)) => respond(id, response.status, response.payload),
+                                Err(e) => Err(into_io(e)),
+                            },
+                            Err(e) => {
+                                // The storage connection is opened again for the next
+                                // request, in case it is the one which failed.
+                                error!("Request {} of {} failed: {}", id, identity, e);
+                                connection = None;
+                                respond(id, 1, &[])
+                            }
+                        };
 
-                            let mut buffer = vec![codes.1];
-                            buffer.push(codes.1);
-                            buffer.push(00);
-                            Tcp::write(&self.inner, &buffer)?;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        if let Err(e) = written {
+                            // The reader notices the connection going away as well.
+                            warn!("Could not respond to request {} of {}: {}", id, identity, e);
                         }
-                    };
+                    }
+                });
+            }
+
+            let mut idle_since = None;
+            loop {
+                let frame = match Tcp::read_frame(&mut *self.inner, &mut frames) {
+                    Ok(Some(_)) => frames.split(),
+                    Ok(None) => {
+                        if !frames.is_empty() {
+                            warn!(
+                                "{} closed the connection in the middle of a request",
+                                identity
+                            );
+                        }
+
+                        return Ok(true);
+                    }
+
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        if shutdown.is_stopping() {
+                            info!(
+                                "Closing the connection of {}, since the node is stopping",
+                                identity
+                            );
+                            return Ok(false);
+                        }
+
+                        // Connections are only idle while none of their requests
+                        // are being handled.
+                        if in_flight.load(Ordering::SeqCst) > 0 {
+                            idle_since = None;
+                            continue;
+                        }
+
+                        let idle =
+                            shutdown::POLL + idle_since.get_or_insert_with(Instant::now).elapsed();
+                        if !idle_timeout.is_zero() && idle >= idle_timeout {
+                            info!(
+                                "Closing the connection of {}, which was idle for {}s",
+                                identity,
+                                idle.as_secs()
+                            );
+                            return Ok(false);
+                        }
+
+                        continue;
+                    }
+
+                    Err(e) => {
+                        let oversized = e.get_ref().and_then(|e| e.downcast_ref::<codec::Error>());
+                        if let Some(codec::Error::Oversized(len)) = oversized {
+                            // The correlation ID is never read, so the response cannot
+                            // be attributed to the request, and the connection is closed.
+                            warn!(
+                                "{} sent a request of {} bytes, above the limit",
+                                identity, len
+                            );
+                            let buffer = codec::encode_response(codec::TOO_LARGE, &[]);
+                            Tcp::write(&mut **writer.lock().unwrap(), &buffer.map_err(into_io)?)?;
+                            return Ok(false);
+                        }
+
+                        return Err(e);
+                    }
+                };
+
+                // The frame was read whole, so decoding it cannot fail.
+                let (request, _) = codec::decode_request(&frame).map_err(into_io)?;
+                let (id, payload) = match multiplex::decode(request.payload) {
+                    Some(decoded) => decoded,
+                    None => {
+                        warn!("{} sent a request without a correlation ID", identity);
+                        return Ok(false);
+                    }
+                };
+
+                idle_since = None;
+                if shutdown.is_stopping() {
+                    info!(
+                        "Closing the connection of {}, since the node is stopping",
+                        identity
+                    );
+                    respond(id, shutdown::SHUTTING_DOWN, &[])?;
+                    return Ok(true);
                 }
 
-                None => api::unknown_command(packet)?,
+                match request.code {
+                    health::PING => respond(id, 0, &health::pong())?,
+                    capabilities::HELLO => respond(id, 0, &capabilities.encode())?,
+                    // Requests which change the state of the connection, and batches,
+                    // are only accepted before the connection is multiplexed.
+                    auth::AUTHENTICATE
+                    | auth::HANDSHAKE
+                    | codec::BATCH
+                    | events::SUBSCRIBE
+                    | multiplex::MULTIPLEX
+                    | streaming::AGGREGATE => respond(id, 1, &request.code.to_be_bytes())?,
+                    code => {
+                        in_flight.fetch_add(1, Ordering::SeqCst);
+                        let job = (id, code, frame.slice_ref(payload), Instant::now());
+                        if tx.send(job).is_err() {
+                            return Err(io::Error::other("The workers of the connection are gone"));
+                        }
+                    }
+                }
             }
+        });
+
+        // The workers are done by now, so that every response has been written.
+        if closed? && close == Close::Graceful {
+            self.inner.shutdown_write()?;
         }
 
         Ok(())
     }
+
+    /// Aggregates the targets of a [streaming::AGGREGATE] request, writing the
+    /// entry of every record in a frame of its own as soon as it is read.
+    ///
+    /// The stream ends with an empty frame once every record has been written,
+    /// or with the footer of the page of a scan. A request which fails is
+    /// answered with a frame carrying its status instead, which also ends the
+    /// stream.
+    #[allow(clippy::too_many_arguments)]
+    fn stream(
+        &mut self,
+        request: codec::Request,
+        frame: &Bytes,
+        auth: &Auth,
+        identity: &Identity,
+        node: &Arc<RwLock<Node>>,
+        storage: &Storage,
+        connection: &mut Box<dyn storage::Connection>,
+        outbound: &Arc<outbound::Pool>,
+        events: &events::Bus,
+    ) -> io::Result<()> {
+        let permitted = roles::permitted(&node.read().unwrap().settings, identity, Role::Read);
+        if !auth::authorized(auth, identity, request.code) || !permitted {
+            warn!("{} is not allowed to stream", identity);
+            let buffer = codec::encode_response(auth::UNAUTHORIZED, &[]);
+            return Tcp::write(&mut *self.inner, &buffer.map_err(into_io)?);
+        }
+
+        let mut timed = timing::Timed(&mut **connection);
+        let packet = Packet {
+            code: request.code,
+            buffer: frame.slice_ref(request.payload),
+            storage: &mut timed,
+            keyspace: storage.keyspace(),
+            identity,
+            outbound,
+            events,
+            node: Arc::clone(node),
+        };
+
+        let (inner, mut written) = (&mut self.inner, 0);
+        let result = api::aggregate_each(packet, &mut |entries| {
+            for entry in api::internal::buf_extract_targets(entries) {
+                let buffer = codec::encode_response(0, &entry)
+                    .map_err(into_io)
+                    .map_err(api::Error::Streaming)?;
+                Tcp::write(&mut **inner, &buffer).map_err(api::Error::Streaming)?;
+                written += buffer.len();
+            }
+
+            Ok(())
+        });
+
+        crash::handled(result.is_err());
+        let (status, footer) = match result {
+            Ok(footer) => (0, footer.unwrap_or_default()),
+            // The client went away in the middle of the stream.
+            Err(api::Error::Streaming(e)) => return Err(e),
+            Err(e) => {
+                error!("Streaming to {} failed: {}", identity, e);
+                if let api::Error::Storage(e) = &e {
+                    storage.recover(connection, e).map_err(into_io)?;
+                }
+
+                match e {
+                    api::Error::Forwarded(status) => (status, vec![]),
+                    _ => (1, vec![]),
+                }
+            }
+        };
+
+        let buffer = codec::encode_response(status, &footer).map_err(into_io)?;
+        metrics::record(
+            identity,
+            request.payload.len(),
+            written + buffer.len(),
+            status != 0,
+        );
+        Tcp::write(&mut *self.inner, &buffer)
+    }
+
+    /// Pushes the events of a subscription over the connection, along with
+    /// keepalives while there are none, until the connection goes away or the
+    /// node stops.
+    fn push(
+        &mut self,
+        events: Receiver<events::Event>,
+        identity: &Identity,
+        shutdown: &Shutdown,
+    ) -> io::Result<()> {
+        let mut pushed = Instant::now();
+        loop {
+            let payload = match events.recv_timeout(shutdown::POLL) {
+                Ok(event) => serde_json::to_vec(&event).map_err(into_io)?,
+                Err(RecvTimeoutError::Timeout) if shutdown.is_stopping() => {
+                    info!(
+                        "Closing the subscription of {}, since the node is stopping",
+                        identity
+                    );
+                    return Ok(());
+                }
+                Err(RecvTimeoutError::Timeout) if pushed.elapsed() >= events::KEEPALIVE => vec![],
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+
+            pushed = Instant::now();
+
+            let buffer = codec::encode_response(0, &payload).map_err(into_io)?;
+            match Tcp::write(&mut *self.inner, &buffer) {
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+                    ) =>
+                {
+                    info!("{} unsubscribed", identity);
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Executes a single request which does not change the state of the
+/// connection, either directly or through the journal if it is a
+/// [journal::JOURNALED] request, and encodes its response.
+#[allow(clippy::too_many_arguments)]
+fn dispatch(
+    request: codec::Request,
+    frame: &Bytes,
+    auth: &Auth,
+    identity: &Identity,
+    node: &Arc<RwLock<Node>>,
+    storage: &Storage,
+    connection: &mut Box<dyn storage::Connection>,
+    outbound: &Arc<outbound::Pool>,
+    events: &events::Bus,
+) -> io::Result<Vec<u8>> {
+    crash::track(identity, Some(request.code), None);
+    if request.code == deadline::DEADLINE {
+        let (budget, inner) = match deadline::decode(request.payload) {
+            Some(decoded) => decoded,
+            None => {
+                warn!("{} sent a malformed request with a deadline", identity);
+                return codec::encode_response(1, &[]).map_err(into_io);
+            }
+        };
+
+        // Only requests which can be executed on their own have a deadline.
+        // Requests wrapping another request are rejected as well, so that
+        // wrappers are never nested in each other.
+        if [
+            auth::AUTHENTICATE,
+            auth::HANDSHAKE,
+            codec::BATCH,
+            events::SUBSCRIBE,
+            deadline::DEADLINE,
+            journal::JOURNALED,
+            timing::TIMED,
+            multiplex::MULTIPLEX,
+            streaming::AGGREGATE,
+        ]
+        .contains(&inner.code)
+        {
+            return codec::encode_response(1, &inner.code.to_be_bytes()).map_err(into_io);
+        }
+
+        // The budget includes the time the request waited to be handled.
+        let guard = deadline::start(Instant::now() + budget.saturating_sub(timing::waited()));
+        let mut response = dispatch(
+            inner, frame, auth, identity, node, storage, connection, outbound, events,
+        )?;
+        response.push(match guard.expired() {
+            true => deadline::EXPIRED,
+            false => 0,
+        });
+        return codec::encode_response(0, &response).map_err(into_io);
+    }
+
+    if request.code == timing::TIMED {
+        let inner = match codec::decode_request(request.payload) {
+            Ok((inner, len)) if len == request.payload.len() => inner,
+            _ => {
+                warn!("{} sent a malformed timed request", identity);
+                return codec::encode_response(1, &[]).map_err(into_io);
+            }
+        };
+
+        // Only requests which can be executed on their own are timed.
+        if [
+            auth::AUTHENTICATE,
+            auth::HANDSHAKE,
+            codec::BATCH,
+            events::SUBSCRIBE,
+            deadline::DEADLINE,
+            journal::JOURNALED,
+            timing::TIMED,
+            multiplex::MULTIPLEX,
+            streaming::AGGREGATE,
+        ]
+        .contains(&inner.code)
+        {
+            return codec::encode_response(1, &inner.code.to_be_bytes()).map_err(into_io);
+        }
+
+        let (queue, started) = (timing::start(), Instant::now());
+        let mut response = dispatch(
+            inner, frame, auth, identity, node, storage, connection, outbound, events,
+        )?;
+        let trailer = timing::finish(started, queue);
+        response.extend(serde_json::to_vec(&trailer).map_err(into_io)?);
+        return codec::encode_response(0, &response).map_err(into_io);
+    }
+
+    if request.code != journal::JOURNALED {
+        return execute(
+            request, frame, auth, identity, node, storage, connection, outbound, events,
+        );
+    }
+
+    let (id, request) = match journal::decode(request.payload) {
+        Some(decoded) => decoded,
+        None => {
+            warn!("{} sent a malformed journaled request", identity);
+            return codec::encode_response(1, &[]).map_err(into_io);
+        }
+    };
+
+    crash::track(identity, Some(request.code), Some(id));
+    // Only requests which can be executed on their own are journaled.
+    if [
+        auth::AUTHENTICATE,
+        auth::HANDSHAKE,
+        codec::BATCH,
+        journal::JOURNALED,
+        events::SUBSCRIBE,
+        deadline::DEADLINE,
+        timing::TIMED,
+        multiplex::MULTIPLEX,
+        streaming::AGGREGATE,
+    ]
+    .contains(&request.code)
+    {
+        return codec::encode_response(1, &request.code.to_be_bytes()).map_err(into_io);
+    }
+
+    let settings = node.read().unwrap().settings.journal;
+    let (client, keyspace) = (journal::client(identity), storage.keyspace());
+    let claim = connection.claim(
+        keyspace,
+        &client,
+        id,
+        std::time::Duration::from_secs(settings.claim),
+    );
+    match claim {
+        Ok(Claim::Acquired) => {
+            let response = execute(
+                request, frame, auth, identity, node, storage, connection, outbound, events,
+            )?;
+            // Failed requests are released, so that the client can retry them.
+            let journaled = match response.first() {
+                Some(0) => connection.complete(
+                    keyspace,
+                    &client,
+                    id,
+                    &response,
+                    std::time::Duration::from_secs(settings.retention),
+                ),
+                _ => connection.release(keyspace, &client, id),
+            };
+
+            // The request has been executed either way, so its response is sent.
+            // Until the claim expires, deliveries of the request are reported as
+            // in progress.
+            if let Err(e) = journaled {
+                error!("Could not journal request {} of {}: {}", id, identity, e);
+                storage.recover(connection, &e).map_err(into_io)?;
+            }
+
+            Ok(response)
+        }
+
+        Ok(Claim::Pending) => codec::encode_response(journal::IN_PROGRESS, &[]).map_err(into_io),
+        Ok(Claim::Done(response)) => {
+            debug!("Replaying request {} of {}", id, identity);
+            Ok(response)
+        }
+
+        Err(e) => {
+            error!("Could not claim request {} of {}: {}", id, identity, e);
+            storage.recover(connection, &e).map_err(into_io)?;
+            codec::encode_response(1, &[]).map_err(into_io)
+        }
+    }
+}
+
+/// Executes a single request which does not change the state of the
+/// connection, and encodes its response.
+#[allow(clippy::too_many_arguments)]
+fn execute(
+    request: codec::Request,
+    frame: &Bytes,
+    auth: &Auth,
+    identity: &Identity,
+    node: &Arc<RwLock<Node>>,
+    storage: &Storage,
+    connection: &mut Box<dyn storage::Connection>,
+    outbound: &Arc<outbound::Pool>,
+    events: &events::Bus,
+) -> io::Result<Vec<u8>> {
+    if !auth::authorized(auth, identity, request.code) {
+        warn!("{} is not allowed to issue {:#06x}", identity, request.code);
+        return codec::encode_response(auth::UNAUTHORIZED, &[]).map_err(into_io);
+    }
+
+    let code = &request.code;
+    let mut timed = timing::Timed(&mut **connection);
+    let packet = Packet {
+        code: *code,
+        buffer: frame.slice_ref(request.payload),
+        storage: &mut timed,
+        keyspace: storage.keyspace(),
+        identity,
+        outbound,
+        events,
+        node: Arc::clone(node),
+    };
+
+    match api::HANDLER_LOOKUP_TABLE.get(code) {
+        Some(route) => {
+            if !roles::permitted(&node.read().unwrap().settings, identity, route.role) {
+                warn!(
+                    "{} lacks the {} role to issue {:#06x}",
+                    identity, route.role, code
+                );
+                return codec::encode_response(auth::UNAUTHORIZED, &[]).map_err(into_io);
+            }
+
+            let codes = route.codes;
+            let result = (route.handler)(packet);
+            let failed = result.is_err();
+            crash::handled(failed);
+            let response = match result {
+                Ok(reply) => codec::encode_response(codes.0, &reply).map_err(into_io)?,
+                Err(e) => {
+                    // TODO: Implement sending the error as a string with the reply in
+                    // some way.
+                    error!("Request {:#06x} from {} failed: {}", code, identity, e);
+                    if let api::Error::Storage(e) = &e {
+                        storage.recover(connection, e).map_err(into_io)?;
+                    }
+
+                    // Forwarded requests fail with the status of the node they
+                    // were forwarded to.
+                    let status = match e {
+                        api::Error::Forwarded(status) => status,
+                        _ => codes.1,
+                    };
+
+                    codec::encode_response(status, &[]).map_err(into_io)?
+                }
+            };
+
+            metrics::record(identity, request.payload.len(), response.len(), failed);
+            Ok(response)
+        }
+
+        None => api::unknown_command(packet).map_err(into_io),
+    }
+}
+
+#[inline(always)]
+fn into_io<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::other(format!("{:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdk;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_pipelined() {
+        let node = Node::new(Settings::new("memory://".into()).unwrap());
+        let (shutdown, local) = (node.shutdown(), node.local());
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Requests written back to back are handled one after another, and
+        // answered in the order they were written in.
+        let mut client = local.client().unwrap();
+        let requests = [
+            (0x0001, &b"first"[..]),
+            (health::PING, &[][..]),
+            (0x0001, &b"second"[..]),
+            (codec::BATCH, &b"\xff"[..]),
+        ];
+        let responses = client.pipeline(&requests).unwrap();
+        let statuses: Vec<_> = responses.iter().map(|(status, _)| *status).collect();
+        assert_eq!(statuses, vec![0, 0, 0, 1]);
+        assert!(responses[1].1.starts_with(health::PONG));
+        assert_ne!(responses[0].1, responses[2].1);
+
+        // The connection is still in sync with the frames after the pipeline.
+        client.ping().unwrap();
+        shutdown.stop();
+    }
+
+    #[test]
+    fn test_roles() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.perms.anonymous = vec![Role::Read];
+        let node = Node::new(settings);
+        let (shutdown, local) = (node.shutdown(), node.local());
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        assert!(matches!(
+            client.create(b"value"),
+            Err(sdk::Error::Status(auth::UNAUTHORIZED))
+        ));
+        assert!(client.aggregate(&ulid::Ulid::new().to_string()).is_ok());
+        shutdown.stop();
+    }
 }
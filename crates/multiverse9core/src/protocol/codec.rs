@@ -0,0 +1,514 @@
+//! Encoding and decoding of the frames exchanged between nodes. The functions
+//! in this module operate on byte slices only, so they can be shared by any
+//! transport which is able to carry bytes.
+//!
+//! Requests start with the code which selects their handler, and responses
+//! with their status:
+//!
+//! ```text
+//! +-------------+----------------------+-------------------+
+//! | code (u16)  | payload length (u32) | payload (n bytes) |
+//! +-------------+----------------------+-------------------+
+//!
+//! +-------------+----------------------+-------------------+
+//! | status (u8) | payload length (u32) | payload (n bytes) |
+//! +-------------+----------------------+-------------------+
+//! ```
+//!
+//! The code and the payload length are encoded in big-endian byte order. Codes
+//! are split into ranges, of which [CORE] is used by the requests of clients,
+//! [FEDERATION] by the requests nodes issue to each other, and [EXTENSION] by
+//! requests which are not part of the protocol. Codes below `0x0100` predate
+//! the ranges and keep their values, whichever of them they serve.
+//!
+//! A [BATCH] request carries a sequence of request frames as its payload. Its
+//! response carries the sequence of their response frames, in the same order.
+
+/// Length of the header which precedes the payload of every request frame.
+pub const REQUEST_HEADER_LEN: usize = 6;
+
+/// Length of the header which precedes the payload of every response frame.
+pub const RESPONSE_HEADER_LEN: usize = 5;
+
+/// Codes of the requests clients issue, such as creating and aggregating
+/// records, and of the requests which change the state of a connection.
+pub const CORE: std::ops::RangeInclusive<u16> = 0x0000..=0x0FFF;
+
+/// Codes of the requests nodes issue to each other, such as replication.
+pub const FEDERATION: std::ops::RangeInclusive<u16> = 0x1000..=0x7FFF;
+
+/// Codes which are never assigned by the protocol, left for extensions built
+/// on top of it. Nodes without an extension answer them like any unknown
+/// request.
+pub const EXTENSION: std::ops::RangeInclusive<u16> = 0x8000..=0xFFFF;
+
+/// The largest payload a frame may carry. Frames announcing a larger payload
+/// are rejected before any of it is read, so that a peer cannot make a node
+/// allocate arbitrary amounts of memory.
+pub const MAX_PAYLOAD_LEN: usize = 16 * 1024 * 1024;
+
+/// Response status sent when a request is larger than the node accepts, after
+/// which the node closes the connection. See
+/// [Settings::max_payload_bytes](crate::settings::Settings::max_payload_bytes).
+pub const TOO_LARGE: u8 = 0x0004;
+
+/// Request code of a batch. Batches reduce round trips for clients which
+/// always issue certain requests together.
+pub const BATCH: u16 = 0x0009;
+
+/// A decoded request frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request<'a> {
+    /// The request code used to lookup the appropriate handler function.
+    pub code: u16,
+    pub payload: &'a [u8],
+}
+
+/// A decoded response frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Response<'a> {
+    /// The status code of the response, `0` on success.
+    pub status: u8,
+    pub payload: &'a [u8],
+}
+
+/// Whether a frame is a request or a response, which tells the length of its
+/// header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Request,
+    Response,
+}
+
+impl Kind {
+    /// Returns the length of the header of frames of this kind.
+    #[inline(always)]
+    pub fn header_len(self) -> usize {
+        match self {
+            Self::Request => REQUEST_HEADER_LEN,
+            Self::Response => RESPONSE_HEADER_LEN,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer ends before the frame does. Contains the number of bytes
+    /// which are still missing.
+    Incomplete(usize),
+    /// The payload is larger than [MAX_PAYLOAD_LEN], or than the limit of the
+    /// node.
+    Oversized(usize),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Incomplete(missing) => write!(f, "Frame is missing {} bytes", missing),
+            Self::Oversized(len) => write!(f, "Payload of {} bytes is too large", len),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Encodes a request with the given code and payload into a frame.
+pub fn encode_request(code: u16, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    encode(&code.to_be_bytes(), payload)
+}
+
+/// Decodes the request frame at the start of the buffer.
+///
+/// # Returns
+///
+/// The decoded request and the number of bytes it occupies in the buffer.
+/// Any bytes after the frame belong to the next frame.
+///
+/// # Errors
+///
+/// Returns [Error::Incomplete] if the buffer does not contain a whole frame.
+pub fn decode_request(buffer: &[u8]) -> Result<(Request<'_>, usize), Error> {
+    let payload = decode(Kind::Request, buffer)?;
+    let code = u16::from_be_bytes([buffer[0], buffer[1]]);
+    Ok((
+        Request { code, payload },
+        REQUEST_HEADER_LEN + payload.len(),
+    ))
+}
+
+/// Encodes a response with the given status and payload into a frame.
+pub fn encode_response(status: u8, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    encode(&[status], payload)
+}
+
+/// Decodes the response frame at the start of the buffer. See
+/// [decode_request] for the returned values and errors.
+pub fn decode_response(buffer: &[u8]) -> Result<(Response<'_>, usize), Error> {
+    let payload = decode(Kind::Response, buffer)?;
+    Ok((
+        Response {
+            status: buffer[0],
+            payload,
+        },
+        RESPONSE_HEADER_LEN + payload.len(),
+    ))
+}
+
+/// Encodes the requests into the payload of a [BATCH] request.
+pub fn encode_batch<'a>(
+    requests: impl IntoIterator<Item = (u16, &'a [u8])>,
+) -> Result<Vec<u8>, Error> {
+    let mut buffer = vec![];
+    for (code, payload) in requests {
+        buffer.extend(encode_request(code, payload)?);
+    }
+
+    Ok(buffer)
+}
+
+/// Decodes the payload of a [BATCH] request into its requests.
+///
+/// # Errors
+///
+/// Returns [Error::Incomplete] if the payload ends in the middle of a frame.
+pub fn decode_batch(payload: &[u8]) -> Result<Vec<Request<'_>>, Error> {
+    let mut requests = vec![];
+    let mut offset = 0;
+    while offset < payload.len() {
+        let (request, consumed) = decode_request(&payload[offset..])?;
+        requests.push(request);
+        offset += consumed;
+    }
+
+    Ok(requests)
+}
+
+/// Decodes the payload of the response to a [BATCH] request into the
+/// responses to its requests. See [decode_batch] for the errors.
+pub fn decode_batch_response(payload: &[u8]) -> Result<Vec<Response<'_>>, Error> {
+    let mut responses = vec![];
+    let mut offset = 0;
+    while offset < payload.len() {
+        let (response, consumed) = decode_response(&payload[offset..])?;
+        responses.push(response);
+        offset += consumed;
+    }
+
+    Ok(responses)
+}
+
+/// Returns the total length of the frame of the given kind at the start of the
+/// buffer, as announced by its header. Only the header has to be present in the
+/// buffer.
+///
+/// # Errors
+///
+/// Returns [Error::Incomplete] if the buffer does not contain the whole header,
+/// and [Error::Oversized] if the announced payload is larger than
+/// [MAX_PAYLOAD_LEN].
+pub fn frame_len(kind: Kind, buffer: &[u8]) -> Result<usize, Error> {
+    frame_len_within(kind, buffer, MAX_PAYLOAD_LEN)
+}
+
+/// Returns the total length of the frame at the start of the buffer like
+/// [frame_len], rejecting payloads larger than `max` bytes instead.
+pub fn frame_len_within(kind: Kind, buffer: &[u8], max: usize) -> Result<usize, Error> {
+    let header_len = kind.header_len();
+    if buffer.len() < header_len {
+        return Err(Error::Incomplete(header_len - buffer.len()));
+    }
+
+    let len = &buffer[header_len - 4..header_len];
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    if len > max.min(MAX_PAYLOAD_LEN) {
+        return Err(Error::Oversized(len));
+    }
+
+    Ok(header_len + len)
+}
+
+#[inline(always)]
+fn encode(prefix: &[u8], payload: &[u8]) -> Result<Vec<u8>, Error> {
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(Error::Oversized(payload.len()));
+    }
+
+    let len = payload.len() as u32;
+    // Responses are written from buffers kept for reuse, which the handler
+    // hands back once they are written. See [crate::buffers].
+    let mut buffer = crate::buffers::take(0);
+    buffer.reserve(prefix.len() + 4 + payload.len());
+    buffer.extend_from_slice(prefix);
+    buffer.extend_from_slice(&len.to_be_bytes());
+    buffer.extend_from_slice(payload);
+    Ok(buffer)
+}
+
+/// Returns the payload of the frame of the given kind at the start of the
+/// buffer.
+#[inline(always)]
+fn decode(kind: Kind, buffer: &[u8]) -> Result<&[u8], Error> {
+    let len = frame_len(kind, buffer)?;
+    if buffer.len() < len {
+        return Err(Error::Incomplete(len - buffer.len()));
+    }
+
+    Ok(&buffer[kind.header_len()..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_roundtrip() {
+        let frame = encode_request(0x03, b"key\x00").unwrap();
+        assert_eq!(frame, b"\x00\x03\x00\x00\x00\x04key\x00");
+
+        let (request, consumed) = decode_request(&frame).unwrap();
+        assert_eq!(request.code, 0x03);
+        assert_eq!(request.payload, b"key\x00");
+        assert_eq!(consumed, frame.len());
+    }
+
+    #[test]
+    fn test_response_roundtrip() {
+        let frame = encode_response(0, b"01GQ:value").unwrap();
+        let (response, consumed) = decode_response(&frame).unwrap();
+        assert_eq!(response.status, 0);
+        assert_eq!(response.payload, b"01GQ:value");
+        assert_eq!(consumed, frame.len());
+    }
+
+    #[test]
+    fn test_empty_payload() {
+        let frame = encode_response(1, &[]).unwrap();
+        assert_eq!(frame, [1, 0, 0, 0, 0]);
+        let (response, consumed) = decode_response(&frame).unwrap();
+        assert!(response.payload.is_empty());
+        assert_eq!(consumed, RESPONSE_HEADER_LEN);
+    }
+
+    #[test]
+    fn test_large_payload() {
+        let payload = vec![0xAB; 70_000];
+        let frame = encode_request(0x01, &payload).unwrap();
+        assert_eq!(
+            frame_len(Kind::Request, &frame),
+            Ok(REQUEST_HEADER_LEN + payload.len())
+        );
+        let (request, _) = decode_request(&frame).unwrap();
+        assert_eq!(request.payload, &payload[..]);
+    }
+
+    #[test]
+    fn test_oversized_payload() {
+        let payload = vec![0; MAX_PAYLOAD_LEN + 1];
+        assert_eq!(
+            encode_request(0x01, &payload),
+            Err(Error::Oversized(payload.len()))
+        );
+
+        let header = [0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(
+            frame_len(Kind::Request, &header),
+            Err(Error::Oversized(u32::MAX as usize))
+        );
+        assert_eq!(
+            frame_len_within(Kind::Response, &[0x01, 0, 0, 0, 9], 8),
+            Err(Error::Oversized(9))
+        );
+        assert_eq!(
+            frame_len_within(Kind::Response, &[0x01, 0, 0, 0, 8], 8),
+            Ok(RESPONSE_HEADER_LEN + 8)
+        );
+        assert_eq!(
+            decode_request(&header),
+            Err(Error::Oversized(u32::MAX as usize))
+        );
+    }
+
+    #[test]
+    fn test_incomplete_header() {
+        assert_eq!(
+            decode_request(&[]),
+            Err(Error::Incomplete(REQUEST_HEADER_LEN))
+        );
+        assert_eq!(decode_request(&[0x00, 0x01]), Err(Error::Incomplete(4)));
+        assert_eq!(decode_response(&[0x01, 0x00]), Err(Error::Incomplete(3)));
+        assert_eq!(
+            frame_len(Kind::Response, &[0x01, 0, 0, 0]),
+            Err(Error::Incomplete(1))
+        );
+    }
+
+    #[test]
+    fn test_incomplete_payload() {
+        let frame = encode_request(0x01, b"payload").unwrap();
+        for cut in REQUEST_HEADER_LEN..frame.len() {
+            assert_eq!(
+                decode_request(&frame[..cut]),
+                Err(Error::Incomplete(frame.len() - cut))
+            );
+        }
+    }
+
+    #[test]
+    fn test_consecutive_frames() {
+        let mut buffer = encode_request(0x01, b"first").unwrap();
+        buffer.extend(encode_request(0x02, b"second").unwrap());
+
+        let (first, consumed) = decode_request(&buffer).unwrap();
+        assert_eq!((first.code, first.payload), (0x01, &b"first"[..]));
+
+        let (second, rest) = decode_request(&buffer[consumed..]).unwrap();
+        assert_eq!((second.code, second.payload), (0x02, &b"second"[..]));
+        assert_eq!(consumed + rest, buffer.len());
+    }
+
+    #[test]
+    fn test_batch_roundtrip() {
+        let payload = encode_batch([(0x01, &b"value"[..]), (0x03, &b"key\x00"[..])]).unwrap();
+        let requests = decode_batch(&payload).unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                Request {
+                    code: 0x01,
+                    payload: b"value"
+                },
+                Request {
+                    code: 0x03,
+                    payload: b"key\x00"
+                },
+            ]
+        );
+
+        assert_eq!(decode_batch(&[]), Ok(vec![]));
+        assert_eq!(
+            decode_batch(&payload[..payload.len() - 1]),
+            Err(Error::Incomplete(1))
+        );
+    }
+
+    #[test]
+    fn test_batch_response() {
+        let mut payload = encode_response(0, b"01GQ").unwrap();
+        payload.extend(encode_response(1, &[]).unwrap());
+        let responses = decode_batch_response(&payload).unwrap();
+        assert_eq!(
+            responses,
+            vec![
+                Response {
+                    status: 0,
+                    payload: b"01GQ"
+                },
+                Response {
+                    status: 1,
+                    payload: &[]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_every_code() {
+        for code in u16::MIN..=u16::MAX {
+            let frame = encode_request(code, &code.to_be_bytes()).unwrap();
+            let (request, _) = decode_request(&frame).unwrap();
+            assert_eq!(
+                request,
+                Request {
+                    code,
+                    payload: &code.to_be_bytes()
+                }
+            );
+        }
+
+        for status in u8::MIN..=u8::MAX {
+            let frame = encode_response(status, &[status]).unwrap();
+            let (response, _) = decode_response(&frame).unwrap();
+            assert_eq!(
+                response,
+                Response {
+                    status,
+                    payload: &[status]
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_ranges() {
+        // The ranges cover every code without overlapping.
+        assert_eq!(*CORE.start(), u16::MIN);
+        assert_eq!(*CORE.end() + 1, *FEDERATION.start());
+        assert_eq!(*FEDERATION.end() + 1, *EXTENSION.start());
+        assert_eq!(*EXTENSION.end(), u16::MAX);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_roundtrip_property(
+            code: u16,
+            status: u8,
+            payload: Vec<u8>,
+            trailing: Vec<u8>,
+        ) {
+            let mut buffer = encode_request(code, &payload).unwrap();
+            let len = buffer.len();
+            buffer.extend(&trailing);
+
+            let (request, consumed) = decode_request(&buffer).unwrap();
+            proptest::prop_assert_eq!(request, Request { code, payload: &payload });
+            proptest::prop_assert_eq!(consumed, len);
+            proptest::prop_assert_eq!(frame_len(Kind::Request, &buffer), Ok(len));
+
+            let mut buffer = encode_response(status, &payload).unwrap();
+            let len = buffer.len();
+            buffer.extend(&trailing);
+
+            let (response, consumed) = decode_response(&buffer).unwrap();
+            proptest::prop_assert_eq!(response, Response { status, payload: &payload });
+            proptest::prop_assert_eq!(consumed, len);
+            proptest::prop_assert_eq!(frame_len(Kind::Response, &buffer), Ok(len));
+        }
+
+        #[test]
+        fn test_truncated_property(code: u16, payload: Vec<u8>, cut: proptest::sample::Index) {
+            let frame = encode_request(code, &payload).unwrap();
+            let cut = cut.index(frame.len());
+            proptest::prop_assert_eq!(
+                decode_request(&frame[..cut]),
+                Err(Error::Incomplete(match cut < REQUEST_HEADER_LEN {
+                    true => REQUEST_HEADER_LEN - cut,
+                    false => frame.len() - cut,
+                }))
+            );
+        }
+
+        #[test]
+        fn test_batch_property(requests: Vec<(u16, Vec<u8>)>) {
+            let payload =
+                encode_batch(requests.iter().map(|(code, payload)| (*code, &payload[..]))).unwrap();
+            let decoded = decode_batch(&payload).unwrap();
+            let expected: Vec<_> = requests
+                .iter()
+                .map(|(code, payload)| Request { code: *code, payload })
+                .collect();
+            proptest::prop_assert_eq!(decoded, expected);
+        }
+
+        #[test]
+        fn test_arbitrary_bytes_property(buffer: Vec<u8>) {
+            // Whatever the bytes, decoding either fails or consumes a whole
+            // frame which encodes back to the same bytes.
+            if let Ok((request, consumed)) = decode_request(&buffer) {
+                let frame = encode_request(request.code, request.payload).unwrap();
+                proptest::prop_assert_eq!(&buffer[..consumed], &frame[..]);
+            }
+            let _ = decode_batch(&buffer);
+            let _ = decode_batch_response(&buffer);
+        }
+    }
+}
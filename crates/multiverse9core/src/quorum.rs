@@ -0,0 +1,337 @@
+//! Quorum reads let an aggregate request read a key from several replicas,
+//! instead of trusting whichever single node the key is addressed to. The
+//! number of replicas read is given per request with a first target such as
+//! `quorum=3`, which may come before or after the `view=` target of
+//! [crate::views].
+//!
+//! The replicas of a key are the node it is addressed to, or current node for
+//! keys without an address, followed by current node and the acknowledged
+//! nodes in the order they are acknowledged in. Replicas are read in that
+//! order until enough of them have replied, and the newest version among the
+//! replies is returned.
+//!
+//! Records never change once created under their ULID or content key, so the
+//! versions of a record only differ in whether a replica holds the record
+//! yet, in whether its copy passed verification, and in how many interactions
+//! the replica has counted. See [Version].
+//!
+//! Replicas which turn out not to hold a record the quorum read found are
+//! repaired with the newest version, the way an anti-entropy exchange would
+//! store it, so that replicas converge as keys are read. Current node is
+//! repaired before the response is sent, while the acknowledged nodes are sent
+//! a [crate::anti_entropy::REPLICATE] request in the background. Replicas
+//! which hold a different copy are never overwritten. Repairs which have not
+//! started by the time the node stops are cancelled, since later reads repair
+//! the replicas as well.
+
+use log::*;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::node::Node;
+use crate::pooling::{self, Handle, Pool};
+use crate::sdk::response::Record;
+use crate::{api, outbound};
+
+/// Prefix of the first target of an aggregate request which sets the number
+/// of replicas read, i.e. `quorum=2`.
+pub const PREFIX: &str = "quorum=";
+
+/// The most replicas read for a single key.
+pub const MAX_READS: usize = 16;
+
+/// The number of workers repairing lagging replicas.
+const WORKERS: usize = 2;
+
+/// The version of a record as it was read from a replica. Versions are
+/// ordered by whether the replica holds the record, then by whether its copy
+/// is intact, and then by the number of interactions the replica counted,
+/// which only ever grows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub held: bool,
+    pub intact: bool,
+    pub interactions: usize,
+}
+
+impl Version {
+    /// Returns the version of an aggregated entry, without its trailing null
+    /// byte, or [None] if the entry is malformed.
+    pub fn of(entry: &[u8]) -> Option<Self> {
+        let record = Record::decode(entry)?;
+        Some(Self {
+            held: !record.is_unknown(),
+            intact: record.value != api::INVALID_SIGNATURE && record.value != api::INVALID_CONTENT,
+            interactions: record.likes + record.replies,
+        })
+    }
+}
+
+/// Parses the number of replicas read out of the value of the [PREFIX]
+/// target.
+///
+/// # Returns
+///
+/// [None] unless the number is between 1 and [MAX_READS].
+pub fn parse(reads: &str) -> Option<usize> {
+    match reads.parse::<usize>() {
+        Ok(reads) if (1..=MAX_READS).contains(&reads) => Some(reads),
+        _ => None,
+    }
+}
+
+/// Returns the replicas of a key in the order they are read in, with [None]
+/// standing for current node.
+///
+/// # Arguments
+///
+/// * `addr` - The address the key is addressed to, if any.
+pub(crate) fn replicas(node: &Arc<RwLock<Node>>, addr: Option<&str>) -> Vec<Option<String>> {
+    let node = node.read().unwrap();
+    let mut replicas = vec![addr.map(String::from), None];
+    for peer in &node.settings.nodes {
+        let peer = Some(peer.addr.to_string());
+        if !replicas.contains(&peer) {
+            replicas.push(peer);
+        }
+    }
+
+    replicas.dedup();
+    replicas
+}
+
+/// Returns the newest of the entries read from the replicas of a key. Ties are
+/// resolved in favor of the replica read first.
+pub fn newest(entries: &[Vec<u8>]) -> Option<&Vec<u8>> {
+    entries
+        .iter()
+        .enumerate()
+        .max_by_key(|(i, entry)| (Version::of(entry), std::cmp::Reverse(*i)))
+        .map(|(_, entry)| entry)
+}
+
+/// Returns whether the replica which replied with the entry does not hold the
+/// record of the newest entry, which is intact.
+pub fn is_lagging(entry: &[u8], newest: &[u8]) -> bool {
+    match (Version::of(entry), Version::of(newest)) {
+        (Some(version), Some(newest)) => !version.held && newest.held && newest.intact,
+        _ => false,
+    }
+}
+
+/// The repairs of lagging replicas, which run on workers of their own.
+pub(crate) struct Repairs {
+    pool: Pool,
+    /// The repairs which have not finished yet.
+    pending: Mutex<Vec<Handle<()>>>,
+}
+
+impl Default for Repairs {
+    fn default() -> Self {
+        Self {
+            pool: Pool::new(WORKERS),
+            pending: Default::default(),
+        }
+    }
+}
+
+impl Repairs {
+    /// Cancels the repairs which have not started, and waits for the others to
+    /// finish within the timeout.
+    ///
+    /// # Returns
+    ///
+    /// Whether every repair finished within the timeout.
+    pub(crate) fn stop(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for handle in &pending {
+            handle.cancel();
+        }
+
+        pending.iter().all(|handle| {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            !matches!(
+                handle.wait_timeout(remaining),
+                Err(pooling::Error::Pending(_))
+            )
+        })
+    }
+
+    fn submit(&self, job: impl FnOnce() + Send + 'static) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|handle| {
+            matches!(
+                handle.wait_timeout(Duration::ZERO),
+                Err(pooling::Error::Pending(_))
+            )
+        });
+        pending.push(self.pool.submit(job));
+    }
+}
+
+/// Sends the newest entry of a key to the acknowledged nodes at the given
+/// addresses in the background, so that the quorum read is not held up.
+pub(crate) fn repair(
+    node: Arc<RwLock<Node>>,
+    pool: Arc<outbound::Pool>,
+    entry: Vec<u8>,
+    lagging: Vec<String>,
+) {
+    if lagging.is_empty() {
+        return;
+    }
+
+    let outbound = Arc::clone(&pool);
+    pool.repairs.submit(move || {
+        for addr in lagging {
+            match outbound.with(&node, &addr, |client, _| client.replicate(&entry)) {
+                Ok(stored) => debug!("Repaired {} records on {}", stored, addr),
+                Err(e) => warn!("Could not repair the replica at {}: {}", addr, e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdk;
+    use crate::settings::Settings;
+    use crate::testing::{FakePeer, Reply};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("1"), Some(1));
+        assert_eq!(parse("16"), Some(MAX_READS));
+        assert_eq!(parse("0"), None);
+        assert_eq!(parse("17"), None);
+        assert_eq!(parse("all"), None);
+    }
+
+    #[test]
+    fn test_newest() {
+        let entries = vec![
+            b"key:Unknown key".to_vec(),
+            b"key:Invalid signature".to_vec(),
+            b"key:value".to_vec(),
+            b"key+2,1:value".to_vec(),
+            b"key+1,2:other".to_vec(),
+        ];
+        assert_eq!(newest(&entries), Some(&entries[3]));
+        assert_eq!(newest(&entries[..3]), Some(&entries[2]));
+        assert_eq!(newest(&entries[..2]), Some(&entries[1]));
+        assert_eq!(newest(&[]), None);
+    }
+
+    #[test]
+    fn test_replicas() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.nodes = vec![
+            serde_json::from_str(r#""127.0.0.1:7000""#).unwrap(),
+            serde_json::from_str(r#""127.0.0.1:7001""#).unwrap(),
+        ];
+        let node = Arc::new(RwLock::new(Node::new(settings)));
+        assert_eq!(
+            replicas(&node, None),
+            vec![
+                None,
+                Some("127.0.0.1:7000".into()),
+                Some("127.0.0.1:7001".into())
+            ]
+        );
+        assert_eq!(
+            replicas(&node, Some("127.0.0.1:7001")),
+            vec![
+                Some("127.0.0.1:7001".into()),
+                None,
+                Some("127.0.0.1:7000".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_lagging() {
+        let newest = b"key+2,1:value";
+        assert!(is_lagging(b"key:Unknown key", newest));
+        assert!(!is_lagging(b"key:value", newest));
+        assert!(!is_lagging(b"key:Invalid content", newest));
+        assert!(!is_lagging(b"key:Unknown key", b"key:Invalid signature"));
+        assert!(!is_lagging(b"key:Unknown key", b"key:Unknown key"));
+    }
+
+    #[test]
+    fn test_stop() {
+        // Repairs which have not started are cancelled, while the running ones
+        // are waited for.
+        let repairs = Repairs::default();
+        let ran = Arc::new(Mutex::new(vec![]));
+        let (tx, rx) = std::sync::mpsc::channel();
+        for i in 0..WORKERS + 1 {
+            let (ran, tx) = (Arc::clone(&ran), tx.clone());
+            repairs.submit(move || {
+                tx.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(100));
+                ran.lock().unwrap().push(i);
+            });
+        }
+
+        for _ in 0..WORKERS {
+            rx.recv().unwrap();
+        }
+
+        assert!(repairs.stop(Duration::from_secs(5)));
+        assert_eq!(ran.lock().unwrap().len(), WORKERS);
+        assert!(!ran.lock().unwrap().contains(&WORKERS));
+    }
+
+    #[test]
+    fn test_quorum() {
+        let key = ulid::Ulid::new().to_string();
+        let replica =
+            FakePeer::bind([(0x0003, Reply::ok(format!("{}+3,0:value\x00", key)))]).unwrap();
+        let lagging = FakePeer::bind([
+            (0x0003, Reply::ok(format!("{}:Unknown key\x00", key))),
+            (crate::anti_entropy::REPLICATE, Reply::ok("1")),
+        ])
+        .unwrap();
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        for peer in [&replica, &lagging] {
+            let peer = serde_json::from_str(&format!(r#""{}""#, peer.addr())).unwrap();
+            settings.nodes.push(peer);
+        }
+
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        assert_eq!(client.aggregate(&key).unwrap().unknown, vec![key.clone()]);
+        let reply = client.quorum(&key, 2).unwrap();
+        assert_eq!(reply.records.len(), 1);
+        assert_eq!(reply.records[0].value, b"value");
+        assert_eq!(reply.records[0].likes, 3);
+
+        // Current node was repaired by the read.
+        assert_eq!(client.aggregate(&key).unwrap().records[0].value, b"value");
+
+        let reply = client.quorum(&key, 3).unwrap();
+        assert_eq!(reply.records[0].likes, 3);
+        let entry = format!("{}+3,0:value\x00", key).into_bytes();
+        let repaired = (crate::anti_entropy::REPLICATE, entry);
+        let started = std::time::Instant::now();
+        while !lagging.requests().contains(&repaired) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Only three replicas of the key are known.
+        assert!(matches!(client.quorum(&key, 4), Err(sdk::Error::Status(1))));
+        assert!(matches!(client.quorum(&key, 0), Err(sdk::Error::Status(1))));
+    }
+}
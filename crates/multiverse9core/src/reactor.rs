@@ -0,0 +1,378 @@
+//! The reactor keeps idle connections from occupying the workers of the node.
+//! A worker serves a connection for as long as it keeps sending requests, and
+//! once the connection sends no request for [shutdown::POLL], the worker parks
+//! it with the reactor and moves on to the next connection. The reactor waits
+//! for the sockets of the parked connections to become readable on a thread of
+//! its own, and queues every connection which does for a worker again, which
+//! resumes serving it where it left off. Thousands of mostly idle keep-alive
+//! connections thereby share a handful of workers.
+//!
+//! Parked connections which stay idle for
+//! [Settings::idle_timeout](crate::settings::Settings::idle_timeout) are closed
+//! by the reactor, and so is every parked connection once the node is stopping.
+//! Handshake proofs are checked on workers of their own, see
+//! [crate::verification], and the connection is queued for a worker again
+//! once its proof is answered, so that no worker waits for the signature to be
+//! verified.
+//!
+//! Plain TCP connections are parked on Unix, where the reactor waits on their
+//! socket directly, and loopback connections are parked everywhere, since
+//! their client wakes them up, see [crate::transport::Transport::readiness].
+//! Other connections are served by their worker until they are closed, as
+//! before.
+
+use log::*;
+use mio::{Events, Poll, Registry, Token};
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::events::Bus;
+use crate::node::Node;
+use crate::outbound;
+use crate::pooling::Pool;
+use crate::protocol::{Handler, Outcome};
+use crate::shutdown::{self, Shutdown};
+use crate::storage::Storage;
+use crate::transport::Readiness;
+use crate::verification;
+
+/// The most readiness events handled at once.
+const EVENTS: usize = 1024;
+
+/// A connection which waits to become readable.
+struct Parked {
+    handler: Handler,
+    /// Another handle to the socket of the connection, which is registered
+    /// with the reactor, unless the connection wakes the reactor itself.
+    socket: Option<TcpStream>,
+}
+
+/// Serves the connections of a running node, parking them while they are idle.
+pub(crate) struct Reactor {
+    node: Arc<RwLock<Node>>,
+    storage: Arc<Storage>,
+    outbound: Arc<outbound::Pool>,
+    events: Arc<Bus>,
+    pool: Arc<Pool>,
+    /// The workers checking handshake proofs.
+    provers: Pool,
+    registry: Registry,
+    parked: Mutex<HashMap<Token, Parked>>,
+    next: AtomicUsize,
+}
+
+impl Reactor {
+    /// Spawns the thread which waits for the parked connections to become
+    /// readable, until the node is stopping.
+    pub(crate) fn spawn(
+        node: Arc<RwLock<Node>>,
+        storage: Arc<Storage>,
+        outbound: Arc<outbound::Pool>,
+        events: Arc<Bus>,
+        pool: Arc<Pool>,
+    ) -> io::Result<Arc<Self>> {
+        let poll = Poll::new()?;
+        let shutdown = node.read().unwrap().shutdown();
+        let reactor = Arc::new(Self {
+            node,
+            storage,
+            outbound,
+            events,
+            pool,
+            provers: Pool::new(verification::WORKERS),
+            registry: poll.registry().try_clone()?,
+            parked: Mutex::new(HashMap::new()),
+            next: AtomicUsize::new(0),
+        });
+
+        let running = Arc::clone(&reactor);
+        std::thread::spawn(move || running.run(poll, shutdown));
+        Ok(reactor)
+    }
+
+    /// Queues the connection for a worker, which serves it like a connection
+    /// accepted by the listener.
+    pub(crate) fn queue(self: &Arc<Self>, handler: Handler) {
+        let reactor = Arc::clone(self);
+        self.pool.execute(move || reactor.serve(handler));
+    }
+
+    /// Serves the connection on the current thread until it is closed, or until
+    /// it is parked.
+    pub(crate) fn serve(self: &Arc<Self>, mut handler: Handler) {
+        let addr = handler.transport().peer_addr();
+        let mut park = true;
+        loop {
+            let outcome = handler.tcp(
+                Arc::clone(&self.node),
+                Arc::clone(&self.storage),
+                Arc::clone(&self.outbound),
+                Arc::clone(&self.events),
+                park,
+            );
+
+            match outcome {
+                Ok(Outcome::Parked) => match self.park(handler) {
+                    Ok(()) => return,
+                    // The connection is served by the current worker from now on.
+                    Err((parked, e)) => {
+                        debug!("Could not park a connection: {}", e);
+                        handler = parked;
+                        park = false;
+                    }
+                },
+                Ok(Outcome::Proving) => return self.prove(handler),
+                Ok(Outcome::Closed) => return,
+                Err(e) => {
+                    match &addr {
+                        Ok(addr) => error!("Stream error from {}: {}", addr, e),
+                        Err(_) => error!("Stream error: {}", e),
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Checks the handshake proof of the connection on a prover, which queues
+    /// the connection for a worker again once the proof is answered.
+    fn prove(self: &Arc<Self>, mut handler: Handler) {
+        let reactor = Arc::clone(self);
+        self.provers.execute(move || {
+            if let Err(e) = handler.prove(&reactor.node, &reactor.storage) {
+                match handler.transport().peer_addr() {
+                    Ok(addr) => error!("Stream error from {}: {}", addr, e),
+                    Err(_) => error!("Stream error: {}", e),
+                }
+                return;
+            }
+
+            handler.wake();
+            reactor.queue(handler);
+        });
+    }
+
+    /// Hands the idle connection to the reactor.
+    ///
+    /// # Errors
+    ///
+    /// Returns the connection along with the error if it cannot be parked.
+    #[allow(clippy::result_large_err)]
+    fn park(self: &Arc<Self>, handler: Handler) -> Result<(), (Handler, io::Error)> {
+        let readiness = match handler.transport().readiness() {
+            Ok(readiness) => readiness,
+            Err(e) => return Err((handler, e)),
+        };
+
+        let token = Token(self.next.fetch_add(1, Ordering::Relaxed));
+        match readiness {
+            Readiness::Socket(socket) => self.register(handler, socket, token),
+            Readiness::Signal(signal) => {
+                let parked = Parked {
+                    handler,
+                    socket: None,
+                };
+                self.parked.lock().unwrap().insert(token, parked);
+                // The connection is parked before the signal may be raised.
+                let reactor = Arc::downgrade(self);
+                signal.notify(Box::new(move || {
+                    if let Some(reactor) = reactor.upgrade() {
+                        reactor.wake(token);
+                    }
+                }));
+                Ok(())
+            }
+        }
+    }
+
+    /// Parks the connection until its socket becomes readable.
+    #[cfg(unix)]
+    #[allow(clippy::result_large_err)]
+    fn register(
+        &self,
+        handler: Handler,
+        socket: TcpStream,
+        token: Token,
+    ) -> Result<(), (Handler, io::Error)> {
+        use mio::unix::SourceFd;
+        use std::os::unix::io::AsRawFd;
+
+        let fd = socket.as_raw_fd();
+        // Registering while holding the lock, so that the reactor cannot see the
+        // socket become readable before the connection is parked.
+        let mut parked = self.parked.lock().unwrap();
+        let registered = self
+            .registry
+            .register(&mut SourceFd(&fd), token, mio::Interest::READABLE);
+        match registered {
+            Ok(()) => {
+                let socket = Some(socket);
+                parked.insert(token, Parked { handler, socket });
+                Ok(())
+            }
+            Err(e) => Err((handler, e)),
+        }
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::result_large_err)]
+    fn register(
+        &self,
+        handler: Handler,
+        _socket: TcpStream,
+        _token: Token,
+    ) -> Result<(), (Handler, io::Error)> {
+        let e = io::Error::new(io::ErrorKind::Unsupported, "Parking requires Unix");
+        Err((handler, e))
+    }
+
+    /// Queues the parked connection for a worker, once it became readable.
+    fn wake(self: &Arc<Self>, token: Token) {
+        let parked = self.parked.lock().unwrap().remove(&token);
+        if let Some(parked) = parked {
+            self.resume(parked);
+        }
+    }
+
+    /// Stops waiting on the socket of the connection, and queues it for a
+    /// worker.
+    fn resume(self: &Arc<Self>, parked: Parked) {
+        let Parked {
+            mut handler,
+            socket,
+        } = parked;
+        #[cfg(unix)]
+        if let Some(socket) = &socket {
+            use std::os::unix::io::AsRawFd;
+            let fd = socket.as_raw_fd();
+            if let Err(e) = self.registry.deregister(&mut mio::unix::SourceFd(&fd)) {
+                warn!("Could not stop waiting on a parked connection: {}", e);
+            }
+        }
+        drop(socket);
+
+        handler.wake();
+        self.queue(handler);
+    }
+
+    fn run(self: Arc<Self>, mut poll: Poll, shutdown: Shutdown) {
+        let mut events = Events::with_capacity(EVENTS);
+        loop {
+            if let Err(e) = poll.poll(&mut events, Some(shutdown::POLL)) {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    error!("Could not wait for the parked connections: {}", e);
+                    std::thread::sleep(shutdown::POLL);
+                }
+                continue;
+            }
+
+            if shutdown.is_stopping() {
+                let parked = std::mem::take(&mut *self.parked.lock().unwrap());
+                if !parked.is_empty() {
+                    info!(
+                        "Closing {} parked connections, since the node is stopping",
+                        parked.len()
+                    );
+                }
+                return;
+            }
+
+            for event in events.iter() {
+                self.wake(event.token());
+            }
+
+            let idle_timeout = Duration::from_secs(self.node.read().unwrap().settings.idle_timeout);
+            if !idle_timeout.is_zero() {
+                self.parked.lock().unwrap().retain(|_, parked| {
+                    let idle = parked.handler.idle();
+                    if idle < idle_timeout {
+                        return true;
+                    }
+
+                    match parked.handler.transport().peer_addr() {
+                        Ok(addr) => info!(
+                            "Closing the connection of {}, which was idle for {}s",
+                            addr,
+                            idle.as_secs()
+                        ),
+                        Err(_) => info!("Closing a connection which was idle"),
+                    }
+                    false
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::Keypair;
+    use crate::node::Node;
+    use crate::sdk::Client;
+    use crate::settings::{Peer, Settings};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parking() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        let node = Node::new(settings);
+        let local = node.local();
+        // A single worker serves every connection.
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let addr = local.addr().unwrap();
+        let mut clients: Vec<Client> = (0..8).map(|_| Client::connect(addr).unwrap()).collect();
+        for client in &mut clients {
+            let id = client.create(b"value").unwrap().id;
+            assert_eq!(client.aggregate(&id).unwrap().records[0].value, b"value");
+        }
+
+        // Connections which went idle are served again once they send a request.
+        std::thread::sleep(crate::shutdown::POLL * 3);
+        for client in &mut clients {
+            assert!(client.ping().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_proving() {
+        let (server, client) = (Keypair::generate(), Keypair::generate());
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        settings.key = Some(server.secret());
+        settings.nodes.push(Peer {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            tags: vec![],
+            token: None,
+            certificate: None,
+            key: Some(client.public()),
+            roles: None,
+        });
+
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Connections are served again once their proof has been checked, with
+        // the identity they have proven.
+        let mut connection = Client::connect(local.addr().unwrap()).unwrap();
+        let identity = connection.handshake(&client, Some(&server.public()));
+        assert_eq!(identity.unwrap(), format!("node:{}", client.public()));
+        assert!(connection.ping().is_ok());
+
+        let mut stranger = Client::connect(local.addr().unwrap()).unwrap();
+        assert!(stranger.handshake(&Keypair::generate(), None).is_err());
+        assert!(stranger.ping().is_ok());
+    }
+}
@@ -0,0 +1,251 @@
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::node::Node;
+use crate::settings::{Limits, Retention};
+use crate::storage::{self, Keyspace, Storage};
+
+/// The outcome of sweeping the records of a node once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Report {
+    /// The number of records looked at.
+    pub scanned: usize,
+    /// The number of records removed, since they exceeded any of the limits.
+    pub removed: usize,
+    /// The number of bytes the values of the removed records added up to.
+    pub freed: u64,
+}
+
+/// The records kept so far from a set of records sharing the same limits.
+#[derive(Debug, Default)]
+struct Usage {
+    count: usize,
+    bytes: u64,
+    /// Whether a record of the set was over the count or size limits. Since
+    /// records are swept starting with the most recent one, every record after
+    /// it is older and gets removed as well.
+    exceeded: bool,
+}
+
+impl Usage {
+    /// Returns whether a record of the given size, created at `at`, can be
+    /// kept within the limits. Records which cannot be kept since they would
+    /// not fit mark the set as exceeded.
+    fn admits(&mut self, limits: &Limits, size: usize, at: u64, now: u64) -> bool {
+        if let Some(max_age) = limits.max_age {
+            if now.saturating_sub(at) > max_age * 1000 {
+                return false;
+            }
+        }
+
+        self.exceeded = self.exceeded
+            || limits.max_count.is_some_and(|max| self.count + 1 > max)
+            || limits
+                .max_bytes
+                .is_some_and(|max| self.bytes + size as u64 > max);
+        !self.exceeded
+    }
+
+    fn keep(&mut self, size: usize) {
+        self.count += 1;
+        self.bytes += size as u64;
+    }
+}
+
+/// Walks the index buckets of the node starting with the most recent one, and
+/// removes the records which exceed the limits of the namespace or the limits
+/// of their owner. Records are kept newest first, so once a set of records
+/// exceeds its count or size limit, the oldest records of the set are the ones
+/// removed. Content-addressed records are not indexed, and thereby never
+/// removed. See [crate::content].
+///
+/// # Arguments
+///
+/// * `now` - The current time in milliseconds since the Unix epoch, which the
+///   ages of the records are measured against.
+pub(crate) fn sweep(
+    connection: &mut dyn storage::Connection,
+    keyspace: &Keyspace,
+    settings: &Retention,
+    now: u64,
+) -> Result<Report, storage::Error> {
+    let mut report = Report::default();
+    if !settings.is_limited() {
+        return Ok(report);
+    }
+
+    let mut buckets: Vec<(u64, String)> = connection
+        .buckets(keyspace)?
+        .into_iter()
+        .filter_map(|bucket| Some((keyspace.bucket_number(&bucket)?, bucket)))
+        .collect();
+    buckets.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut namespace = Usage::default();
+    let mut owners: HashMap<String, Usage> = HashMap::new();
+    for (_, bucket) in buckets {
+        let mut ids: Vec<ulid::Ulid> = connection
+            .bucket(keyspace, &bucket)?
+            .iter()
+            .filter_map(|id| ulid::Ulid::from_string(id).ok())
+            .collect();
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut removed = vec![];
+        for id in ids {
+            report.scanned += 1;
+            let key = id.to_string();
+            let size = connection.size(keyspace, &key)?;
+            let at = id.timestamp_ms();
+
+            // Owners are only looked up if there are limits on any of them.
+            let owner = match settings.owners.is_empty() {
+                true => None,
+                false => connection
+                    .owner(keyspace, &key)?
+                    .and_then(|owner| Some((settings.owners.get(&owner)?, owner))),
+            };
+
+            let mut admitted = namespace.admits(&settings.namespace, size, at, now);
+            if let Some((limits, owner)) = &owner {
+                let usage = owners.entry(owner.clone()).or_default();
+                admitted = usage.admits(limits, size, at, now) && admitted;
+            }
+
+            if admitted {
+                namespace.keep(size);
+                if let Some((_, owner)) = owner {
+                    owners.entry(owner).or_default().keep(size);
+                }
+            } else {
+                report.removed += 1;
+                report.freed += size as u64;
+                removed.push(key);
+            }
+        }
+
+        if !removed.is_empty() {
+            connection.del(keyspace, &removed)?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Spawns the thread which sweeps the records of the node every
+/// [Retention::interval] seconds. Nothing is spawned if sweeping is disabled,
+/// or if there are no limits to enforce.
+pub(crate) fn spawn(node: Arc<RwLock<Node>>, storage: Arc<Storage>) {
+    let settings = node.read().unwrap().settings.retention.clone();
+    if settings.interval == 0 || !settings.is_limited() {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let mut connection = None;
+        loop {
+            std::thread::sleep(Duration::from_secs(settings.interval));
+            if connection.is_none() {
+                match storage.connection() {
+                    Ok(opened) => connection = Some(opened),
+                    Err(e) => {
+                        error!("Could not sweep the records: {}", e);
+                        continue;
+                    }
+                }
+            }
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default();
+            let connection = connection.as_mut().unwrap();
+            match sweep(connection.as_mut(), storage.keyspace(), &settings, now) {
+                Ok(report) => info!(
+                    "Swept {} records, removed {} freeing {} bytes",
+                    report.scanned, report.removed, report.freed
+                ),
+                Err(e) => {
+                    error!("Could not sweep the records: {}", e);
+                    if let Err(e) = storage.recover(connection, &e) {
+                        error!("Could not reconnect to the storage: {}", e);
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Partition;
+    use crate::storage::memory::Memory;
+    use crate::storage::Backend;
+
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    #[test]
+    fn test_sweep() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Hour,
+        };
+        let mut connection = Memory::default().connection().unwrap();
+        let mut create = |hours: u64, value: &[u8], owner: Option<&str>| {
+            let id = ulid::Ulid::from_parts(hours * HOUR, hours as u128);
+            connection
+                .create(&keyspace, &id, value, None, owner)
+                .unwrap();
+            id.to_string()
+        };
+
+        let oldest = create(1, b"1", None);
+        let old = create(2, b"22", Some("alice"));
+        let alice = create(3, b"333", Some("alice"));
+        let bob = create(4, b"4444", Some("bob"));
+        let newest = create(5, b"55555", Some("alice"));
+
+        let mut settings = Retention::default();
+        settings.namespace.max_age = Some(3 * 60 * 60);
+        settings.owners.insert(
+            "alice".into(),
+            Limits {
+                max_count: Some(1),
+                ..Default::default()
+            },
+        );
+
+        let report = sweep(connection.as_mut(), &keyspace, &settings, 5 * HOUR).unwrap();
+        assert_eq!(
+            report,
+            Report {
+                scanned: 5,
+                removed: 3,
+                freed: 6,
+            }
+        );
+        for (key, kept) in [
+            (oldest, false),
+            (old, false),
+            (alice, false),
+            (bob.clone(), true),
+            (newest.clone(), true),
+        ] {
+            assert_eq!(connection.get(&keyspace, &key).unwrap().is_some(), kept);
+        }
+
+        // The older record is removed once both do not fit anymore.
+        settings.namespace = Limits {
+            max_bytes: Some(8),
+            ..Default::default()
+        };
+        let report = sweep(connection.as_mut(), &keyspace, &settings, 5 * HOUR).unwrap();
+        assert_eq!((report.scanned, report.removed), (2, 1));
+        assert!(connection.get(&keyspace, &newest).unwrap().is_some());
+        assert!(connection.get(&keyspace, &bob).unwrap().is_none());
+    }
+}
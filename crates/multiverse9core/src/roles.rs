@@ -0,0 +1,209 @@
+//! Roles, which govern what a connection may do on current node. Every request
+//! code requires a single role, such as [Role::Write] for creating records, and
+//! connections are granted roles by their identity through
+//! [Settings::perms](crate::settings::Settings::perms). Acknowledged nodes may
+//! be granted roles of their own with [Peer::roles](crate::settings::Peer::roles),
+//! which take the place of those granted to every node. Requests which require
+//! a role the connection lacks are answered with [crate::auth::UNAUTHORIZED].
+//!
+//! Roles are checked after [crate::auth::authorized], which limits connections
+//! which have not authenticated to [Auth::public](crate::settings::Auth::public)
+//! once tokens are configured. Connections may read, write and delete by
+//! default, and acknowledged nodes may federate as well. [Role::Admin] is only
+//! granted when it is set explicitly. Settings which predate roles keep
+//! working, and their `open_interactions` flag only grants nodes
+//! [Role::Read] and [Role::Federate] when it is off.
+//!
+//! Only nodes which proved their key in a handshake, or presented their pinned
+//! certificate, are granted the roles of nodes by default. Nodes which can
+//! only be told apart by their address are trusted once
+//! [Permissions::trust_unpinned](crate::settings::Permissions::trust_unpinned)
+//! is set.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::protocol::Identity;
+use crate::settings::Settings;
+
+/// What a request does on current node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Reading records, feeds, users and the metadata of the node.
+    Read,
+    /// Creating records, posting to feeds and interacting with records and
+    /// users.
+    Write,
+    /// Removing records.
+    Delete,
+    /// Managing the node. No request of the public listener requires it, since
+    /// the node is managed over its admin listener. See [crate::admin].
+    Admin,
+    /// Requests between nodes, such as replication and forwarded records.
+    Federate,
+}
+
+impl Role {
+    /// Every role.
+    pub const ALL: [Role; 5] = [
+        Role::Read,
+        Role::Write,
+        Role::Delete,
+        Role::Admin,
+        Role::Federate,
+    ];
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Read => "read",
+            Role::Write => "write",
+            Role::Delete => "delete",
+            Role::Admin => "admin",
+            Role::Federate => "federate",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|known| known.to_string() == role)
+            .ok_or_else(|| format!("Unknown role {:?}", role))
+    }
+}
+
+/// Returns the roles granted to a connection with the given identity. Nodes
+/// are identified by their public key, or by their address if they presented a
+/// pinned certificate. Connections which have not authenticated are only
+/// granted the roles of nodes if
+/// [Permissions::trust_unpinned](crate::settings::Permissions::trust_unpinned)
+/// is set.
+pub fn granted<'a>(settings: &'a Settings, identity: &Identity) -> &'a [Role] {
+    let perms = &settings.perms;
+    let peer = match identity {
+        Identity::Anonymous(addr) if perms.trust_unpinned => settings.nodes.iter().find(|peer| {
+            let pinned = peer.key.is_some() || peer.certificate.is_some();
+            !pinned && peer.addr.ip() == addr.ip()
+        }),
+        Identity::Node(id) => settings.nodes.iter().find(|peer| {
+            let key = peer.key.as_deref();
+            key.is_some_and(|key| key.eq_ignore_ascii_case(id)) || peer.addr.to_string() == *id
+        }),
+        _ => None,
+    };
+
+    match identity {
+        Identity::Anonymous(_) if peer.is_none() => &perms.anonymous,
+        Identity::Anonymous(_) | Identity::Node(_) => {
+            match peer.and_then(|peer| peer.roles.as_deref()) {
+                Some(roles) => roles,
+                None => &perms.nodes,
+            }
+        }
+        Identity::Subject(subject) => match perms.subjects.get(subject) {
+            Some(roles) => roles,
+            None => &perms.authenticated,
+        },
+        Identity::User(_) => &perms.authenticated,
+    }
+}
+
+/// Returns whether a connection with the given identity is granted the role.
+#[inline(always)]
+pub(crate) fn permitted(settings: &Settings, identity: &Identity, role: Role) -> bool {
+    granted(settings, identity).contains(&role)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Permissions;
+
+    #[test]
+    fn test_permitted() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        let anonymous = Identity::Anonymous("127.0.0.1:7000".parse().unwrap());
+        for role in [Role::Read, Role::Write, Role::Delete] {
+            assert!(permitted(&settings, &anonymous, role));
+        }
+        assert!(!permitted(&settings, &anonymous, Role::Admin));
+        assert!(!permitted(&settings, &anonymous, Role::Federate));
+        let node = Identity::Node("127.0.0.1:7000".into());
+        assert!(permitted(&settings, &node, Role::Federate));
+        assert!(!permitted(&settings, &node, Role::Admin));
+
+        settings.perms.anonymous = vec![Role::Read];
+        settings
+            .perms
+            .subjects
+            .insert("reader".into(), vec![Role::Read]);
+        assert!(permitted(&settings, &anonymous, Role::Read));
+        assert!(!permitted(&settings, &anonymous, Role::Write));
+        let reader = Identity::Subject("reader".into());
+        assert!(!permitted(&settings, &reader, Role::Delete));
+        let writer = Identity::Subject("writer".into());
+        assert!(permitted(&settings, &writer, Role::Delete));
+    }
+
+    #[test]
+    fn test_peer_roles() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.perms.nodes = vec![Role::Federate];
+        settings.nodes = serde_json::from_str(
+            r#"[
+                {"addr": "127.0.0.1:7000", "key": "AB01", "roles": ["read"]},
+                {"addr": "127.0.0.1:7001", "roles": ["read", "write"]},
+                "127.0.0.1:7002"
+            ]"#,
+        )
+        .unwrap();
+
+        // The roles of the peer take the place of those of every node.
+        let pinned = Identity::Node("ab01".into());
+        assert!(permitted(&settings, &pinned, Role::Read));
+        assert!(!permitted(&settings, &pinned, Role::Federate));
+        let certified = Identity::Node("127.0.0.1:7001".into());
+        assert!(permitted(&settings, &certified, Role::Write));
+        let other = Identity::Node("127.0.0.1:7002".into());
+        assert_eq!(granted(&settings, &other), &[Role::Federate]);
+
+        // Unpinned nodes are only recognized by their IP address once trusted.
+        let unpinned = Identity::Anonymous("127.0.0.1:45000".parse().unwrap());
+        assert!(!permitted(&settings, &unpinned, Role::Federate));
+        settings.perms.trust_unpinned = true;
+        assert!(permitted(&settings, &unpinned, Role::Write));
+        let client = Identity::Anonymous("10.0.0.1:45000".parse().unwrap());
+        assert!(!permitted(&settings, &client, Role::Federate));
+    }
+
+    #[test]
+    fn test_legacy() {
+        let perms: Permissions = serde_json::from_str("{}").unwrap();
+        assert_eq!(perms, Permissions::default());
+        let perms: Permissions =
+            serde_json::from_str(r#"{"open_metadata": false, "open_interactions": false}"#)
+                .unwrap();
+        assert_eq!(perms.nodes, vec![Role::Read, Role::Federate]);
+        let perms: Permissions =
+            serde_json::from_str(r#"{"open_interactions": false, "nodes": ["federate"]}"#).unwrap();
+        assert_eq!(perms.nodes, vec![Role::Federate]);
+    }
+
+    #[test]
+    fn test_parse() {
+        for role in Role::ALL {
+            assert_eq!(role.to_string().parse::<Role>(), Ok(role));
+        }
+
+        assert!("root".parse::<Role>().is_err());
+        let roles: Vec<Role> = serde_json::from_str(r#"["read", "federate"]"#).unwrap();
+        assert_eq!(roles, vec![Role::Read, Role::Federate]);
+    }
+}
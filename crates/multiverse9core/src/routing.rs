@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+
+use crate::settings::Peer;
+
+/// A single `key=value` label attached to a node, such as `region=eu` or
+/// `tier=archive`. Tags are serialized in their textual form so that they
+/// stay readable in the settings file.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Tag {
+    pub key: String,
+    pub value: String,
+}
+
+impl Tag {
+    pub fn new(key: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+}
+
+impl std::str::FromStr for Tag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !value.is_empty() => {
+                Ok(Self::new(key.trim(), value.trim()))
+            }
+            _ => Err(format!("Invalid tag `{}`, expected `key=value`", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Tag {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Tag> for String {
+    fn from(tag: Tag) -> Self {
+        format!("{}={}", tag.key, tag.value)
+    }
+}
+
+impl std::fmt::Display for Tag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+/// Describes which peers are eligible for an operation which fans out to
+/// other nodes, such as replication or aggregation.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    /// For every tag in this list, at least one of the selected peers must
+    /// carry it. Selection fails if no acknowledged peer has the tag.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub require: Vec<Tag>,
+    /// Peers carrying more of these tags are selected before the others.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prefer: Vec<Tag>,
+}
+
+/// Routing policies for each kind of operation that reaches out to peers.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Routing {
+    #[serde(default)]
+    pub replication: Policy,
+    #[serde(default)]
+    pub aggregation: Policy,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// None of the peers carry the required tag.
+    Unsatisfiable(Tag),
+}
+
+impl Policy {
+    /// Selects up to `count` peers according to the policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `peers` - The acknowledged peers to choose from.
+    /// * `count` - The maximum number of peers to select.
+    ///
+    /// # Functionality
+    ///
+    /// For each required tag, the most preferred peer carrying it is picked
+    /// first. The remaining slots are then filled with the rest of the peers,
+    /// ordered by how many preferred tags they carry. Peers with an equal score
+    /// keep their order from the settings file.
+    pub fn select<'a>(&self, peers: &'a [Peer], count: usize) -> Result<Vec<&'a Peer>, Error> {
+        let mut ranked: Vec<&Peer> = peers.iter().collect();
+        ranked.sort_by_key(|peer| std::cmp::Reverse(self.score(peer)));
+
+        let mut selected: Vec<&Peer> = vec![];
+        for tag in &self.require {
+            if selected.iter().any(|peer| peer.has_tag(tag)) {
+                continue;
+            }
+
+            match ranked.iter().copied().find(|peer| peer.has_tag(tag)) {
+                Some(peer) => selected.push(peer),
+                None => return Err(Error::Unsatisfiable(tag.clone())),
+            }
+        }
+
+        for peer in ranked {
+            if selected.len() >= count.max(self.require.len()) {
+                break;
+            }
+
+            if !selected.iter().any(|p| p.addr == peer.addr) {
+                selected.push(peer);
+            }
+        }
+
+        Ok(selected)
+    }
+
+    #[inline(always)]
+    fn score(&self, peer: &Peer) -> usize {
+        self.prefer.iter().filter(|tag| peer.has_tag(tag)).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(addr: &str, tags: &[&str]) -> Peer {
+        Peer {
+            addr: addr.parse().unwrap(),
+            tags: tags.iter().map(|t| t.parse().unwrap()).collect(),
+            token: None,
+            certificate: None,
+            key: None,
+            roles: None,
+        }
+    }
+
+    #[test]
+    fn test_tag_parse() {
+        assert_eq!("region=eu".parse::<Tag>(), Ok(Tag::new("region", "eu")));
+        assert!("region".parse::<Tag>().is_err());
+        assert!("=eu".parse::<Tag>().is_err());
+    }
+
+    #[test]
+    fn test_select_prefers_tags() {
+        let peers = vec![
+            peer("127.0.0.1:1", &["region=us"]),
+            peer("127.0.0.1:2", &["region=eu"]),
+        ];
+
+        let policy = Policy {
+            prefer: vec![Tag::new("region", "eu")],
+            ..Default::default()
+        };
+
+        let selected = policy.select(&peers, 1).unwrap();
+        assert_eq!(selected, vec![&peers[1]]);
+    }
+
+    #[test]
+    fn test_select_requires_tags() {
+        let peers = vec![
+            peer("127.0.0.1:1", &["region=eu"]),
+            peer("127.0.0.1:2", &["region=eu"]),
+            peer("127.0.0.1:3", &["tier=archive"]),
+        ];
+
+        let policy = Policy {
+            require: vec![Tag::new("tier", "archive")],
+            ..Default::default()
+        };
+
+        let selected = policy.select(&peers, 2).unwrap();
+        assert_eq!(selected, vec![&peers[2], &peers[0]]);
+
+        let policy = Policy {
+            require: vec![Tag::new("tier", "hot")],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            policy.select(&peers, 2),
+            Err(Error::Unsatisfiable(Tag::new("tier", "hot")))
+        );
+    }
+}
@@ -1,16 +1,1459 @@
-use std::net::TcpStream;
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 
-use super::Tcp;
+use super::{FrameBuffer, Tcp};
+use crate::anti_entropy;
+use crate::capabilities::{self, Capabilities};
+use crate::changelog::{self, Page};
+use crate::events::{self, Event};
+use crate::health::{self, Load, Report};
+use crate::keys::{self, Keypair};
+use crate::listing::{self, Query};
+use crate::pagination::{self, Paging};
+use crate::placement::{self, Ring};
+use crate::protocol::{codec, Metadata};
+use crate::settings::Socket;
+use crate::timing::{self, Trailer};
+use crate::transport::Transport;
+use crate::users::Profile;
+use crate::{
+    auth, bulk, deadline, envelope, journal, loopback, multiplex, search, sharding, streaming, tags,
+};
 
-crate::enum_with_impl_to_string! {
+/// Contains the client of the admin listener of a node.
+pub mod admin;
+/// Contains the async variant of the client, for applications running on
+/// tokio.
+#[cfg(feature = "async-sdk")]
+pub mod asynchronous;
+/// Contains the typed responses of the client.
+pub mod response;
+/// Contains the retry policies of the client.
+pub mod retry;
+/// Contains the connection URLs of the client.
+pub mod url;
+
+pub use admin::Admin;
+pub use response::{AggregateResponse, CreateResponse, Pong, Record, Removal};
+pub use retry::Retry;
+pub use url::Url;
+
+crate::enum_with_impl_error! {
     pub Error,
-    .Io(std::io::Error)
+    .Io(std::io::Error) [source]
+    .Codec(codec::Error) [source]
+    .Keys(keys::Error) [source]
+    .Json(serde_json::Error) [source]
+    .Status(u8)
+    .Malformed(String)
+    .Rejected(String)
     ~Debug
 }
 
 type SdkResult = Result<Vec<u8>, Error>;
 
+/// Establishes connections to nodes, either over plain TCP or over TLS.
+#[derive(Clone, Default)]
+pub enum Connector {
+    #[default]
+    Plain,
+    /// Connects over TLS with the given configuration, which can be created
+    /// with [crate::tls::connector].
+    #[cfg(feature = "tls")]
+    Tls(std::sync::Arc<rustls::ClientConfig>),
+    /// Connects with the inner connector, applying the options to the TCP
+    /// streams it opens. Created with [Connector::with_socket].
+    Tuned(Box<Connector>, Socket),
+}
+
+impl Connector {
+    /// Applies the options to the TCP streams opened by the connector, such as
+    /// `TCP_NODELAY` for latency-sensitive clients.
+    pub fn with_socket(self, socket: Socket) -> Self {
+        Self::Tuned(Box::new(self), socket)
+    }
+
+    /// Connects to the node at the given address. Nodes in the same process
+    /// which are registered for loopback connections are reached through an
+    /// in-process pipe instead, whatever the transport. See [crate::loopback].
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<Client, Error> {
+        let addrs: Vec<_> = addr.to_socket_addrs().map_err(Error::Io)?.collect();
+        self.client(addrs, None)
+    }
+
+    /// Connects to the node at the given address, giving up if connecting, or
+    /// later on any single read or write, takes longer than the timeout.
+    pub fn connect_timeout(&self, addr: &SocketAddr, timeout: Duration) -> Result<Client, Error> {
+        self.client(vec![*addr], Some(timeout))
+    }
+
+    /// Same as [Connector::connect], but connecting is retried as well as the
+    /// requests issued over the connection, following the policy.
+    pub fn connect_with<A: ToSocketAddrs>(&self, addr: A, retry: Retry) -> Result<Client, Error> {
+        let addrs: Vec<_> = addr.to_socket_addrs().map_err(Error::Io)?.collect();
+        let client = retry.run(|_| self.client(addrs.clone(), None))?;
+        Ok(client.with_retry(retry))
+    }
+
+    fn client(&self, addrs: Vec<SocketAddr>, timeout: Option<Duration>) -> Result<Client, Error> {
+        let stream = self.open(&addrs, timeout)?;
+        let mut client = Client::over(stream);
+        client.reconnect = Some(Reconnect {
+            connector: self.clone(),
+            addrs,
+            timeout,
+            secret: None,
+        });
+        Ok(client)
+    }
+
+    fn open(
+        &self,
+        addrs: &[SocketAddr],
+        timeout: Option<Duration>,
+    ) -> Result<Box<dyn Transport>, Error> {
+        if let Some(stream) = loopback::connect(&addrs).map_err(Error::Io)? {
+            return Ok(stream);
+        }
+
+        let stream = match (timeout, addrs) {
+            (Some(timeout), [addr, ..]) => {
+                let stream = TcpStream::connect_timeout(addr, timeout).map_err(Error::Io)?;
+                stream.set_read_timeout(Some(timeout)).map_err(Error::Io)?;
+                stream.set_write_timeout(Some(timeout)).map_err(Error::Io)?;
+                stream
+            }
+            _ => TcpStream::connect(addrs).map_err(Error::Io)?,
+        };
+
+        self.wrap(stream)
+    }
+
+    /// Wraps the connected stream into the transport of the connector.
+    fn wrap(&self, stream: TcpStream) -> Result<Box<dyn Transport>, Error> {
+        Ok(match self {
+            Self::Plain => Box::new(stream),
+            #[cfg(feature = "tls")]
+            Self::Tls(config) => Box::new(
+                crate::tls::connect(stream, std::sync::Arc::clone(config)).map_err(Error::Io)?,
+            ),
+            Self::Tuned(connector, socket) => {
+                Tcp::configure(&stream, socket).map_err(Error::Io)?;
+                connector.wrap(stream)?
+            }
+        })
+    }
+}
+
+/// A connection to a single node, over which any number of requests can be
+/// issued one after another.
+pub struct Client {
+    stream: Box<dyn Transport>,
+    /// The receive buffer, which is reused for every response.
+    frames: FrameBuffer,
+    /// How failed requests are retried.
+    retry: Retry,
+    /// How the connection is established again before a request is retried,
+    /// unless it cannot be.
+    reconnect: Option<Reconnect>,
+}
+
+/// What a [Client] needs to establish its connection again.
+struct Reconnect {
+    connector: Connector,
+    addrs: Vec<SocketAddr>,
+    timeout: Option<Duration>,
+    /// The secret the connection authenticated with, which new connections
+    /// authenticate with as well.
+    secret: Option<String>,
+}
+
+impl Client {
+    /// Connects to the node at the given address over plain TCP, or through
+    /// an in-process pipe if the node runs in the same process and is
+    /// registered for loopback connections. See [crate::loopback].
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        Connector::Plain.connect(addr)
+    }
+
+    /// Connects to the node of a connection URL over plain TCP, applying the
+    /// options of the URL. See [url].
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Rejected] if the URL is invalid, or requires TLS. URLs
+    /// which require TLS connect through [Url::connect] instead.
+    pub fn open(url: &str) -> Result<Self, Error> {
+        url.parse::<Url>()?.connect(Connector::Plain)
+    }
+
+    pub(crate) fn over(stream: Box<dyn Transport>) -> Self {
+        Self {
+            stream,
+            frames: FrameBuffer::new(codec::Kind::Response),
+            retry: Retry::never(),
+            reconnect: None,
+        }
+    }
+
+    /// Retries the requests which fail following the policy, over a new
+    /// connection to the node. See [retry].
+    ///
+    /// Connections which proved an identity with [Client::handshake] are not
+    /// established again, since the handshake cannot be replayed, so their
+    /// requests are not retried.
+    pub fn with_retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Authenticates the connection with the secret of a token.
+    ///
+    /// # Returns
+    ///
+    /// The identity the node attributes requests on this connection to.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Status] with [auth::UNAUTHORIZED] if the node does not
+    /// accept the secret.
+    pub fn authenticate(&mut self, secret: &str) -> Result<String, Error> {
+        let reply = self.request(auth::AUTHENTICATE, secret.as_bytes())?;
+        if let Some(reconnect) = &mut self.reconnect {
+            reconnect.secret = Some(secret.into());
+        }
+
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    /// Proves the identity of a node to the remote node with a signed handshake,
+    /// while verifying the identity of the remote node in turn.
+    ///
+    /// # Arguments
+    ///
+    /// * `keypair` - The keypair of the node the connection is made on behalf of.
+    /// * `expected` - The hex-encoded public key the remote node is expected to
+    ///   have. If [None], any key is accepted.
+    ///
+    /// # Returns
+    ///
+    /// The identity the node attributes requests on this connection to.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Keys] if the remote node does not hold the expected key,
+    /// and [Error::Status] with [auth::UNAUTHORIZED] if the node does not
+    /// acknowledge the keypair.
+    pub fn handshake(
+        &mut self,
+        keypair: &Keypair,
+        expected: Option<&str>,
+    ) -> Result<String, Error> {
+        // The identity proven by the handshake is bound to this connection.
+        self.reconnect = None;
+        let nonce = keys::nonce();
+        let reply = self.request(auth::HANDSHAKE, &nonce)?;
+        let (public, proof) = keys::prove(keypair, &nonce, &reply).map_err(Error::Keys)?;
+        if expected.is_some_and(|expected| !expected.eq_ignore_ascii_case(&public)) {
+            return Err(Error::Keys(keys::Error::Handshake(
+                "Unexpected key of the node",
+            )));
+        }
+
+        let reply = self.request(auth::HANDSHAKE, &proof)?;
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    /// Requests the metadata the node publishes about itself, such as its name,
+    /// its version, its peers and its capabilities, for taking inventory of a
+    /// federation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the metadata of the node is not open to
+    /// the connection.
+    pub fn metadata(&mut self) -> Result<Metadata, Error> {
+        let reply = self.request(0x0005, &[])?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// Requests the addresses of the nodes the node acknowledges, from its
+    /// metadata. Nodes which predate peers in their metadata know none.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the metadata of the node is not open to
+    /// the connection.
+    pub fn peers(&mut self) -> Result<Vec<SocketAddr>, Error> {
+        Ok(self.metadata()?.peers)
+    }
+
+    /// Sets how long the client waits for a response before the request fails
+    /// with an [Error::Io]. The client waits forever with [None]. Clients
+    /// connected with [Connector::connect_timeout] wait for the timeout they
+    /// were connected with by default.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.stream.set_read_timeout(timeout).map_err(Error::Io)
+    }
+
+    /// Probes whether the node is alive, without authenticating first. See
+    /// [health::PING].
+    ///
+    /// # Returns
+    ///
+    /// The load of the node.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Malformed] if the node does not reply with a pong,
+    /// such as nodes which do not support probes yet.
+    pub fn ping(&mut self) -> Result<Load, Error> {
+        let reply = self.request(health::PING, &[])?;
+        Load::decode(&reply).ok_or_else(|| Error::Malformed(String::from_utf8_lossy(&reply).into()))
+    }
+
+    /// Same as [Client::ping], but measures the round trip of the probe, and
+    /// fails with an [Error::Io] unless the node replies within the timeout.
+    /// Once the probe is done, the client waits for the timeout it was
+    /// connected with again, see [Client::set_read_timeout].
+    ///
+    /// # Returns
+    ///
+    /// The round trip of the probe, along with the load of the node.
+    pub fn ping_timeout(&mut self, timeout: Option<Duration>) -> Result<Pong, Error> {
+        if timeout.is_some() {
+            self.set_read_timeout(timeout)?;
+        }
+
+        let started = std::time::Instant::now();
+        let load = self.ping();
+        let rtt = started.elapsed();
+        if timeout.is_some() {
+            let connected = self
+                .reconnect
+                .as_ref()
+                .and_then(|reconnect| reconnect.timeout);
+            self.set_read_timeout(connected)?;
+        }
+
+        Ok(Pong { rtt, load: load? })
+    }
+
+    /// Exchanges capabilities with the node, without authenticating first. See
+    /// [capabilities::HELLO].
+    ///
+    /// # Arguments
+    ///
+    /// * `own` - The capabilities of the client.
+    ///
+    /// # Returns
+    ///
+    /// The capabilities of the node, which are empty for nodes which do not
+    /// support capabilities yet.
+    pub fn hello(&mut self, own: Capabilities) -> Result<Capabilities, Error> {
+        let reply = match self.request(capabilities::HELLO, &own.encode()) {
+            Ok(reply) => reply,
+            Err(Error::Status(1)) => return Ok(Capabilities::NONE),
+            Err(e) => return Err(e),
+        };
+
+        Capabilities::decode(&reply)
+            .ok_or_else(|| Error::Malformed(String::from_utf8_lossy(&reply).into()))
+    }
+
+    /// Creates a record with the given value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the value is empty, or if the connection
+    /// is not allowed to create records.
+    pub fn create(&mut self, value: &[u8]) -> Result<CreateResponse, Error> {
+        let reply = self.request(0x0001, value)?;
+        Ok(CreateResponse {
+            id: String::from_utf8_lossy(&reply).to_string(),
+        })
+    }
+
+    /// Creates several records with a single request. See [crate::bulk].
+    ///
+    /// # Returns
+    ///
+    /// The keys of the records, in the order their values were given.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if no values are given, if any of the values
+    /// is empty, or if more than [bulk::MAX_RECORDS] values are given, in which
+    /// case no record is created.
+    pub fn create_many(&mut self, values: &[&[u8]]) -> Result<Vec<CreateResponse>, Error> {
+        let reply = self.request(bulk::CREATE_MANY, &bulk::encode(values))?;
+        Ok(decode_list(&reply)
+            .into_iter()
+            .map(|id| CreateResponse { id })
+            .collect())
+    }
+
+    /// Creates a record with the given tags attached to it. See [crate::tags].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if any of the tags is invalid, or if there are
+    /// more than [tags::MAX_TAGS] of them.
+    pub fn create_tagged(&mut self, tags: &[&str], value: &[u8]) -> Result<CreateResponse, Error> {
+        let reply = self.request(tags::CREATE, &tags::encode_create(tags, value))?;
+        Ok(CreateResponse {
+            id: String::from_utf8_lossy(&reply).to_string(),
+        })
+    }
+
+    /// Returns the keys of the records tagged with all of the given tags, or
+    /// with any of them, in lexicographic order. See [crate::tags].
+    pub fn tagged(&mut self, tags: &[&str], all: bool) -> Result<Vec<String>, Error> {
+        let reply = self.request(tags::QUERY, &encode_tagged(tags, all))?;
+        Ok(decode_list(&reply))
+    }
+
+    /// Removes the records with the given keys. Keys followed by `@` and the
+    /// address of a node the node acknowledges are removed from that node.
+    ///
+    /// # Returns
+    ///
+    /// Whether each of the keys was removed, in the order they were given.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if no keys are given, or if any of the keys
+    /// to be removed from the node itself cannot be removed, in which case
+    /// none of the keys are removed.
+    pub fn remove(&mut self, keys: &[&str]) -> Result<Vec<Removal>, Error> {
+        let reply = self.request(0x0002, &encode_keys(keys))?;
+        Removal::decode(keys, &reply)
+    }
+
+    /// Stores a record created on current node on the node which owns it. See
+    /// [crate::sharding::STORE].
+    ///
+    /// # Returns
+    ///
+    /// The key of the record.
+    pub(crate) fn forward(&mut self, value: &[u8]) -> Result<String, Error> {
+        let reply = self.request(sharding::STORE, value)?;
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    /// Creates a record wrapped in an envelope, which the node stamps with the
+    /// time it was created at and the user who created it. See
+    /// [crate::envelope].
+    ///
+    /// # Arguments
+    ///
+    /// * `content_type` - The media type of the body, such as `text/plain`.
+    /// * `body` - The body of the record.
+    pub fn create_envelope(
+        &mut self,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<CreateResponse, Error> {
+        let payload = [content_type.as_bytes(), &[00], body].concat();
+        let reply = self.request(envelope::CREATE, &payload)?;
+        Ok(CreateResponse {
+            id: String::from_utf8_lossy(&reply).to_string(),
+        })
+    }
+
+    /// Aggregates the value of the specified key from the node.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to aggregate.
+    ///
+    /// # Returns
+    ///
+    /// The aggregated records, along with the keys the node does not know.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Io] if there is an issue reading the response, an
+    /// [Error::Status] if the node replied with an error, and an
+    /// [Error::Malformed] if the node replied with entries which cannot be
+    /// decoded.
+    pub fn aggregate(&mut self, key: &str) -> Result<AggregateResponse, Error> {
+        self.aggregate_many(&[key])
+    }
+
+    /// Same as [Client::aggregate], but aggregates several keys with a single
+    /// request. The records are returned in the order of their keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Rejected] without issuing the request if any of the
+    /// keys is empty or holds a null byte, since the node would read it as
+    /// another number of keys.
+    pub fn aggregate_many(&mut self, keys: &[&str]) -> Result<AggregateResponse, Error> {
+        AggregateResponse::decode(&self.aggregate_entries(keys)?)
+    }
+
+    /// Same as [Client::aggregate_many], but returns the entries as the node
+    /// encoded them, for passing them on.
+    pub(crate) fn aggregate_entries(&mut self, keys: &[&str]) -> SdkResult {
+        check_keys(keys)?;
+        self.request(0x0003, &encode_keys(keys))
+    }
+
+    /// Same as [Client::aggregate], but with the values transformed by the
+    /// view with the given name, such as [crate::views::SUMMARY]. Entries
+    /// returned through a view other than [crate::views::RAW] are not signed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the node has no view with the given name.
+    pub fn view(&mut self, key: &str, view: &str) -> Result<AggregateResponse, Error> {
+        let buffer = format!("{}{}\x00{}\x00", crate::views::PREFIX, view, key);
+        AggregateResponse::decode(&self.request(0x0003, buffer.as_bytes())?)
+    }
+
+    /// Same as [Client::aggregate], but the node reads the key from the given
+    /// number of its replicas and returns the newest version among them. See
+    /// [crate::quorum].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the number of replicas is out of range,
+    /// or if fewer replicas replied.
+    pub fn quorum(&mut self, key: &str, reads: usize) -> Result<AggregateResponse, Error> {
+        let buffer = format!("{}{}\x00{}\x00", crate::quorum::PREFIX, reads, key);
+        AggregateResponse::decode(&self.request(0x0003, buffer.as_bytes())?)
+    }
+
+    /// Aggregates the given targets into a stream of records, which the node
+    /// writes as soon as it reads them. Targets take the same form as those of
+    /// [Client::aggregate], and may start with the options of [Client::view]
+    /// and [Client::quorum]. See [crate::streaming].
+    ///
+    /// The records have to be read before the next request is issued, and the
+    /// records left once the stream is dropped are skipped.
+    ///
+    /// # Errors
+    ///
+    /// The stream yields an [Error::Status] if the aggregation fails, after
+    /// the records read before the failure.
+    pub fn stream(&mut self, targets: &[&str]) -> Result<Records<'_>, Error> {
+        let buffer = codec::encode_request(streaming::AGGREGATE, &encode_keys(targets))
+            .map_err(Error::Codec)?;
+        Tcp::write(&mut *self.stream, &buffer).map_err(Error::Io)?;
+        Ok(Records {
+            client: self,
+            done: false,
+            cursor: None,
+        })
+    }
+
+    /// Posts the records with the given keys to a feed of the node.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the name of the feed or any of the keys is
+    /// invalid.
+    pub fn post(&mut self, feed: &str, keys: &[&str]) -> Result<(), Error> {
+        self.request(0x0007, &encode_post(feed, keys)).map(|_| ())
+    }
+
+    /// Returns the latest entries of a feed, starting with the most recent one.
+    ///
+    /// # Arguments
+    ///
+    /// * `feed` - The name of the feed, optionally followed by `@` and the
+    ///   address of an acknowledged node the feed is on.
+    /// * `count` - The number of entries to return, which the node caps at
+    ///   [crate::feed::MAX_COUNT].
+    ///
+    /// # Returns
+    ///
+    /// The records of the entries, the same way [Client::aggregate] returns
+    /// them.
+    pub fn feed(&mut self, feed: &str, count: usize) -> Result<AggregateResponse, Error> {
+        AggregateResponse::decode(&self.feed_entries(feed, count)?)
+    }
+
+    /// Same as [Client::feed], but returns the entries as the node encoded
+    /// them, for passing them on.
+    pub(crate) fn feed_entries(&mut self, feed: &str, count: usize) -> SdkResult {
+        let buffer = format!("{}\x00{}", feed, count);
+        self.request(0x0008, buffer.as_bytes())
+    }
+
+    /// Returns a page of the entries of a feed, starting with the most recent
+    /// entry after the cursor, or with the most recent one without a cursor.
+    /// See [crate::pagination].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the name of the feed, the cursor or the
+    /// limit is invalid.
+    pub fn feed_page(
+        &mut self,
+        feed: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<pagination::Page, Error> {
+        let paging = Paging {
+            cursor: cursor.unwrap_or_default().to_string(),
+            limit,
+        };
+        pagination::Page::decode(&self.feed_page_entries(feed, &paging)?)
+    }
+
+    /// Same as [Client::feed_page], but returns the entries and footer as the
+    /// node encoded them, for passing them on.
+    pub(crate) fn feed_page_entries(&mut self, feed: &str, paging: &Paging) -> SdkResult {
+        let buffer = pagination::encode(Some(&paging.cursor), paging.limit) + feed;
+        self.request(0x0008, buffer.as_bytes())
+    }
+
+    /// Returns a page of the records of the node in the order of their keys,
+    /// starting after the cursor, or with the first record without a cursor.
+    /// See [crate::pagination].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the cursor or the limit is invalid.
+    pub fn scan(&mut self, cursor: Option<&str>, limit: usize) -> Result<pagination::Page, Error> {
+        let buffer = pagination::encode(cursor, limit);
+        pagination::Page::decode(&self.request(0x0003, buffer.as_bytes())?)
+    }
+
+    /// Lists the keys of the records of the node which match the query, in
+    /// lexicographic order. See [crate::listing].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the query holds characters keys are not
+    /// made of.
+    pub fn list(&mut self, query: &Query) -> Result<Vec<String>, Error> {
+        let reply = self.request(listing::LIST, query.encode().as_bytes())?;
+        Ok(decode_list(&reply))
+    }
+
+    /// Same as [Client::list], but returns a page of the keys which follow the
+    /// cursor, or the first page without a cursor. See [crate::pagination].
+    ///
+    /// # Returns
+    ///
+    /// The keys of the page, and the cursor of the next page, if any.
+    pub fn list_page(
+        &mut self,
+        query: &Query,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>), Error> {
+        let buffer = pagination::encode(cursor, limit) + &query.encode();
+        let reply = self.request(listing::LIST, buffer.as_bytes())?;
+        match pagination::split(&reply) {
+            (keys, Some(next)) => Ok((decode_list(keys), next.map(String::from))),
+            (_, None) => Err(Error::Malformed(String::from_utf8_lossy(&reply).into())),
+        }
+    }
+
+    /// Returns the keys of the records of the node which hold every word of the
+    /// text, in lexicographic order. See [crate::search].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the node does not search its records, or
+    /// if the text holds no words or more than [search::MAX_QUERY_TERMS] of
+    /// them.
+    pub fn search(&mut self, text: &str) -> Result<Vec<String>, Error> {
+        let reply = self.request(search::SEARCH, encode_search(text).as_bytes())?;
+        Ok(decode_list(&reply))
+    }
+
+    /// Same as [Client::search], but returns a page of the keys which follow
+    /// the cursor, or the first page without a cursor. See [crate::pagination].
+    ///
+    /// # Returns
+    ///
+    /// The keys of the page, and the cursor of the next page, if any.
+    pub fn search_page(
+        &mut self,
+        text: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>), Error> {
+        let buffer = pagination::encode(cursor, limit) + &encode_search(text);
+        let reply = self.request(search::SEARCH, buffer.as_bytes())?;
+        match pagination::split(&reply) {
+            (keys, Some(next)) => Ok((decode_list(keys), next.map(String::from))),
+            (_, None) => Err(Error::Malformed(String::from_utf8_lossy(&reply).into())),
+        }
+    }
+
+    /// Registers a user with the given handle and keypair on the node. Once
+    /// registered, the user proves its identity with [Client::handshake], after
+    /// which the records created on the connection are attributed to it.
+    ///
+    /// # Returns
+    ///
+    /// The profile the node stored for the user.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the handle is invalid, or if either the
+    /// handle or the key is registered already.
+    pub fn register(&mut self, handle: &str, keypair: &Keypair) -> Result<Profile, Error> {
+        let reply = self.request(0x000A, encode_register(handle, keypair).as_bytes())?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// Looks up the profile of the user with the given handle.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if no user is registered with the handle.
+    pub fn profile(&mut self, handle: &str) -> Result<Profile, Error> {
+        let reply = self.request(0x000B, handle.as_bytes())?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// Likes the record with the given key on behalf of the identity of the
+    /// connection. Liking a record again has no effect.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the connection is anonymous, or if the
+    /// record does not exist.
+    pub fn like(&mut self, key: &str) -> Result<(), Error> {
+        self.request(0x0011, key.as_bytes()).map(|_| ())
+    }
+
+    /// Replies to the record with the given key with a new record.
+    ///
+    /// # Returns
+    ///
+    /// The key of the reply.
+    pub fn reply(&mut self, key: &str, value: &[u8]) -> Result<String, Error> {
+        let reply = self.request(0x0012, &encode_reply(key, value))?;
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    /// Returns the earliest replies to the record with the given key, in the
+    /// order in which they were created, the same way [Client::aggregate]
+    /// returns records.
+    pub fn replies(&mut self, key: &str, count: usize) -> Result<AggregateResponse, Error> {
+        let buffer = format!("{}\x00{}", key, count);
+        AggregateResponse::decode(&self.request(0x0013, buffer.as_bytes())?)
+    }
+
+    /// Follows an actor on behalf of the user the connection proved to be with
+    /// [Client::handshake].
+    ///
+    /// # Arguments
+    ///
+    /// * `actor` - The handle of a user of the node, or `handle@addr` for a user
+    ///   of a node the node acknowledges.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the connection is not a user, or if the
+    /// actor does not exist.
+    pub fn follow(&mut self, actor: &str) -> Result<(), Error> {
+        self.relate(actor, None, true)
+    }
+
+    /// Stops following an actor. See [Client::follow].
+    pub fn unfollow(&mut self, actor: &str) -> Result<(), Error> {
+        self.relate(actor, None, false)
+    }
+
+    /// Returns the actors following the user with the given handle.
+    pub fn followers(&mut self, handle: &str) -> Result<Vec<String>, Error> {
+        self.members(0x000E, handle)
+    }
+
+    /// Returns the actors the user with the given handle follows.
+    pub fn following(&mut self, handle: &str) -> Result<Vec<String>, Error> {
+        self.members(0x000F, handle)
+    }
+
+    /// Follows or unfollows an actor. Nodes relaying the request of one of
+    /// their users pass the user as the follower.
+    pub(crate) fn relate(
+        &mut self,
+        actor: &str,
+        follower: Option<&str>,
+        follow: bool,
+    ) -> Result<(), Error> {
+        let code = if follow { 0x000C } else { 0x000D };
+        self.request(code, &encode_relate(actor, follower))
+            .map(|_| ())
+    }
+
+    fn members(&mut self, code: u16, handle: &str) -> Result<Vec<String>, Error> {
+        let reply = self.request(code, handle.as_bytes())?;
+        Ok(decode_list(&reply))
+    }
+
+    /// Issues several requests in a single frame, which the node executes one
+    /// after another.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests` - The code and payload of each request.
+    ///
+    /// # Returns
+    ///
+    /// The status and payload of the response to each request, in the order of
+    /// the requests. A failed request does not stop the requests after it.
+    pub fn batch(&mut self, requests: &[(u16, &[u8])]) -> Result<Vec<(u8, Vec<u8>)>, Error> {
+        let payload = codec::encode_batch(requests.iter().copied()).map_err(Error::Codec)?;
+        let reply = self.request(codec::BATCH, &payload)?;
+        let responses = codec::decode_batch_response(&reply).map_err(Error::Codec)?;
+        Ok(responses
+            .into_iter()
+            .map(|response| (response.status, response.payload.to_vec()))
+            .collect())
+    }
+
+    /// Writes several requests back to back without waiting for their responses,
+    /// and then reads the response to each of them. Unlike [Client::batch], the
+    /// requests are regular frames, so that any request can be pipelined, and
+    /// the node replies to each of them separately, in the order they were
+    /// written in.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests` - The code and payload of each request.
+    ///
+    /// # Returns
+    ///
+    /// The status and payload of the response to each request, in the order of
+    /// the requests. A failed request does not stop the requests after it.
+    /// Pipelined requests are not retried, since some of them may have been
+    /// executed already.
+    pub fn pipeline(&mut self, requests: &[(u16, &[u8])]) -> Result<Vec<(u8, Vec<u8>)>, Error> {
+        let mut buffer = vec![];
+        for (code, payload) in requests {
+            buffer.extend(codec::encode_request(*code, payload).map_err(Error::Codec)?);
+        }
+
+        Tcp::write(&mut *self.stream, &buffer).map_err(Error::Io)?;
+        let mut responses = Vec::with_capacity(requests.len());
+        for _ in requests {
+            let frame = Tcp::read_frame(&mut *self.stream, &mut self.frames)
+                .map_err(Error::Io)?
+                .ok_or_else(|| Error::Io(std::io::ErrorKind::UnexpectedEof.into()))?;
+            let (response, _) = codec::decode_response(frame).map_err(Error::Codec)?;
+            responses.push((response.status, response.payload.to_vec()));
+        }
+
+        Ok(responses)
+    }
+
+    /// Returns the health history of the nodes acknowledged by the node, or of
+    /// the one at the given address only.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the node does not acknowledge a node at the
+    /// given address.
+    pub fn health(&mut self, addr: Option<&str>) -> Result<Vec<Report>, Error> {
+        let reply = self.request(0x0014, addr.unwrap_or_default().as_bytes())?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// Returns at most `count` changes from the changelog of the node, starting
+    /// at the cursor. Reading from the [Page::next] cursor of a page continues
+    /// where the page left off. See [crate::changelog].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the node keeps no changelog, or if the
+    /// cursor is not at the start of a change.
+    pub fn changes(&mut self, cursor: u64, count: usize) -> Result<Page, Error> {
+        let buffer = format!("{}\x00{}", cursor, count);
+        let reply = self.request(changelog::CHANGES, buffer.as_bytes())?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// Returns the IDs of the records of the node. See [crate::anti_entropy].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] unless the connection belongs to an
+    /// acknowledged node or has authenticated with a token.
+    pub fn inventory(&mut self) -> Result<Vec<String>, Error> {
+        let reply = self.request(anti_entropy::INVENTORY, &[])?;
+        Ok(decode_list(&reply))
+    }
+
+    /// Stores records on the node which it is missing, leaving the records it
+    /// has already alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - The records in the format of aggregated entries.
+    ///
+    /// # Returns
+    ///
+    /// The number of records the node stored.
+    pub fn replicate(&mut self, entries: &[u8]) -> Result<usize, Error> {
+        let reply = self.request(anti_entropy::REPLICATE, entries)?;
+        Ok(String::from_utf8_lossy(&reply).parse().unwrap_or_default())
+    }
+
+    /// Requests the ring of the node, made of the node and the nodes it
+    /// acknowledges, for computing which node owns a key. See
+    /// [crate::placement].
+    pub fn ring(&mut self) -> Result<Ring, Error> {
+        let reply = self.request(placement::RING, &[])?;
+        let nodes: Vec<SocketAddr> = serde_json::from_slice(&reply).map_err(Error::Json)?;
+        Ok(Ring::new(nodes))
+    }
+
+    /// Issues a request through the journal of the node, which executes it only
+    /// once no matter how often it is delivered. Retrying with the same ID after
+    /// a lost connection or a restart of the node returns the response to the
+    /// first successful delivery.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Identifies the request among the requests of the client, at most
+    ///   [journal::MAX_ID_LEN] bytes long. A ULID is a good choice.
+    /// * `code` - The code of the request.
+    /// * `payload` - The payload of the request.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] with [journal::IN_PROGRESS] if the request is
+    /// still being executed on behalf of an earlier delivery.
+    pub fn journaled(&mut self, id: &str, code: u16, payload: &[u8]) -> SdkResult {
+        let payload = journal::encode(id, code, payload).map_err(Error::Codec)?;
+        self.request(journal::JOURNALED, &payload)
+    }
+
+    /// Issues a request whose response carries a breakdown of the time the node
+    /// spent on it, so that the latency of the network can be told apart from
+    /// the latency of the node. See [crate::timing].
+    ///
+    /// # Returns
+    ///
+    /// The payload of the response along with its [Trailer].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the node replied with an error, or if the
+    /// request cannot be timed.
+    pub fn timed(&mut self, code: u16, payload: &[u8]) -> Result<(Vec<u8>, Trailer), Error> {
+        let payload = codec::encode_request(code, payload).map_err(Error::Codec)?;
+        let reply = self.request(timing::TIMED, &payload)?;
+        decode_timed(&reply)
+    }
+
+    /// Issues a request which the node spends at most `budget` on. Remote
+    /// calls the node would make past the deadline are cut short, and requests
+    /// fanning out to remote nodes, such as [Client::aggregate], return what
+    /// they collected in time. See [crate::deadline].
+    ///
+    /// # Returns
+    ///
+    /// The payload of the response, along with whether the deadline passed, in
+    /// which case the response may be partial.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if the node replied with an error, or if the
+    /// request cannot have a deadline.
+    pub fn within(
+        &mut self,
+        budget: Duration,
+        code: u16,
+        payload: &[u8],
+    ) -> Result<(Vec<u8>, bool), Error> {
+        let payload = deadline::encode(budget, code, payload).map_err(Error::Codec)?;
+        let reply = self.request(deadline::DEADLINE, &payload)?;
+        decode_within(&reply)
+    }
+
+    /// Creates a record from the contents of the reader, which are streamed to
+    /// the node in chunks, so that neither end has to hold the whole value in
+    /// memory. Values which the node has to sign or hash are read whole by the
+    /// node when the upload is committed.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The source of the value.
+    /// * `chunk_len` - The largest chunk sent in a single request, which is
+    ///   capped so that requests stay below [codec::MAX_PAYLOAD_LEN].
+    ///
+    /// # Returns
+    ///
+    /// The key of the record.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Io] if reading from the reader fails, and an
+    /// [Error::Status] if the value is empty or grows beyond the limit of the
+    /// node.
+    pub fn upload<R: Read>(&mut self, reader: R, chunk_len: usize) -> Result<String, Error> {
+        let reply = self.request(0x0015, &[])?;
+        let id = String::from_utf8_lossy(&reply).to_string();
+
+        let chunk_len = chunk_len.clamp(1, codec::MAX_PAYLOAD_LEN - id.len() - 1);
+        let mut reader = reader.take(0);
+        let mut buffer = Vec::with_capacity(id.len() + 1 + chunk_len);
+        loop {
+            buffer.clear();
+            buffer.extend_from_slice(id.as_bytes());
+            buffer.push(00);
+
+            reader.set_limit(chunk_len as u64);
+            if reader.read_to_end(&mut buffer).map_err(Error::Io)? == 0 {
+                break;
+            }
+
+            self.request(0x0016, &buffer)?;
+        }
+
+        let reply = self.request(0x0017, id.as_bytes())?;
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    /// Subscribes the connection to the events of the given topics, or to every
+    /// event if there are none, after which the connection only receives the
+    /// events. See [crate::events].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Status] if any of the topics is invalid.
+    pub fn subscribe(mut self, topics: &[&str]) -> Result<Subscription, Error> {
+        self.request(events::SUBSCRIBE, topics.join("\x00").as_bytes())?;
+        Ok(Subscription { client: self })
+    }
+
+    /// Switches the connection to multiplexing, after which requests can be
+    /// issued from any number of threads without waiting for the responses to
+    /// the previous ones. See [crate::multiplex].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Io] of kind [std::io::ErrorKind::Unsupported] if the
+    /// connection cannot be multiplexed, such as over TLS or loopback, and an
+    /// [Error::Status] if the node does not multiplex connections.
+    pub fn multiplex(mut self) -> Result<Multiplexed, Error> {
+        // The connection is only switched once it is known that responses can
+        // be read on a thread of their own.
+        let mut reader = self.stream.try_clone().map_err(Error::Io)?;
+        let reply = self.request(multiplex::MULTIPLEX, &[])?;
+        let max_in_flight = match reply[..] {
+            [a, b, c, d] => u32::from_be_bytes([a, b, c, d]) as usize,
+            _ => return Err(Error::Malformed(String::from_utf8_lossy(&reply).into())),
+        };
+
+        reader.set_read_timeout(None).map_err(Error::Io)?;
+        let shared = Arc::new(Shared {
+            writer: Mutex::new(self.stream),
+            pending: Mutex::new(Some(HashMap::new())),
+            next: AtomicU32::new(0),
+        });
+
+        let (routed, mut frames) = (Arc::clone(&shared), self.frames);
+        std::thread::spawn(move || {
+            while let Ok(Some(frame)) = Tcp::read_frame(&mut *reader, &mut frames) {
+                let (id, result) = match decode_multiplexed(frame) {
+                    Some(decoded) => decoded,
+                    None => break,
+                };
+
+                let mut pending = routed.pending.lock().unwrap();
+                if let Some(tx) = pending.as_mut().and_then(|pending| pending.remove(&id)) {
+                    // The caller may have stopped waiting for the response.
+                    let _ = tx.send(result);
+                }
+            }
+
+            // Requests still waiting for their responses fail once the senders
+            // are dropped, and no requests are issued from now on.
+            routed.pending.lock().unwrap().take();
+        });
+
+        Ok(Multiplexed {
+            shared,
+            max_in_flight,
+        })
+    }
+
+    /// Sends a single request over the connection and waits for its response.
+    ///
+    /// # Returns
+    ///
+    /// The payload of the response if the node replied with a success status.
+    /// Requests which fail are issued again over a new connection as long as
+    /// the retry policy allows, and the connection can be established again.
+    pub(crate) fn request(&mut self, code: u16, payload: &[u8]) -> SdkResult {
+        let buffer = codec::encode_request(code, payload).map_err(Error::Codec)?;
+        let mut result = self.send(&buffer);
+        let mut attempt = 1;
+        while let Err(e) = &result {
+            if self.reconnect.is_none() || !self.retry.retries(attempt, e) {
+                break;
+            }
+
+            std::thread::sleep(self.retry.delay(attempt));
+            attempt += 1;
+            // Failing to connect again counts as a failed attempt too.
+            result = self.reopen().and_then(|_| self.send(&buffer));
+        }
+
+        result
+    }
+
+    /// Writes the encoded request and waits for its response.
+    fn send(&mut self, buffer: &[u8]) -> SdkResult {
+        Tcp::write(&mut *self.stream, buffer).map_err(Error::Io)?;
+        self.response()?
+            .ok_or_else(|| Error::Io(std::io::ErrorKind::UnexpectedEof.into()))
+    }
+
+    /// Establishes the connection again, authenticating it with the secret the
+    /// previous connection authenticated with.
+    fn reopen(&mut self) -> Result<(), Error> {
+        let reconnect = match &self.reconnect {
+            Some(reconnect) => reconnect,
+            None => return Err(Error::Io(std::io::ErrorKind::NotConnected.into())),
+        };
+
+        self.stream = reconnect
+            .connector
+            .open(&reconnect.addrs, reconnect.timeout)?;
+        // Leaving out whatever was left of a response on the previous connection.
+        self.frames = FrameBuffer::new(codec::Kind::Response);
+        if let Some(secret) = reconnect.secret.clone() {
+            let buffer = codec::encode_request(auth::AUTHENTICATE, secret.as_bytes())
+                .map_err(Error::Codec)?;
+            self.send(&buffer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next response from the connection, or [None] if the node has
+    /// closed the connection.
+    fn response(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match Tcp::read_frame(&mut *self.stream, &mut self.frames).map_err(Error::Io)? {
+            Some(frame) => decode(frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A connection subscribed to events, which yields the events as the node
+/// pushes them until the connection is closed. See [Client::subscribe].
+pub struct Subscription {
+    client: Client,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.client.response() {
+                // Keepalives carry no event.
+                Ok(Some(payload)) if payload.is_empty() => continue,
+                Ok(Some(payload)) => {
+                    return Some(serde_json::from_slice(&payload).map_err(Error::Json))
+                }
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// The records of a streaming aggregation, in the order the node wrote them
+/// in. See [Client::stream].
+pub struct Records<'a> {
+    client: &'a mut Client,
+    /// Whether the node has ended the stream.
+    done: bool,
+    /// The cursor of the next page, once a streamed scan has ended.
+    cursor: Option<String>,
+}
+
+impl Records<'_> {
+    /// Returns the cursor the next page of a streamed scan starts after, once
+    /// every record of the stream has been read. See [crate::pagination].
+    pub fn cursor(&self) -> Option<&str> {
+        self.cursor.as_deref()
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let entry = match self.client.response() {
+            Ok(Some(entry))
+                if !entry.is_empty() && !entry.starts_with(pagination::CURSOR.as_bytes()) =>
+            {
+                entry
+            }
+            // The empty frame ends the stream, or the footer of the page which
+            // ends a streamed scan.
+            Ok(Some(footer)) => {
+                self.done = true;
+                if let (_, Some(Some(next))) = pagination::split(&footer) {
+                    self.cursor = Some(next.to_string());
+                }
+
+                return None;
+            }
+            Ok(None) => {
+                self.done = true;
+                return Some(Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into())));
+            }
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+
+        Some(
+            Record::decode(&entry)
+                .ok_or_else(|| Error::Malformed(String::from_utf8_lossy(&entry).into())),
+        )
+    }
+}
+
+impl Drop for Records<'_> {
+    fn drop(&mut self) {
+        // The rest of the stream is read, so that the response to the next
+        // request is not mistaken for one of its records.
+        for _ in self.by_ref() {}
+    }
+}
+
+/// A connection over which many requests can be outstanding at once, from any
+/// number of threads. Responses are read on a thread of their own, and handed
+/// to the requests they answer. The connection is closed once it is dropped.
+/// See [Client::multiplex].
+pub struct Multiplexed {
+    shared: Arc<Shared>,
+    max_in_flight: usize,
+}
+
+/// What a [Multiplexed] connection shares with the thread reading its
+/// responses.
+struct Shared {
+    writer: Mutex<Box<dyn Transport>>,
+    /// The requests waiting for their responses, by their correlation ID, or
+    /// [None] once the connection has been closed.
+    pending: Mutex<Option<HashMap<u32, mpsc::Sender<SdkResult>>>>,
+    /// The correlation ID of the next request.
+    next: AtomicU32,
+}
+
+impl Multiplexed {
+    /// Returns the most requests the node handles at once on the connection.
+    /// Further requests are received once one of them is done.
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    /// Sends a single request over the connection without waiting for its
+    /// response.
+    ///
+    /// # Returns
+    ///
+    /// The response to the request, which can be waited for with
+    /// [Pending::wait]. Requests are not retried.
+    pub fn send(&self, code: u16, payload: &[u8]) -> Result<Pending, Error> {
+        let id = self.shared.next.fetch_add(1, Ordering::Relaxed);
+        let buffer = multiplex::encode(id, code, payload).map_err(Error::Codec)?;
+        let (tx, rx) = mpsc::channel();
+        match self.shared.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => return Err(Error::Io(std::io::ErrorKind::NotConnected.into())),
+        };
+
+        if let Err(e) = Tcp::write(&mut **self.shared.writer.lock().unwrap(), &buffer) {
+            if let Some(pending) = self.shared.pending.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+
+            return Err(Error::Io(e));
+        }
+
+        Ok(Pending { rx })
+    }
+
+    /// Sends a single request over the connection and waits for its response.
+    pub fn request(&self, code: u16, payload: &[u8]) -> SdkResult {
+        self.send(code, payload)?.wait()
+    }
+}
+
+impl Drop for Multiplexed {
+    fn drop(&mut self) {
+        // The node finishes the requests in flight and closes its side of the
+        // connection, which stops the thread reading the responses.
+        let _ = self.shared.writer.lock().unwrap().shutdown_write();
+    }
+}
+
+/// The response to a request sent over a [Multiplexed] connection.
+pub struct Pending {
+    rx: mpsc::Receiver<SdkResult>,
+}
+
+impl Pending {
+    /// Waits for the response to the request.
+    ///
+    /// # Returns
+    ///
+    /// The payload of the response if the node replied with a success status,
+    /// or an [Error::Io] of kind [std::io::ErrorKind::UnexpectedEof] if the
+    /// connection was closed before the response arrived.
+    pub fn wait(self) -> SdkResult {
+        self.rx
+            .recv()
+            .unwrap_or_else(|_| Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into())))
+    }
+}
+
+/// Returns the payload of the response frame if the node replied with a
+/// success status.
+fn decode(frame: &[u8]) -> SdkResult {
+    let (response, _) = codec::decode_response(frame).map_err(Error::Codec)?;
+    match response.status {
+        0 => Ok(response.payload.to_vec()),
+        status => Err(Error::Status(status)),
+    }
+}
+
+/// Decodes a response frame of a multiplexed connection into the correlation
+/// ID of the request it answers, and its payload if the node replied with a
+/// success status.
+///
+/// # Returns
+///
+/// [None] if the frame carries no correlation ID.
+fn decode_multiplexed(frame: &[u8]) -> Option<(u32, SdkResult)> {
+    let (response, _) = codec::decode_response(frame).ok()?;
+    let (id, payload) = multiplex::decode(response.payload)?;
+    Some(match response.status {
+        0 => (id, Ok(payload.to_vec())),
+        status => (id, Err(Error::Status(status))),
+    })
+}
+
+/// Splits a reply into the strings separated by null bytes.
+fn decode_list(reply: &[u8]) -> Vec<String> {
+    reply
+        .split(|c| *c == 00)
+        .filter(|item| !item.is_empty())
+        .map(|item| String::from_utf8_lossy(item).to_string())
+        .collect()
+}
+
+/// Splits the reply to a timed request into the payload of the inner response
+/// and its trailer.
+fn decode_timed(reply: &[u8]) -> Result<(Vec<u8>, Trailer), Error> {
+    let (response, trailer) = timing::decode(reply).map_err(Error::Codec)?;
+    match response.status {
+        0 => Ok((response.payload.to_vec(), trailer)),
+        status => Err(Error::Status(status)),
+    }
+}
+
+/// Splits the reply to a request with a deadline into the payload of the inner
+/// response and whether the deadline passed.
+fn decode_within(reply: &[u8]) -> Result<(Vec<u8>, bool), Error> {
+    let (response, expired) = deadline::decode_response(reply).map_err(Error::Codec)?;
+    match response.status {
+        0 => Ok((response.payload.to_vec(), expired)),
+        status => Err(Error::Status(status)),
+    }
+}
+
+/// Encodes keys into the payload of a request, each followed by a null byte.
+/// Rejects the keys which the node would read as another number of keys.
+fn check_keys(keys: &[&str]) -> Result<(), Error> {
+    match keys.iter().find(|key| key.is_empty() || key.contains('\0')) {
+        Some(key) => Err(Error::Rejected(format!("Invalid key {:?}", key))),
+        None => Ok(()),
+    }
+}
+
+fn encode_keys(keys: &[&str]) -> Vec<u8> {
+    let mut buffer = vec![];
+    for key in keys {
+        buffer.extend(key.as_bytes());
+        buffer.push(00);
+    }
+
+    buffer
+}
+
+fn encode_tagged(tags: &[&str], all: bool) -> Vec<u8> {
+    let mode = match all {
+        true => tags::ALL,
+        false => tags::ANY,
+    };
+
+    let mut buffer = encode_keys(&[mode]);
+    buffer.extend(encode_keys(tags));
+    buffer
+}
+
+/// Encodes the text of a search as its words, so that no part of it can be
+/// mistaken for the options of [pagination].
+fn encode_search(text: &str) -> String {
+    format!("{}\x00", search::tokenize(text).join(" "))
+}
+
+fn encode_post(feed: &str, keys: &[&str]) -> Vec<u8> {
+    let mut buffer: Vec<u8> = vec![];
+    buffer.extend_from_slice(feed.as_bytes());
+    for key in keys {
+        buffer.push(00);
+        buffer.extend_from_slice(key.as_bytes());
+    }
+
+    buffer
+}
+
+fn encode_reply(key: &str, value: &[u8]) -> Vec<u8> {
+    let mut buffer = key.as_bytes().to_vec();
+    buffer.push(00);
+    buffer.extend_from_slice(value);
+    buffer
+}
+
+fn encode_relate(actor: &str, follower: Option<&str>) -> Vec<u8> {
+    let mut buffer = actor.as_bytes().to_vec();
+    if let Some(follower) = follower {
+        buffer.push(00);
+        buffer.extend_from_slice(follower.as_bytes());
+    }
+
+    buffer
+}
+
+fn encode_register(handle: &str, keypair: &Keypair) -> String {
+    let signature = keys::hex(&keypair.sign_registration(handle));
+    format!("{}\x00{}\x00{}", handle, keypair.public(), signature)
+}
+
 /// Aggregates the values of the specified keys from the node at the given address.
+/// Connecting and the request are retried following [Retry::default].
 ///
 /// # Arguments
 ///
@@ -19,21 +1462,606 @@ type SdkResult = Result<Vec<u8>, Error>;
 ///
 /// # Returns
 ///
-/// The aggregated values of the keys.
+/// The aggregated records, along with the keys the node does not know.
 ///
 /// # Errors
 ///
 /// Returns an [Error::Io] if there is an issue connecting to the node or reading
-/// the response.
-pub fn aggregate(addr: String, key: String) -> SdkResult {
-    let stream = TcpStream::connect(addr).map_err(Error::Io)?;
-    let mut buffer: Vec<u8> = vec![0x0003];
+/// the response, and an [Error::Status] if the node replied with an error.
+pub fn aggregate(addr: String, key: String) -> Result<AggregateResponse, Error> {
+    aggregate_many(addr, &[&key])
+}
 
-    // for key in keys {
-    buffer.extend_from_slice(key.as_bytes());
-    buffer.push(00);
-    // }
+/// Same as [aggregate], but aggregates several keys with a single request. See
+/// [Client::aggregate_many].
+pub fn aggregate_many(addr: String, keys: &[&str]) -> Result<AggregateResponse, Error> {
+    Connector::Plain
+        .connect_with(addr, Retry::default())?
+        .aggregate_many(keys)
+}
+
+/// Discovers the nodes of a federation from a single seed node, for clients
+/// which fail over to other nodes once the seed goes away. The returned
+/// addresses can be connected to in turn with [Client::connect], which
+/// connects to the first of them that accepts the connection.
+///
+/// # Returns
+///
+/// The addresses of the seed, followed by the addresses of the nodes it
+/// acknowledges, without duplicates.
+///
+/// # Errors
+///
+/// Returns an [Error::Io] if there is an issue connecting to the seed, and an
+/// [Error::Status] if its metadata is not open to anonymous connections.
+pub fn discover<A: ToSocketAddrs>(seed: A) -> Result<Vec<SocketAddr>, Error> {
+    let mut addrs: Vec<_> = seed.to_socket_addrs().map_err(Error::Io)?.collect();
+    let peers = Client::connect(addrs.as_slice())?.peers()?;
+    for peer in peers {
+        if !addrs.contains(&peer) {
+            addrs.push(peer);
+        }
+    }
+
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePeer, Reply};
+
+    #[test]
+    fn test_aggregate() {
+        let peer = FakePeer::bind([(0x0003, Reply::ok("key~alice:value\x00"))]).unwrap();
+        let reply = aggregate(peer.addr().to_string(), "key".into()).unwrap();
+        assert_eq!(
+            reply.records,
+            vec![Record {
+                key: "key".into(),
+                owner: Some("alice".into()),
+                likes: 0,
+                replies: 0,
+                signature: None,
+                value: b"value".to_vec(),
+            }]
+        );
+        assert_eq!(peer.requests(), vec![(0x0003, b"key\x00".to_vec())]);
+    }
+
+    #[test]
+    fn test_create() {
+        let peer = FakePeer::bind([
+            (0x0001, Reply::ok("01GQ")),
+            (0x0003, Reply::ok("01GQ:value\x0001GR:Unknown key\x00")),
+        ])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        assert_eq!(
+            client.create(b"value").unwrap(),
+            CreateResponse { id: "01GQ".into() }
+        );
+        let response = client.aggregate_many(&["01GQ", "01GR"]).unwrap();
+        assert_eq!(response.records[0].value, b"value");
+        assert_eq!(response.unknown, vec!["01GR"]);
+        assert!(matches!(
+            client.aggregate_many(&["01GQ\x0001GR"]),
+            Err(Error::Rejected(_))
+        ));
+    }
+
+    #[test]
+    fn test_create_envelope() {
+        let peer = FakePeer::bind([(envelope::CREATE, Reply::ok("01GQ"))]).unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        assert_eq!(
+            client.create_envelope("text/plain", b"value").unwrap(),
+            CreateResponse { id: "01GQ".into() }
+        );
+        assert_eq!(
+            peer.requests(),
+            vec![(envelope::CREATE, b"text/plain\x00value".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_view() {
+        let peer = FakePeer::bind([(0x0003, Reply::ok("key:val…\x00"))]).unwrap();
+        let mut client = Client::connect(peer.addr()).unwrap();
+        let response = client.view("key", "summary").unwrap();
+        assert_eq!(response.records[0].value, "val…".as_bytes());
+        assert_eq!(
+            peer.requests(),
+            vec![(0x0003, b"view=summary\x00key\x00".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut frames = codec::encode_response(0, &[]).unwrap();
+        for payload in [&b""[..], br#"{"kind":"posted","feed":"news","key":"key"}"#] {
+            frames.extend(codec::encode_response(0, payload).unwrap());
+        }
+
+        let peer = FakePeer::bind([(events::SUBSCRIBE, Reply::Raw(frames))]).unwrap();
+        let client = Client::connect(peer.addr()).unwrap();
+        let events: Vec<Event> = client
+            .subscribe(&["created", "posted:news"])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![Event::Posted {
+                feed: "news".into(),
+                key: "key".into(),
+            }]
+        );
+        assert_eq!(
+            peer.requests(),
+            vec![(events::SUBSCRIBE, b"created\x00posted:news".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_retry() {
+        let peer = FakePeer::bind([
+            (auth::AUTHENTICATE, Reply::ok("token:ops")),
+            (0x0003, Reply::Disconnect),
+            (0x0003, Reply::ok("key:value\x00")),
+        ])
+        .unwrap();
+
+        let retry = Retry {
+            backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let mut client = Connector::Plain.connect_with(peer.addr(), retry).unwrap();
+        client.authenticate("secret").unwrap();
+        assert_eq!(client.aggregate("key").unwrap().records[0].value, b"value");
+        // The new connection authenticated again before the request was retried.
+        assert_eq!(
+            peer.requests(),
+            vec![
+                (auth::AUTHENTICATE, b"secret".to_vec()),
+                (0x0003, b"key\x00".to_vec()),
+                (auth::AUTHENTICATE, b"secret".to_vec()),
+                (0x0003, b"key\x00".to_vec()),
+            ]
+        );
+
+        // Clients without a policy give up right away.
+        let peer = FakePeer::bind([(0x0003, Reply::Disconnect)]).unwrap();
+        let mut client = Client::connect(peer.addr()).unwrap();
+        assert!(matches!(client.aggregate("key"), Err(Error::Io(_))));
+        assert_eq!(peer.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_error_status() {
+        let peer = FakePeer::bind([(0x0003, Reply::status(1))]).unwrap();
+        let result = aggregate(peer.addr().to_string(), "key".into());
+        assert!(matches!(result, Err(Error::Status(1))));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let peer = FakePeer::bind([(0x0003, Reply::Disconnect)]).unwrap();
+        let result = aggregate(peer.addr().to_string(), "key".into());
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_truncated_reply() {
+        // The header announces 16 bytes of payload, but only 2 are sent.
+        let peer = FakePeer::bind([(0x0003, Reply::Raw(vec![0, 0, 0, 0, 16, 1, 2]))]).unwrap();
+        let result = aggregate(peer.addr().to_string(), "key".into());
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_scripted_sequence() {
+        let peer = FakePeer::bind([
+            (auth::AUTHENTICATE, Reply::status(auth::UNAUTHORIZED)),
+            (auth::AUTHENTICATE, Reply::ok("subject:frontend")),
+            (
+                0x0003,
+                Reply::ok("key:value\x00").delayed(Duration::from_millis(50)),
+            ),
+        ])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        assert!(matches!(
+            client.authenticate("wrong"),
+            Err(Error::Status(auth::UNAUTHORIZED))
+        ));
+        assert_eq!(client.authenticate("s3cr3t").unwrap(), "subject:frontend");
+        assert_eq!(client.aggregate("key").unwrap().records[0].value, b"value");
+    }
+
+    #[test]
+    fn test_feed() {
+        let peer = FakePeer::bind([
+            (0x0007, Reply::ok("")),
+            (0x0008, Reply::ok("key2:second\x00key1:first\x00")),
+        ])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        client.post("news", &["key1", "key2"]).unwrap();
+        let keys: Vec<_> = client
+            .feed("news", 2)
+            .unwrap()
+            .records
+            .into_iter()
+            .map(|record| record.key)
+            .collect();
+        assert_eq!(keys, vec!["key2", "key1"]);
+        assert_eq!(
+            peer.requests(),
+            vec![
+                (0x0007, b"news\x00key1\x00key2".to_vec()),
+                (0x0008, b"news\x002".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut reply = codec::encode_response(0, b"01GQ").unwrap();
+        reply.extend(codec::encode_response(1, &[]).unwrap());
+        let peer = FakePeer::bind([(codec::BATCH, Reply::ok(reply))]).unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        let responses = client
+            .batch(&[(0x0001, &b"value"[..]), (0x0007, &b"news\x0001GQ"[..])])
+            .unwrap();
+        assert_eq!(responses, vec![(0, b"01GQ".to_vec()), (1, vec![])]);
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let peer =
+            FakePeer::bind([(0x0001, Reply::ok("01GQ")), (0x0007, Reply::status(1))]).unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        let requests = [(0x0001, &b"value"[..]), (0x0007, &b"news\x0001GQ"[..])];
+        let responses = client.pipeline(&requests).unwrap();
+        assert_eq!(responses, vec![(0, b"01GQ".to_vec()), (1, vec![])]);
+        assert_eq!(
+            peer.requests(),
+            vec![
+                (0x0001, b"value".to_vec()),
+                (0x0007, b"news\x0001GQ".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let peer = FakePeer::bind([(
+            0x0005,
+            Reply::ok(r#"{"name": "multiverse9_test", "version": "0.1.0"}"#),
+        )])
+        .unwrap();
+
+        let metadata = Client::connect(peer.addr()).unwrap().metadata().unwrap();
+        assert_eq!(metadata.name, "multiverse9_test");
+        assert_eq!(metadata.key, None);
+        // Nodes which predate peers and capabilities in their metadata.
+        assert!(metadata.peers.is_empty());
+        assert_eq!(metadata.capabilities, None);
+    }
+
+    #[test]
+    fn test_discover() {
+        let peer = FakePeer::bind([(
+            0x0005,
+            Reply::ok(
+                r#"{"name": "seed", "version": "0.1.0", "peers": ["127.0.0.1:7891", "127.0.0.1:7892"]}"#,
+            ),
+        )])
+        .unwrap();
+
+        let addrs = discover(peer.addr()).unwrap();
+        let peers: Vec<SocketAddr> = ["127.0.0.1:7891", "127.0.0.1:7892"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(addrs, [vec![peer.addr()], peers].concat());
+
+        let peer = FakePeer::bind([(0x0005, Reply::status(1))]).unwrap();
+        assert!(matches!(discover(peer.addr()), Err(Error::Status(1))));
+    }
+
+    #[test]
+    fn test_ping() {
+        let peer = FakePeer::bind([(
+            health::PING,
+            Reply::ok(&b"pong\x00{\"active\": 2, \"queued\": 1}"[..]),
+        )])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        let pong = client.ping_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(pong.load.active, 2);
+        assert!(pong.rtt < Duration::from_secs(1));
+        assert_eq!(
+            client.ping().unwrap(),
+            Load {
+                active: 2,
+                queued: 1
+            }
+        );
+
+        let peer = FakePeer::bind([(health::PING, Reply::ok("ping"))]).unwrap();
+        let result = Client::connect(peer.addr()).unwrap().ping();
+        assert!(matches!(result, Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn test_hello() {
+        let peer =
+            FakePeer::bind([(capabilities::HELLO, Reply::ok(&[0, 0, 0, 0b0110][..]))]).unwrap();
+        let mut client = Client::connect(peer.addr()).unwrap();
+        let capabilities = client.hello(Capabilities::FEEDS).unwrap();
+        assert_eq!(
+            capabilities,
+            Capabilities::REPLICATION | Capabilities::PUBSUB
+        );
+        assert_eq!(
+            peer.requests(),
+            vec![(capabilities::HELLO, vec![0, 0, 0, 0b1000])]
+        );
+
+        // Nodes which predate capabilities reply with an unknown command.
+        let peer = FakePeer::bind([(capabilities::HELLO, Reply::status(1))]).unwrap();
+        let result = Client::connect(peer.addr())
+            .unwrap()
+            .hello(Capabilities::NONE);
+        assert_eq!(result.unwrap(), Capabilities::NONE);
+    }
+
+    #[test]
+    fn test_register() {
+        let keypair = Keypair::generate();
+        let profile = format!(
+            r#"{{"handle": "alice", "key": "{}", "registered": 1}}"#,
+            keypair.public()
+        );
+        let peer = FakePeer::bind([
+            (0x000A, Reply::ok(profile.clone())),
+            (0x000B, Reply::ok(profile)),
+        ])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        let registered = client.register("alice", &keypair).unwrap();
+        assert_eq!(registered.key, keypair.public());
+        assert_eq!(client.profile("alice").unwrap(), registered);
+
+        let requests = peer.requests();
+        let payload = String::from_utf8(requests[0].1.clone()).unwrap();
+        let parts: Vec<&str> = payload.split('\x00').collect();
+        assert_eq!(parts[..2], ["alice", keypair.public().as_str()]);
+        assert!(keys::verify_registration(
+            &keypair.public(),
+            "alice",
+            &keys::unhex(parts[2]).unwrap()
+        ));
+        assert_eq!(requests[1], (0x000B, b"alice".to_vec()));
+    }
+
+    #[test]
+    fn test_follow() {
+        let peer = FakePeer::bind([
+            (0x000C, Reply::ok("")),
+            (0x000F, Reply::ok("bob\x00carol@127.0.0.1:8000\x00")),
+            (0x000D, Reply::ok("")),
+            (0x000E, Reply::ok("")),
+        ])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        client.follow("carol@127.0.0.1:8000").unwrap();
+        assert_eq!(
+            client.following("alice").unwrap(),
+            vec!["bob", "carol@127.0.0.1:8000"]
+        );
+        client.unfollow("bob").unwrap();
+        assert!(client.followers("alice").unwrap().is_empty());
+        assert_eq!(
+            peer.requests()[..3],
+            [
+                (0x000C, b"carol@127.0.0.1:8000".to_vec()),
+                (0x000F, b"alice".to_vec()),
+                (0x000D, b"bob".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_journaled() {
+        let peer = FakePeer::bind([
+            (journal::JOURNALED, Reply::ok("01GQ")),
+            (journal::JOURNALED, Reply::status(journal::IN_PROGRESS)),
+        ])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        assert_eq!(client.journaled("1", 0x0001, b"value").unwrap(), b"01GQ");
+        assert!(matches!(
+            client.journaled("2", 0x0001, b"value"),
+            Err(Error::Status(journal::IN_PROGRESS))
+        ));
+        assert_eq!(
+            peer.requests()[0],
+            (
+                journal::JOURNALED,
+                journal::encode("1", 0x0001, b"value").unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_interactions() {
+        let peer = FakePeer::bind([
+            (0x0011, Reply::ok("")),
+            (0x0012, Reply::ok("01GR")),
+            (0x0013, Reply::ok("01GR~alice:reply\x00")),
+        ])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        client.like("01GQ").unwrap();
+        assert_eq!(client.reply("01GQ", b"reply").unwrap(), "01GR");
+        let replies = client.replies("01GQ", 5).unwrap().records;
+        assert_eq!(
+            (replies[0].key.as_str(), replies[0].owner.as_deref()),
+            ("01GR", Some("alice"))
+        );
+        assert_eq!(
+            peer.requests(),
+            vec![
+                (0x0011, b"01GQ".to_vec()),
+                (0x0012, b"01GQ\x00reply".to_vec()),
+                (0x0013, b"01GQ\x005".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_health() {
+        let peer = FakePeer::bind([(
+            0x0014,
+            Reply::ok(
+                r#"[{"addr": "127.0.0.1:1", "samples": [
+                    {"at": 2, "up": true, "rtt": 12},
+                    {"at": 1, "up": false, "error": "Connection refused"}
+                ]}]"#,
+            ),
+        )])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        let reports = client.health(Some("127.0.0.1:1")).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].is_up(), Some(true));
+        assert_eq!(reports[0].uptime(), 0.5);
+        assert_eq!(peer.requests(), vec![(0x0014, b"127.0.0.1:1".to_vec())]);
+    }
+
+    #[test]
+    fn test_changes() {
+        let peer = FakePeer::bind([(
+            changelog::CHANGES,
+            Reply::ok(
+                r#"{"changes": [
+                    {"cursor": 80, "at": 1, "by": "user:alice", "kind": "removed", "key": "key"}
+                ], "next": 150}"#,
+            ),
+        )])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        let page = client.changes(80, 1).unwrap();
+        assert_eq!(page.next, 150);
+        assert_eq!(page.changes[0].event, Event::Removed { key: "key".into() });
+        assert_eq!(
+            peer.requests(),
+            vec![(changelog::CHANGES, b"80\x001".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_anti_entropy() {
+        let peer = FakePeer::bind([
+            (anti_entropy::INVENTORY, Reply::ok("key1\x00key2")),
+            (anti_entropy::REPLICATE, Reply::ok("1")),
+        ])
+        .unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        assert_eq!(client.inventory().unwrap(), vec!["key1", "key2"]);
+        assert_eq!(client.replicate(b"key3:value3\x00").unwrap(), 1);
+        assert_eq!(
+            peer.requests(),
+            vec![
+                (anti_entropy::INVENTORY, vec![]),
+                (anti_entropy::REPLICATE, b"key3:value3\x00".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timed() {
+        let mut reply = codec::encode_response(0, b"key:value\x00").unwrap();
+        reply.extend(br#"{"queue": 10, "storage": 120, "remote": 0, "total": 200}"#);
+        let peer = FakePeer::bind([(timing::TIMED, Reply::ok(reply))]).unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        let (payload, trailer) = client.timed(0x0003, b"key\x00").unwrap();
+        assert_eq!(payload, b"key:value\x00");
+        assert_eq!(
+            trailer,
+            Trailer {
+                queue: 10,
+                storage: 120,
+                remote: 0,
+                total: 200,
+            }
+        );
+        assert_eq!(
+            peer.requests(),
+            vec![(
+                timing::TIMED,
+                codec::encode_request(0x0003, b"key\x00").unwrap()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_within() {
+        let mut reply = codec::encode_response(0, b"key:value\x00").unwrap();
+        reply.push(deadline::EXPIRED);
+        let peer = FakePeer::bind([(deadline::DEADLINE, Reply::ok(reply))]).unwrap();
+
+        let mut client = Client::connect(peer.addr()).unwrap();
+        let budget = Duration::from_millis(250);
+        let (payload, expired) = client.within(budget, 0x0003, b"key\x00").unwrap();
+        assert_eq!((payload, expired), (b"key:value\x00".to_vec(), true));
+        assert_eq!(
+            peer.requests(),
+            vec![(
+                deadline::DEADLINE,
+                deadline::encode(budget, 0x0003, b"key\x00").unwrap()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_upload() {
+        let peer = FakePeer::bind([
+            (0x0015, Reply::ok("01GU")),
+            (0x0016, Reply::ok("4")),
+            (0x0016, Reply::ok("5")),
+            (0x0017, Reply::ok("01GQ")),
+        ])
+        .unwrap();
 
-    Tcp::write(&stream, &buffer).map_err(Error::Io)?;
-    Tcp::read(stream).map_err(Error::Io)
+        let mut client = Client::connect(peer.addr()).unwrap();
+        assert_eq!(client.upload(&b"value"[..], 4).unwrap(), "01GQ");
+        assert_eq!(
+            peer.requests(),
+            vec![
+                (0x0015, vec![]),
+                (0x0016, b"01GU\x00valu".to_vec()),
+                (0x0016, b"01GU\x00e".to_vec()),
+                (0x0017, b"01GU".to_vec()),
+            ]
+        );
+    }
 }
@@ -0,0 +1,138 @@
+//! The client of the admin listener of a node. See [crate::admin].
+
+use std::net::{TcpStream, ToSocketAddrs};
+
+use super::Error;
+use crate::admin::{self, Listed};
+use crate::anti_entropy::Summary;
+use crate::crash::Snapshot;
+use crate::protocol::codec;
+use crate::settings::{Peer, Token};
+use crate::{FrameBuffer, Tcp};
+
+/// A connection to the admin listener of a node.
+pub struct Admin {
+    stream: TcpStream,
+    frames: FrameBuffer,
+}
+
+impl Admin {
+    /// Connects to the admin listener at the given address.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        Ok(Self {
+            stream: TcpStream::connect(addr).map_err(Error::Io)?,
+            frames: FrameBuffer::new(codec::Kind::Response),
+        })
+    }
+
+    /// Lists the nodes acknowledged by the node.
+    pub fn peers(&mut self) -> Result<Vec<Peer>, Error> {
+        let reply = self.request(admin::PEERS, &[])?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// Acknowledges the node until the node is restarted. Nodes which are
+    /// meant to stay acknowledged are added to the settings file as well.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Rejected] if a node with the same address is
+    /// acknowledged already.
+    pub fn add_peer(&mut self, peer: &Peer) -> Result<(), Error> {
+        let payload = serde_json::to_vec(peer).map_err(Error::Json)?;
+        self.request(admin::ADD_PEER, &payload).map(|_| ())
+    }
+
+    /// No longer acknowledges the node at the given address.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Rejected] if no node is acknowledged at the address.
+    pub fn remove_peer(&mut self, addr: &str) -> Result<(), Error> {
+        self.request(admin::REMOVE_PEER, addr.as_bytes())
+            .map(|_| ())
+    }
+
+    /// Requests the counters of the node.
+    pub fn stats(&mut self) -> Result<Snapshot, Error> {
+        let reply = self.request(admin::STATS, &[])?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// Stops the node once the requests in flight have been processed.
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.request(admin::SHUTDOWN, &[]).map(|_| ())
+    }
+
+    /// Reloads the settings of the node from the file they were loaded from.
+    ///
+    /// # Returns
+    ///
+    /// The names of the settings which changed, but only take effect once the
+    /// node is restarted.
+    pub fn reload(&mut self) -> Result<Vec<String>, Error> {
+        let reply = self.request(admin::RELOAD, &[])?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// Lists the tokens of the node, without their secrets.
+    pub fn tokens(&mut self) -> Result<Vec<Listed>, Error> {
+        let reply = self.request(admin::TOKENS, &[])?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// Adds a token, which connections may authenticate with right away. The
+    /// token is written to the settings file of the node as well, if it has
+    /// one.
+    ///
+    /// # Returns
+    ///
+    /// The ID of the token, which is generated unless the token has one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Rejected] if a token with the same ID or the same
+    /// secret exists already.
+    pub fn add_token(&mut self, token: &Token) -> Result<String, Error> {
+        let payload = serde_json::to_vec(token).map_err(Error::Json)?;
+        let reply = self.request(admin::ADD_TOKEN, &payload)?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// Revokes the token with the given ID, which is removed from the settings
+    /// file of the node as well, if it has one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Rejected] if the node has no token with the ID.
+    pub fn revoke_token(&mut self, id: &str) -> Result<(), Error> {
+        self.request(admin::REVOKE_TOKEN, id.as_bytes()).map(|_| ())
+    }
+
+    /// Makes the node reconcile its records with the acknowledged node at the
+    /// given address right away. See [crate::anti_entropy].
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Rejected] if the node does not acknowledge a node at
+    /// the address, or if the exchange failed.
+    pub fn sync_with(&mut self, addr: &str) -> Result<Summary, Error> {
+        let reply = self.request(admin::SYNC_WITH, addr.as_bytes())?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    fn request(&mut self, code: u16, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let buffer = codec::encode_request(code, payload).map_err(Error::Codec)?;
+        Tcp::write(&mut self.stream, &buffer).map_err(Error::Io)?;
+        let frame = Tcp::read_frame(&mut self.stream, &mut self.frames)
+            .map_err(Error::Io)?
+            .ok_or_else(|| Error::Io(std::io::ErrorKind::UnexpectedEof.into()))?;
+        let (response, _) = codec::decode_response(frame).map_err(Error::Codec)?;
+        match response.status {
+            0 => Ok(response.payload.to_vec()),
+            _ => Err(Error::Rejected(
+                String::from_utf8_lossy(response.payload).into(),
+            )),
+        }
+    }
+}
@@ -0,0 +1,517 @@
+//! The async variant of [super::Client], which issues the same requests over a
+//! tokio [TcpStream], so that applications running on tokio can call nodes
+//! without blocking their runtime. Every method mirrors the method of the
+//! blocking client with the same name.
+//!
+//! Connections are made over plain TCP only. Timeouts are left to the caller,
+//! e.g. by wrapping calls in `tokio::time::timeout`.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use super::{
+    check_keys, decode, decode_list, decode_timed, decode_within, encode_keys, encode_post,
+    encode_register, encode_relate, encode_reply, encode_search, encode_tagged, AggregateResponse,
+    CreateResponse, Error, Removal, SdkResult,
+};
+use crate::anti_entropy;
+use crate::capabilities::{self, Capabilities};
+use crate::changelog::{self, Page};
+use crate::events::{self, Event};
+use crate::health::{self, Load, Report};
+use crate::keys::{self, Keypair};
+use crate::placement::{self, Ring};
+use crate::protocol::{codec, Metadata};
+use crate::timing::{self, Trailer};
+use crate::users::Profile;
+use crate::{auth, bulk, deadline, envelope, journal, search, tags};
+
+/// A connection to a single node, over which any number of requests can be
+/// issued one after another. See [super::Client].
+pub struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    /// Connects to the node at the given address.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        let stream = TcpStream::connect(addr).await.map_err(Error::Io)?;
+        Ok(Self { stream })
+    }
+
+    /// See [super::Client::authenticate].
+    pub async fn authenticate(&mut self, secret: &str) -> Result<String, Error> {
+        let reply = self.request(auth::AUTHENTICATE, secret.as_bytes()).await?;
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    /// See [super::Client::handshake].
+    pub async fn handshake(
+        &mut self,
+        keypair: &Keypair,
+        expected: Option<&str>,
+    ) -> Result<String, Error> {
+        let nonce = keys::nonce();
+        let reply = self.request(auth::HANDSHAKE, &nonce).await?;
+        let (public, proof) = keys::prove(keypair, &nonce, &reply).map_err(Error::Keys)?;
+        if expected.is_some_and(|expected| !expected.eq_ignore_ascii_case(&public)) {
+            return Err(Error::Keys(keys::Error::Handshake(
+                "Unexpected key of the node",
+            )));
+        }
+
+        let reply = self.request(auth::HANDSHAKE, &proof).await?;
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    /// See [super::Client::metadata].
+    pub async fn metadata(&mut self) -> Result<Metadata, Error> {
+        let reply = self.request(0x0005, &[]).await?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// See [super::Client::peers].
+    pub async fn peers(&mut self) -> Result<Vec<SocketAddr>, Error> {
+        Ok(self.metadata().await?.peers)
+    }
+
+    /// See [super::Client::ping].
+    pub async fn ping(&mut self) -> Result<Load, Error> {
+        let reply = self.request(health::PING, &[]).await?;
+        Load::decode(&reply).ok_or_else(|| Error::Malformed(String::from_utf8_lossy(&reply).into()))
+    }
+
+    /// See [super::Client::hello].
+    pub async fn hello(&mut self, own: Capabilities) -> Result<Capabilities, Error> {
+        let reply = match self.request(capabilities::HELLO, &own.encode()).await {
+            Ok(reply) => reply,
+            Err(Error::Status(1)) => return Ok(Capabilities::NONE),
+            Err(e) => return Err(e),
+        };
+
+        Capabilities::decode(&reply)
+            .ok_or_else(|| Error::Malformed(String::from_utf8_lossy(&reply).into()))
+    }
+
+    /// See [super::Client::create].
+    pub async fn create(&mut self, value: &[u8]) -> Result<CreateResponse, Error> {
+        let reply = self.request(0x0001, value).await?;
+        Ok(CreateResponse {
+            id: String::from_utf8_lossy(&reply).to_string(),
+        })
+    }
+
+    /// See [super::Client::create_many].
+    pub async fn create_many(&mut self, values: &[&[u8]]) -> Result<Vec<CreateResponse>, Error> {
+        let reply = self
+            .request(bulk::CREATE_MANY, &bulk::encode(values))
+            .await?;
+        Ok(decode_list(&reply)
+            .into_iter()
+            .map(|id| CreateResponse { id })
+            .collect())
+    }
+
+    /// See [super::Client::create_tagged].
+    pub async fn create_tagged(
+        &mut self,
+        tags: &[&str],
+        value: &[u8],
+    ) -> Result<CreateResponse, Error> {
+        let reply = self
+            .request(tags::CREATE, &tags::encode_create(tags, value))
+            .await?;
+        Ok(CreateResponse {
+            id: String::from_utf8_lossy(&reply).to_string(),
+        })
+    }
+
+    /// See [super::Client::tagged].
+    pub async fn tagged(&mut self, tags: &[&str], all: bool) -> Result<Vec<String>, Error> {
+        let reply = self.request(tags::QUERY, &encode_tagged(tags, all)).await?;
+        Ok(decode_list(&reply))
+    }
+
+    /// See [super::Client::search].
+    pub async fn search(&mut self, text: &str) -> Result<Vec<String>, Error> {
+        let reply = self
+            .request(search::SEARCH, encode_search(text).as_bytes())
+            .await?;
+        Ok(decode_list(&reply))
+    }
+
+    /// See [super::Client::remove].
+    pub async fn remove(&mut self, keys: &[&str]) -> Result<Vec<Removal>, Error> {
+        let reply = self.request(0x0002, &encode_keys(keys)).await?;
+        Removal::decode(keys, &reply)
+    }
+
+    /// See [super::Client::create_envelope].
+    pub async fn create_envelope(
+        &mut self,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<CreateResponse, Error> {
+        let payload = [content_type.as_bytes(), &[00], body].concat();
+        let reply = self.request(envelope::CREATE, &payload).await?;
+        Ok(CreateResponse {
+            id: String::from_utf8_lossy(&reply).to_string(),
+        })
+    }
+
+    /// See [super::Client::aggregate].
+    pub async fn aggregate(&mut self, key: &str) -> Result<AggregateResponse, Error> {
+        self.aggregate_many(&[key]).await
+    }
+
+    /// See [super::Client::aggregate_many].
+    pub async fn aggregate_many(&mut self, keys: &[&str]) -> Result<AggregateResponse, Error> {
+        check_keys(keys)?;
+        let reply = self.request(0x0003, &encode_keys(keys)).await?;
+        AggregateResponse::decode(&reply)
+    }
+
+    /// See [super::Client::view].
+    pub async fn view(&mut self, key: &str, view: &str) -> Result<AggregateResponse, Error> {
+        let buffer = format!("{}{}\x00{}\x00", crate::views::PREFIX, view, key);
+        AggregateResponse::decode(&self.request(0x0003, buffer.as_bytes()).await?)
+    }
+
+    /// See [super::Client::quorum].
+    pub async fn quorum(&mut self, key: &str, reads: usize) -> Result<AggregateResponse, Error> {
+        let buffer = format!("{}{}\x00{}\x00", crate::quorum::PREFIX, reads, key);
+        AggregateResponse::decode(&self.request(0x0003, buffer.as_bytes()).await?)
+    }
+
+    /// See [super::Client::post].
+    pub async fn post(&mut self, feed: &str, keys: &[&str]) -> Result<(), Error> {
+        self.request(0x0007, &encode_post(feed, keys))
+            .await
+            .map(|_| ())
+    }
+
+    /// See [super::Client::feed].
+    pub async fn feed(&mut self, feed: &str, count: usize) -> Result<AggregateResponse, Error> {
+        let buffer = format!("{}\x00{}", feed, count);
+        AggregateResponse::decode(&self.request(0x0008, buffer.as_bytes()).await?)
+    }
+
+    /// See [super::Client::register].
+    pub async fn register(&mut self, handle: &str, keypair: &Keypair) -> Result<Profile, Error> {
+        let buffer = encode_register(handle, keypair);
+        let reply = self.request(0x000A, buffer.as_bytes()).await?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// See [super::Client::profile].
+    pub async fn profile(&mut self, handle: &str) -> Result<Profile, Error> {
+        let reply = self.request(0x000B, handle.as_bytes()).await?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// See [super::Client::like].
+    pub async fn like(&mut self, key: &str) -> Result<(), Error> {
+        self.request(0x0011, key.as_bytes()).await.map(|_| ())
+    }
+
+    /// See [super::Client::reply].
+    pub async fn reply(&mut self, key: &str, value: &[u8]) -> Result<String, Error> {
+        let reply = self.request(0x0012, &encode_reply(key, value)).await?;
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    /// See [super::Client::replies].
+    pub async fn replies(&mut self, key: &str, count: usize) -> Result<AggregateResponse, Error> {
+        let buffer = format!("{}\x00{}", key, count);
+        AggregateResponse::decode(&self.request(0x0013, buffer.as_bytes()).await?)
+    }
+
+    /// See [super::Client::follow].
+    pub async fn follow(&mut self, actor: &str) -> Result<(), Error> {
+        self.request(0x000C, &encode_relate(actor, None))
+            .await
+            .map(|_| ())
+    }
+
+    /// See [super::Client::unfollow].
+    pub async fn unfollow(&mut self, actor: &str) -> Result<(), Error> {
+        self.request(0x000D, &encode_relate(actor, None))
+            .await
+            .map(|_| ())
+    }
+
+    /// See [super::Client::followers].
+    pub async fn followers(&mut self, handle: &str) -> Result<Vec<String>, Error> {
+        let reply = self.request(0x000E, handle.as_bytes()).await?;
+        Ok(decode_list(&reply))
+    }
+
+    /// See [super::Client::following].
+    pub async fn following(&mut self, handle: &str) -> Result<Vec<String>, Error> {
+        let reply = self.request(0x000F, handle.as_bytes()).await?;
+        Ok(decode_list(&reply))
+    }
+
+    /// See [super::Client::batch].
+    pub async fn batch(&mut self, requests: &[(u16, &[u8])]) -> Result<Vec<(u8, Vec<u8>)>, Error> {
+        let payload = codec::encode_batch(requests.iter().copied()).map_err(Error::Codec)?;
+        let reply = self.request(codec::BATCH, &payload).await?;
+        let responses = codec::decode_batch_response(&reply).map_err(Error::Codec)?;
+        Ok(responses
+            .into_iter()
+            .map(|response| (response.status, response.payload.to_vec()))
+            .collect())
+    }
+
+    /// See [super::Client::health].
+    pub async fn health(&mut self, addr: Option<&str>) -> Result<Vec<Report>, Error> {
+        let reply = self
+            .request(0x0014, addr.unwrap_or_default().as_bytes())
+            .await?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// See [super::Client::changes].
+    pub async fn changes(&mut self, cursor: u64, count: usize) -> Result<Page, Error> {
+        let buffer = format!("{}\x00{}", cursor, count);
+        let reply = self.request(changelog::CHANGES, buffer.as_bytes()).await?;
+        serde_json::from_slice(&reply).map_err(Error::Json)
+    }
+
+    /// See [super::Client::inventory].
+    pub async fn inventory(&mut self) -> Result<Vec<String>, Error> {
+        let reply = self.request(anti_entropy::INVENTORY, &[]).await?;
+        Ok(decode_list(&reply))
+    }
+
+    /// See [super::Client::replicate].
+    pub async fn replicate(&mut self, entries: &[u8]) -> Result<usize, Error> {
+        let reply = self.request(anti_entropy::REPLICATE, entries).await?;
+        Ok(String::from_utf8_lossy(&reply).parse().unwrap_or_default())
+    }
+
+    /// See [super::Client::ring].
+    pub async fn ring(&mut self) -> Result<Ring, Error> {
+        let reply = self.request(placement::RING, &[]).await?;
+        let nodes: Vec<SocketAddr> = serde_json::from_slice(&reply).map_err(Error::Json)?;
+        Ok(Ring::new(nodes))
+    }
+
+    /// See [super::Client::journaled].
+    pub async fn journaled(&mut self, id: &str, code: u16, payload: &[u8]) -> SdkResult {
+        let payload = journal::encode(id, code, payload).map_err(Error::Codec)?;
+        self.request(journal::JOURNALED, &payload).await
+    }
+
+    /// See [super::Client::timed].
+    pub async fn timed(&mut self, code: u16, payload: &[u8]) -> Result<(Vec<u8>, Trailer), Error> {
+        let payload = codec::encode_request(code, payload).map_err(Error::Codec)?;
+        let reply = self.request(timing::TIMED, &payload).await?;
+        decode_timed(&reply)
+    }
+
+    /// See [super::Client::within].
+    pub async fn within(
+        &mut self,
+        budget: Duration,
+        code: u16,
+        payload: &[u8],
+    ) -> Result<(Vec<u8>, bool), Error> {
+        let payload = deadline::encode(budget, code, payload).map_err(Error::Codec)?;
+        let reply = self.request(deadline::DEADLINE, &payload).await?;
+        decode_within(&reply)
+    }
+
+    /// See [super::Client::upload].
+    pub async fn upload<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: R,
+        chunk_len: usize,
+    ) -> Result<String, Error> {
+        let reply = self.request(0x0015, &[]).await?;
+        let id = String::from_utf8_lossy(&reply).to_string();
+
+        let chunk_len = chunk_len.clamp(1, codec::MAX_PAYLOAD_LEN - id.len() - 1);
+        let mut reader = reader.take(0);
+        let mut buffer = Vec::with_capacity(id.len() + 1 + chunk_len);
+        loop {
+            buffer.clear();
+            buffer.extend_from_slice(id.as_bytes());
+            buffer.push(00);
+
+            reader.set_limit(chunk_len as u64);
+            if reader.read_to_end(&mut buffer).await.map_err(Error::Io)? == 0 {
+                break;
+            }
+
+            self.request(0x0016, &buffer).await?;
+        }
+
+        let reply = self.request(0x0017, id.as_bytes()).await?;
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    /// See [super::Client::subscribe].
+    pub async fn subscribe(mut self, topics: &[&str]) -> Result<Subscription, Error> {
+        self.request(events::SUBSCRIBE, topics.join("\x00").as_bytes())
+            .await?;
+        Ok(Subscription { client: self })
+    }
+
+    /// Sends a single request over the connection and waits for its response.
+    async fn request(&mut self, code: u16, payload: &[u8]) -> SdkResult {
+        let buffer = codec::encode_request(code, payload).map_err(Error::Codec)?;
+        self.stream.write_all(&buffer).await.map_err(Error::Io)?;
+        self.stream.flush().await.map_err(Error::Io)?;
+        self.response()
+            .await?
+            .ok_or_else(|| Error::Io(std::io::ErrorKind::UnexpectedEof.into()))
+    }
+
+    /// Reads the next response from the connection, or [None] if the node has
+    /// closed the connection before a whole frame was received.
+    async fn response(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut frame = vec![0; codec::RESPONSE_HEADER_LEN];
+        if !self.read(&mut frame).await? {
+            return Ok(None);
+        }
+
+        let len = codec::frame_len(codec::Kind::Response, &frame)
+            .map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+        frame.resize(len, 0);
+        match self.read(&mut frame[codec::RESPONSE_HEADER_LEN..]).await? {
+            true => decode(&frame).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    /// Fills the buffer from the connection.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the connection was closed before the buffer was filled.
+    async fn read(&mut self, buffer: &mut [u8]) -> Result<bool, Error> {
+        match self.stream.read_exact(buffer).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+}
+
+/// A connection subscribed to events, which yields the events as the node
+/// pushes them until the connection is closed. See [Client::subscribe].
+pub struct Subscription {
+    client: Client,
+}
+
+impl Subscription {
+    /// Waits for the next event, or returns [None] once the connection is
+    /// closed.
+    pub async fn next(&mut self) -> Option<Result<Event, Error>> {
+        loop {
+            match self.client.response().await {
+                // Keepalives carry no event.
+                Ok(Some(payload)) if payload.is_empty() => continue,
+                Ok(Some(payload)) => {
+                    return Some(serde_json::from_slice(&payload).map_err(Error::Json))
+                }
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// See [super::aggregate].
+pub async fn aggregate(addr: String, key: String) -> Result<AggregateResponse, Error> {
+    Client::connect(addr).await?.aggregate(&key).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePeer, Reply};
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_requests() {
+        let peer = FakePeer::bind([
+            (auth::AUTHENTICATE, Reply::ok("subject:frontend")),
+            (0x0007, Reply::ok("")),
+            (0x0008, Reply::ok("key2:second\x00key1:first\x00")),
+            (0x000E, Reply::ok("bob\x00carol@127.0.0.1:1")),
+            (0x0003, Reply::status(1)),
+        ])
+        .unwrap();
+
+        block_on(async {
+            let mut client = Client::connect(peer.addr()).await.unwrap();
+            assert_eq!(
+                client.authenticate("s3cr3t").await.unwrap(),
+                "subject:frontend"
+            );
+            client.post("news", &["key1", "key2"]).await.unwrap();
+            let feed = client.feed("news", 2).await.unwrap();
+            assert_eq!(feed.records[0].key, "key2");
+            assert_eq!(feed.records[1].value, b"first");
+            assert_eq!(
+                client.followers("alice").await.unwrap(),
+                vec!["bob", "carol@127.0.0.1:1"]
+            );
+            assert!(matches!(
+                client.aggregate("key").await,
+                Err(Error::Status(1))
+            ));
+        });
+
+        assert_eq!(
+            peer.requests(),
+            vec![
+                (auth::AUTHENTICATE, b"s3cr3t".to_vec()),
+                (0x0007, b"news\x00key1\x00key2".to_vec()),
+                (0x0008, b"news\x002".to_vec()),
+                (0x000E, b"alice".to_vec()),
+                (0x0003, b"key\x00".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncated_reply() {
+        // The header announces 16 bytes of payload, but only 2 are sent.
+        let peer = FakePeer::bind([(0x0003, Reply::Raw(vec![0, 0, 0, 0, 16, 1, 2]))]).unwrap();
+        let result = block_on(aggregate(peer.addr().to_string(), "key".into()));
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut frames = codec::encode_response(0, &[]).unwrap();
+        for payload in [&b""[..], br#"{"kind":"created","key":"key"}"#] {
+            frames.extend(codec::encode_response(0, payload).unwrap());
+        }
+
+        let peer = FakePeer::bind([(events::SUBSCRIBE, Reply::Raw(frames))]).unwrap();
+        let events = block_on(async {
+            let client = Client::connect(peer.addr()).await.unwrap();
+            let mut subscription = client.subscribe(&[]).await.unwrap();
+            let mut events = vec![];
+            while let Some(event) = subscription.next().await {
+                events.push(event.unwrap());
+            }
+
+            events
+        });
+        assert_eq!(events, vec![Event::Created { key: "key".into() }]);
+    }
+}
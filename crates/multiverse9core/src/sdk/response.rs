@@ -0,0 +1,205 @@
+//! Typed responses of the client, decoded from the payloads the node replies
+//! with.
+
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+use crate::api;
+use crate::envelope::{self, Envelope};
+
+/// The reply of a node to a probe with [super::Client::ping_timeout].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pong {
+    /// How long the probe took, from writing the request to reading the reply.
+    pub rtt: std::time::Duration,
+    /// The load of the node when it replied.
+    pub load: crate::health::Load,
+}
+
+/// The response to creating a record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateResponse {
+    /// The key of the record, either a ULID or the hash of its value depending
+    /// on how the node keys its records.
+    pub id: String,
+}
+
+/// A record as it is carried by an aggregated entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub key: String,
+    /// The handle of the user who created the record.
+    pub owner: Option<String>,
+    /// The number of distinct identities which liked the record.
+    pub likes: usize,
+    /// The number of direct replies to the record.
+    pub replies: usize,
+    /// The signature of the node which created the record, if the node signs
+    /// its records and the entry was not verified on the way.
+    pub signature: Option<Vec<u8>>,
+    pub value: Vec<u8>,
+}
+
+impl Record {
+    /// Decodes a single entry, `key[~owner][+likes,replies][#signature]:value`,
+    /// without its trailing null byte. See [api::internal::push_entry].
+    ///
+    /// # Returns
+    ///
+    /// [None] if the entry has no value, or if its signature is not valid hex.
+    pub(crate) fn decode(entry: &[u8]) -> Option<Self> {
+        let split = entry.iter().position(|c| *c == b':')?;
+        let (head, value) = (
+            String::from_utf8_lossy(&entry[..split]),
+            &entry[split + 1..],
+        );
+        let (head, signature) = match head.split_once(api::SIGNATURE_DELIMITER as char) {
+            Some((head, signature)) => (head, Some(crate::keys::unhex(signature)?)),
+            None => (&*head, None),
+        };
+
+        let (head, likes, replies) = match head.split_once(api::INTERACTIONS_DELIMITER as char) {
+            Some((head, interactions)) => {
+                let (likes, replies) = interactions.split_once(',').unwrap_or((interactions, ""));
+                (
+                    head,
+                    likes.parse().unwrap_or_default(),
+                    replies.parse().unwrap_or_default(),
+                )
+            }
+            None => (head, 0, 0),
+        };
+
+        let (key, owner) = match head.split_once(api::OWNER_DELIMITER as char) {
+            Some((key, owner)) => (key, Some(owner.to_string())),
+            None => (head, None),
+        };
+
+        Some(Self {
+            key: key.to_string(),
+            owner,
+            likes,
+            replies,
+            signature,
+            value: value.to_vec(),
+        })
+    }
+
+    /// Decodes the envelope the value of the record is wrapped in, or returns
+    /// [None] if the value is a raw blob. See [crate::envelope].
+    pub fn envelope(&self) -> Result<Option<Envelope>, envelope::Error> {
+        Envelope::decode(&self.value)
+    }
+
+    /// Returns whether the entry stands for a key the node does not know, see
+    /// [api::UNKNOWN_KEY].
+    pub(crate) fn is_unknown(&self) -> bool {
+        self.owner.is_none() && self.signature.is_none() && self.value == api::UNKNOWN_KEY
+    }
+}
+
+/// The response to aggregating keys, which is also the response to reading
+/// feeds and replies.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AggregateResponse {
+    /// The records in the order the node returned them.
+    pub records: Vec<Record>,
+    /// The keys the node does not know.
+    pub unknown: Vec<String>,
+}
+
+impl AggregateResponse {
+    /// Decodes the entries of a reply, each of which is followed by a null byte.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Malformed] if any of the entries cannot be decoded.
+    pub fn decode(entries: &[u8]) -> Result<Self, Error> {
+        let mut response = Self::default();
+        for entry in entries
+            .split(|c| *c == 00)
+            .filter(|entry| !entry.is_empty())
+        {
+            match Record::decode(entry) {
+                Some(record) if record.is_unknown() => response.unknown.push(record.key),
+                Some(record) => response.records.push(record),
+                None => return Err(Error::Malformed(String::from_utf8_lossy(entry).to_string())),
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+/// The outcome of removing a key, as reported by the node the removal was
+/// requested on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Removal {
+    /// The key as it was given, along with the address of the node it was
+    /// removed from if it was not removed from the node itself.
+    pub key: String,
+    pub removed: bool,
+    /// Why the key was not removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Removal {
+    /// Decodes the reply to removing the given keys. Nodes which predate
+    /// remote removals reply without a report once every key is removed.
+    pub fn decode(keys: &[&str], reply: &[u8]) -> Result<Vec<Self>, Error> {
+        if reply.is_empty() {
+            return Ok(keys
+                .iter()
+                .map(|key| Self {
+                    key: key.to_string(),
+                    removed: true,
+                    error: None,
+                })
+                .collect());
+        }
+
+        serde_json::from_slice(reply).map_err(Error::Json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let entries = b"01ARZ3NDEKTSV4RRFFQ69G5FAV~alice+2,1#abcd:value:1\x00\
+                        01ARZ3NDEKTSV4RRFFQ69G5FAW:Unknown key\x00\
+                        01ARZ3NDEKTSV4RRFFQ69G5FAY:\x00";
+        assert_eq!(
+            AggregateResponse::decode(entries).unwrap(),
+            AggregateResponse {
+                records: vec![
+                    Record {
+                        key: "01ARZ3NDEKTSV4RRFFQ69G5FAV".into(),
+                        owner: Some("alice".into()),
+                        likes: 2,
+                        replies: 1,
+                        signature: Some(vec![0xab, 0xcd]),
+                        value: b"value:1".to_vec(),
+                    },
+                    Record {
+                        key: "01ARZ3NDEKTSV4RRFFQ69G5FAY".into(),
+                        owner: None,
+                        likes: 0,
+                        replies: 0,
+                        signature: None,
+                        value: vec![],
+                    },
+                ],
+                unknown: vec!["01ARZ3NDEKTSV4RRFFQ69G5FAW".into()],
+            }
+        );
+
+        let result = AggregateResponse::decode(b"01ARZ3NDEKTSV4RRFFQ69G5FAV#zz:value\x00");
+        assert!(matches!(result, Err(Error::Malformed(_))));
+        let result = AggregateResponse::decode(b"01ARZ3NDEKTSV4RRFFQ69G5FAV\x00");
+        assert!(matches!(result, Err(Error::Malformed(_))));
+    }
+}
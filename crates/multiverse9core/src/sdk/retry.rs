@@ -0,0 +1,171 @@
+//! Retry policies of the blocking [Client](super::Client). Requests which fail
+//! with an error the policy classifies as retryable are issued again over a
+//! new connection to the same node, after waiting for an exponentially growing
+//! backoff with random jitter, until the policy runs out of attempts.
+//!
+//! Requests may be executed by the node before the connection goes away, so a
+//! retried request may be executed twice. Requests which must be executed once
+//! are issued through [Client::journaled](super::Client::journaled), which the
+//! node deduplicates.
+
+use rand_core::{OsRng, RngCore};
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use super::Error;
+use crate::{journal, shutdown};
+
+/// How failed requests are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    /// The number of attempts, including the first one. Retries are disabled
+    /// with `1`.
+    pub attempts: u32,
+    /// The wait before the first retry, which doubles with every further one.
+    pub backoff: Duration,
+    /// The longest wait between two attempts.
+    pub max_backoff: Duration,
+    /// The fraction of each wait, between `0` and `1`, which is drawn at
+    /// random, so that clients which failed together do not retry together.
+    pub jitter: f64,
+    /// Classifies the errors which are worth retrying, [is_transient] by
+    /// default.
+    pub retryable: fn(&Error) -> bool,
+}
+
+impl Retry {
+    /// A policy which never retries, which clients follow unless they are
+    /// given another one.
+    pub fn never() -> Self {
+        Self {
+            attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Returns how long to wait after the given number of failed attempts.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let backoff = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.max_backoff);
+        let jitter = self.jitter.clamp(0.0, 1.0) * (OsRng.next_u32() as f64 / u32::MAX as f64);
+        backoff.mul_f64(1.0 - jitter)
+    }
+
+    /// Calls the function until it succeeds, fails with an error which is not
+    /// retryable, or the policy runs out of attempts. The function is passed
+    /// the number of the attempt, starting at `1`.
+    pub fn run<T>(&self, mut f: impl FnMut(u32) -> Result<T, Error>) -> Result<T, Error> {
+        let mut attempt = 1;
+        loop {
+            match f(attempt) {
+                Err(e) if self.retries(attempt, &e) => {
+                    std::thread::sleep(self.delay(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Returns whether the given attempt, which failed with the error, is
+    /// followed by another one.
+    pub(crate) fn retries(&self, attempt: u32, error: &Error) -> bool {
+        attempt < self.attempts && (self.retryable)(error)
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            jitter: 0.5,
+            retryable: is_transient,
+        }
+    }
+}
+
+/// Returns whether the error is likely to go away on its own: connections which
+/// were refused, reset or timed out, journaled requests which are still in
+/// progress, and nodes which are shutting down.
+pub fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::Io(e) => matches!(
+            e.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::WouldBlock
+                | ErrorKind::Interrupted
+                | ErrorKind::UnexpectedEof
+        ),
+        Error::Status(status) => [journal::IN_PROGRESS, shutdown::SHUTTING_DOWN].contains(status),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let mut retry = Retry {
+            attempts: 5,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(30),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let delays: Vec<_> = (1..=4).map(|failures| retry.delay(failures)).collect();
+        assert_eq!(delays, [10, 20, 30, 30].map(Duration::from_millis).to_vec());
+
+        retry.jitter = 0.5;
+        for _ in 0..100 {
+            let delay = retry.delay(1);
+            assert!(delay >= Duration::from_millis(5) && delay <= Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let retry = Retry {
+            backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let refused = || Error::Io(ErrorKind::ConnectionRefused.into());
+
+        let mut attempts = vec![];
+        let result = retry.run(|attempt| {
+            attempts.push(attempt);
+            match attempt {
+                1 => Err(refused()),
+                _ => Ok(attempt),
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(attempts, vec![1, 2]);
+
+        let mut calls = 0;
+        let result: Result<(), _> = retry.run(|_| {
+            calls += 1;
+            Err(refused())
+        });
+        assert!(matches!(result, Err(Error::Io(_))));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: Result<(), _> = retry.run(|_| {
+            calls += 1;
+            Err(Error::Status(1))
+        });
+        assert!(matches!(result, Err(Error::Status(1))));
+        assert_eq!(calls, 1);
+    }
+}
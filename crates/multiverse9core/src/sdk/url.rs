@@ -0,0 +1,229 @@
+//! Connection URLs of the [Client](super::Client), which configure a client
+//! the way `redis_uri` configures the storage of a node, such as
+//! `multiverse9://127.0.0.1:7890?timeout=5s&tls=true`.
+//!
+//! The options of the query are all optional:
+//!
+//! * `timeout` - How long connecting, and then any single read or write, may
+//!   take, in `ms`, `s` or `m`, or in seconds without a unit.
+//! * `tls` - Whether the connection must be made over TLS, `false` by default.
+//!   TLS is configured by the [Connector](super::Connector) the URL connects
+//!   with, which the URL cannot provide itself.
+//! * `nodelay` - Whether `TCP_NODELAY` is set on the connection.
+//! * `retries` - How often failed requests are retried, see [super::retry].
+//! * `token` - The secret of a token the connection authenticates with.
+
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::{Client, Connector, Error, Retry};
+use crate::settings::Socket;
+
+/// The scheme of connection URLs.
+pub const SCHEME: &str = "multiverse9://";
+
+/// A parsed connection URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    /// The host and the port of the node, such as `127.0.0.1:7890`.
+    pub addr: String,
+    pub timeout: Option<Duration>,
+    pub tls: bool,
+    pub nodelay: bool,
+    pub retries: u32,
+    pub token: Option<String>,
+}
+
+impl Url {
+    /// Connects to the node of the URL with the connector, applying the options
+    /// of the URL.
+    ///
+    /// # Errors
+    ///
+    /// Returns [Error::Rejected] if the URL requires TLS, but the connector
+    /// does not connect over TLS.
+    pub fn connect(&self, connector: Connector) -> Result<Client, Error> {
+        if self.tls && !secure(&connector) {
+            return Err(Error::Rejected(
+                "The URL requires TLS, which the connector does not provide".into(),
+            ));
+        }
+
+        let connector = match self.nodelay {
+            true => connector.with_socket(Socket {
+                nodelay: true,
+                ..Default::default()
+            }),
+            false => connector,
+        };
+
+        let mut client = match self.timeout {
+            Some(timeout) => {
+                let addr = self.addr.to_socket_addrs().map_err(Error::Io)?.next();
+                let addr = addr
+                    .ok_or_else(|| Error::Rejected(format!("{:?} does not resolve", self.addr)))?;
+                connector.connect_timeout(&addr, timeout)?
+            }
+            None => connector.connect(self.addr.as_str())?,
+        };
+
+        if self.retries > 0 {
+            client = client.with_retry(Retry {
+                attempts: self.retries + 1,
+                ..Default::default()
+            });
+        }
+
+        if let Some(token) = &self.token {
+            client.authenticate(token)?;
+        }
+
+        Ok(client)
+    }
+}
+
+/// Returns whether the connector connects over TLS.
+fn secure(connector: &Connector) -> bool {
+    match connector {
+        Connector::Plain => false,
+        #[cfg(feature = "tls")]
+        Connector::Tls(_) => true,
+        Connector::Tuned(connector, _) => secure(connector),
+    }
+}
+
+impl FromStr for Url {
+    type Err = Error;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let rejected = |reason: &str| Error::Rejected(format!("{} in URL {:?}", reason, url));
+        let rest = url
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| rejected("Expected the scheme"))?;
+        let (addr, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let addr = addr.trim_end_matches('/');
+        if addr.is_empty() || addr.contains('/') || !addr.contains(':') {
+            return Err(rejected("Expected a host and a port"));
+        }
+
+        let mut parsed = Self {
+            addr: addr.into(),
+            timeout: None,
+            tls: false,
+            nodelay: false,
+            retries: 0,
+            token: None,
+        };
+
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, "true"));
+            match name {
+                "timeout" => {
+                    let timeout = duration(value).ok_or_else(|| rejected("Invalid timeout"))?;
+                    parsed.timeout = Some(timeout);
+                }
+                "tls" => parsed.tls = value.parse().map_err(|_| rejected("Invalid tls"))?,
+                "nodelay" => {
+                    parsed.nodelay = value.parse().map_err(|_| rejected("Invalid nodelay"))?
+                }
+                "retries" => {
+                    parsed.retries = value.parse().map_err(|_| rejected("Invalid retries"))?
+                }
+                "token" => parsed.token = Some(value.into()),
+                _ => return Err(rejected(&format!("Unknown option {:?}", name))),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for Url {
+    /// Formats the URL without its token, so that it can be logged.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}?tls={}", SCHEME, self.addr, self.tls)?;
+        if let Some(timeout) = self.timeout {
+            write!(f, "&timeout={}ms", timeout.as_millis())?;
+        }
+        if self.nodelay {
+            write!(f, "&nodelay=true")?;
+        }
+        if self.retries > 0 {
+            write!(f, "&retries={}", self.retries)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a duration such as `250ms`, `5s` or `1m`, taking seconds if there is
+/// no unit.
+fn duration(value: &str) -> Option<Duration> {
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let number: u64 = number.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "s" | "" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number * 60)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_parse() {
+        let url: Url = "multiverse9://127.0.0.1:7890?timeout=5s&tls=true&retries=2"
+            .parse()
+            .unwrap();
+        assert_eq!(url.addr, "127.0.0.1:7890");
+        assert_eq!(url.timeout, Some(Duration::from_secs(5)));
+        assert!(url.tls && !url.nodelay);
+        assert_eq!(url.retries, 2);
+        assert_eq!(url.to_string().parse::<Url>().unwrap(), url);
+
+        let url: Url = "multiverse9://localhost:7890/".parse().unwrap();
+        assert_eq!((url.addr.as_str(), url.timeout), ("localhost:7890", None));
+        assert_eq!(duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(duration("7"), Some(Duration::from_secs(7)));
+
+        for invalid in [
+            "redis://127.0.0.1:7890",
+            "multiverse9://127.0.0.1",
+            "multiverse9://127.0.0.1:7890?timeout=5h",
+            "multiverse9://127.0.0.1:7890?tls=yes",
+            "multiverse9://127.0.0.1:7890?unknown=1",
+        ] {
+            assert!(matches!(invalid.parse::<Url>(), Err(Error::Rejected(_))));
+        }
+    }
+
+    #[test]
+    fn test_connect() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let addr = local.addr().unwrap();
+        let url = format!("{}{}?timeout=1s&nodelay&retries=1", SCHEME, addr);
+        let mut client = Client::open(&url).unwrap();
+        assert!(client.ping().is_ok());
+
+        let url: Url = format!("{}{}?tls=true", SCHEME, addr).parse().unwrap();
+        let connected = url.connect(Connector::Plain);
+        assert!(matches!(connected, Err(Error::Rejected(_))));
+    }
+}
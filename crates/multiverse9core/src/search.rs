@@ -0,0 +1,130 @@
+//! Full-text search finds the records of current node by the words they hold,
+//! for building search interfaces over the content of an instance without an
+//! external search system. Nodes which enable it in their settings keep an
+//! inverted index, in which every word indexes the keys of its records in a
+//! set of its own.
+//!
+//! Records are indexed as they are created on current node or replicated to
+//! it, except for uploads which are committed without being read. Only values
+//! which are valid UTF-8 are indexed, and envelopes by their body, see
+//! [crate::envelope]. Words are the runs of letters and digits of the value,
+//! lowercased, and a record is indexed by no more than [MAX_TERMS] of them.
+//! Removing a record removes it from the index.
+//!
+//! The payload of a [SEARCH] request is the text to search for, and may be
+//! preceded by the options of [crate::pagination]. Its response is the keys of
+//! the records which hold every word of the text, the way a listing returns
+//! them, see [crate::listing]. The text holds no more than [MAX_QUERY_TERMS]
+//! words.
+
+/// Request code for searching the records of a node by the words they hold.
+pub const SEARCH: u16 = 0x002F;
+
+/// The most words a record is indexed by.
+pub const MAX_TERMS: usize = 1024;
+
+/// The most words the text of a [SEARCH] request holds.
+pub const MAX_QUERY_TERMS: usize = 16;
+
+/// The longest word which is indexed, in bytes. Longer runs are rarely words,
+/// and are left out.
+pub const MAX_TERM_LEN: usize = 64;
+
+/// Splits the text into its distinct words, lowercased, in the order they
+/// first appear in.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut terms: Vec<String> = vec![];
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        if word.is_empty() || word.len() > MAX_TERM_LEN {
+            continue;
+        }
+
+        let term = word.to_lowercase();
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+
+    terms
+}
+
+/// Returns the words the value of a record is indexed by.
+pub fn terms(value: &[u8]) -> Vec<String> {
+    let body = match crate::envelope::Envelope::decode(value) {
+        Ok(Some(envelope)) => envelope.body,
+        _ => value.to_vec(),
+    };
+
+    match std::str::from_utf8(&body) {
+        Ok(text) => {
+            let mut terms = tokenize(text);
+            terms.truncate(MAX_TERMS);
+            terms
+        }
+        Err(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Envelope;
+    use crate::node::Node;
+    use crate::settings::Settings;
+    use std::time::Duration;
+
+    #[test]
+    fn test_terms() {
+        assert_eq!(
+            tokenize("Hello, world! hello-World 42"),
+            vec!["hello", "world", "42"]
+        );
+        assert!(tokenize(" ,.! ").is_empty());
+        assert!(tokenize(&"a".repeat(MAX_TERM_LEN + 1)).is_empty());
+        assert!(terms(b"\xFF\xFEbinary").is_empty());
+
+        let envelope = Envelope::new("text/plain", b"Inside an envelope");
+        assert_eq!(
+            terms(&envelope.encode().unwrap()),
+            vec!["inside", "an", "envelope"]
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        settings.search = true;
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        let both = client.create(b"Rust news of the week").unwrap().id;
+        let news = client.create(b"Other news").unwrap().id;
+        let bulk = client
+            .create_many(&[&b"rust, again"[..]])
+            .unwrap()
+            .remove(0)
+            .id;
+
+        let mut rust = vec![both.clone(), bulk.clone()];
+        rust.sort();
+        assert_eq!(client.search("rust").unwrap(), rust);
+        assert_eq!(client.search("NEWS rust").unwrap(), vec![both.clone()]);
+        assert!(client.search("nothing").unwrap().is_empty());
+        assert!(client.search("!?").is_err());
+
+        let (page, next) = client.search_page("rust", None, 1).unwrap();
+        assert_eq!(page, vec![rust[0].clone()]);
+        let (page, _) = client.search_page("rust", next.as_deref(), 1).unwrap();
+        assert_eq!(page, vec![rust[1].clone()]);
+
+        // Removed records are no longer found by their words.
+        client.remove(&[&both]).unwrap();
+        assert_eq!(client.search("news").unwrap(), vec![news]);
+    }
+}
@@ -0,0 +1,280 @@
+//! Seeds ease bootstrapping nodes in environments where the addresses of
+//! nodes are not known up front, but a DNS name resolving to some of them is.
+//! Once the node has started, every name in [Settings::seeds] is resolved, and
+//! the nodes which respond at the resolved addresses are acknowledged as peers
+//! of current node for as long as it runs.
+//!
+//! Seeds are asked to prove their identity with the signed handshake, and are
+//! pinned by the key they proved. Seeds which do not acknowledge current node
+//! in turn still prove their key before refusing the handshake, so they are
+//! acknowledged all the same. Seeds without a keypair are acknowledged by their
+//! address alone.
+
+use log::*;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::auth;
+use crate::keys::{self, Keypair};
+use crate::node::Node;
+use crate::sdk;
+use crate::settings::{Peer, Settings};
+
+/// Resolves the seeds into the addresses of the nodes behind them, in the
+/// order they were resolved in. Seeds which cannot be resolved are skipped.
+pub fn resolve(seeds: &[String]) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = vec![];
+    for seed in seeds {
+        match seed.to_socket_addrs() {
+            Ok(resolved) => {
+                for addr in resolved {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            }
+
+            Err(e) => warn!("Could not resolve the seed {}: {}", seed, e),
+        }
+    }
+
+    addrs
+}
+
+/// Spawns the thread which acknowledges the nodes behind the seeds of the
+/// node listening at the given address. Nothing is spawned without seeds.
+pub(crate) fn spawn(node: Arc<RwLock<Node>>, connector: Arc<sdk::Connector>, addr: SocketAddr) {
+    if node.read().unwrap().settings.seeds.is_empty() {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let joined = bootstrap(&node, &connector, addr);
+        info!("Acknowledged {} nodes found through the seeds", joined);
+    });
+}
+
+/// Acknowledges the nodes behind the seeds which respond, skipping current
+/// node at the given address and the nodes which are acknowledged already.
+///
+/// # Returns
+///
+/// The number of nodes which were acknowledged.
+pub(crate) fn bootstrap(
+    node: &Arc<RwLock<Node>>,
+    connector: &sdk::Connector,
+    addr: SocketAddr,
+) -> usize {
+    let (seeds, own, timeout) = {
+        let node = node.read().unwrap();
+        let own = own_keypair(&node.settings);
+        let timeout = Duration::from_secs(node.settings.health.timeout);
+        (node.settings.seeds.clone(), own, timeout)
+    };
+
+    let mut joined = 0;
+    for seed in resolve(&seeds).iter().filter(|seed| **seed != addr) {
+        match acknowledge(node, connector, seed, &own, None, timeout) {
+            Ok(Some(_)) => joined += 1,
+            Ok(None) => {}
+            Err(e) => debug!("The seed at {} did not respond: {}", seed, e),
+        }
+    }
+
+    joined
+}
+
+/// Acknowledges the node at the given address after the handshake, unless it
+/// is acknowledged already, or turns out to be current node.
+///
+/// # Arguments
+///
+/// * `expected` - The key the node has to prove, if it is known up front.
+///
+/// # Returns
+///
+/// The node as it was acknowledged, or [None] if it was skipped.
+pub(crate) fn acknowledge(
+    node: &Arc<RwLock<Node>>,
+    connector: &sdk::Connector,
+    addr: &SocketAddr,
+    own: &Keypair,
+    expected: Option<&str>,
+    timeout: Duration,
+) -> Result<Option<Peer>, sdk::Error> {
+    if is_acknowledged(node, addr) {
+        return Ok(None);
+    }
+
+    let peer = join(connector, addr, own, timeout)?;
+    if let Some(expected) = expected {
+        if !peer
+            .key
+            .as_deref()
+            .is_some_and(|key| key.eq_ignore_ascii_case(expected))
+        {
+            return Err(sdk::Error::Keys(keys::Error::Handshake(
+                "Unexpected key of the node",
+            )));
+        }
+    }
+
+    if peer.key == Some(own.public()) {
+        return Ok(None);
+    }
+
+    let mut node = node.write().unwrap();
+    if is_known(&node.settings.nodes, &peer) {
+        return Ok(None);
+    }
+
+    info!("Acknowledging the node at {}", addr);
+    node.settings.nodes.push(peer.clone());
+    Ok(Some(peer))
+}
+
+/// Performs the handshake with the node at the given address.
+///
+/// # Returns
+///
+/// The node as a peer, pinned by its key if it has one.
+fn join(
+    connector: &sdk::Connector,
+    addr: &SocketAddr,
+    own: &Keypair,
+    timeout: Duration,
+) -> Result<Peer, sdk::Error> {
+    let mut client = connector.connect_timeout(addr, timeout)?;
+    let nonce = keys::nonce();
+    let key = match client.request(auth::HANDSHAKE, &nonce) {
+        Ok(reply) => {
+            let (public, proof) = keys::prove(own, &nonce, &reply).map_err(sdk::Error::Keys)?;
+            match client.request(auth::HANDSHAKE, &proof) {
+                Ok(_) | Err(sdk::Error::Status(auth::UNAUTHORIZED)) => Some(public),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Nodes without a keypair refuse the handshake right away.
+        Err(sdk::Error::Status(auth::UNAUTHORIZED)) => None,
+        Err(e) => return Err(e),
+    };
+
+    Ok(Peer {
+        addr: *addr,
+        tags: vec![],
+        token: None,
+        certificate: None,
+        key,
+        roles: None,
+    })
+}
+
+/// Returns the keypair of the node, or a keypair generated for the handshake
+/// if the node has none, since seeds prove their key either way.
+pub(crate) fn own_keypair(settings: &Settings) -> Keypair {
+    match settings.key.as_deref().map(Keypair::from_hex) {
+        Some(Ok(keypair)) => keypair,
+        _ => Keypair::generate(),
+    }
+}
+
+fn is_acknowledged(node: &Arc<RwLock<Node>>, addr: &SocketAddr) -> bool {
+    let node = node.read().unwrap();
+    node.settings.nodes.iter().any(|peer| peer.addr == *addr)
+}
+
+/// Returns whether the peer is acknowledged already, by its address or by
+/// its key.
+fn is_known(nodes: &[Peer], peer: &Peer) -> bool {
+    nodes.iter().any(|known| {
+        known.addr == peer.addr
+            || matches!((&known.key, &peer.key), (Some(a), Some(b)) if a.eq_ignore_ascii_case(b))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(addr: &str, key: Option<&str>) -> Peer {
+        Peer {
+            addr: addr.parse().unwrap(),
+            tags: vec![],
+            token: None,
+            certificate: None,
+            key: key.map(String::from),
+            roles: None,
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let addrs = resolve(&[
+            "127.0.0.1:7000".into(),
+            "127.0.0.1:7000".into(),
+            "127.0.0.1:7001".into(),
+            "seed.invalid:7000".into(),
+        ]);
+        assert_eq!(
+            addrs,
+            vec![
+                "127.0.0.1:7000".parse().unwrap(),
+                "127.0.0.1:7001".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_known() {
+        let nodes = [peer("127.0.0.1:7000", Some("AB"))];
+        assert!(is_known(&nodes, &peer("127.0.0.1:7000", None)));
+        assert!(is_known(&nodes, &peer("127.0.0.1:7001", Some("ab"))));
+        assert!(!is_known(&nodes, &peer("127.0.0.1:7001", Some("cd"))));
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        let seed = Node::new(settings);
+        let local = seed.local();
+        let public = Keypair::from_hex(seed.settings.key.as_ref().unwrap())
+            .unwrap()
+            .public();
+        std::thread::spawn(move || seed.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let seed = local.addr().unwrap();
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.seeds = vec![seed.to_string(), seed.to_string()];
+        let node = Arc::new(RwLock::new(Node::new(settings)));
+        let own = "127.0.0.1:1".parse().unwrap();
+        assert_eq!(bootstrap(&node, &sdk::Connector::Plain, own), 1);
+        assert_eq!(
+            node.read().unwrap().settings.nodes,
+            vec![peer(&seed.to_string(), Some(&public))]
+        );
+
+        // Seeds which are acknowledged already are skipped.
+        assert_eq!(bootstrap(&node, &sdk::Connector::Plain, own), 0);
+
+        // Nodes have to prove the key they are expected to have.
+        let node = Arc::new(RwLock::new(Node::new(
+            Settings::new("memory://".into()).unwrap(),
+        )));
+        let keypair = Keypair::generate();
+        let timeout = Duration::from_secs(1);
+        let connector = sdk::Connector::Plain;
+        let result = acknowledge(&node, &connector, &seed, &keypair, Some("00"), timeout);
+        assert!(matches!(result, Err(sdk::Error::Keys(_))));
+        let result = acknowledge(&node, &connector, &seed, &keypair, Some(&public), timeout);
+        assert_eq!(
+            result.unwrap(),
+            Some(peer(&seed.to_string(), Some(&public)))
+        );
+    }
+}
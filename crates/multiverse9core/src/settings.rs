@@ -3,6 +3,11 @@ use serde::Deserialize;
 use serde::Serialize;
 use std::io::prelude::*;
 
+use crate::cidr::Cidr;
+use crate::keys::Keypair;
+use crate::roles::Role;
+use crate::routing::{Routing, Tag};
+
 /// Default address when binding the [std::net::TcpListener] locally.
 const DEFAULT_HOST_ADDRESS: &str = "127.0.0.1:0";
 /// Default instance name prefix.
@@ -12,35 +17,877 @@ const DEFAULT_INSTANCE_PREFIX: &str = "multiverse9";
 pub struct Settings {
     /// Human-readable identifier of current instance.
     pub name: String,
-    /// Redis connection string.
+    /// Redis connection string. Nodes which run without Redis keep their data
+    /// in memory instead, which is selected with `memory://`.
     pub redis_uri: String,
+    /// Sentinels monitoring the Redis master. When set, the host and port in
+    /// [Settings::redis_uri] are ignored in favor of the master reported by
+    /// the sentinels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sentinel: Option<Sentinel>,
     /// The version of current node.
     pub version: String,
+    /// Hex-encoded Ed25519 secret key of current node. Peers identify current
+    /// node by the public key derived from it, which is published in the
+    /// metadata of the node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Whether records created on current node are signed with its key, so
+    /// that nodes aggregating them can detect tampered content.
+    #[serde(default)]
+    pub sign: bool,
+    /// How the records created on current node are keyed.
+    #[serde(default)]
+    pub addressing: Addressing,
     /// Permissions for interacting with current node.
     pub perms: Permissions,
+    /// Tokens which connections may authenticate with.
+    #[serde(default)]
+    pub auth: Auth,
+    /// Mutual TLS for incoming and outgoing connections. Requires the `tls`
+    /// feature to be enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<Tls>,
     /// Binding IP address of the node.
     pub addr: std::net::SocketAddr,
+    /// The admin listener, which serves the management of the node apart from
+    /// its public address. No admin listener is bound if this is not set. See
+    /// [crate::admin].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin: Option<Admin>,
+    /// Whether the node advertises itself on the local network over mDNS, so
+    /// that clients can find it with [crate::discovery::discover]. Requires
+    /// the `discovery` feature to be enabled.
+    #[serde(default)]
+    pub advertise: bool,
+    /// Whether the node browses the local network for nodes advertised over
+    /// mDNS, and acknowledges the nodes it finds once they have completed the
+    /// handshake. Nodes which both advertise and discover find each other
+    /// without being listed in [Settings::nodes]. Requires the `discovery`
+    /// feature to be enabled.
+    #[serde(default)]
+    pub discover: bool,
+    /// Whether clients in the same process which connect to the address of the
+    /// node reach it through an in-process pipe instead of TCP. See
+    /// [crate::loopback].
+    #[serde(default)]
+    pub loopback: bool,
+    /// The largest request payload in bytes the node accepts. Requests
+    /// announcing a larger payload are answered with
+    /// [crate::protocol::codec::TOO_LARGE] before any of the payload is read,
+    /// and the connection is closed. Limits above
+    /// [crate::protocol::codec::MAX_PAYLOAD_LEN] have no effect.
+    #[serde(default = "Settings::default_max_payload_bytes")]
+    pub max_payload_bytes: usize,
+    /// Size in bytes of the receive buffer of a connection, which is also the
+    /// most bytes read at once while waiting for the next request. Requests
+    /// larger than the buffer grow it to their size.
+    #[serde(default = "Settings::default_read_buffer_bytes")]
+    pub read_buffer_bytes: usize,
+    /// File the crash reports of the node are appended to, besides being
+    /// logged. See [crate::crash].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crash_file: Option<std::path::PathBuf>,
+    /// File the changes to the records of the node are appended to. No
+    /// changelog is kept if this is not set. See [crate::changelog].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changelog: Option<std::path::PathBuf>,
+    /// Whether every request the node serves over a connection is logged,
+    /// along with the status of its response and how long it took. See
+    /// [crate::access].
+    #[serde(default)]
+    pub access_log: bool,
+    /// What the node does once a client shuts down its side of a connection.
+    #[serde(default)]
+    pub close: Close,
+    /// Seconds a connection may go without sending a request before the node
+    /// closes it, and frees the worker serving it. Connections stay open while
+    /// a request is being handled, and while they are subscribed to events.
+    /// Idle connections are never closed with `0`.
+    #[serde(default = "Settings::default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Seconds a stopping node waits for the requests in flight to be
+    /// processed before it stops anyway. See [crate::shutdown].
+    #[serde(default = "Settings::default_drain_timeout")]
+    pub drain_timeout: u64,
+    /// Most requests a multiplexed connection has handled at once, after which
+    /// the node stops reading the requests of the connection until one of them
+    /// is done. Connections cannot be multiplexed with `0`. See
+    /// [crate::multiplex].
+    #[serde(default = "Settings::default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// What the node does when accepting a connection fails.
+    #[serde(default)]
+    pub accept: Accept,
+    /// How long the responses to journaled requests are kept.
+    #[serde(default)]
+    pub journal: Journal,
+    /// How often the acknowledged nodes are probed, and how many of their
+    /// health samples are kept.
+    #[serde(default)]
+    pub health: Health,
+    /// Migration of the records stored under bare IDs, before keys were
+    /// namespaced.
+    #[serde(default)]
+    pub migration: Migration,
+    /// Limits of chunked uploads.
+    #[serde(default)]
+    pub uploads: Uploads,
+    /// How connections to the acknowledged nodes are kept open between
+    /// requests.
+    #[serde(default)]
+    pub outbound: Outbound,
+    /// Options of the TCP sockets of the node, both accepted and outbound.
+    #[serde(default)]
+    pub socket: Socket,
+    /// The addresses connections are accepted from.
+    #[serde(default)]
+    pub firewall: Firewall,
+    /// Limits on the records kept by the node, beyond which the oldest
+    /// records are removed. See [crate::retention].
+    #[serde(default)]
+    pub retention: Retention,
+    /// How often the request counters are saved. See [crate::metrics].
+    #[serde(default)]
+    pub metrics: Metrics,
     /// Acknowledged list of nodes which are allowed to have any type of
     /// interaction with current node. Essentially, this is a list of the
     /// nodes which are directly connected with current node.
-    pub nodes: Vec<std::net::SocketAddr>,
+    pub nodes: Vec<Peer>,
+    /// DNS names or addresses of nodes along with their port, such as
+    /// `seeds.example.com:7000`. The nodes they resolve to are acknowledged
+    /// once the node has started. See [crate::seeds].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub seeds: Vec<String>,
+    /// Granularity of the time buckets which the record index is partitioned by.
+    #[serde(default)]
+    pub partition: Partition,
+    /// Tags describing the placement of current node, e.g. `region=eu`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<Tag>,
+    /// Tag-aware routing policies for operations which reach out to peers.
+    #[serde(default)]
+    pub routing: Routing,
+    /// Whether current node and the acknowledged nodes share one keyspace,
+    /// with every record stored on the node which owns its key. See
+    /// [crate::sharding].
+    #[serde(default)]
+    pub sharding: bool,
+    /// Whether the words of the records created on current node are indexed,
+    /// so that they can be searched. See [crate::search].
+    #[serde(default)]
+    pub search: bool,
+}
+
+/// Granularity of the time buckets which indexes are partitioned by. Smaller
+/// buckets make sweeps over recent data cheaper, at the cost of more keys.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Partition {
+    Hour,
+    #[default]
+    Day,
+}
+
+impl Partition {
+    /// Returns the length of a single bucket in seconds.
+    #[inline(always)]
+    pub fn seconds(&self) -> u64 {
+        match self {
+            Self::Hour => 60 * 60,
+            Self::Day => 24 * 60 * 60,
+        }
+    }
+}
+
+impl std::fmt::Display for Partition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Hour => f.write_str("hour"),
+            Self::Day => f.write_str("day"),
+        }
+    }
+}
+
+/// How records are keyed when they are created. See [crate::content].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Addressing {
+    /// Every record is keyed by a fresh ULID, which orders records by their
+    /// creation time.
+    #[default]
+    Ulid,
+    /// Records are keyed by the hash of their value, so that identical values
+    /// are stored once, and nodes aggregating them can check their integrity
+    /// without knowing the key of current node.
+    Content,
+}
+
+/// What a node does once a client shuts down its side of a connection, which
+/// clients commonly do right after sending their last request.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Close {
+    /// Replies to every request received before the shutdown, then shuts down
+    /// the write side as well, so that the client reads all replies followed
+    /// by a clean end of the stream.
+    #[default]
+    Graceful,
+    /// Drops the connection as soon as the end of the stream is read. Replies
+    /// which the client has not read yet may be lost.
+    Immediate,
+}
+
+/// What a node does when accepting a connection fails, such as when it runs
+/// out of file descriptors, or a client resets the connection before it is
+/// accepted. Every failure is counted in the crash report statistics.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Accept {
+    #[serde(default)]
+    pub policy: AcceptPolicy,
+    /// Milliseconds the node waits after the first of consecutive failures,
+    /// with [AcceptPolicy::Backoff]. The wait doubles with every further
+    /// failure.
+    #[serde(default = "Accept::default_backoff")]
+    pub backoff: u64,
+    /// The longest wait in milliseconds between consecutive failures, with
+    /// [AcceptPolicy::Backoff].
+    #[serde(default = "Accept::default_max_backoff")]
+    pub max_backoff: u64,
+    /// Priority with which the connections of acknowledged nodes are queued
+    /// for a worker, such as their health probes, ahead of the connections of
+    /// clients which are queued with [Priority::Normal].
+    #[serde(default = "Accept::default_peers")]
+    pub peers: Priority,
+}
+
+impl Accept {
+    fn default_backoff() -> u64 {
+        10
+    }
+
+    fn default_max_backoff() -> u64 {
+        1000
+    }
+
+    fn default_peers() -> Priority {
+        Priority::High
+    }
+}
+
+impl Default for Accept {
+    fn default() -> Self {
+        Self {
+            policy: Default::default(),
+            backoff: Self::default_backoff(),
+            max_backoff: Self::default_max_backoff(),
+            peers: Self::default_peers(),
+        }
+    }
+}
+
+/// See [Accept].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AcceptPolicy {
+    /// Logs the failure and accepts the next connection right away.
+    Log,
+    /// Logs the failure and waits before accepting the next connection, so
+    /// that the node does not spin while the failure persists, e.g. until
+    /// file descriptors are freed.
+    #[default]
+    Backoff,
+    /// Stops the node with the failure.
+    Fail,
+}
+
+/// Priority of a job queued for a worker of the node. Idle workers pick up the
+/// jobs of a higher priority first.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// Retention of the journal of processed requests. See [crate::journal].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Journal {
+    /// Seconds for which the response to a journaled request is replayed to
+    /// clients delivering the request again.
+    #[serde(default = "Journal::default_retention")]
+    pub retention: u64,
+    /// Seconds for which a journaled request is considered to be in progress.
+    /// If the node goes away while executing the request, it is executed again
+    /// once this has passed.
+    #[serde(default = "Journal::default_claim")]
+    pub claim: u64,
+}
+
+impl Journal {
+    fn default_retention() -> u64 {
+        24 * 60 * 60
+    }
+
+    fn default_claim() -> u64 {
+        30
+    }
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self {
+            retention: Self::default_retention(),
+            claim: Self::default_claim(),
+        }
+    }
+}
+
+/// Probing of the acknowledged nodes. See [crate::health].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Health {
+    /// Seconds between two probes of every acknowledged node. Probing is
+    /// disabled with `0`.
+    #[serde(default = "Health::default_interval")]
+    pub interval: u64,
+    /// Seconds after which a probe which is not answered fails.
+    #[serde(default = "Health::default_timeout")]
+    pub timeout: u64,
+    /// The number of samples kept per node, which with the default interval
+    /// covers the last two hours.
+    #[serde(default = "Health::default_window")]
+    pub window: usize,
+}
+
+impl Health {
+    fn default_interval() -> u64 {
+        60
+    }
+
+    fn default_timeout() -> u64 {
+        5
+    }
+
+    fn default_window() -> usize {
+        120
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+            timeout: Self::default_timeout(),
+            window: Self::default_window(),
+        }
+    }
+}
+
+/// Migration of legacy records into the namespace. See [crate::migration].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Migration {
+    /// Whether the migration runs when the node starts, before connections are
+    /// accepted. It can be run separately with `multiverse9ctl migrate` too.
+    #[serde(default)]
+    pub startup: bool,
+    /// The number of keys looked at per step. The progress is reported and
+    /// saved after every step.
+    #[serde(default = "Migration::default_batch")]
+    pub batch: usize,
+    /// Handle of the user the migrated records are attributed to. Records are
+    /// left without an owner if this is not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+impl Migration {
+    fn default_batch() -> usize {
+        1000
+    }
+}
+
+impl Default for Migration {
+    fn default() -> Self {
+        Self {
+            startup: false,
+            batch: Self::default_batch(),
+            owner: None,
+        }
+    }
+}
+
+/// Pooling of the connections to the acknowledged nodes.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Outbound {
+    /// The most idle connections kept open per acknowledged node.
+    #[serde(default = "Outbound::default_max_idle")]
+    pub max_idle: usize,
+    /// Whether a connection to every acknowledged node is established when the
+    /// node starts, so that the first requests reaching out to peers do not
+    /// have to wait for connecting and introducing current node.
+    #[serde(default)]
+    pub warmup: bool,
+    /// The most connections established at once while warming up.
+    #[serde(default = "Outbound::default_parallelism")]
+    pub parallelism: usize,
+}
+
+impl Outbound {
+    fn default_max_idle() -> usize {
+        4
+    }
+
+    fn default_parallelism() -> usize {
+        8
+    }
+}
+
+impl Default for Outbound {
+    fn default() -> Self {
+        Self {
+            max_idle: Self::default_max_idle(),
+            warmup: false,
+            parallelism: Self::default_parallelism(),
+        }
+    }
+}
+
+/// The allow and deny lists of the addresses connections are accepted from,
+/// which are enforced as soon as a connection is accepted, before anything is
+/// read from it. Loopback connections, see [crate::loopback], are not affected.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Firewall {
+    /// Blocks of addresses connections are accepted from. Connections are
+    /// accepted from any address which is not denied if this is empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<Cidr>,
+    /// Blocks of addresses connections are never accepted from, even if they
+    /// are allowed as well.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<Cidr>,
+}
+
+impl Firewall {
+    /// Returns whether connections from the address are accepted.
+    pub fn permits(&self, ip: std::net::IpAddr) -> bool {
+        !self.deny.iter().any(|block| block.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|block| block.contains(ip)))
+    }
+}
+
+/// Options of the TCP sockets of a node.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Socket {
+    /// Whether small writes are sent right away rather than coalesced by
+    /// Nagle's algorithm (`TCP_NODELAY`), trading bandwidth for latency.
+    #[serde(default)]
+    pub nodelay: bool,
+    /// Seconds a connection is idle before the first keepalive probe is sent
+    /// (`SO_KEEPALIVE`), so that connections to peers which went away are
+    /// eventually closed. No probes are sent with [None].
+    #[serde(default)]
+    pub keepalive: Option<u64>,
+    /// Whether the listener binds its address even though connections of a
+    /// previous process are still closing on it (`SO_REUSEADDR`). Only applies
+    /// to the listener, since outbound streams are not bound to an address.
+    #[serde(default = "Socket::default_reuseaddr")]
+    pub reuseaddr: bool,
+}
+
+impl Socket {
+    fn default_reuseaddr() -> bool {
+        true
+    }
+}
+
+impl Default for Socket {
+    fn default() -> Self {
+        Self {
+            nodelay: false,
+            keepalive: None,
+            reuseaddr: Self::default_reuseaddr(),
+        }
+    }
+}
+
+/// Limits of uploads which are streamed to the node in chunks, for values too
+/// large to be sent in a single request.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Uploads {
+    /// The largest value in bytes an upload may grow to.
+    #[serde(default = "Uploads::default_max_size")]
+    pub max_size: usize,
+    /// Seconds after which an upload which has not received a chunk is
+    /// dropped.
+    #[serde(default = "Uploads::default_ttl")]
+    pub ttl: u64,
+}
+
+impl Uploads {
+    fn default_max_size() -> usize {
+        64 * 1024 * 1024
+    }
+
+    fn default_ttl() -> u64 {
+        600
+    }
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Self {
+            max_size: Self::default_max_size(),
+            ttl: Self::default_ttl(),
+        }
+    }
+}
+
+/// Persistence of the request counters. See [crate::metrics].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Metrics {
+    /// Seconds between two saves of the counters. The counters are neither
+    /// saved nor loaded with `0`.
+    #[serde(default = "Metrics::default_interval")]
+    pub interval: u64,
+}
+
+impl Metrics {
+    fn default_interval() -> u64 {
+        60
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+/// Retention of the records of the node. See [crate::retention].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Retention {
+    /// Seconds between two sweeps of the records. Sweeping is disabled with
+    /// `0`.
+    #[serde(default = "Retention::default_interval")]
+    pub interval: u64,
+    /// Limits on all records of the namespace of the node.
+    #[serde(default)]
+    pub namespace: Limits,
+    /// Limits on the records owned by the users with the given handles, on
+    /// top of the limits on the namespace.
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub owners: std::collections::BTreeMap<String, Limits>,
+}
+
+impl Retention {
+    fn default_interval() -> u64 {
+        60 * 60
+    }
+
+    /// Returns whether any limits are set at all.
+    pub fn is_limited(&self) -> bool {
+        self.namespace.is_limited() || self.owners.values().any(Limits::is_limited)
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+            namespace: Default::default(),
+            owners: Default::default(),
+        }
+    }
+}
+
+/// Limits on a set of records. Records are unlimited by default.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Limits {
+    /// Seconds after their creation records are kept for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+    /// The most records kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_count: Option<usize>,
+    /// The most bytes the values of the kept records may add up to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+impl Limits {
+    /// Returns whether any of the limits is set.
+    pub fn is_limited(&self) -> bool {
+        self.max_age.is_some() || self.max_count.is_some() || self.max_bytes.is_some()
+    }
+}
+
+/// The admin listener of a node. See [crate::admin].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Admin {
+    /// Address the admin listener binds to, which should be a loopback
+    /// address, since admin connections are not authenticated.
+    pub addr: std::net::SocketAddr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Sentinel {
+    /// Name of the monitored master, as configured in the sentinels.
+    pub master: String,
+    /// Connection strings of the sentinels, e.g. `redis://10.0.0.1:26379`.
+    pub endpoints: Vec<String>,
+}
+
+/// An acknowledged remote node. In the settings file a peer can either be
+/// written as a plain address, or as an object carrying its tags as well.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(from = "PeerRepr")]
+pub struct Peer {
+    /// Address of the remote node.
+    pub addr: std::net::SocketAddr,
+    /// Tags describing the placement of the remote node.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<Tag>,
+    /// Secret current node authenticates with when sending requests to the
+    /// remote node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Hex-encoded SHA-256 fingerprint of the certificate the remote node
+    /// presents over TLS. A connection presenting this certificate is
+    /// identified as the remote node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<String>,
+    /// Hex-encoded Ed25519 public key of the remote node. A connection which
+    /// completes the signed handshake with this key is identified as the
+    /// remote node, regardless of the address it connects from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Roles of the remote node, in place of those in [Permissions::nodes].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<Role>>,
+}
+
+impl Peer {
+    /// Returns whether the peer is labeled with the given tag.
+    #[inline(always)]
+    pub fn has_tag(&self, tag: &Tag) -> bool {
+        self.tags.contains(tag)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PeerRepr {
+    Addr(std::net::SocketAddr),
+    Tagged {
+        addr: std::net::SocketAddr,
+        #[serde(default)]
+        tags: Vec<Tag>,
+        #[serde(default)]
+        token: Option<String>,
+        #[serde(default)]
+        certificate: Option<String>,
+        #[serde(default)]
+        key: Option<String>,
+        #[serde(default)]
+        roles: Option<Vec<Role>>,
+    },
+}
+
+impl From<PeerRepr> for Peer {
+    fn from(repr: PeerRepr) -> Self {
+        match repr {
+            PeerRepr::Addr(addr) => Self {
+                addr,
+                tags: vec![],
+                token: None,
+                certificate: None,
+                key: None,
+                roles: None,
+            },
+            PeerRepr::Tagged {
+                addr,
+                tags,
+                token,
+                certificate,
+                key,
+                roles,
+            } => Self {
+                addr,
+                tags,
+                token,
+                certificate,
+                key,
+                roles,
+            },
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Default)]
+/// The roles granted to connections by their identity. See [crate::roles].
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(from = "PermissionsRepr")]
 pub struct Permissions {
     /// Whether the instance allows anyone to request for its metadata.
     pub open_metadata: bool,
-    /// Whether the instance is open for any kind of interaction from any
-    /// remote instance. This essentially grants unrestricted access for
-    /// posting, interacting, etc. on current instance for remote nodes.
-    pub open_interactions: bool,
+    /// Roles of connections which have not authenticated.
+    pub anonymous: Vec<Role>,
+    /// Roles of connections which authenticated with a token, or as a user
+    /// registered on current node.
+    pub authenticated: Vec<Role>,
+    /// Roles of acknowledged nodes, unless they have roles of their own. See
+    /// [Peer::roles].
+    pub nodes: Vec<Role>,
+    /// Roles of the subjects of particular tokens, in place of those in
+    /// [Permissions::authenticated].
+    #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub subjects: std::collections::BTreeMap<String, Vec<Role>>,
+    /// Whether connections which have not authenticated are granted the roles
+    /// of the acknowledged node whose IP address they come from, if that node
+    /// has neither a key nor a certificate to identify it by. Anyone sharing
+    /// the address of such a node is trusted as the node, so this is only
+    /// meant for private networks.
+    pub trust_unpinned: bool,
+}
+
+impl Permissions {
+    /// Clients may read, write and delete records by default.
+    fn default_roles() -> Vec<Role> {
+        vec![Role::Read, Role::Write, Role::Delete]
+    }
+
+    /// Nodes may federate by default, and issue the requests of clients on
+    /// their behalf, such as aggregating records they do not own.
+    fn default_node_roles() -> Vec<Role> {
+        vec![Role::Read, Role::Write, Role::Delete, Role::Federate]
+    }
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Self {
+            open_metadata: false,
+            anonymous: Self::default_roles(),
+            authenticated: Self::default_roles(),
+            nodes: Self::default_node_roles(),
+            subjects: Default::default(),
+            trust_unpinned: false,
+        }
+    }
+}
+
+/// Permissions as written in the settings file, which may still carry the
+/// `open_interactions` flag that predates roles.
+#[derive(Deserialize)]
+struct PermissionsRepr {
+    #[serde(default)]
+    open_metadata: bool,
+    /// Whether acknowledged nodes may post and interact on current node, in
+    /// place of [Permissions::nodes] when those are not given.
+    #[serde(default)]
+    open_interactions: Option<bool>,
+    #[serde(default = "Permissions::default_roles")]
+    anonymous: Vec<Role>,
+    #[serde(default = "Permissions::default_roles")]
+    authenticated: Vec<Role>,
+    #[serde(default)]
+    nodes: Option<Vec<Role>>,
+    #[serde(default)]
+    subjects: std::collections::BTreeMap<String, Vec<Role>>,
+    #[serde(default)]
+    trust_unpinned: bool,
+}
+
+impl From<PermissionsRepr> for Permissions {
+    fn from(repr: PermissionsRepr) -> Self {
+        let nodes = match (repr.nodes, repr.open_interactions) {
+            (Some(nodes), _) => nodes,
+            (None, Some(false)) => vec![Role::Read, Role::Federate],
+            (None, _) => Self::default_node_roles(),
+        };
+        Self {
+            open_metadata: repr.open_metadata,
+            anonymous: repr.anonymous,
+            authenticated: repr.authenticated,
+            nodes,
+            subjects: repr.subjects,
+            trust_unpinned: repr.trust_unpinned,
+        }
+    }
+}
+
+/// Token authentication for incoming connections. When no tokens are
+/// configured, authentication is disabled and any connection may issue any
+/// request.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Auth {
+    #[serde(default)]
+    pub tokens: Vec<Token>,
+    /// Request codes which connections may issue before authenticating.
+    #[serde(default = "Auth::default_public")]
+    pub public: Vec<u16>,
+}
+
+impl Auth {
+    /// Only aggregation, metadata, reading feeds and replies, registering and
+    /// looking up users, and listing their followers and followed actors are
+    /// open to unauthenticated connections by default. Whether the
+    /// metadata is readable without an identity is further governed by
+    /// [Permissions::open_metadata].
+    fn default_public() -> Vec<u16> {
+        vec![
+            0x0003, 0x0005, 0x0008, 0x000A, 0x000B, 0x000E, 0x000F, 0x0013,
+        ]
+    }
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            tokens: vec![],
+            public: Self::default_public(),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum Error {
-    Io(std::io::Error),
-    Redis(redis::RedisError),
-    Parsing(serde_json::Error),
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Tls {
+    /// PEM file with the certificate chain current node presents.
+    pub certificate: std::path::PathBuf,
+    /// PEM file with the private key of the certificate.
+    pub key: std::path::PathBuf,
+    /// PEM file with the CA certificates which remote certificates are verified
+    /// against. Without a CA, only the certificates pinned for acknowledged
+    /// nodes are accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca: Option<std::path::PathBuf>,
+}
+
+/// A token connections authenticate with. Any number of tokens may be valid at
+/// once, including several for the same subject, so that a secret can be
+/// rotated by adding its successor before revoking it. See [crate::admin].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Token {
+    /// ID the token is revoked by. Tokens added at runtime are given a ULID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Name of the client or peer holding the token. Requests issued after
+    /// authenticating are attributed to it.
+    pub subject: String,
+    /// The pre-shared secret the client authenticates with.
+    pub secret: String,
+}
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .Io(std::io::Error) [source]
+    #[cfg(feature = "redis")] .Redis(redis::RedisError) [source]
+    .Storage(crate::storage::Error) [source]
+    .Parsing(serde_json::Error) [source]
+    ~Debug
 }
 
 impl Settings {
@@ -48,7 +895,15 @@ impl Settings {
     /// will create a new directory in the filesystem for keeping the data for
     /// current instance.
     pub fn new(redis_uri: String) -> Result<Self, Error> {
-        redis::Client::open(&*redis_uri).map_err(Error::Redis)?;
+        if !redis_uri.starts_with(crate::storage::MEMORY_SCHEME) {
+            #[cfg(feature = "redis")]
+            redis::Client::open(&*redis_uri).map_err(Error::Redis)?;
+            #[cfg(not(feature = "redis"))]
+            return Err(Error::Storage(crate::storage::Error::Unsupported(format!(
+                "{} requires the `redis` feature",
+                redis_uri
+            ))));
+        }
 
         let hash = ulid::Ulid::new().to_string();
         let name = format!("{}_{}", DEFAULT_INSTANCE_PREFIX, hash);
@@ -56,18 +911,164 @@ impl Settings {
         Ok(Self {
             name,
             redis_uri,
+            sentinel: None,
             nodes: vec![],
+            seeds: vec![],
+            tags: vec![],
+            partition: Default::default(),
+            routing: Default::default(),
+            sharding: false,
+            search: false,
             perms: Default::default(),
+            auth: Default::default(),
+            tls: None,
+            key: Some(Keypair::generate().secret()),
+            sign: false,
+            addressing: Default::default(),
             version: env!("CARGO_PKG_VERSION").into(),
             addr: DEFAULT_HOST_ADDRESS.parse().unwrap(),
+            admin: None,
+            advertise: false,
+            discover: false,
+            loopback: false,
+            close: Default::default(),
+            idle_timeout: Self::default_idle_timeout(),
+            drain_timeout: Self::default_drain_timeout(),
+            max_in_flight: Self::default_max_in_flight(),
+            accept: Default::default(),
+            journal: Default::default(),
+            health: Default::default(),
+            migration: Default::default(),
+            uploads: Default::default(),
+            max_payload_bytes: Self::default_max_payload_bytes(),
+            read_buffer_bytes: Self::default_read_buffer_bytes(),
+            crash_file: None,
+            changelog: None,
+            access_log: false,
+            outbound: Default::default(),
+            socket: Default::default(),
+            firewall: Default::default(),
+            retention: Default::default(),
+            metrics: Default::default(),
         })
     }
+
+    /// Replaces the settings which take effect while the node is running with
+    /// those of `from`, such as the acknowledged nodes, the tokens and the
+    /// permissions. The other settings are kept, since they are only read when
+    /// the node starts.
+    ///
+    /// # Returns
+    ///
+    /// The names of the settings which differ in `from`, but which only take
+    /// effect once the node is restarted.
+    pub fn reload(&mut self, from: Settings) -> Vec<&'static str> {
+        let Settings {
+            name,
+            redis_uri,
+            sentinel,
+            version,
+            key,
+            sign,
+            addressing,
+            perms,
+            auth,
+            tls,
+            addr,
+            admin,
+            advertise,
+            discover,
+            loopback,
+            max_payload_bytes,
+            read_buffer_bytes,
+            crash_file,
+            changelog,
+            access_log,
+            close,
+            idle_timeout,
+            drain_timeout,
+            max_in_flight,
+            accept,
+            journal,
+            health,
+            migration,
+            uploads,
+            outbound,
+            socket,
+            firewall,
+            retention,
+            metrics,
+            nodes,
+            seeds,
+            partition,
+            tags,
+            routing,
+            sharding,
+            search,
+        } = from;
+
+        self.sign = sign;
+        self.addressing = addressing;
+        self.perms = perms;
+        self.auth = auth;
+        self.max_payload_bytes = max_payload_bytes;
+        self.read_buffer_bytes = read_buffer_bytes;
+        self.access_log = access_log;
+        self.close = close;
+        self.idle_timeout = idle_timeout;
+        self.drain_timeout = drain_timeout;
+        self.max_in_flight = max_in_flight;
+        self.journal = journal;
+        self.uploads = uploads;
+        self.firewall = firewall;
+        self.nodes = nodes;
+        self.tags = tags;
+        self.routing = routing;
+        self.sharding = sharding;
+        self.search = search;
+
+        let mut restart = vec![];
+        macro_rules! restart {
+            ($($field:ident),*) => {
+                $(if self.$field != $field {
+                    restart.push(stringify!($field));
+                })*
+            };
+        }
+
+        restart!(
+            name, redis_uri, sentinel, version, key, tls, addr, admin, advertise, discover,
+            loopback, crash_file, changelog, accept, health, migration, outbound, socket,
+            retention, metrics, seeds, partition
+        );
+        restart
+    }
+
+    fn default_max_payload_bytes() -> usize {
+        crate::protocol::codec::MAX_PAYLOAD_LEN
+    }
+
+    fn default_read_buffer_bytes() -> usize {
+        crate::FrameBuffer::INITIAL_LEN
+    }
+
+    fn default_idle_timeout() -> u64 {
+        300
+    }
+
+    fn default_drain_timeout() -> u64 {
+        30
+    }
+
+    fn default_max_in_flight() -> usize {
+        16
+    }
 }
 
-impl ToString for Settings {
+impl std::fmt::Display for Settings {
     #[cold]
-    fn to_string(&self) -> String {
-        serde_json::to_string_pretty(&self).unwrap()
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&serde_json::to_string_pretty(&self).unwrap())
     }
 }
 
@@ -79,10 +1080,10 @@ impl TryFrom<std::path::PathBuf> for Settings {
         let mut settings = std::fs::File::open(&path).map_err(Error::Io)?;
         let mut contents = String::new();
         settings.read_to_string(&mut contents).map_err(Error::Io)?;
-        // The contents of the settings file will be kept in memory until the program ends running.
-        let contents: &'static str = Box::leak(contents.into_boxed_str());
-        let settings = serde_json::from_str(contents).map_err(Error::Parsing);
+        // All fields of the settings are owned, so the contents can be dropped
+        // as soon as they are parsed.
+        let settings = serde_json::from_str(&contents).map_err(Error::Parsing)?;
         debug!("Settings loaded successfully from {:?}", &path);
-        settings
+        Ok(settings)
     }
 }
@@ -0,0 +1,253 @@
+//! Sharding turns current node and the nodes it acknowledges into one logical
+//! keyspace, with every record stored on the node which owns its key on the
+//! ring of [crate::placement]. It is enabled with
+//! [Settings::sharding](crate::settings::Settings::sharding), and nodes which
+//! shard their records should acknowledge each other.
+//!
+//! A record created on a node which does not own it is forwarded to its owner
+//! with a [STORE] request, and the key the owner replies with is returned to
+//! the client, as is the status of an owner which refuses the record. Owners
+//! which cannot be reached are failed over from to the next node on the ring,
+//! and current node stores the record itself once [MAX_ATTEMPTS] nodes have
+//! failed, so that clients never need to know the topology. Content keys are owned by the node their hash is placed on,
+//! while nodes keying their records by ULIDs only ever draw IDs they own
+//! themselves, so the owner of every key can be computed from the key alone.
+//! Aggregating a key without an address reads it from its owner, unless
+//! current node holds it. Content-addressed records stored on another node
+//! after a failover are read with quorum reads, see [crate::quorum].
+//!
+//! Records created by users stay on current node, since only the node a user
+//! is registered on can attribute records to them.
+
+use std::net::SocketAddr;
+
+use crate::node::Node;
+use crate::placement::{self, Ring};
+use crate::protocol::Identity;
+use crate::settings::Addressing;
+use crate::{sdk, shutdown};
+
+/// Request code for storing a record forwarded by the node it was created on.
+/// Forwarded records are stored by the node which receives them, and are
+/// never forwarded any further. Its response is the key of the record.
+pub const STORE: u16 = 0x1000;
+
+/// The most nodes a record is forwarded to before current node stores it
+/// itself.
+pub const MAX_ATTEMPTS: usize = 3;
+
+/// The most IDs drawn for a record before settling for an ID owned by another
+/// node.
+const MAX_DRAWS: usize = 1024;
+
+/// Returns the nodes a new record with the value is offered to, in the order
+/// of the ring, with [None] standing for current node. The list ends with
+/// current node if it is among the first [MAX_ATTEMPTS] nodes, and is empty if
+/// the record is stored on current node right away.
+pub(crate) fn destinations(
+    node: &Node,
+    identity: &Identity,
+    value: &[u8],
+) -> Vec<Option<SocketAddr>> {
+    if !node.settings.sharding || matches!(identity, Identity::User(_)) {
+        return vec![];
+    }
+
+    let key = match node.settings.addressing {
+        // The owner of the record draws its ID, so a node is picked the way
+        // one would be for a drawn ID.
+        Addressing::Ulid => ulid::Ulid::new().to_string(),
+        Addressing::Content => crate::content::key(value),
+    };
+
+    let own = placement::own(node);
+    let mut destinations = vec![];
+    for owner in Ring::of(node).owners(&key, MAX_ATTEMPTS) {
+        if owner == own {
+            break;
+        }
+
+        destinations.push(Some(owner));
+    }
+
+    if !destinations.is_empty() {
+        destinations.push(None);
+    }
+
+    destinations
+}
+
+/// Returns whether a record whose forwarding failed with the error is offered
+/// to the next node, since the node it was forwarded to could not be reached
+/// or is stopping. Nodes which refused the record otherwise are not failed
+/// over from, and their status is passed on to the client.
+pub(crate) fn is_failover(e: &sdk::Error) -> bool {
+    matches!(
+        e,
+        sdk::Error::Io(_) | sdk::Error::Status(shutdown::SHUTTING_DOWN)
+    )
+}
+
+/// Returns the node which owns the key, or [None] if current node owns it or
+/// does not shard its records.
+pub(crate) fn owner(node: &Node, key: &str) -> Option<SocketAddr> {
+    if !node.settings.sharding {
+        return None;
+    }
+
+    let owner = Ring::of(node).owner(key)?;
+    match owner == placement::own(node) {
+        true => None,
+        false => Some(owner),
+    }
+}
+
+/// Draws the ID of a new record, owned by current node if it shards its
+/// records.
+pub(crate) fn draw(node: &Node) -> ulid::Ulid {
+    if !node.settings.sharding {
+        return ulid::Ulid::new();
+    }
+
+    let (ring, own) = (Ring::of(node), placement::own(node));
+    for _ in 0..MAX_DRAWS {
+        let id = ulid::Ulid::new();
+        if ring.owner(&id.to_string()) == Some(own) {
+            return id;
+        }
+    }
+
+    log::warn!("Could not draw an ID owned by current node");
+    ulid::Ulid::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::codec;
+    use crate::settings::{Peer, Settings};
+    use crate::testing::{FakePeer, Reply};
+    use std::time::Duration;
+
+    fn start(settings: Settings) -> crate::loopback::Local {
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(4)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        local
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:7000".parse().unwrap();
+        settings.nodes = vec![serde_json::from_str(r#""127.0.0.1:7001""#).unwrap()];
+        settings.sharding = true;
+        let node = Node::new(settings);
+        for _ in 0..32 {
+            assert_eq!(owner(&node, &draw(&node).to_string()), None);
+        }
+    }
+
+    #[test]
+    fn test_sharding() {
+        // Both nodes need to know each other by the addresses they listen at,
+        // so they are acknowledged once they have started.
+        let settings = || {
+            let mut settings = Settings::new("memory://".into()).unwrap();
+            settings.addr = "127.0.0.1:0".parse().unwrap();
+            settings.admin = Some(crate::settings::Admin {
+                addr: "127.0.0.1:0".parse().unwrap(),
+            });
+            settings.sharding = true;
+            // The nodes forward records to each other without identifying
+            // themselves.
+            settings.perms.trust_unpinned = true;
+            settings
+        };
+        let nodes = [start(settings()), start(settings())];
+        for (local, peer) in [(&nodes[0], &nodes[1]), (&nodes[1], &nodes[0])] {
+            let peer: Peer =
+                serde_json::from_str(&format!(r#""{}""#, peer.addr().unwrap())).unwrap();
+            let mut admin = sdk::Admin::connect(local.admin_addr().unwrap()).unwrap();
+            admin.add_peer(&peer).unwrap();
+        }
+
+        let mut clients = [nodes[0].client().unwrap(), nodes[1].client().unwrap()];
+        let ring = clients[0].ring().unwrap();
+        assert_eq!(ring, clients[1].ring().unwrap());
+        let mut owners = vec![];
+        for i in 0..16 {
+            let value = format!("value{}", i);
+            let key = clients[i % 2].create(value.as_bytes()).unwrap().id;
+            owners.push(ring.owner(&key).unwrap());
+            for client in clients.iter_mut() {
+                let records = client.aggregate(&key).unwrap().records;
+                assert_eq!(records[0].value, value.as_bytes());
+            }
+        }
+
+        // Records are spread across both nodes, whichever node created them.
+        for local in &nodes {
+            assert!(owners.contains(&local.addr().unwrap()));
+        }
+    }
+
+    fn sharded(peer: std::net::SocketAddr) -> Settings {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        settings.nodes = vec![serde_json::from_str(&format!(r#""{}""#, peer)).unwrap()];
+        settings.sharding = true;
+        settings
+    }
+
+    #[test]
+    fn test_forward() {
+        let key = ulid::Ulid::new().to_string();
+        let peer = FakePeer::bind([
+            (STORE, Reply::ok(key.clone())),
+            (STORE, Reply::status(codec::TOO_LARGE)),
+        ])
+        .unwrap();
+        let local = start(sharded(peer.addr()));
+        let mut client = local.client().unwrap();
+
+        // Records are forwarded to the peer for about half of the drawn IDs, and
+        // the key and status it replies with are passed on.
+        let mut replies = vec![];
+        let forwarded = || {
+            peer.requests()
+                .iter()
+                .filter(|(code, _)| *code == STORE)
+                .count()
+        };
+        while forwarded() < 2 {
+            replies.push(client.create(b"value").map(|reply| reply.id));
+        }
+
+        assert!(replies
+            .iter()
+            .any(|reply| matches!(reply, Ok(id) if *id == key)));
+        assert!(matches!(
+            replies.last(),
+            Some(Err(sdk::Error::Status(codec::TOO_LARGE)))
+        ));
+    }
+
+    #[test]
+    fn test_failover() {
+        let gone = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let local = start(sharded(gone));
+        let mut client = local.client().unwrap();
+        for _ in 0..16 {
+            let key = client.create(b"value").unwrap().id;
+            assert_eq!(client.aggregate(&key).unwrap().records[0].value, b"value");
+        }
+    }
+}
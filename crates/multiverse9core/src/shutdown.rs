@@ -0,0 +1,125 @@
+//! Graceful shutdown of a running node. Once a node is told to stop through its
+//! [Shutdown] handle, it closes its listener, finishes the requests which are
+//! being processed, and replies to every request received from then on with
+//! [SHUTTING_DOWN] before closing the connection. The node waits up to
+//! [Settings::drain_timeout](crate::settings::Settings::drain_timeout) seconds
+//! for the requests in flight, after which [Node::start](crate::node::Node::start)
+//! returns.
+
+use log::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Response status sent in reply to requests which a stopping node receives,
+/// after which the node closes the connection. Clients should retry the
+/// request against another node, or once the node is back.
+pub const SHUTTING_DOWN: u8 = 0x0005;
+
+/// How often connections which wait for a request or an event check whether
+/// the node is stopping.
+pub(crate) const POLL: Duration = Duration::from_millis(500);
+
+/// A handle which stops a running node. The handle can be cloned, and is taken
+/// from the node before the node is started, since [Node::start] takes the
+/// node.
+///
+/// [Node::start]: crate::node::Node::start
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    state: Arc<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    stopping: AtomicBool,
+    /// The address the node listens on, once it has started.
+    addr: Mutex<Option<SocketAddr>>,
+}
+
+impl Shutdown {
+    /// Tells the node to stop. Nodes which have not started yet stop as soon
+    /// as they start.
+    pub fn stop(&self) {
+        if self.state.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        info!("Stopping the node");
+        if let Some(addr) = *self.state.addr.lock().unwrap() {
+            // Waking up the listener, which is blocked on accepting a connection.
+            if let Err(e) = TcpStream::connect(reachable(addr)) {
+                warn!("Could not wake up the listener at {}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Returns whether the node has been told to stop.
+    pub fn is_stopping(&self) -> bool {
+        self.state.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Records the address the node listens on, which [Shutdown::stop] wakes
+    /// up the listener through.
+    pub(crate) fn listening(&self, addr: SocketAddr) {
+        *self.state.addr.lock().unwrap() = Some(addr);
+    }
+}
+
+/// Returns the address a listener bound to the given address is reached at,
+/// which is the loopback address for listeners bound to every interface.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::sdk::Error;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_reachable() {
+        assert_eq!(
+            reachable("0.0.0.0:9000".parse().unwrap()),
+            "127.0.0.1:9000".parse().unwrap()
+        );
+        assert_eq!(
+            reachable("[::]:9000".parse().unwrap()),
+            "[::1]:9000".parse().unwrap()
+        );
+        assert_eq!(
+            reachable("10.0.0.1:9000".parse().unwrap()),
+            "10.0.0.1:9000".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_stop() {
+        let node = Node::new(Settings::new("memory://".into()).unwrap());
+        let (shutdown, local) = (node.shutdown(), node.local());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || tx.send(node.start(Some(3)).is_ok()));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        client.ping().unwrap();
+
+        // The connection is open already, so its next request is answered
+        // with the status instead of being dropped.
+        shutdown.stop();
+        assert!(shutdown.is_stopping());
+        assert!(matches!(client.ping(), Err(Error::Status(SHUTTING_DOWN))));
+
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        assert!(!local.is_running());
+    }
+}
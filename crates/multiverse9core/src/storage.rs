@@ -0,0 +1,776 @@
+use serde::{Deserialize, Serialize};
+
+use crate::migration;
+use crate::settings::{Migration, Partition, Sentinel, Settings};
+
+/// Contains the backend which keeps all data in the memory of the process.
+pub(crate) mod memory;
+/// Contains the Redis backend, including the resolution of the master through
+/// Redis Sentinel.
+#[cfg(feature = "redis")]
+pub(crate) mod redis;
+/// Contains the backend which keeps all data in a sled database on disk.
+#[cfg(feature = "sled")]
+pub(crate) mod sled;
+
+/// Connection strings starting with this scheme select the in-memory backend.
+pub const MEMORY_SCHEME: &str = "memory://";
+
+/// Connection strings starting with this scheme select the sled backend, with
+/// the path of the database following the scheme, such as `sled://data/node`.
+pub const SLED_SCHEME: &str = "sled://";
+
+crate::enum_with_impl_error! {
+    pub Error,
+    #[cfg(feature = "redis")] .Redis(::redis::RedisError) [source]
+    #[cfg(feature = "sled")] .Sled(::sled::Error) [source]
+    .Corrupt(String)
+    .Unsupported(String)
+    ~Debug
+}
+
+/// Returns whether the backend selected by the connection string keeps its
+/// data once the process exits, which only the in-memory backend does not.
+pub fn is_persistent(uri: &str) -> bool {
+    !uri.starts_with(MEMORY_SCHEME)
+}
+
+/// The sets of the follow graph kept for every local user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relation {
+    /// The actors following the user.
+    Followers,
+    /// The actors the user follows.
+    Following,
+}
+
+impl std::fmt::Display for Relation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Followers => "followers",
+            Self::Following => "following",
+        })
+    }
+}
+
+/// The number of interactions with a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Interactions {
+    /// The number of distinct identities which liked the record.
+    pub likes: usize,
+    /// The number of direct replies to the record.
+    pub replies: usize,
+}
+
+/// The state of a journaled request when it is delivered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    /// The request has not been seen before and is now claimed by the caller,
+    /// which has to execute it.
+    Acquired,
+    /// The request is being executed by another connection.
+    Pending,
+    /// The request has been executed with the given response.
+    Done(Vec<u8>),
+}
+
+/// The outcome of appending a chunk to an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Append {
+    /// The chunk was appended, and the upload is now of the given length.
+    Appended(usize),
+    /// The client has no upload with the given ID, or it has expired.
+    Unknown,
+    /// The upload would grow beyond its limit, so the chunk was not appended.
+    Oversized,
+}
+
+/// The value of a key as it is kept in the backend, for moving the keys of an
+/// instance between backends. See [crate::backup].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    String(Vec<u8>),
+    /// Items of a list, starting with the head of the list.
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    /// Members of a sorted set along with their scores, in ascending order.
+    SortedSet(Vec<(Vec<u8>, f64)>),
+}
+
+/// A key of an instance along with its value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// The key without the namespace of the instance, so that it can be moved
+    /// into another namespace.
+    pub key: String,
+    pub value: Value,
+    /// Time left until the key expires, if it expires at all.
+    pub ttl: Option<std::time::Duration>,
+}
+
+/// Describes how the keys of an instance are laid out in the backend.
+#[derive(Debug, Clone)]
+pub struct Keyspace {
+    /// Prefix for all keys of the instance, so that several instances can
+    /// share the same Redis without their keys colliding.
+    pub namespace: String,
+    /// Granularity of the time buckets the record index is partitioned by.
+    pub partition: Partition,
+}
+
+impl Keyspace {
+    /// Prefixes the key with the namespace of the instance.
+    #[inline(always)]
+    pub fn key(&self, key: &str) -> String {
+        format!("{}:{}", self.namespace, key)
+    }
+
+    /// Returns the key under which the signature of the record with the given
+    /// ID is stored.
+    #[inline(always)]
+    pub fn signature(&self, id: &str) -> String {
+        self.key(&format!("signature:{}", id))
+    }
+
+    /// Returns the key of the sorted set holding the record IDs of the feed
+    /// with the given name.
+    #[inline(always)]
+    pub fn feed(&self, name: &str) -> String {
+        self.key(&format!("feed:{}", name))
+    }
+
+    /// Returns the key under which the handle of the user who created the
+    /// record with the given ID is stored.
+    #[inline(always)]
+    pub fn owner(&self, id: &str) -> String {
+        self.key(&format!("owner:{}", id))
+    }
+
+    /// Returns the key under which the profile of the user with the given
+    /// handle is stored.
+    #[inline(always)]
+    pub fn user(&self, handle: &str) -> String {
+        self.key(&format!("user:{}", handle))
+    }
+
+    /// Returns the key under which the handle of the user with the given
+    /// hex-encoded public key is stored.
+    #[inline(always)]
+    pub fn user_key(&self, public: &str) -> String {
+        self.key(&format!("user-key:{}", public))
+    }
+
+    /// Returns the key of the set holding the actors related to the user with
+    /// the given handle.
+    #[inline(always)]
+    pub fn graph(&self, relation: Relation, handle: &str) -> String {
+        self.key(&format!("{}:{}", relation, handle))
+    }
+
+    /// Returns the key of the set holding the identities which liked the record
+    /// with the given ID.
+    #[inline(always)]
+    pub fn likes(&self, id: &str) -> String {
+        self.key(&format!("likes:{}", id))
+    }
+
+    /// Returns the key of the sorted set holding the IDs of the replies to the
+    /// record with the given ID.
+    #[inline(always)]
+    pub fn replies(&self, id: &str) -> String {
+        self.key(&format!("replies:{}", id))
+    }
+
+    /// Returns the key of the set holding who references the content-addressed
+    /// record with the given key.
+    #[inline(always)]
+    pub fn references(&self, key: &str) -> String {
+        self.key(&format!("references:{}", key))
+    }
+
+    /// Returns the key of the set holding the keys of the records tagged with
+    /// the given tag.
+    #[inline(always)]
+    pub fn tag(&self, tag: &str) -> String {
+        self.key(&format!("tag:{}", tag))
+    }
+
+    /// Returns the key of the set holding the tags of the record with the
+    /// given key, so that the record can be removed from their sets.
+    #[inline(always)]
+    pub fn tags(&self, key: &str) -> String {
+        self.key(&format!("tags:{}", key))
+    }
+
+    /// Returns the key of the set holding the keys of the records which hold
+    /// the given word. See [crate::search].
+    #[inline(always)]
+    pub fn term(&self, term: &str) -> String {
+        self.key(&format!("term:{}", term))
+    }
+
+    /// Returns the key of the set holding the words of the record with the
+    /// given key, so that the record can be removed from their sets.
+    #[inline(always)]
+    pub fn terms(&self, key: &str) -> String {
+        self.key(&format!("terms:{}", key))
+    }
+
+    /// Returns the key under which the ID of the record the record with the
+    /// given ID replies to is stored.
+    #[inline(always)]
+    pub fn parent(&self, id: &str) -> String {
+        self.key(&format!("parent:{}", id))
+    }
+
+    /// Returns the key of the list holding the recent health samples of the
+    /// acknowledged node with the given address.
+    #[inline(always)]
+    pub fn health(&self, addr: &str) -> String {
+        self.key(&format!("health:{}", addr))
+    }
+
+    /// Returns the key under which the progress of the migration of legacy
+    /// records is saved.
+    #[inline(always)]
+    pub fn migration(&self) -> String {
+        self.key("migration")
+    }
+
+    /// Returns the key under which the journal entry of the request with the
+    /// given ID is stored.
+    #[inline(always)]
+    pub fn journal(&self, client: &str, id: &str) -> String {
+        self.key(&format!("journal:{}:{}", client, id))
+    }
+
+    /// Returns the key under which the value of the upload with the given ID is
+    /// accumulated.
+    #[inline(always)]
+    pub fn upload(&self, id: &str) -> String {
+        self.key(&format!("upload:{}", id))
+    }
+
+    /// Returns the key holding the client which started the upload with the
+    /// given ID.
+    #[inline(always)]
+    pub fn uploader(&self, id: &str) -> String {
+        self.key(&format!("upload:{}:client", id))
+    }
+
+    /// Returns the key of the index bucket holding the IDs of the records
+    /// created at the given time. Since records are keyed by ULIDs, the bucket
+    /// of a record can always be derived from its ID.
+    #[inline(always)]
+    pub fn bucket(&self, timestamp_ms: u64) -> String {
+        self.bucket_key(timestamp_ms / 1000 / self.partition.seconds())
+    }
+
+    /// Returns the keys of all index buckets overlapping the given time range,
+    /// starting with the most recent one. Maintenance jobs and queries for
+    /// recent records walk these instead of scanning the whole keyspace.
+    pub fn buckets(&self, from_ms: u64, to_ms: u64) -> Vec<String> {
+        let seconds = self.partition.seconds();
+        (from_ms / 1000 / seconds..=to_ms / 1000 / seconds)
+            .rev()
+            .map(|bucket| self.bucket_key(bucket))
+            .collect()
+    }
+
+    /// Returns the prefix shared by the keys of all index buckets.
+    #[inline(always)]
+    pub fn index(&self) -> String {
+        self.key(&format!("index:{}:", self.partition))
+    }
+
+    /// Returns the number of the index bucket with the given key, which grows
+    /// with the time the bucket covers.
+    pub fn bucket_number(&self, key: &str) -> Option<u64> {
+        key.strip_prefix(&self.index())?.parse().ok()
+    }
+
+    /// Returns whether the key with the given name in the backend holds a
+    /// sorted set, which the in-memory backend keeps as a plain set.
+    pub fn is_sorted(&self, name: &str) -> bool {
+        name.starts_with(&self.feed("")) || name.starts_with(&self.replies(""))
+    }
+
+    #[inline(always)]
+    fn bucket_key(&self, bucket: u64) -> String {
+        format!("{}{}", self.index(), bucket)
+    }
+}
+
+/// A record stored along with other records by [Connection::create_many].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewRecord<'a> {
+    pub id: ulid::Ulid,
+    pub value: &'a [u8],
+    pub signature: Option<Vec<u8>>,
+    /// The handle of the user who created the record, if any.
+    pub owner: Option<&'a str>,
+}
+
+/// A connection to the storage backend, over which the handlers read and
+/// write the data of the instance.
+pub trait Connection: Send {
+    /// Reads the value of a record.
+    fn get(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Reads the signature of a record, if the record was signed.
+    fn signature(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Reads the handle of the user who created a record, if any.
+    fn owner(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<String>, Error>;
+
+    /// Stores the value under a new record ID along with its signature and
+    /// owner, and adds the ID to the index bucket of its creation time.
+    fn create(
+        &mut self,
+        keyspace: &Keyspace,
+        id: &ulid::Ulid,
+        value: &[u8],
+        signature: Option<&[u8]>,
+        owner: Option<&str>,
+    ) -> Result<(), Error>;
+
+    /// Stores several records the way [Connection::create] stores each of
+    /// them, in a single round trip to the backend.
+    fn create_many(&mut self, keyspace: &Keyspace, records: &[NewRecord]) -> Result<(), Error>;
+
+    /// Stores the value under its content key along with its signature and
+    /// owner, unless a record with the key exists already, and adds the
+    /// referrer to the references of the record in the same transaction.
+    /// Records stored before references were kept are first referenced by
+    /// their owner. Returns whether the record was stored. See
+    /// [crate::content].
+    fn create_content(
+        &mut self,
+        keyspace: &Keyspace,
+        key: &str,
+        value: &[u8],
+        signature: Option<&[u8]>,
+        owner: Option<&str>,
+        referrer: &str,
+    ) -> Result<bool, Error>;
+
+    /// Returns who references the content-addressed record, in no particular
+    /// order.
+    fn references(&mut self, keyspace: &Keyspace, key: &str) -> Result<Vec<String>, Error>;
+
+    /// Drops the reference of the referrer to the content-addressed record,
+    /// and removes the record like [Connection::del] in the same transaction
+    /// if it was the last one. Returns the number of references left, or
+    /// [None] if the referrer did not reference the record.
+    fn dereference(
+        &mut self,
+        keyspace: &Keyspace,
+        key: &str,
+        referrer: &str,
+    ) -> Result<Option<usize>, Error>;
+
+    /// Removes the records along with their signatures, owners, references,
+    /// interactions and tags, and their IDs from the index buckets, from the
+    /// sets of their tags and from the replies of the records they reply to.
+    fn del(&mut self, keyspace: &Keyspace, keys: &[String]) -> Result<(), Error>;
+
+    /// Posts the records to the feed. Feeds are ordered by the IDs of their
+    /// records, which since they are ULIDs is the order in which the records
+    /// were created, regardless of the order in which they are posted.
+    fn post(&mut self, keyspace: &Keyspace, feed: &str, ids: &[ulid::Ulid]) -> Result<(), Error>;
+
+    /// Adds the record with the given key to the sets of the tags, and keeps
+    /// its tags along with it, so that [Connection::del] removes it from their
+    /// sets.
+    fn tag(&mut self, keyspace: &Keyspace, key: &str, tags: &[String]) -> Result<(), Error>;
+
+    /// Returns the keys of the records tagged with all of the tags, or with any
+    /// of them, in no particular order.
+    fn tagged(
+        &mut self,
+        keyspace: &Keyspace,
+        tags: &[String],
+        all: bool,
+    ) -> Result<Vec<String>, Error>;
+
+    /// Adds the record with the given key to the sets of the words it holds,
+    /// and keeps its words along with it, so that [Connection::del] removes it
+    /// from their sets.
+    fn index(&mut self, keyspace: &Keyspace, key: &str, terms: &[String]) -> Result<(), Error>;
+
+    /// Returns the keys of the records which hold every one of the words, in no
+    /// particular order.
+    fn search(&mut self, keyspace: &Keyspace, terms: &[String]) -> Result<Vec<String>, Error>;
+
+    /// Returns the IDs of the latest records in the feed, starting with the
+    /// most recent one.
+    fn latest(
+        &mut self,
+        keyspace: &Keyspace,
+        feed: &str,
+        count: usize,
+    ) -> Result<Vec<String>, Error>;
+
+    /// Returns the IDs of the latest records in the feed which are older than
+    /// the record with the given ID, starting with the most recent one.
+    fn latest_before(
+        &mut self,
+        keyspace: &Keyspace,
+        feed: &str,
+        before: &str,
+        count: usize,
+    ) -> Result<Vec<String>, Error>;
+
+    /// Stores the profile of a user under its handle, and associates the
+    /// public key of the user with the handle. Returns `false` without storing
+    /// anything if either the handle or the key is registered already.
+    fn register(
+        &mut self,
+        keyspace: &Keyspace,
+        handle: &str,
+        public: &str,
+        profile: &[u8],
+    ) -> Result<bool, Error>;
+
+    /// Reads the profile of the user with the given handle.
+    fn profile(&mut self, keyspace: &Keyspace, handle: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Returns the handle of the user with the given hex-encoded public key.
+    fn handle(&mut self, keyspace: &Keyspace, public: &str) -> Result<Option<String>, Error>;
+
+    /// Adds the actor to a set of the follow graph of the user. Returns `false`
+    /// if the actor was in the set already.
+    fn link(
+        &mut self,
+        keyspace: &Keyspace,
+        relation: Relation,
+        handle: &str,
+        actor: &str,
+    ) -> Result<bool, Error>;
+
+    /// Removes the actor from a set of the follow graph of the user. Returns
+    /// `false` if the actor was not in the set.
+    fn unlink(
+        &mut self,
+        keyspace: &Keyspace,
+        relation: Relation,
+        handle: &str,
+        actor: &str,
+    ) -> Result<bool, Error>;
+
+    /// Returns the actors in a set of the follow graph of the user, in no
+    /// particular order.
+    fn members(
+        &mut self,
+        keyspace: &Keyspace,
+        relation: Relation,
+        handle: &str,
+    ) -> Result<Vec<String>, Error>;
+
+    /// Adds a like of the identity with the given key to the record. Returns
+    /// `false` if the identity liked the record already.
+    fn like(&mut self, keyspace: &Keyspace, id: &str, identity: &str) -> Result<bool, Error>;
+
+    /// Records that the record with the given ID replies to the parent record.
+    /// Replies are ordered by their IDs like the records of feeds.
+    fn reply(&mut self, keyspace: &Keyspace, parent: &str, id: &ulid::Ulid) -> Result<(), Error>;
+
+    /// Returns the IDs of the earliest replies to the record, in the order in
+    /// which they were created.
+    fn replies(
+        &mut self,
+        keyspace: &Keyspace,
+        parent: &str,
+        count: usize,
+    ) -> Result<Vec<String>, Error>;
+
+    /// Returns the ID of the record the record replies to, if any.
+    fn parent(&mut self, keyspace: &Keyspace, id: &str) -> Result<Option<String>, Error>;
+
+    /// Counts the interactions with the record.
+    fn interactions(&mut self, keyspace: &Keyspace, id: &str) -> Result<Interactions, Error>;
+
+    /// Adds a health sample of the acknowledged node with the given address,
+    /// dropping the oldest samples beyond the window.
+    fn push_sample(
+        &mut self,
+        keyspace: &Keyspace,
+        addr: &str,
+        sample: &[u8],
+        window: usize,
+    ) -> Result<(), Error>;
+
+    /// Returns the health samples of the acknowledged node with the given
+    /// address, starting with the most recent one.
+    fn samples(&mut self, keyspace: &Keyspace, addr: &str) -> Result<Vec<Vec<u8>>, Error>;
+
+    /// Claims a journaled request of the client for the given time, unless the
+    /// request has been claimed or executed already. Pending claims are kept as
+    /// empty values, since responses are never empty.
+    fn claim(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        ttl: std::time::Duration,
+    ) -> Result<Claim, Error>;
+
+    /// Replaces the claim of a journaled request with its response, which is
+    /// kept for the given time.
+    fn complete(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        response: &[u8],
+        ttl: std::time::Duration,
+    ) -> Result<(), Error>;
+
+    /// Drops the claim of a journaled request, so that it can be executed
+    /// again.
+    fn release(&mut self, keyspace: &Keyspace, client: &str, id: &str) -> Result<(), Error>;
+
+    /// Starts an empty upload of the client, which is dropped unless a chunk
+    /// is appended to it within the given time.
+    fn begin_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        ttl: std::time::Duration,
+    ) -> Result<(), Error>;
+
+    /// Appends a chunk to the upload of the client, unless it would grow
+    /// beyond `max` bytes, and extends its lifetime by the given time.
+    fn append_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        chunk: &[u8],
+        max: usize,
+        ttl: std::time::Duration,
+    ) -> Result<Append, Error>;
+
+    /// Reads the value of the upload of the client.
+    fn read_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+    ) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Moves the value of the upload of the client into a new record with the
+    /// given ID and owner, without reading it, and adds the ID to the index
+    /// bucket of its creation time. Returns `false` without creating anything
+    /// if the client has no upload with the given ID, or if it is empty.
+    fn commit_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        record: &ulid::Ulid,
+        owner: Option<&str>,
+    ) -> Result<bool, Error>;
+
+    /// Drops the upload of the client.
+    fn discard_upload(&mut self, keyspace: &Keyspace, client: &str, id: &str) -> Result<(), Error>;
+
+    /// Returns the keys of the index buckets holding any records, in no
+    /// particular order.
+    fn buckets(&mut self, keyspace: &Keyspace) -> Result<Vec<String>, Error>;
+
+    /// Returns the IDs of the records in the index bucket with the given key,
+    /// in no particular order.
+    fn bucket(&mut self, keyspace: &Keyspace, bucket: &str) -> Result<Vec<String>, Error>;
+
+    /// Returns the length of the value of a record in bytes, or `0` if there is
+    /// no such record.
+    fn size(&mut self, keyspace: &Keyspace, key: &str) -> Result<usize, Error>;
+
+    /// Returns every key of the instance without its namespace, in no
+    /// particular order. Uploads which have not been committed yet are left
+    /// out, since they cannot be resumed elsewhere.
+    fn keys(&mut self, keyspace: &Keyspace) -> Result<Vec<String>, Error>;
+
+    /// Returns the keys of the instance which start with the prefix, without
+    /// their namespace, in no particular order. Uploads are left out the way
+    /// [Connection::keys] leaves them out.
+    fn scan_keys(&mut self, keyspace: &Keyspace, prefix: &str) -> Result<Vec<String>, Error>;
+
+    /// Reads the key of the instance with the given name, as returned by
+    /// [Connection::keys], or [None] if the key does not exist anymore.
+    fn export(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<Entry>, Error>;
+
+    /// Returns whether the key of the instance with the given name exists.
+    fn exists(&mut self, keyspace: &Keyspace, key: &str) -> Result<bool, Error>;
+
+    /// Replaces the key of the instance named by the entry with its value and
+    /// time to live, in a single transaction.
+    fn import(&mut self, keyspace: &Keyspace, entry: &Entry) -> Result<(), Error>;
+}
+
+/// A storage backend, which hands out connections to the handlers.
+pub(crate) trait Backend: Send + Sync {
+    /// Opens a new connection to the backend.
+    fn connection(&self) -> Result<Box<dyn Connection>, Error>;
+
+    /// Replaces a connection which failed with the given error, if the backend
+    /// is able to recover from it. Returns `false` if the error is unrelated to
+    /// the connection itself, in which case it is left untouched.
+    fn recover(&self, connection: &mut Box<dyn Connection>, e: &Error) -> Result<bool, Error> {
+        let _ = (connection, e);
+        Ok(false)
+    }
+
+    /// Moves the records stored under bare IDs into the namespace, resuming
+    /// from the saved progress, and reports the progress after every step.
+    /// Backends which never stored records under bare IDs have nothing to do.
+    fn migrate(
+        &self,
+        keyspace: &Keyspace,
+        settings: &Migration,
+        progress: &mut dyn FnMut(&migration::Report),
+    ) -> Result<migration::Report, Error> {
+        let _ = (keyspace, settings, progress);
+        Ok(migration::Report {
+            done: true,
+            ..Default::default()
+        })
+    }
+}
+
+/// The storage of a node, which is backed by Redis unless the connection
+/// string in the settings selects the in-memory backend with [MEMORY_SCHEME],
+/// or the sled backend with [SLED_SCHEME].
+pub(crate) struct Storage {
+    keyspace: Keyspace,
+    backend: Box<dyn Backend>,
+}
+
+impl Storage {
+    pub(crate) fn new(settings: &Settings) -> Result<Self, Error> {
+        Self::at(settings, &settings.redis_uri, settings.sentinel.as_ref())
+    }
+
+    /// Opens the storage of the node behind another connection string, such as
+    /// the backend a node is moved onto. See [crate::transfer].
+    pub(crate) fn at(
+        settings: &Settings,
+        uri: &str,
+        sentinel: Option<&Sentinel>,
+    ) -> Result<Self, Error> {
+        let backend: Box<dyn Backend> = if uri.starts_with(MEMORY_SCHEME) {
+            Box::<memory::Memory>::default()
+        } else if let Some(path) = uri.strip_prefix(SLED_SCHEME) {
+            sled(path)?
+        } else {
+            backend(uri, sentinel)?
+        };
+
+        Ok(Self {
+            keyspace: Keyspace {
+                namespace: settings.name.clone(),
+                partition: settings.partition,
+            },
+            backend,
+        })
+    }
+
+    /// Returns the layout of the keys of the instance.
+    #[inline(always)]
+    pub(crate) fn keyspace(&self) -> &Keyspace {
+        &self.keyspace
+    }
+
+    /// Opens a new connection to the backend.
+    #[inline(always)]
+    pub(crate) fn connection(&self) -> Result<Box<dyn Connection>, Error> {
+        self.backend.connection()
+    }
+
+    /// Migrates the records stored under bare IDs. See [Backend::migrate].
+    #[inline(always)]
+    pub(crate) fn migrate(
+        &self,
+        settings: &Migration,
+        progress: &mut dyn FnMut(&migration::Report),
+    ) -> Result<migration::Report, Error> {
+        self.backend.migrate(&self.keyspace, settings, progress)
+    }
+
+    /// Replaces a connection which failed with the given error. See
+    /// [Backend::recover].
+    #[inline(always)]
+    pub(crate) fn recover(
+        &self,
+        connection: &mut Box<dyn Connection>,
+        e: &Error,
+    ) -> Result<bool, Error> {
+        self.backend.recover(connection, e)
+    }
+}
+
+#[cfg(feature = "redis")]
+fn backend(uri: &str, sentinel: Option<&Sentinel>) -> Result<Box<dyn Backend>, Error> {
+    Ok(Box::new(
+        self::redis::Redis::new(uri, sentinel).map_err(Error::Redis)?,
+    ))
+}
+
+#[cfg(not(feature = "redis"))]
+fn backend(uri: &str, _: Option<&Sentinel>) -> Result<Box<dyn Backend>, Error> {
+    Err(Error::Unsupported(format!(
+        "{} requires the `redis` feature, only {} is available",
+        uri, MEMORY_SCHEME
+    )))
+}
+
+#[cfg(feature = "sled")]
+fn sled(path: &str) -> Result<Box<dyn Backend>, Error> {
+    Ok(Box::new(self::sled::Sled::open(path)?))
+}
+
+#[cfg(not(feature = "sled"))]
+fn sled(path: &str) -> Result<Box<dyn Backend>, Error> {
+    Err(Error::Unsupported(format!(
+        "{}{} requires the `sled` feature",
+        SLED_SCHEME, path
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyspace_buckets() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Hour,
+        };
+
+        let hour = 60 * 60 * 1000;
+        assert_eq!(keyspace.bucket(0), "multiverse9_test:index:hour:0");
+        assert_eq!(keyspace.bucket(hour - 1), keyspace.bucket(0));
+        assert_eq!(
+            keyspace.buckets(hour / 2, 2 * hour),
+            vec![
+                "multiverse9_test:index:hour:2",
+                "multiverse9_test:index:hour:1",
+                "multiverse9_test:index:hour:0",
+            ]
+        );
+        assert_eq!(keyspace.bucket_number(&keyspace.bucket(2 * hour)), Some(2));
+        assert_eq!(keyspace.bucket_number("multiverse9_test:user:alice"), None);
+        assert_eq!(keyspace.user("alice"), "multiverse9_test:user:alice");
+        assert_eq!(
+            keyspace.graph(Relation::Following, "alice"),
+            "multiverse9_test:following:alice"
+        );
+        assert_eq!(
+            keyspace.journal("user:alice", "01GQ"),
+            "multiverse9_test:journal:user:alice:01GQ"
+        );
+    }
+}
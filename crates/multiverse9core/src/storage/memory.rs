@@ -0,0 +1,1245 @@
+use std::collections::{hash_map, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{
+    Append, Backend, Claim, Connection, Entry, Error, Interactions, Keyspace, NewRecord, Relation,
+    Value,
+};
+
+/// Keeps all data in the memory of the process, so it is lost once the node
+/// stops. Used for embedded and local nodes which run without Redis. The keys
+/// are laid out the same way as in Redis, so that the handlers behave the same
+/// with either backend.
+#[derive(Default, Clone)]
+pub(crate) struct Memory {
+    data: Arc<Mutex<Data>>,
+}
+
+#[derive(Default)]
+struct Data {
+    values: Tracked<Vec<u8>>,
+    /// Sets, such as the index buckets and the feeds. Since [BTreeSet] orders
+    /// its members, feeds are kept in the order of their IDs like in Redis.
+    sets: Tracked<BTreeSet<String>>,
+    /// Lists, such as the health samples, with the most recent item first.
+    lists: Tracked<VecDeque<Vec<u8>>>,
+    /// Journal entries along with the time they expire at.
+    journal: HashMap<String, (Vec<u8>, Instant)>,
+    /// Uploads which have not been committed yet.
+    uploads: HashMap<String, Upload>,
+}
+
+struct Upload {
+    /// The client which started the upload.
+    client: String,
+    value: Vec<u8>,
+    expires: Instant,
+}
+
+/// Keys along with their values, which the changes made through the maps are
+/// recorded in once they are tracked.
+#[derive(Default)]
+struct Tracked<V> {
+    map: HashMap<String, V>,
+    /// The keys which were changed since they were last taken, if changes are
+    /// tracked at all.
+    changed: Option<HashSet<String>>,
+}
+
+impl<V> std::ops::Deref for Tracked<V> {
+    type Target = HashMap<String, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+impl<V> Tracked<V> {
+    fn change(&mut self, key: &str) {
+        if let Some(changed) = &mut self.changed {
+            changed.insert(key.into());
+        }
+    }
+
+    fn insert(&mut self, key: String, value: V) -> Option<V> {
+        self.change(&key);
+        self.map.insert(key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        self.change(key);
+        self.map.remove(key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        self.change(key);
+        self.map.get_mut(key)
+    }
+
+    fn entry(&mut self, key: String) -> hash_map::Entry<'_, String, V> {
+        self.change(&key);
+        self.map.entry(key)
+    }
+
+    #[cfg(feature = "sled")]
+    fn take_changed(&mut self) -> HashSet<String> {
+        self.changed
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+/// Writing through to a persistent backend, which only the
+/// [Sled](super::sled::Sled) backend does.
+#[cfg(feature = "sled")]
+impl Memory {
+    /// Creates a backend which keeps track of the keys changed in it, so that
+    /// they can be written through to a persistent backend, see
+    /// [Memory::flush].
+    pub(super) fn tracked() -> Self {
+        let memory = Self::default();
+        {
+            let mut data = memory.data.lock().unwrap();
+            data.values.changed = Some(Default::default());
+            data.sets.changed = Some(Default::default());
+            data.lists.changed = Some(Default::default());
+        }
+
+        memory
+    }
+
+    /// Loads a key of a persistent backend by its full name, without taking it
+    /// as changed. Sorted sets are kept as sets, like the ones in the memory
+    /// are.
+    pub(super) fn load(&self, name: String, value: Value) {
+        let mut data = self.data.lock().unwrap();
+        match value {
+            Value::String(value) => {
+                data.values.map.insert(name, value);
+            }
+            Value::List(items) => {
+                data.lists.map.insert(name, items.into());
+            }
+            Value::Set(members) => {
+                let members = members
+                    .iter()
+                    .map(|member| String::from_utf8_lossy(member).to_string());
+                data.sets.map.insert(name, members.collect());
+            }
+            Value::SortedSet(members) => {
+                let members = members
+                    .iter()
+                    .map(|(member, _)| String::from_utf8_lossy(member).to_string());
+                data.sets.map.insert(name, members.collect());
+            }
+        }
+    }
+
+    /// Passes the keys changed since the last flush to `write` by their full
+    /// name, along with their values, or [None] for the keys which went away.
+    /// The data stays locked until `write` returns, so that changes reach the
+    /// persistent backend in the order they were made, and the changes are
+    /// kept for the next flush if `write` fails. Journal entries and uploads
+    /// which have not been committed are never tracked, since they expire.
+    pub(super) fn flush(
+        &self,
+        write: impl FnOnce(Vec<(String, Option<Value>)>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut data = self.data.lock().unwrap();
+        let mut changed = data.values.take_changed();
+        changed.extend(data.sets.take_changed());
+        changed.extend(data.lists.take_changed());
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        let changes = changed
+            .iter()
+            .map(|name| {
+                let value = if let Some(value) = data.values.get(name) {
+                    Some(Value::String(value.clone()))
+                } else if let Some(set) = data.sets.get(name).filter(|set| !set.is_empty()) {
+                    let members = set.iter().map(|member| member.as_bytes().to_vec());
+                    Some(Value::Set(members.collect()))
+                } else {
+                    let list = data.lists.get(name).filter(|list| !list.is_empty());
+                    list.map(|list| Value::List(list.iter().cloned().collect()))
+                };
+
+                (name.clone(), value)
+            })
+            .collect();
+
+        write(changes).inspect_err(|_e| {
+            // The keys are taken as changed again, whichever map they are in,
+            // since the maps are flushed together.
+            for name in &changed {
+                data.values.change(name);
+            }
+        })
+    }
+}
+
+impl Backend for Memory {
+    fn connection(&self) -> Result<Box<dyn Connection>, Error> {
+        Ok(Box::new(MemoryConnection {
+            data: Arc::clone(&self.data),
+        }))
+    }
+}
+
+/// A handle to the data of a [Memory] backend.
+struct MemoryConnection {
+    data: Arc<Mutex<Data>>,
+}
+
+impl Connection for MemoryConnection {
+    fn get(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(data.values.get(&keyspace.key(key)).cloned())
+    }
+
+    fn signature(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(data.values.get(&keyspace.signature(key)).cloned())
+    }
+
+    fn owner(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<String>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .values
+            .get(&keyspace.owner(key))
+            .map(|owner| String::from_utf8_lossy(owner).to_string()))
+    }
+
+    fn create(
+        &mut self,
+        keyspace: &Keyspace,
+        id: &ulid::Ulid,
+        value: &[u8],
+        signature: Option<&[u8]>,
+        owner: Option<&str>,
+    ) -> Result<(), Error> {
+        let key = id.to_string();
+        let mut data = self.data.lock().unwrap();
+        data.values.insert(keyspace.key(&key), value.to_vec());
+        if let Some(signature) = signature {
+            data.values
+                .insert(keyspace.signature(&key), signature.to_vec());
+        }
+        if let Some(owner) = owner {
+            data.values
+                .insert(keyspace.owner(&key), owner.as_bytes().to_vec());
+        }
+
+        data.sets
+            .entry(keyspace.bucket(id.timestamp_ms()))
+            .or_default()
+            .insert(key);
+        Ok(())
+    }
+
+    fn create_many(&mut self, keyspace: &Keyspace, records: &[NewRecord]) -> Result<(), Error> {
+        for record in records {
+            let signature = record.signature.as_deref();
+            self.create(keyspace, &record.id, record.value, signature, record.owner)?;
+        }
+
+        Ok(())
+    }
+
+    fn create_content(
+        &mut self,
+        keyspace: &Keyspace,
+        key: &str,
+        value: &[u8],
+        signature: Option<&[u8]>,
+        owner: Option<&str>,
+        referrer: &str,
+    ) -> Result<bool, Error> {
+        let mut data = self.data.lock().unwrap();
+        let references = keyspace.references(key);
+        let created = !data.values.contains_key(&keyspace.key(key));
+        if created {
+            data.values.insert(keyspace.key(key), value.to_vec());
+            if let Some(signature) = signature {
+                data.values
+                    .insert(keyspace.signature(key), signature.to_vec());
+            }
+            if let Some(owner) = owner {
+                data.values
+                    .insert(keyspace.owner(key), owner.as_bytes().to_vec());
+            }
+        } else if !data.sets.contains_key(&references) {
+            if let Some(owner) = data.values.get(&keyspace.owner(key)) {
+                let owner = String::from_utf8_lossy(owner).to_string();
+                data.sets
+                    .entry(references.clone())
+                    .or_default()
+                    .insert(owner);
+            }
+        }
+
+        data.sets
+            .entry(references)
+            .or_default()
+            .insert(referrer.into());
+        Ok(created)
+    }
+
+    fn references(&mut self, keyspace: &Keyspace, key: &str) -> Result<Vec<String>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(match data.sets.get(&keyspace.references(key)) {
+            Some(references) => references.iter().cloned().collect(),
+            None => vec![],
+        })
+    }
+
+    fn dereference(
+        &mut self,
+        keyspace: &Keyspace,
+        key: &str,
+        referrer: &str,
+    ) -> Result<Option<usize>, Error> {
+        let mut data = self.data.lock().unwrap();
+        let left = match data.sets.get_mut(&keyspace.references(key)) {
+            Some(references) => match references.remove(referrer) {
+                true => references.len(),
+                false => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+
+        if left == 0 {
+            remove(&mut data, keyspace, key);
+        }
+
+        Ok(Some(left))
+    }
+
+    fn del(&mut self, keyspace: &Keyspace, keys: &[String]) -> Result<(), Error> {
+        let mut data = self.data.lock().unwrap();
+        for key in keys {
+            remove(&mut data, keyspace, key);
+        }
+
+        Ok(())
+    }
+
+    fn tag(&mut self, keyspace: &Keyspace, key: &str, tags: &[String]) -> Result<(), Error> {
+        let mut data = self.data.lock().unwrap();
+        for tag in tags {
+            let set = data.sets.entry(keyspace.tag(tag)).or_default();
+            set.insert(key.to_string());
+        }
+
+        let set = data.sets.entry(keyspace.tags(key)).or_default();
+        set.extend(tags.iter().cloned());
+        Ok(())
+    }
+
+    fn tagged(
+        &mut self,
+        keyspace: &Keyspace,
+        tags: &[String],
+        all: bool,
+    ) -> Result<Vec<String>, Error> {
+        let data = self.data.lock().unwrap();
+        let empty = BTreeSet::new();
+        let mut sets = tags
+            .iter()
+            .map(|tag| data.sets.get(&keyspace.tag(tag)).unwrap_or(&empty));
+        let first = sets.next().cloned().unwrap_or_default();
+        Ok(sets
+            .fold(first, |keys, set| match all {
+                true => keys.intersection(set).cloned().collect(),
+                false => keys.union(set).cloned().collect(),
+            })
+            .into_iter()
+            .collect())
+    }
+
+    fn index(&mut self, keyspace: &Keyspace, key: &str, terms: &[String]) -> Result<(), Error> {
+        let mut data = self.data.lock().unwrap();
+        for term in terms {
+            let set = data.sets.entry(keyspace.term(term)).or_default();
+            set.insert(key.to_string());
+        }
+
+        let set = data.sets.entry(keyspace.terms(key)).or_default();
+        set.extend(terms.iter().cloned());
+        Ok(())
+    }
+
+    fn search(&mut self, keyspace: &Keyspace, terms: &[String]) -> Result<Vec<String>, Error> {
+        let data = self.data.lock().unwrap();
+        let empty = BTreeSet::new();
+        let mut sets = terms
+            .iter()
+            .map(|term| data.sets.get(&keyspace.term(term)).unwrap_or(&empty));
+        let first = sets.next().cloned().unwrap_or_default();
+        Ok(sets
+            .fold(first, |keys, set| keys.intersection(set).cloned().collect())
+            .into_iter()
+            .collect())
+    }
+
+    fn post(&mut self, keyspace: &Keyspace, feed: &str, ids: &[ulid::Ulid]) -> Result<(), Error> {
+        let mut data = self.data.lock().unwrap();
+        let feed = data.sets.entry(keyspace.feed(feed)).or_default();
+        feed.extend(ids.iter().map(|id| id.to_string()));
+        Ok(())
+    }
+
+    fn latest(
+        &mut self,
+        keyspace: &Keyspace,
+        feed: &str,
+        count: usize,
+    ) -> Result<Vec<String>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(match data.sets.get(&keyspace.feed(feed)) {
+            Some(feed) => feed.iter().rev().take(count).cloned().collect(),
+            None => vec![],
+        })
+    }
+
+    fn latest_before(
+        &mut self,
+        keyspace: &Keyspace,
+        feed: &str,
+        before: &str,
+        count: usize,
+    ) -> Result<Vec<String>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(match data.sets.get(&keyspace.feed(feed)) {
+            Some(feed) => feed
+                .range::<str, _>((Bound::Unbounded, Bound::Excluded(before)))
+                .rev()
+                .take(count)
+                .cloned()
+                .collect(),
+            None => vec![],
+        })
+    }
+
+    fn register(
+        &mut self,
+        keyspace: &Keyspace,
+        handle: &str,
+        public: &str,
+        profile: &[u8],
+    ) -> Result<bool, Error> {
+        let (user, user_key) = (keyspace.user(handle), keyspace.user_key(public));
+        let mut data = self.data.lock().unwrap();
+        if data.values.contains_key(&user) || data.values.contains_key(&user_key) {
+            return Ok(false);
+        }
+
+        data.values.insert(user, profile.to_vec());
+        data.values.insert(user_key, handle.as_bytes().to_vec());
+        Ok(true)
+    }
+
+    fn profile(&mut self, keyspace: &Keyspace, handle: &str) -> Result<Option<Vec<u8>>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(data.values.get(&keyspace.user(handle)).cloned())
+    }
+
+    fn handle(&mut self, keyspace: &Keyspace, public: &str) -> Result<Option<String>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .values
+            .get(&keyspace.user_key(public))
+            .map(|handle| String::from_utf8_lossy(handle).to_string()))
+    }
+
+    fn link(
+        &mut self,
+        keyspace: &Keyspace,
+        relation: Relation,
+        handle: &str,
+        actor: &str,
+    ) -> Result<bool, Error> {
+        let mut data = self.data.lock().unwrap();
+        let set = data
+            .sets
+            .entry(keyspace.graph(relation, handle))
+            .or_default();
+        Ok(set.insert(actor.to_string()))
+    }
+
+    fn unlink(
+        &mut self,
+        keyspace: &Keyspace,
+        relation: Relation,
+        handle: &str,
+        actor: &str,
+    ) -> Result<bool, Error> {
+        let mut data = self.data.lock().unwrap();
+        Ok(match data.sets.get_mut(&keyspace.graph(relation, handle)) {
+            Some(set) => set.remove(actor),
+            None => false,
+        })
+    }
+
+    fn members(
+        &mut self,
+        keyspace: &Keyspace,
+        relation: Relation,
+        handle: &str,
+    ) -> Result<Vec<String>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(match data.sets.get(&keyspace.graph(relation, handle)) {
+            Some(set) => set.iter().cloned().collect(),
+            None => vec![],
+        })
+    }
+
+    fn like(&mut self, keyspace: &Keyspace, id: &str, identity: &str) -> Result<bool, Error> {
+        let mut data = self.data.lock().unwrap();
+        let likes = data.sets.entry(keyspace.likes(id)).or_default();
+        Ok(likes.insert(identity.to_string()))
+    }
+
+    fn reply(&mut self, keyspace: &Keyspace, parent: &str, id: &ulid::Ulid) -> Result<(), Error> {
+        let key = id.to_string();
+        let mut data = self.data.lock().unwrap();
+        data.values
+            .insert(keyspace.parent(&key), parent.as_bytes().to_vec());
+        data.sets
+            .entry(keyspace.replies(parent))
+            .or_default()
+            .insert(key);
+        Ok(())
+    }
+
+    fn replies(
+        &mut self,
+        keyspace: &Keyspace,
+        parent: &str,
+        count: usize,
+    ) -> Result<Vec<String>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(match data.sets.get(&keyspace.replies(parent)) {
+            Some(replies) => replies.iter().take(count).cloned().collect(),
+            None => vec![],
+        })
+    }
+
+    fn parent(&mut self, keyspace: &Keyspace, id: &str) -> Result<Option<String>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .values
+            .get(&keyspace.parent(id))
+            .map(|parent| String::from_utf8_lossy(parent).to_string()))
+    }
+
+    fn interactions(&mut self, keyspace: &Keyspace, id: &str) -> Result<Interactions, Error> {
+        let data = self.data.lock().unwrap();
+        let len = |key: String| data.sets.get(&key).map_or(0, |set| set.len());
+        Ok(Interactions {
+            likes: len(keyspace.likes(id)),
+            replies: len(keyspace.replies(id)),
+        })
+    }
+
+    fn push_sample(
+        &mut self,
+        keyspace: &Keyspace,
+        addr: &str,
+        sample: &[u8],
+        window: usize,
+    ) -> Result<(), Error> {
+        let mut data = self.data.lock().unwrap();
+        let samples = data.lists.entry(keyspace.health(addr)).or_default();
+        samples.push_front(sample.to_vec());
+        samples.truncate(window);
+        Ok(())
+    }
+
+    fn samples(&mut self, keyspace: &Keyspace, addr: &str) -> Result<Vec<Vec<u8>>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(match data.lists.get(&keyspace.health(addr)) {
+            Some(samples) => samples.iter().cloned().collect(),
+            None => vec![],
+        })
+    }
+
+    fn claim(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        ttl: Duration,
+    ) -> Result<Claim, Error> {
+        let key = keyspace.journal(client, id);
+        let now = Instant::now();
+        let mut data = self.data.lock().unwrap();
+        Ok(match data.journal.get(&key) {
+            Some((response, expires)) if *expires > now => match response.is_empty() {
+                true => Claim::Pending,
+                false => Claim::Done(response.clone()),
+            },
+            _ => {
+                data.journal.insert(key, (vec![], now + ttl));
+                Claim::Acquired
+            }
+        })
+    }
+
+    /// Expired entries are dropped along the way, since there is nothing else
+    /// which would drop them.
+    fn complete(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        response: &[u8],
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut data = self.data.lock().unwrap();
+        data.journal.retain(|_, (_, expires)| *expires > now);
+        data.journal
+            .insert(keyspace.journal(client, id), (response.to_vec(), now + ttl));
+        Ok(())
+    }
+
+    fn release(&mut self, keyspace: &Keyspace, client: &str, id: &str) -> Result<(), Error> {
+        let mut data = self.data.lock().unwrap();
+        data.journal.remove(&keyspace.journal(client, id));
+        Ok(())
+    }
+
+    /// Expired uploads are dropped along the way, since there is nothing else
+    /// which would drop them.
+    fn begin_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut data = self.data.lock().unwrap();
+        data.uploads.retain(|_, upload| upload.expires > now);
+        data.uploads.insert(
+            keyspace.upload(id),
+            Upload {
+                client: client.into(),
+                value: vec![],
+                expires: now + ttl,
+            },
+        );
+        Ok(())
+    }
+
+    fn append_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        chunk: &[u8],
+        max: usize,
+        ttl: Duration,
+    ) -> Result<Append, Error> {
+        let now = Instant::now();
+        let mut data = self.data.lock().unwrap();
+        let upload = match data.uploads.get_mut(&keyspace.upload(id)) {
+            Some(upload) if upload.client == client && upload.expires > now => upload,
+            _ => return Ok(Append::Unknown),
+        };
+
+        if upload.value.len() + chunk.len() > max {
+            return Ok(Append::Oversized);
+        }
+
+        upload.value.extend_from_slice(chunk);
+        upload.expires = now + ttl;
+        Ok(Append::Appended(upload.value.len()))
+    }
+
+    fn read_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(match data.uploads.get(&keyspace.upload(id)) {
+            Some(upload) if upload.client == client && upload.expires > Instant::now() => {
+                Some(upload.value.clone())
+            }
+            _ => None,
+        })
+    }
+
+    fn commit_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        record: &ulid::Ulid,
+        owner: Option<&str>,
+    ) -> Result<bool, Error> {
+        let now = Instant::now();
+        let mut data = self.data.lock().unwrap();
+        let key = keyspace.upload(id);
+        match data.uploads.get(&key) {
+            Some(upload)
+                if upload.client == client && upload.expires > now && !upload.value.is_empty() => {}
+            _ => return Ok(false),
+        }
+
+        let value = data.uploads.remove(&key).unwrap().value;
+        let bucket = keyspace.bucket(record.timestamp_ms());
+        let record = record.to_string();
+        data.values.insert(keyspace.key(&record), value);
+        if let Some(owner) = owner {
+            data.values
+                .insert(keyspace.owner(&record), owner.as_bytes().to_vec());
+        }
+
+        data.sets.entry(bucket).or_default().insert(record);
+        Ok(true)
+    }
+
+    fn discard_upload(&mut self, keyspace: &Keyspace, client: &str, id: &str) -> Result<(), Error> {
+        let mut data = self.data.lock().unwrap();
+        let key = keyspace.upload(id);
+        if data
+            .uploads
+            .get(&key)
+            .is_some_and(|upload| upload.client == client)
+        {
+            data.uploads.remove(&key);
+        }
+
+        Ok(())
+    }
+
+    fn buckets(&mut self, keyspace: &Keyspace) -> Result<Vec<String>, Error> {
+        let data = self.data.lock().unwrap();
+        let index = keyspace.index();
+        Ok(data
+            .sets
+            .iter()
+            .filter(|(key, set)| key.starts_with(&index) && !set.is_empty())
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn bucket(&mut self, _: &Keyspace, bucket: &str) -> Result<Vec<String>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(match data.sets.get(bucket) {
+            Some(set) => set.iter().cloned().collect(),
+            None => vec![],
+        })
+    }
+
+    fn size(&mut self, keyspace: &Keyspace, key: &str) -> Result<usize, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(data.values.get(&keyspace.key(key)).map_or(0, Vec::len))
+    }
+
+    fn keys(&mut self, keyspace: &Keyspace) -> Result<Vec<String>, Error> {
+        let now = Instant::now();
+        let data = self.data.lock().unwrap();
+        let namespace = keyspace.key("");
+        Ok(data
+            .values
+            .keys()
+            .chain(
+                data.sets
+                    .iter()
+                    .filter(|(_, set)| !set.is_empty())
+                    .map(|(key, _)| key),
+            )
+            .chain(data.lists.keys())
+            .chain(
+                data.journal
+                    .iter()
+                    .filter(|(_, (_, expires))| *expires > now)
+                    .map(|(key, _)| key),
+            )
+            .filter_map(|key| key.strip_prefix(&namespace))
+            .map(String::from)
+            .collect())
+    }
+
+    fn scan_keys(&mut self, keyspace: &Keyspace, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut keys = self.keys(keyspace)?;
+        keys.retain(|key| key.starts_with(prefix));
+        Ok(keys)
+    }
+
+    /// Sets holding feeds and replies are exported as the sorted sets they are
+    /// in Redis, with every member having the same score.
+    fn export(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<Entry>, Error> {
+        let now = Instant::now();
+        let name = keyspace.key(key);
+        let data = self.data.lock().unwrap();
+        let (value, ttl) = if let Some(value) = data.values.get(&name) {
+            (Value::String(value.clone()), None)
+        } else if let Some(set) = data.sets.get(&name).filter(|set| !set.is_empty()) {
+            let members = set.iter().map(|member| member.as_bytes().to_vec());
+            match keyspace.is_sorted(&name) {
+                true => (
+                    Value::SortedSet(members.map(|member| (member, 0.0)).collect()),
+                    None,
+                ),
+                false => (Value::Set(members.collect()), None),
+            }
+        } else if let Some(list) = data.lists.get(&name) {
+            (Value::List(list.iter().cloned().collect()), None)
+        } else {
+            match data.journal.get(&name) {
+                Some((response, expires)) if *expires > now => {
+                    (Value::String(response.clone()), Some(*expires - now))
+                }
+                _ => return Ok(None),
+            }
+        };
+
+        Ok(Some(Entry {
+            key: key.into(),
+            value,
+            ttl,
+        }))
+    }
+
+    fn exists(&mut self, keyspace: &Keyspace, key: &str) -> Result<bool, Error> {
+        let name = keyspace.key(key);
+        let data = self.data.lock().unwrap();
+        Ok(data.values.contains_key(&name)
+            || data.sets.get(&name).is_some_and(|set| !set.is_empty())
+            || data.lists.contains_key(&name)
+            || data
+                .journal
+                .get(&name)
+                .is_some_and(|(_, expires)| *expires > Instant::now()))
+    }
+
+    /// Only journal entries expire, so strings with a time to live are kept as
+    /// journal entries and every other time to live is dropped.
+    fn import(&mut self, keyspace: &Keyspace, entry: &Entry) -> Result<(), Error> {
+        let name = keyspace.key(&entry.key);
+        let mut data = self.data.lock().unwrap();
+        data.values.remove(&name);
+        data.sets.remove(&name);
+        data.lists.remove(&name);
+        data.journal.remove(&name);
+        match &entry.value {
+            Value::String(value) => match entry.ttl {
+                Some(ttl) => {
+                    data.journal
+                        .insert(name, (value.clone(), Instant::now() + ttl));
+                }
+                None => {
+                    data.values.insert(name, value.clone());
+                }
+            },
+            Value::List(items) => {
+                data.lists.insert(name, items.iter().cloned().collect());
+            }
+            Value::Set(members) => {
+                let members = members
+                    .iter()
+                    .map(|member| String::from_utf8_lossy(member).to_string());
+                data.sets.insert(name, members.collect());
+            }
+            Value::SortedSet(members) => {
+                let members = members
+                    .iter()
+                    .map(|(member, _)| String::from_utf8_lossy(member).to_string());
+                data.sets.insert(name, members.collect());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes a record along with everything kept about it. See
+/// [Connection::del].
+fn remove(data: &mut Data, keyspace: &Keyspace, key: &str) {
+    data.values.remove(&keyspace.key(key));
+    data.values.remove(&keyspace.signature(key));
+    data.values.remove(&keyspace.owner(key));
+    data.sets.remove(&keyspace.references(key));
+    data.sets.remove(&keyspace.likes(key));
+    data.sets.remove(&keyspace.replies(key));
+    for tag in data.sets.remove(&keyspace.tags(key)).unwrap_or_default() {
+        if let Some(tagged) = data.sets.get_mut(&keyspace.tag(&tag)) {
+            tagged.remove(key);
+        }
+    }
+    for term in data.sets.remove(&keyspace.terms(key)).unwrap_or_default() {
+        if let Some(indexed) = data.sets.get_mut(&keyspace.term(&term)) {
+            indexed.remove(key);
+        }
+    }
+    if let Some(parent) = data.values.remove(&keyspace.parent(key)) {
+        let parent = String::from_utf8_lossy(&parent).to_string();
+        if let Some(replies) = data.sets.get_mut(&keyspace.replies(&parent)) {
+            replies.remove(key);
+        }
+    }
+    if let Ok(id) = ulid::Ulid::from_string(key) {
+        if let Some(bucket) = data.sets.get_mut(&keyspace.bucket(id.timestamp_ms())) {
+            bucket.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Partition;
+
+    #[test]
+    fn test_records() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Day,
+        };
+
+        let mut connection = Memory::default().connection().unwrap();
+        let id = ulid::Ulid::new();
+        connection
+            .create(&keyspace, &id, b"value", Some(b"signature"), Some("alice"))
+            .unwrap();
+
+        let key = id.to_string();
+        assert_eq!(
+            connection.get(&keyspace, &key).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            connection.signature(&keyspace, &key).unwrap(),
+            Some(b"signature".to_vec())
+        );
+        assert_eq!(
+            connection.owner(&keyspace, &key).unwrap(),
+            Some("alice".into())
+        );
+
+        connection
+            .del(&keyspace, std::slice::from_ref(&key))
+            .unwrap();
+        assert_eq!(connection.get(&keyspace, &key).unwrap(), None);
+        assert_eq!(connection.signature(&keyspace, &key).unwrap(), None);
+        assert_eq!(connection.owner(&keyspace, &key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_content_records() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Day,
+        };
+
+        let mut connection = Memory::default().connection().unwrap();
+        let key = crate::content::key(b"value");
+        assert!(connection
+            .create_content(&keyspace, &key, b"value", None, Some("alice"), "alice")
+            .unwrap());
+        // Identical content is only stored once, and keeps its first owner.
+        assert!(!connection
+            .create_content(&keyspace, &key, b"value", None, Some("bob"), "bob")
+            .unwrap());
+        assert_eq!(
+            connection.owner(&keyspace, &key).unwrap(),
+            Some("alice".into())
+        );
+        assert_eq!(
+            connection.references(&keyspace, &key).unwrap(),
+            vec!["alice", "bob"]
+        );
+
+        // The record is only removed along with its last reference.
+        assert_eq!(
+            connection.dereference(&keyspace, &key, "alice").unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            connection.dereference(&keyspace, &key, "alice").unwrap(),
+            None
+        );
+        assert!(connection.get(&keyspace, &key).unwrap().is_some());
+        assert_eq!(
+            connection.dereference(&keyspace, &key, "bob").unwrap(),
+            Some(0)
+        );
+        assert_eq!(connection.get(&keyspace, &key).unwrap(), None);
+        assert_eq!(connection.owner(&keyspace, &key).unwrap(), None);
+
+        connection
+            .create_content(&keyspace, &key, b"value", None, None, "anonymous")
+            .unwrap();
+        connection
+            .del(&keyspace, std::slice::from_ref(&key))
+            .unwrap();
+        assert_eq!(connection.get(&keyspace, &key).unwrap(), None);
+        assert!(connection.references(&keyspace, &key).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_register() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Day,
+        };
+
+        let mut connection = Memory::default().connection().unwrap();
+        assert!(connection
+            .register(&keyspace, "alice", "aa", b"profile")
+            .unwrap());
+        // Neither the handle nor the key can be registered twice.
+        assert!(!connection
+            .register(&keyspace, "alice", "bb", b"other")
+            .unwrap());
+        assert!(!connection
+            .register(&keyspace, "bob", "aa", b"other")
+            .unwrap());
+
+        assert_eq!(
+            connection.profile(&keyspace, "alice").unwrap(),
+            Some(b"profile".to_vec())
+        );
+        assert_eq!(
+            connection.handle(&keyspace, "aa").unwrap(),
+            Some("alice".into())
+        );
+        assert_eq!(connection.handle(&keyspace, "bb").unwrap(), None);
+    }
+
+    #[test]
+    fn test_feed_order() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Day,
+        };
+
+        let backend = Memory::default();
+        let ids: Vec<ulid::Ulid> = (0..3).map(|ms| ulid::Ulid::from_parts(ms, 0)).collect();
+        // Posting out of order, over different connections to the same data.
+        backend
+            .connection()
+            .unwrap()
+            .post(&keyspace, "news", &[ids[1], ids[0]])
+            .unwrap();
+        backend
+            .connection()
+            .unwrap()
+            .post(&keyspace, "news", &[ids[2]])
+            .unwrap();
+
+        let latest = backend
+            .connection()
+            .unwrap()
+            .latest(&keyspace, "news", 2)
+            .unwrap();
+        assert_eq!(latest, vec![ids[2].to_string(), ids[1].to_string()]);
+
+        let before = backend
+            .connection()
+            .unwrap()
+            .latest_before(&keyspace, "news", &ids[1].to_string(), 2)
+            .unwrap();
+        assert_eq!(before, vec![ids[0].to_string()]);
+    }
+
+    #[test]
+    fn test_graph() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Day,
+        };
+
+        let mut connection = Memory::default().connection().unwrap();
+        let following = Relation::Following;
+        assert!(connection
+            .link(&keyspace, following, "alice", "bob")
+            .unwrap());
+        assert!(!connection
+            .link(&keyspace, following, "alice", "bob")
+            .unwrap());
+        assert!(connection
+            .link(&keyspace, following, "alice", "carol@127.0.0.1:8000")
+            .unwrap());
+        assert_eq!(
+            connection.members(&keyspace, following, "alice").unwrap(),
+            vec!["bob", "carol@127.0.0.1:8000"]
+        );
+        assert!(connection
+            .members(&keyspace, Relation::Followers, "alice")
+            .unwrap()
+            .is_empty());
+
+        assert!(connection
+            .unlink(&keyspace, following, "alice", "bob")
+            .unwrap());
+        assert!(!connection
+            .unlink(&keyspace, following, "alice", "bob")
+            .unwrap());
+    }
+
+    #[test]
+    fn test_journal() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Day,
+        };
+
+        let mut connection = Memory::default().connection().unwrap();
+        let ttl = Duration::from_secs(60);
+        let claim = |connection: &mut Box<dyn Connection>, id| {
+            connection.claim(&keyspace, "user:alice", id, ttl).unwrap()
+        };
+
+        assert_eq!(claim(&mut connection, "1"), Claim::Acquired);
+        assert_eq!(claim(&mut connection, "1"), Claim::Pending);
+        connection
+            .complete(&keyspace, "user:alice", "1", b"response", ttl)
+            .unwrap();
+        assert_eq!(
+            claim(&mut connection, "1"),
+            Claim::Done(b"response".to_vec())
+        );
+
+        // Released and expired claims can be acquired again.
+        assert_eq!(claim(&mut connection, "2"), Claim::Acquired);
+        connection.release(&keyspace, "user:alice", "2").unwrap();
+        assert_eq!(claim(&mut connection, "2"), Claim::Acquired);
+        assert_eq!(
+            connection
+                .claim(&keyspace, "user:alice", "3", Duration::ZERO)
+                .unwrap(),
+            Claim::Acquired
+        );
+        assert_eq!(claim(&mut connection, "3"), Claim::Acquired);
+    }
+
+    #[test]
+    fn test_uploads() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Day,
+        };
+
+        let mut connection = Memory::default().connection().unwrap();
+        let ttl = Duration::from_secs(60);
+        connection
+            .begin_upload(&keyspace, "user:alice", "1", ttl)
+            .unwrap();
+        let mut append = |client, chunk: &[u8]| {
+            connection
+                .append_upload(&keyspace, client, "1", chunk, 8, ttl)
+                .unwrap()
+        };
+
+        assert_eq!(append("user:alice", b"val"), Append::Appended(3));
+        assert_eq!(append("user:alice", b"ue"), Append::Appended(5));
+        assert_eq!(append("user:alice", b"toolong"), Append::Oversized);
+        // Uploads can only be continued by the client which started them.
+        assert_eq!(append("user:bob", b"!"), Append::Unknown);
+
+        let record = ulid::Ulid::new();
+        assert!(!connection
+            .commit_upload(&keyspace, "user:bob", "1", &record, None)
+            .unwrap());
+        assert!(connection
+            .commit_upload(&keyspace, "user:alice", "1", &record, Some("alice"))
+            .unwrap());
+        assert_eq!(
+            connection.get(&keyspace, &record.to_string()).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(
+            connection
+                .read_upload(&keyspace, "user:alice", "1")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_interactions() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Day,
+        };
+
+        let mut connection = Memory::default().connection().unwrap();
+        let (parent, reply) = (ulid::Ulid::new(), ulid::Ulid::new());
+        let (parent_key, reply_key) = (parent.to_string(), reply.to_string());
+        connection
+            .create(&keyspace, &parent, b"parent", None, None)
+            .unwrap();
+        connection
+            .create(&keyspace, &reply, b"reply", None, None)
+            .unwrap();
+        connection.reply(&keyspace, &parent_key, &reply).unwrap();
+
+        assert!(connection
+            .like(&keyspace, &parent_key, "user:alice")
+            .unwrap());
+        assert!(!connection
+            .like(&keyspace, &parent_key, "user:alice")
+            .unwrap());
+        assert!(connection.like(&keyspace, &parent_key, "user:bob").unwrap());
+        assert_eq!(
+            connection.interactions(&keyspace, &parent_key).unwrap(),
+            Interactions {
+                likes: 2,
+                replies: 1
+            }
+        );
+        assert_eq!(
+            connection.replies(&keyspace, &parent_key, 10).unwrap(),
+            vec![reply_key.clone()]
+        );
+        assert_eq!(
+            connection.parent(&keyspace, &reply_key).unwrap(),
+            Some(parent_key.clone())
+        );
+
+        // Removing the reply takes it out of the replies to its parent.
+        connection
+            .del(&keyspace, std::slice::from_ref(&reply_key))
+            .unwrap();
+        assert_eq!(
+            connection.interactions(&keyspace, &parent_key).unwrap(),
+            Interactions {
+                likes: 2,
+                replies: 0
+            }
+        );
+        assert_eq!(connection.parent(&keyspace, &reply_key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_samples_window() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Day,
+        };
+
+        let mut connection = Memory::default().connection().unwrap();
+        for sample in [b"1", b"2", b"3"] {
+            connection
+                .push_sample(&keyspace, "127.0.0.1:1", sample, 2)
+                .unwrap();
+        }
+
+        assert_eq!(
+            connection.samples(&keyspace, "127.0.0.1:1").unwrap(),
+            vec![b"3".to_vec(), b"2".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,998 @@
+use log::*;
+use redis::{Commands, IntoConnectionInfo};
+use std::sync::Mutex;
+
+use super::{
+    Append, Backend, Claim, Connection, Entry, Error, Interactions, Keyspace, NewRecord, Relation,
+    Value,
+};
+use crate::migration::Report;
+use crate::settings::{Migration, Sentinel};
+
+/// Matches keys with the length of a ULID, which legacy records are stored
+/// under.
+const LEGACY_PATTERN: &str = "??????????????????????????";
+
+/// Hands out connections to the Redis backend of a node. When the node is
+/// configured with sentinels, the address of the current master is resolved
+/// through them, and resolved again whenever the master goes away.
+pub(crate) struct Redis {
+    /// Connection string from the settings. When sentinels are used, only the
+    /// credentials and database index are taken from it.
+    uri: String,
+    sentinel: Option<Sentinel>,
+    /// Client for the currently known master. Cleared on failover.
+    client: Mutex<Option<redis::Client>>,
+}
+
+impl Redis {
+    pub(crate) fn new(uri: &str, sentinel: Option<&Sentinel>) -> redis::RedisResult<Self> {
+        let backend = Self {
+            uri: uri.into(),
+            sentinel: sentinel.cloned(),
+            client: Mutex::new(None),
+        };
+
+        // Resolving eagerly, so that misconfigurations are reported on startup
+        // instead of on the first request.
+        let client = backend.resolve()?;
+        *backend.client.lock().unwrap() = Some(client);
+        Ok(backend)
+    }
+
+    /// Opens a new connection to the current master. If the connection cannot
+    /// be established and sentinels are configured, the master is resolved
+    /// again and the connection is retried once.
+    fn connect(&self) -> redis::RedisResult<redis::Connection> {
+        let client = self.client()?;
+        match client.get_connection() {
+            Ok(connection) => Ok(connection),
+            Err(e) if self.sentinel.is_some() && is_failover(&e) => {
+                warn!("Redis master is unreachable, asking sentinels: {}", e);
+                self.invalidate();
+                self.client()?.get_connection()
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    #[inline(always)]
+    fn invalidate(&self) {
+        self.client.lock().unwrap().take();
+    }
+
+    fn client(&self) -> redis::RedisResult<redis::Client> {
+        let mut client = self.client.lock().unwrap();
+        if let Some(client) = &*client {
+            return Ok(client.clone());
+        }
+
+        let resolved = self.resolve()?;
+        *client = Some(resolved.clone());
+        Ok(resolved)
+    }
+
+    /// Creates a client for the master. Without sentinels this is simply the
+    /// client for the configured connection string.
+    fn resolve(&self) -> redis::RedisResult<redis::Client> {
+        let mut info = self.uri.as_str().into_connection_info()?;
+        let sentinel = match &self.sentinel {
+            Some(sentinel) => sentinel,
+            None => return redis::Client::open(info),
+        };
+
+        let mut last_error = None;
+        for endpoint in &sentinel.endpoints {
+            match master_addr(endpoint, &sentinel.master) {
+                Ok((host, port)) => {
+                    info!("Sentinel {} reports master at {}:{}", endpoint, host, port);
+                    info.addr = redis::ConnectionAddr::Tcp(host, port);
+                    return redis::Client::open(info);
+                }
+
+                Err(e) => {
+                    warn!("Sentinel {} could not be queried: {}", endpoint, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            (
+                redis::ErrorKind::InvalidClientConfig,
+                "No sentinel endpoints configured",
+            )
+                .into()
+        }))
+    }
+}
+
+impl Backend for Redis {
+    fn connection(&self) -> Result<Box<dyn Connection>, Error> {
+        Ok(Box::new(self.connect().map_err(Error::Redis)?))
+    }
+
+    /// Replaces the connection if the error indicates that the master went away.
+    fn recover(&self, connection: &mut Box<dyn Connection>, e: &Error) -> Result<bool, Error> {
+        match e {
+            Error::Redis(e) if is_failover(e) => {
+                self.invalidate();
+                *connection = self.connection()?;
+                info!("Reconnected to Redis after a failover");
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn migrate(
+        &self,
+        keyspace: &Keyspace,
+        settings: &Migration,
+        progress: &mut dyn FnMut(&Report),
+    ) -> Result<Report, Error> {
+        let mut connection = self.connect().map_err(Error::Redis)?;
+        migrate(&mut connection, keyspace, settings, progress).map_err(Error::Redis)
+    }
+}
+
+impl Connection for redis::Connection {
+    /// # Functionality
+    ///
+    /// Keys written before namespacing was introduced are stored without a
+    /// prefix. If the namespaced key does not exist but a bare one does, the bare
+    /// key is renamed into the namespace and indexed before being read, so that
+    /// existing data is migrated lazily as it is accessed.
+    fn get(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        get(self, keyspace, key).map_err(Error::Redis)
+    }
+
+    fn signature(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Commands::get(self, keyspace.signature(key)).map_err(Error::Redis)
+    }
+
+    fn owner(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<String>, Error> {
+        Commands::get(self, keyspace.owner(key)).map_err(Error::Redis)
+    }
+
+    fn create(
+        &mut self,
+        keyspace: &Keyspace,
+        id: &ulid::Ulid,
+        value: &[u8],
+        signature: Option<&[u8]>,
+        owner: Option<&str>,
+    ) -> Result<(), Error> {
+        let key = id.to_string();
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .set(keyspace.key(&key), value)
+            .ignore()
+            .sadd(keyspace.bucket(id.timestamp_ms()), &key)
+            .ignore();
+        if let Some(signature) = signature {
+            pipe.set(keyspace.signature(&key), signature).ignore();
+        }
+        if let Some(owner) = owner {
+            pipe.set(keyspace.owner(&key), owner).ignore();
+        }
+
+        pipe.query(self).map_err(Error::Redis)
+    }
+
+    fn create_many(&mut self, keyspace: &Keyspace, records: &[NewRecord]) -> Result<(), Error> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for record in records {
+            let key = record.id.to_string();
+            pipe.set(keyspace.key(&key), record.value)
+                .ignore()
+                .sadd(keyspace.bucket(record.id.timestamp_ms()), &key)
+                .ignore();
+            if let Some(signature) = &record.signature {
+                pipe.set(keyspace.signature(&key), signature).ignore();
+            }
+            if let Some(owner) = record.owner {
+                pipe.set(keyspace.owner(&key), owner).ignore();
+            }
+        }
+
+        pipe.query(self).map_err(Error::Redis)
+    }
+
+    /// The record is created and referenced by a script, so that concurrent
+    /// creations of the same content store a single signature and owner, and
+    /// every one of them is counted.
+    fn create_content(
+        &mut self,
+        keyspace: &Keyspace,
+        key: &str,
+        value: &[u8],
+        signature: Option<&[u8]>,
+        owner: Option<&str>,
+        referrer: &str,
+    ) -> Result<bool, Error> {
+        redis::Script::new(CONTENT_SCRIPT)
+            .key(keyspace.key(key))
+            .key(keyspace.signature(key))
+            .key(keyspace.owner(key))
+            .key(keyspace.references(key))
+            .arg(value)
+            .arg(signature.unwrap_or_default())
+            .arg(owner.unwrap_or_default())
+            .arg(referrer)
+            .invoke(self)
+            .map_err(Error::Redis)
+    }
+
+    fn references(&mut self, keyspace: &Keyspace, key: &str) -> Result<Vec<String>, Error> {
+        self.smembers(keyspace.references(key))
+            .map_err(Error::Redis)
+    }
+
+    fn dereference(
+        &mut self,
+        keyspace: &Keyspace,
+        key: &str,
+        referrer: &str,
+    ) -> Result<Option<usize>, Error> {
+        let left: i64 = redis::Script::new(DEREFERENCE_SCRIPT)
+            .key(keyspace.references(key))
+            .key(keyspace.key(key))
+            .key(keyspace.signature(key))
+            .key(keyspace.owner(key))
+            .key(keyspace.likes(key))
+            .key(keyspace.replies(key))
+            .key(keyspace.parent(key))
+            .arg(referrer)
+            .arg(keyspace.replies(""))
+            .arg(key)
+            .invoke(self)
+            .map_err(Error::Redis)?;
+        Ok(usize::try_from(left).ok())
+    }
+
+    /// Bare keys which have not been migrated yet are removed as well.
+    fn del(&mut self, keyspace: &Keyspace, keys: &[String]) -> Result<(), Error> {
+        // The parents, tags and words are read up front, since the transaction
+        // cannot read.
+        let mut parents = vec![];
+        for key in keys {
+            let parent: Option<String> =
+                Commands::get(self, keyspace.parent(key)).map_err(Error::Redis)?;
+            let tags: Vec<String> = self.smembers(keyspace.tags(key)).map_err(Error::Redis)?;
+            let terms: Vec<String> = self.smembers(keyspace.terms(key)).map_err(Error::Redis)?;
+            parents.push((parent, tags, terms));
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (key, (parent, tags, terms)) in keys.iter().zip(parents) {
+            for tag in tags {
+                pipe.srem(keyspace.tag(&tag), key).ignore();
+            }
+            for term in terms {
+                pipe.srem(keyspace.term(&term), key).ignore();
+            }
+
+            pipe.del(keyspace.tags(key))
+                .ignore()
+                .del(keyspace.terms(key))
+                .ignore()
+                .del(keyspace.likes(key))
+                .ignore()
+                .del(keyspace.replies(key))
+                .ignore()
+                .del(keyspace.parent(key))
+                .ignore();
+            if let Some(parent) = parent {
+                pipe.zrem(keyspace.replies(&parent), key).ignore();
+            }
+
+            pipe.del(keyspace.key(key))
+                .ignore()
+                .del(keyspace.signature(key))
+                .ignore()
+                .del(keyspace.owner(key))
+                .ignore()
+                .del(keyspace.references(key))
+                .ignore()
+                .del(key)
+                .ignore();
+            if let Ok(id) = ulid::Ulid::from_string(key) {
+                pipe.srem(keyspace.bucket(id.timestamp_ms()), key).ignore();
+            }
+        }
+
+        pipe.query(self).map_err(Error::Redis)
+    }
+
+    fn tag(&mut self, keyspace: &Keyspace, key: &str, tags: &[String]) -> Result<(), Error> {
+        if tags.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().sadd(keyspace.tags(key), tags).ignore();
+        for tag in tags {
+            pipe.sadd(keyspace.tag(tag), key).ignore();
+        }
+
+        pipe.query(self).map_err(Error::Redis)
+    }
+
+    fn tagged(
+        &mut self,
+        keyspace: &Keyspace,
+        tags: &[String],
+        all: bool,
+    ) -> Result<Vec<String>, Error> {
+        let sets: Vec<String> = tags.iter().map(|tag| keyspace.tag(tag)).collect();
+        match all {
+            true => self.sinter(sets),
+            false => self.sunion(sets),
+        }
+        .map_err(Error::Redis)
+    }
+
+    fn index(&mut self, keyspace: &Keyspace, key: &str, terms: &[String]) -> Result<(), Error> {
+        if terms.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().sadd(keyspace.terms(key), terms).ignore();
+        for term in terms {
+            pipe.sadd(keyspace.term(term), key).ignore();
+        }
+
+        pipe.query(self).map_err(Error::Redis)
+    }
+
+    fn search(&mut self, keyspace: &Keyspace, terms: &[String]) -> Result<Vec<String>, Error> {
+        let sets: Vec<String> = terms.iter().map(|term| keyspace.term(term)).collect();
+        self.sinter(sets).map_err(Error::Redis)
+    }
+
+    /// Feeds are sorted sets in which every member has the same score, so Redis
+    /// orders them lexicographically.
+    fn post(&mut self, keyspace: &Keyspace, feed: &str, ids: &[ulid::Ulid]) -> Result<(), Error> {
+        let members: Vec<(u8, String)> = ids.iter().map(|id| (0, id.to_string())).collect();
+        self.zadd_multiple(keyspace.feed(feed), &members)
+            .map_err(Error::Redis)
+    }
+
+    fn latest(
+        &mut self,
+        keyspace: &Keyspace,
+        feed: &str,
+        count: usize,
+    ) -> Result<Vec<String>, Error> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        self.zrevrange(keyspace.feed(feed), 0, count as isize - 1)
+            .map_err(Error::Redis)
+    }
+
+    fn latest_before(
+        &mut self,
+        keyspace: &Keyspace,
+        feed: &str,
+        before: &str,
+        count: usize,
+    ) -> Result<Vec<String>, Error> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        // Every ID is posted with the same score, so the feed is ordered by the
+        // IDs themselves.
+        let max = format!("({}", before);
+        self.zrevrangebylex_limit(keyspace.feed(feed), max, "-", 0, count as isize)
+            .map_err(Error::Redis)
+    }
+
+    /// Both keys are checked and set by a script, so that concurrent
+    /// registrations cannot claim the same handle or key.
+    fn register(
+        &mut self,
+        keyspace: &Keyspace,
+        handle: &str,
+        public: &str,
+        profile: &[u8],
+    ) -> Result<bool, Error> {
+        redis::Script::new(REGISTER_SCRIPT)
+            .key(keyspace.user(handle))
+            .key(keyspace.user_key(public))
+            .arg(profile)
+            .arg(handle)
+            .invoke(self)
+            .map_err(Error::Redis)
+    }
+
+    fn profile(&mut self, keyspace: &Keyspace, handle: &str) -> Result<Option<Vec<u8>>, Error> {
+        Commands::get(self, keyspace.user(handle)).map_err(Error::Redis)
+    }
+
+    fn handle(&mut self, keyspace: &Keyspace, public: &str) -> Result<Option<String>, Error> {
+        Commands::get(self, keyspace.user_key(public)).map_err(Error::Redis)
+    }
+
+    fn link(
+        &mut self,
+        keyspace: &Keyspace,
+        relation: Relation,
+        handle: &str,
+        actor: &str,
+    ) -> Result<bool, Error> {
+        self.sadd(keyspace.graph(relation, handle), actor)
+            .map_err(Error::Redis)
+    }
+
+    fn unlink(
+        &mut self,
+        keyspace: &Keyspace,
+        relation: Relation,
+        handle: &str,
+        actor: &str,
+    ) -> Result<bool, Error> {
+        self.srem(keyspace.graph(relation, handle), actor)
+            .map_err(Error::Redis)
+    }
+
+    fn members(
+        &mut self,
+        keyspace: &Keyspace,
+        relation: Relation,
+        handle: &str,
+    ) -> Result<Vec<String>, Error> {
+        self.smembers(keyspace.graph(relation, handle))
+            .map_err(Error::Redis)
+    }
+    fn like(&mut self, keyspace: &Keyspace, id: &str, identity: &str) -> Result<bool, Error> {
+        self.sadd(keyspace.likes(id), identity)
+            .map_err(Error::Redis)
+    }
+
+    /// Like feeds, replies are sorted sets in which every member has the same
+    /// score.
+    fn reply(&mut self, keyspace: &Keyspace, parent: &str, id: &ulid::Ulid) -> Result<(), Error> {
+        let key = id.to_string();
+        redis::pipe()
+            .atomic()
+            .set(keyspace.parent(&key), parent)
+            .ignore()
+            .zadd(keyspace.replies(parent), &key, 0)
+            .ignore()
+            .query(self)
+            .map_err(Error::Redis)
+    }
+
+    fn replies(
+        &mut self,
+        keyspace: &Keyspace,
+        parent: &str,
+        count: usize,
+    ) -> Result<Vec<String>, Error> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        self.zrange(keyspace.replies(parent), 0, count as isize - 1)
+            .map_err(Error::Redis)
+    }
+
+    fn parent(&mut self, keyspace: &Keyspace, id: &str) -> Result<Option<String>, Error> {
+        Commands::get(self, keyspace.parent(id)).map_err(Error::Redis)
+    }
+
+    fn interactions(&mut self, keyspace: &Keyspace, id: &str) -> Result<Interactions, Error> {
+        let (likes, replies) = redis::pipe()
+            .scard(keyspace.likes(id))
+            .zcard(keyspace.replies(id))
+            .query(self)
+            .map_err(Error::Redis)?;
+        Ok(Interactions { likes, replies })
+    }
+
+    fn push_sample(
+        &mut self,
+        keyspace: &Keyspace,
+        addr: &str,
+        sample: &[u8],
+        window: usize,
+    ) -> Result<(), Error> {
+        let key = keyspace.health(addr);
+        redis::pipe()
+            .atomic()
+            .lpush(&key, sample)
+            .ignore()
+            .ltrim(&key, 0, window as isize - 1)
+            .ignore()
+            .query(self)
+            .map_err(Error::Redis)
+    }
+
+    fn samples(&mut self, keyspace: &Keyspace, addr: &str) -> Result<Vec<Vec<u8>>, Error> {
+        self.lrange(keyspace.health(addr), 0, -1)
+            .map_err(Error::Redis)
+    }
+
+    /// The claim expires by itself, so that requests whose execution was
+    /// interrupted by a crash are not blocked forever.
+    fn claim(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        ttl: std::time::Duration,
+    ) -> Result<Claim, Error> {
+        let key = keyspace.journal(client, id);
+        loop {
+            let claimed: Option<String> = redis::cmd("SET")
+                .arg(&key)
+                .arg("")
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl.as_millis().max(1) as u64)
+                .query(self)
+                .map_err(Error::Redis)?;
+            if claimed.is_some() {
+                return Ok(Claim::Acquired);
+            }
+
+            let response: Option<Vec<u8>> = Commands::get(self, &key).map_err(Error::Redis)?;
+            match response {
+                Some(response) if response.is_empty() => return Ok(Claim::Pending),
+                Some(response) => return Ok(Claim::Done(response)),
+                // The entry expired in the meantime, so claiming is attempted again.
+                None => continue,
+            }
+        }
+    }
+
+    fn complete(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        response: &[u8],
+        ttl: std::time::Duration,
+    ) -> Result<(), Error> {
+        self.pset_ex(
+            keyspace.journal(client, id),
+            response,
+            ttl.as_millis().max(1) as usize,
+        )
+        .map_err(Error::Redis)
+    }
+
+    fn release(&mut self, keyspace: &Keyspace, client: &str, id: &str) -> Result<(), Error> {
+        Commands::del(self, keyspace.journal(client, id)).map_err(Error::Redis)
+    }
+
+    /// The value of an upload is only created by its first chunk, so only the
+    /// client is stored up front.
+    fn begin_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        ttl: std::time::Duration,
+    ) -> Result<(), Error> {
+        self.pset_ex(
+            keyspace.uploader(id),
+            client,
+            ttl.as_millis().max(1) as usize,
+        )
+        .map_err(Error::Redis)
+    }
+
+    /// Chunks are appended in place with `APPEND`, so the value of the upload is
+    /// never read back.
+    fn append_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        chunk: &[u8],
+        max: usize,
+        ttl: std::time::Duration,
+    ) -> Result<Append, Error> {
+        let appended: i64 = redis::Script::new(APPEND_SCRIPT)
+            .key(keyspace.upload(id))
+            .key(keyspace.uploader(id))
+            .arg(client)
+            .arg(chunk)
+            .arg(max)
+            .arg(ttl.as_millis().max(1) as u64)
+            .invoke(self)
+            .map_err(Error::Redis)?;
+        Ok(match appended {
+            -1 => Append::Unknown,
+            -2 => Append::Oversized,
+            len => Append::Appended(len as usize),
+        })
+    }
+
+    fn read_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let uploader: Option<String> =
+            Commands::get(self, keyspace.uploader(id)).map_err(Error::Redis)?;
+        if uploader.as_deref() != Some(client) {
+            return Ok(None);
+        }
+
+        let value: Option<Vec<u8>> =
+            Commands::get(self, keyspace.upload(id)).map_err(Error::Redis)?;
+        Ok(Some(value.unwrap_or_default()))
+    }
+
+    /// The value is renamed into the record, so committing does not depend on
+    /// the size of the upload.
+    fn commit_upload(
+        &mut self,
+        keyspace: &Keyspace,
+        client: &str,
+        id: &str,
+        record: &ulid::Ulid,
+        owner: Option<&str>,
+    ) -> Result<bool, Error> {
+        let key = record.to_string();
+        redis::Script::new(COMMIT_SCRIPT)
+            .key(keyspace.upload(id))
+            .key(keyspace.uploader(id))
+            .key(keyspace.key(&key))
+            .key(keyspace.bucket(record.timestamp_ms()))
+            .key(keyspace.owner(&key))
+            .arg(client)
+            .arg(&key)
+            .arg(owner.unwrap_or_default())
+            .invoke(self)
+            .map_err(Error::Redis)
+    }
+
+    fn discard_upload(&mut self, keyspace: &Keyspace, client: &str, id: &str) -> Result<(), Error> {
+        let uploader: Option<String> =
+            Commands::get(self, keyspace.uploader(id)).map_err(Error::Redis)?;
+        if uploader.as_deref() != Some(client) {
+            return Ok(());
+        }
+
+        redis::pipe()
+            .del(keyspace.upload(id))
+            .ignore()
+            .del(keyspace.uploader(id))
+            .ignore()
+            .query(self)
+            .map_err(Error::Redis)
+    }
+
+    fn buckets(&mut self, keyspace: &Keyspace) -> Result<Vec<String>, Error> {
+        let buckets: redis::Iter<String> = self
+            .scan_match(format!("{}*", keyspace.index()))
+            .map_err(Error::Redis)?;
+        Ok(buckets.collect())
+    }
+
+    fn bucket(&mut self, _: &Keyspace, bucket: &str) -> Result<Vec<String>, Error> {
+        self.smembers(bucket).map_err(Error::Redis)
+    }
+
+    fn size(&mut self, keyspace: &Keyspace, key: &str) -> Result<usize, Error> {
+        self.strlen(keyspace.key(key)).map_err(Error::Redis)
+    }
+
+    fn keys(&mut self, keyspace: &Keyspace) -> Result<Vec<String>, Error> {
+        let namespace = keyspace.key("");
+        let uploads = keyspace.upload("");
+        let keys: redis::Iter<String> = self
+            .scan_match(format!("{}*", namespace))
+            .map_err(Error::Redis)?;
+        Ok(keys
+            .filter(|key| !key.starts_with(&uploads))
+            .filter_map(|key| key.strip_prefix(&namespace).map(String::from))
+            .collect())
+    }
+
+    /// The prefix is matched by `SCAN`, so it must not hold any of the special
+    /// characters of glob patterns.
+    fn scan_keys(&mut self, keyspace: &Keyspace, prefix: &str) -> Result<Vec<String>, Error> {
+        let namespace = keyspace.key("");
+        let uploads = keyspace.upload("");
+        let keys: redis::Iter<String> = self
+            .scan_match(format!("{}{}*", namespace, prefix))
+            .map_err(Error::Redis)?;
+        Ok(keys
+            .filter(|key| !key.starts_with(&uploads))
+            .filter_map(|key| key.strip_prefix(&namespace).map(String::from))
+            .collect())
+    }
+
+    /// The type of the key is read before its value, so keys which are removed
+    /// in between are read as empty, apart from strings.
+    fn export(&mut self, keyspace: &Keyspace, key: &str) -> Result<Option<Entry>, Error> {
+        export(self, keyspace, key).map_err(Error::Redis)
+    }
+
+    fn exists(&mut self, keyspace: &Keyspace, key: &str) -> Result<bool, Error> {
+        Commands::exists(self, keyspace.key(key)).map_err(Error::Redis)
+    }
+
+    /// Collections without any items are removed, since Redis does not keep
+    /// empty collections.
+    fn import(&mut self, keyspace: &Keyspace, entry: &Entry) -> Result<(), Error> {
+        let name = keyspace.key(&entry.key);
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&name).ignore();
+        match &entry.value {
+            Value::String(value) => pipe.set(&name, value).ignore(),
+            Value::List(items) if !items.is_empty() => pipe.rpush(&name, items).ignore(),
+            Value::Set(members) if !members.is_empty() => pipe.sadd(&name, members).ignore(),
+            Value::SortedSet(members) if !members.is_empty() => {
+                let members: Vec<(f64, &[u8])> = members
+                    .iter()
+                    .map(|(member, score)| (*score, member.as_slice()))
+                    .collect();
+                pipe.zadd_multiple(&name, &members).ignore()
+            }
+            _ => &mut pipe,
+        };
+
+        if let Some(ttl) = entry.ttl {
+            pipe.pexpire(&name, ttl.as_millis().max(1) as usize)
+                .ignore();
+        }
+
+        pipe.query(self).map_err(Error::Redis)
+    }
+}
+
+/// Stores the profile and the handle of a user, unless either of its keys
+/// exists already.
+const REGISTER_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 or redis.call('EXISTS', KEYS[2]) == 1 then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[1])
+redis.call('SET', KEYS[2], ARGV[2])
+return 1
+";
+
+/// Stores a content-addressed record along with its signature and owner unless
+/// it exists already, and adds the referrer to its references. Records which
+/// exist without references were stored before references were kept, and are
+/// first referenced by their owner. Returns whether the record was stored.
+const CONTENT_SCRIPT: &str = r"
+local created = redis.call('SETNX', KEYS[1], ARGV[1])
+if created == 1 then
+    if ARGV[2] ~= '' then
+        redis.call('SET', KEYS[2], ARGV[2])
+    end
+    if ARGV[3] ~= '' then
+        redis.call('SET', KEYS[3], ARGV[3])
+    end
+elseif redis.call('EXISTS', KEYS[4]) == 0 then
+    local owner = redis.call('GET', KEYS[3])
+    if owner then
+        redis.call('SADD', KEYS[4], owner)
+    end
+end
+redis.call('SADD', KEYS[4], ARGV[4])
+return created
+";
+
+/// Drops the reference of the referrer to a content-addressed record, and
+/// removes the record if no references are left. Returns the number of
+/// references left, or `-1` if the referrer did not reference the record.
+const DEREFERENCE_SCRIPT: &str = r"
+if redis.call('SREM', KEYS[1], ARGV[1]) == 0 then
+    return -1
+end
+local left = redis.call('SCARD', KEYS[1])
+if left == 0 then
+    local parent = redis.call('GET', KEYS[7])
+    if parent then
+        redis.call('ZREM', ARGV[2] .. parent, ARGV[3])
+    end
+    redis.call('DEL', KEYS[2], KEYS[3], KEYS[4], KEYS[5], KEYS[6], KEYS[7])
+end
+return left
+";
+
+/// Appends a chunk to an upload and extends the lifetime of the upload,
+/// unless the upload belongs to another client or would grow beyond its
+/// limit. Returns the new length, `-1` for unknown uploads and `-2` for
+/// oversized ones.
+const APPEND_SCRIPT: &str = r"
+if redis.call('GET', KEYS[2]) ~= ARGV[1] then
+    return -1
+end
+if redis.call('STRLEN', KEYS[1]) + string.len(ARGV[2]) > tonumber(ARGV[3]) then
+    return -2
+end
+local len = redis.call('APPEND', KEYS[1], ARGV[2])
+redis.call('PEXPIRE', KEYS[1], ARGV[4])
+redis.call('PEXPIRE', KEYS[2], ARGV[4])
+return len
+";
+
+/// Renames a non-empty upload of the client into a record, and indexes and
+/// attributes the record.
+const COMMIT_SCRIPT: &str = r"
+if redis.call('GET', KEYS[2]) ~= ARGV[1] or redis.call('EXISTS', KEYS[1]) == 0 then
+    return 0
+end
+redis.call('RENAME', KEYS[1], KEYS[3])
+redis.call('PERSIST', KEYS[3])
+redis.call('DEL', KEYS[2])
+redis.call('SADD', KEYS[4], ARGV[2])
+if ARGV[3] ~= '' then
+    redis.call('SET', KEYS[5], ARGV[3])
+end
+return 1
+";
+
+fn export(
+    connection: &mut redis::Connection,
+    keyspace: &Keyspace,
+    key: &str,
+) -> redis::RedisResult<Option<Entry>> {
+    let name = keyspace.key(key);
+    let (kind, ttl): (String, i64) = redis::pipe()
+        .cmd("TYPE")
+        .arg(&name)
+        .cmd("PTTL")
+        .arg(&name)
+        .query(connection)?;
+
+    let value = match kind.as_str() {
+        "string" => match Commands::get(connection, &name)? {
+            Some(value) => Value::String(value),
+            None => return Ok(None),
+        },
+        "list" => Value::List(connection.lrange(&name, 0, -1)?),
+        "set" => Value::Set(connection.smembers(&name)?),
+        "zset" => Value::SortedSet(connection.zrange_withscores(&name, 0, -1)?),
+        "none" => return Ok(None),
+        _ => {
+            return Err((
+                redis::ErrorKind::TypeError,
+                "Unsupported type of key",
+                format!("{} is a {}", name, kind),
+            )
+                .into())
+        }
+    };
+
+    Ok(Some(Entry {
+        key: key.into(),
+        value,
+        // Negative times to live mark keys which do not expire.
+        ttl: u64::try_from(ttl)
+            .ok()
+            .map(|ttl| std::time::Duration::from_millis(ttl.max(1))),
+    }))
+}
+
+fn get(
+    connection: &mut redis::Connection,
+    keyspace: &Keyspace,
+    key: &str,
+) -> redis::RedisResult<Option<Vec<u8>>> {
+    let namespaced = keyspace.key(key);
+    let value: Option<Vec<u8>> = Commands::get(connection, &namespaced)?;
+    if value.is_some() {
+        return Ok(value);
+    }
+
+    let legacy: bool = Commands::exists(connection, key)?;
+    if !legacy {
+        return Ok(None);
+    }
+
+    let renamed: bool = connection.rename_nx(key, &namespaced)?;
+    if renamed {
+        debug!("Migrated legacy key {} to {}", key, namespaced);
+        if let Ok(id) = ulid::Ulid::from_string(key) {
+            connection.sadd::<_, _, ()>(keyspace.bucket(id.timestamp_ms()), key)?;
+        }
+    }
+
+    Commands::get(connection, &namespaced)
+}
+
+/// Walks the keyspace with `SCAN`, renaming the records stored under bare IDs
+/// into the namespace and indexing them. The cursor is saved along with the
+/// report after every step, and since `SCAN` cursors stay valid across
+/// connections, an interrupted migration resumes where it left off.
+fn migrate(
+    connection: &mut redis::Connection,
+    keyspace: &Keyspace,
+    settings: &Migration,
+    progress: &mut dyn FnMut(&Report),
+) -> redis::RedisResult<Report> {
+    let state: Option<String> = Commands::get(connection, keyspace.migration())?;
+    let (mut cursor, mut report) = state
+        .and_then(|state| serde_json::from_str::<(u64, Report)>(&state).ok())
+        .unwrap_or_default();
+    if report.done {
+        return Ok(report);
+    }
+
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(LEGACY_PATTERN)
+            .arg("COUNT")
+            .arg(settings.batch)
+            .query(connection)?;
+
+        for key in keys {
+            report.scanned += 1;
+            let id = match ulid::Ulid::from_string(&key) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+
+            let renamed: bool = match connection.rename_nx(&key, keyspace.key(&key)) {
+                Ok(renamed) => renamed,
+                // The key was read, and thereby migrated, in the meantime.
+                Err(e) if e.kind() == redis::ErrorKind::ResponseError => continue,
+                Err(e) => return Err(e),
+            };
+
+            if !renamed {
+                warn!("Legacy key {} is shadowed by a namespaced record", key);
+                report.skipped += 1;
+                continue;
+            }
+
+            let mut pipe = redis::pipe();
+            pipe.sadd(keyspace.bucket(id.timestamp_ms()), &key).ignore();
+            if let Some(owner) = &settings.owner {
+                pipe.set_nx(keyspace.owner(&key), owner).ignore();
+            }
+
+            pipe.query::<()>(connection)?;
+            report.migrated += 1;
+        }
+
+        cursor = next;
+        report.done = cursor == 0;
+        let state = serde_json::to_string(&(cursor, report)).unwrap();
+        connection.set::<_, _, ()>(keyspace.migration(), state)?;
+        progress(&report);
+        if report.done {
+            return Ok(report);
+        }
+    }
+}
+
+/// Asks a single sentinel for the address of the master with the given name.
+fn master_addr(endpoint: &str, master: &str) -> redis::RedisResult<(String, u16)> {
+    let mut connection = redis::Client::open(endpoint)?.get_connection()?;
+    let addr: Option<(String, u16)> = redis::cmd("SENTINEL")
+        .arg("get-master-addr-by-name")
+        .arg(master)
+        .query(&mut connection)?;
+    addr.ok_or_else(|| (redis::ErrorKind::ResponseError, "Unknown master").into())
+}
+
+/// Returns whether the error indicates that the connection no longer points
+/// to a writable master, either because it was dropped or because the server
+/// has been demoted to a replica.
+#[inline(always)]
+pub(crate) fn is_failover(e: &redis::RedisError) -> bool {
+    e.is_connection_dropped()
+        || e.is_connection_refusal()
+        || e.is_io_error()
+        || e.kind() == redis::ErrorKind::ReadOnly
+}
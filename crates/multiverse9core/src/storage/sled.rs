@@ -0,0 +1,266 @@
+use std::time::Duration;
+
+use super::memory::Memory;
+use super::{
+    Append, Backend, Claim, Connection, Entry, Error, Interactions, Keyspace, NewRecord, Relation,
+    Value,
+};
+
+/// Keeps all data in a sled database on disk, so that nodes keep their data
+/// across restarts without running Redis. The keys are served from memory the
+/// same way the in-memory backend serves them, and every key a request changes
+/// is written through to the database before the request is answered. The
+/// database is loaded into memory once the backend is opened.
+///
+/// Journal entries and uploads which have not been committed are only kept in
+/// memory, since they expire shortly anyway.
+pub(crate) struct Sled {
+    memory: Memory,
+    db: sled::Db,
+}
+
+impl Sled {
+    /// Opens the database at the path, creating it if there is none yet.
+    pub(crate) fn open(path: &str) -> Result<Self, Error> {
+        let db = sled::open(path).map_err(Error::Sled)?;
+        let memory = Memory::tracked();
+        for item in db.iter() {
+            let (name, value) = item.map_err(Error::Sled)?;
+            let name = String::from_utf8_lossy(&name).to_string();
+            let value: Value = rmp_serde::from_slice(&value)
+                .map_err(|e| Error::Corrupt(format!("{}: {}", name, e)))?;
+            memory.load(name, value);
+        }
+
+        Ok(Self { memory, db })
+    }
+}
+
+impl Backend for Sled {
+    fn connection(&self) -> Result<Box<dyn Connection>, Error> {
+        Ok(Box::new(SledConnection {
+            inner: self.memory.connection()?,
+            memory: self.memory.clone(),
+            db: self.db.clone(),
+        }))
+    }
+}
+
+/// A handle to the data of a [Sled] backend.
+struct SledConnection {
+    inner: Box<dyn Connection>,
+    memory: Memory,
+    db: sled::Db,
+}
+
+impl SledConnection {
+    /// Writes the keys changed so far through to the database.
+    fn flush(&self) -> Result<(), Error> {
+        self.memory.flush(|changes| {
+            let mut batch = sled::Batch::default();
+            for (name, value) in changes {
+                match value {
+                    Some(value) => {
+                        let value = rmp_serde::to_vec(&value)
+                            .map_err(|e| Error::Corrupt(format!("{}: {}", name, e)))?;
+                        batch.insert(name.as_bytes(), value);
+                    }
+                    None => batch.remove(name.as_bytes()),
+                }
+            }
+
+            self.db.apply_batch(batch).map_err(Error::Sled)
+        })
+    }
+}
+
+/// Implements [Connection] by calling the in-memory connection, and writing
+/// the keys it changed through to the database before returning.
+macro_rules! written_through {
+    ($(fn $name:ident(&mut self $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        $(
+            fn $name(&mut self $(, $arg: $ty)*) -> Result<$ret, Error> {
+                let result = self.inner.$name($($arg),*);
+                self.flush()?;
+                result
+            }
+        )*
+    };
+}
+
+impl Connection for SledConnection {
+    written_through! {
+        fn get(&mut self, keyspace: &Keyspace, key: &str) -> Option<Vec<u8>>;
+        fn signature(&mut self, keyspace: &Keyspace, key: &str) -> Option<Vec<u8>>;
+        fn owner(&mut self, keyspace: &Keyspace, key: &str) -> Option<String>;
+        fn create(
+            &mut self,
+            keyspace: &Keyspace,
+            id: &ulid::Ulid,
+            value: &[u8],
+            signature: Option<&[u8]>,
+            owner: Option<&str>
+        ) -> ();
+        fn create_many(&mut self, keyspace: &Keyspace, records: &[NewRecord]) -> ();
+        fn create_content(
+            &mut self,
+            keyspace: &Keyspace,
+            key: &str,
+            value: &[u8],
+            signature: Option<&[u8]>,
+            owner: Option<&str>,
+            referrer: &str
+        ) -> bool;
+        fn references(&mut self, keyspace: &Keyspace, key: &str) -> Vec<String>;
+        fn dereference(&mut self, keyspace: &Keyspace, key: &str, referrer: &str) -> Option<usize>;
+        fn del(&mut self, keyspace: &Keyspace, keys: &[String]) -> ();
+        fn post(&mut self, keyspace: &Keyspace, feed: &str, ids: &[ulid::Ulid]) -> ();
+        fn tag(&mut self, keyspace: &Keyspace, key: &str, tags: &[String]) -> ();
+        fn tagged(&mut self, keyspace: &Keyspace, tags: &[String], all: bool) -> Vec<String>;
+        fn index(&mut self, keyspace: &Keyspace, key: &str, terms: &[String]) -> ();
+        fn search(&mut self, keyspace: &Keyspace, terms: &[String]) -> Vec<String>;
+        fn latest(&mut self, keyspace: &Keyspace, feed: &str, count: usize) -> Vec<String>;
+        fn latest_before(
+            &mut self,
+            keyspace: &Keyspace,
+            feed: &str,
+            before: &str,
+            count: usize
+        ) -> Vec<String>;
+        fn register(
+            &mut self,
+            keyspace: &Keyspace,
+            handle: &str,
+            public: &str,
+            profile: &[u8]
+        ) -> bool;
+        fn profile(&mut self, keyspace: &Keyspace, handle: &str) -> Option<Vec<u8>>;
+        fn handle(&mut self, keyspace: &Keyspace, public: &str) -> Option<String>;
+        fn link(
+            &mut self,
+            keyspace: &Keyspace,
+            relation: Relation,
+            handle: &str,
+            actor: &str
+        ) -> bool;
+        fn unlink(
+            &mut self,
+            keyspace: &Keyspace,
+            relation: Relation,
+            handle: &str,
+            actor: &str
+        ) -> bool;
+        fn members(&mut self, keyspace: &Keyspace, relation: Relation, handle: &str) -> Vec<String>;
+        fn like(&mut self, keyspace: &Keyspace, id: &str, identity: &str) -> bool;
+        fn reply(&mut self, keyspace: &Keyspace, parent: &str, id: &ulid::Ulid) -> ();
+        fn replies(&mut self, keyspace: &Keyspace, parent: &str, count: usize) -> Vec<String>;
+        fn parent(&mut self, keyspace: &Keyspace, id: &str) -> Option<String>;
+        fn interactions(&mut self, keyspace: &Keyspace, id: &str) -> Interactions;
+        fn push_sample(
+            &mut self,
+            keyspace: &Keyspace,
+            addr: &str,
+            sample: &[u8],
+            window: usize
+        ) -> ();
+        fn samples(&mut self, keyspace: &Keyspace, addr: &str) -> Vec<Vec<u8>>;
+        fn claim(&mut self, keyspace: &Keyspace, client: &str, id: &str, ttl: Duration) -> Claim;
+        fn complete(
+            &mut self,
+            keyspace: &Keyspace,
+            client: &str,
+            id: &str,
+            response: &[u8],
+            ttl: Duration
+        ) -> ();
+        fn release(&mut self, keyspace: &Keyspace, client: &str, id: &str) -> ();
+        fn begin_upload(
+            &mut self,
+            keyspace: &Keyspace,
+            client: &str,
+            id: &str,
+            ttl: Duration
+        ) -> ();
+        fn append_upload(
+            &mut self,
+            keyspace: &Keyspace,
+            client: &str,
+            id: &str,
+            chunk: &[u8],
+            max: usize,
+            ttl: Duration
+        ) -> Append;
+        fn read_upload(&mut self, keyspace: &Keyspace, client: &str, id: &str) -> Option<Vec<u8>>;
+        fn commit_upload(
+            &mut self,
+            keyspace: &Keyspace,
+            client: &str,
+            id: &str,
+            record: &ulid::Ulid,
+            owner: Option<&str>
+        ) -> bool;
+        fn discard_upload(&mut self, keyspace: &Keyspace, client: &str, id: &str) -> ();
+        fn buckets(&mut self, keyspace: &Keyspace) -> Vec<String>;
+        fn bucket(&mut self, keyspace: &Keyspace, bucket: &str) -> Vec<String>;
+        fn size(&mut self, keyspace: &Keyspace, key: &str) -> usize;
+        fn keys(&mut self, keyspace: &Keyspace) -> Vec<String>;
+        fn scan_keys(&mut self, keyspace: &Keyspace, prefix: &str) -> Vec<String>;
+        fn export(&mut self, keyspace: &Keyspace, key: &str) -> Option<Entry>;
+        fn exists(&mut self, keyspace: &Keyspace, key: &str) -> bool;
+        fn import(&mut self, keyspace: &Keyspace, entry: &Entry) -> ();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Partition;
+
+    #[test]
+    fn test_reopen() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Hour,
+        };
+        let path = std::env::temp_dir().join(format!("multiverse9_{}.sled", ulid::Ulid::new()));
+        let path = path.to_str().unwrap();
+        let id = ulid::Ulid::new();
+        let removed = ulid::Ulid::new();
+        {
+            let mut connection = Sled::open(path).unwrap().connection().unwrap();
+            connection
+                .create(&keyspace, &id, b"value", None, Some("alice"))
+                .unwrap();
+            connection
+                .create(&keyspace, &removed, b"removed", None, None)
+                .unwrap();
+            connection.post(&keyspace, "news", &[id]).unwrap();
+            connection.del(&keyspace, &[removed.to_string()]).unwrap();
+        }
+
+        // The keys written through are loaded once the database is opened again.
+        // Sled releases the lock on the database in the background once it is
+        // dropped, so opening it may fail for a moment.
+        let mut attempts = 0;
+        let sled = loop {
+            match Sled::open(path) {
+                Err(Error::Sled(_)) if attempts < 100 => {
+                    attempts += 1;
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                opened => break opened.unwrap(),
+            }
+        };
+        let mut connection = sled.connection().unwrap();
+        let key = id.to_string();
+        assert_eq!(connection.get(&keyspace, &key).unwrap().unwrap(), b"value");
+        assert_eq!(connection.owner(&keyspace, &key).unwrap().unwrap(), "alice");
+        assert_eq!(connection.latest(&keyspace, "news", 10).unwrap(), vec![key]);
+        assert_eq!(
+            connection.get(&keyspace, &removed.to_string()).unwrap(),
+            None
+        );
+        drop(connection);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+//! Streaming aggregations return the records of an aggregate request one by
+//! one, instead of collecting every record into a single response first, so
+//! that clients receive the first record as soon as it is read, and neither
+//! side holds the whole aggregation in memory.
+//!
+//! A [AGGREGATE] request takes the same targets as an aggregate request,
+//! including the options of [crate::views] and [crate::quorum]. Its response
+//! is a frame with status `0` for every entry, carrying the entry without its
+//! trailing null byte, followed by an empty frame once every target has been
+//! read. Entries are never empty, so the empty frame cannot be mistaken for
+//! one. Streamed scans end with the footer of their page instead, see
+//! [crate::pagination]. A request which fails is answered with a frame carrying the status
+//! it failed with instead, which may follow the entries written before the
+//! failure and ends the stream just the same.
+//!
+//! Streams take over the connection until they end, so they are only
+//! accepted on their own, and never in batches, with deadlines, timed,
+//! journaled or over multiplexed connections. See [crate::sdk::Client::stream].
+
+/// Request code for aggregating the targets of a request into a stream of
+/// entries.
+pub const AGGREGATE: u16 = 0x002B;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::sdk;
+    use crate::settings::Settings;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stream() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        let mut keys = vec![];
+        for i in 0..8 {
+            keys.push(client.create(format!("value{}", i).as_bytes()).unwrap().id);
+        }
+
+        let unknown = ulid::Ulid::new().to_string();
+        keys.push(unknown.clone());
+        let targets: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let records: Vec<_> = client
+            .stream(&targets)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(records.len(), keys.len());
+        for (i, record) in records[..8].iter().enumerate() {
+            assert_eq!(record.key, keys[i]);
+            assert_eq!(record.value, format!("value{}", i).as_bytes());
+        }
+        assert!(records[8].is_unknown());
+
+        // Streams which fail end with the status of the failure, after the
+        // entries written before it.
+        let mut stream = client.stream(&[&keys[0], "invalid"]).unwrap();
+        assert_eq!(stream.next().unwrap().unwrap().key, keys[0]);
+        assert!(matches!(stream.next(), Some(Err(sdk::Error::Status(1)))));
+        assert!(stream.next().is_none());
+        drop(stream);
+
+        // Streams are only accepted on their own.
+        let requests = [(AGGREGATE, targets[0].as_bytes())];
+        assert_eq!(client.batch(&requests).unwrap()[0].0, 1);
+
+        // The connection is still in sync with the frames after a stream which
+        // was dropped before it ended.
+        drop(client.stream(&targets).unwrap());
+        assert_eq!(client.aggregate(&keys[1]).unwrap().records.len(), 1);
+    }
+}
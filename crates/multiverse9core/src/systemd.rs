@@ -0,0 +1,193 @@
+//! Supervision of nodes by systemd. Nodes started by a service with
+//! `Type=notify` tell systemd once they accept connections, and once they are
+//! stopping (`sd_notify`). Services with `WatchdogSec=` are sent keep-alives
+//! for as long as the node makes progress, that is as long as it accepts
+//! connections or its workers finish jobs, so that systemd restarts nodes
+//! which hang. Workers are given a job of their own to finish every now and
+//! then, so that idle nodes keep making progress.
+//!
+//! Nodes started through a socket unit accept connections on the listener
+//! passed by systemd (`LISTEN_FDS`) instead of binding one of their own, so
+//! that connections are queued by systemd while the node restarts.
+//!
+//! Nodes which are not supervised by systemd are not affected.
+
+use log::*;
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::crash;
+use crate::loopback::Local;
+use crate::pooling::{self, Pool};
+use crate::shutdown::Shutdown;
+
+/// Takes the listener passed by systemd to the current process, if there is
+/// one. The variables describing it are removed, so that they are not
+/// inherited by child processes.
+#[cfg(unix)]
+pub(crate) fn listener() -> Option<TcpListener> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let fds = listen_fds(pid.as_deref(), fds.as_deref(), std::process::id());
+    // Reads and removes `LISTEN_PID` and `LISTEN_FDS`.
+    let mut passed = listenfd::ListenFd::from_env();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if fds == 0 {
+        return None;
+    }
+
+    if fds > 1 {
+        warn!("Accepting on the first of {} passed sockets only", fds);
+    }
+
+    match passed.take_tcp_listener(0) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Could not accept on the passed socket: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn listener() -> Option<TcpListener> {
+    None
+}
+
+/// Returns the number of sockets passed to the process, which are only meant
+/// for it if `LISTEN_PID` matches its ID.
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, current: u32) -> usize {
+    match pid.and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) if pid == current => fds.and_then(|fds| fds.parse().ok()).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Tells systemd the node accepts connections, and sends it keep-alives until
+/// the node stops, if it watches the node. A keep-alive is only sent if a
+/// connection was accepted, or a worker of the pool finished a job, since the
+/// previous one.
+pub(crate) fn ready(local: Local, shutdown: Shutdown, pool: Arc<Pool>) {
+    notify("READY=1");
+    let interval = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(Duration::from_micros);
+    let interval = match interval {
+        Some(interval) if !interval.is_zero() => interval,
+        _ => return,
+    };
+
+    // Checking for progress twice per interval, so that a late keep-alive does
+    // not get the node restarted.
+    std::thread::spawn(move || {
+        let mut last = progress(&pool);
+        let mut probe = pool.submit(|| ());
+        while local.is_running() && !shutdown.is_stopping() {
+            std::thread::sleep(interval / 2);
+            let current = progress(&pool);
+            match current == last {
+                true => warn!("The node made no progress, so systemd is not kept alive"),
+                false => notify("WATCHDOG=1"),
+            }
+
+            last = current;
+            // Probing the workers again only once the previous probe finished,
+            // so that probes do not pile up behind hung workers.
+            if !matches!(
+                probe.wait_timeout(Duration::ZERO),
+                Err(pooling::Error::Pending(_))
+            ) {
+                probe = pool.submit(|| ());
+            }
+        }
+    });
+}
+
+/// Returns the number of connections accepted, and of jobs finished by the
+/// workers of the pool, so far.
+fn progress(pool: &Pool) -> (u64, u64) {
+    (crash::Snapshot::take().connections, pool.stats().completed)
+}
+
+/// Tells systemd the node is stopping.
+pub(crate) fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Sends the state to systemd, unless the process is not supervised by it.
+fn notify(state: &str) {
+    let socket = match std::env::var("NOTIFY_SOCKET") {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+
+    if let Err(e) = send(&socket, state) {
+        warn!("Could not notify systemd of {}: {}", state, e);
+    }
+}
+
+#[cfg(unix)]
+fn send(socket: &str, state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let datagram = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Abstract sockets require Linux",
+            ))
+        }
+        None => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_: &str, _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Notifying systemd requires Unix",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fds(Some("43"), Some("2"), 42), 0);
+        assert_eq!(listen_fds(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send() {
+        let path = std::env::temp_dir().join(format!("multiverse9_{}.sock", ulid::Ulid::new()));
+        let receiver = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+
+        let mut buffer = [0; 16];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1");
+        std::fs::remove_file(path).unwrap();
+    }
+}
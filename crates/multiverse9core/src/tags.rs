@@ -0,0 +1,126 @@
+//! Tags group records without an external search system. A record is tagged
+//! when it is created with a [CREATE] request, and every tag indexes the keys
+//! of its records in a set of its own, which a [QUERY] request intersects or
+//! unites.
+//!
+//! The payload of a [CREATE] request is the tags of the record, each followed
+//! by a null byte, then another null byte and the value of the record. Tags are
+//! named the way feeds are, see [crate::feed::is_valid_name], and a record has
+//! no more than [MAX_TAGS] of them. Its response is the key of the record.
+//! Tagged records are stored on current node, whether it shards its records
+//! or not, since the sets of their tags are kept there.
+//!
+//! The payload of a [QUERY] request is [ALL] or [ANY] followed by the tags,
+//! each followed by a null byte, and may be preceded by the options of
+//! [crate::pagination]. Its response is the keys of the records tagged with
+//! all or any of the tags, the way a listing returns them, see
+//! [crate::listing].
+//!
+//! Removing a record removes it from the sets of its tags.
+
+/// Request code for creating a record with tags.
+pub const CREATE: u16 = 0x002D;
+
+/// Request code for querying the keys of the records by their tags.
+pub const QUERY: u16 = 0x002E;
+
+/// The most tags a record has.
+pub const MAX_TAGS: usize = 16;
+
+/// Queries the records tagged with every tag of the query.
+pub const ALL: &str = "all";
+
+/// Queries the records tagged with any tag of the query.
+pub const ANY: &str = "any";
+
+/// Returns whether the tag can be attached to a record.
+pub fn is_valid(tag: &str) -> bool {
+    crate::feed::is_valid_name(tag)
+}
+
+/// Encodes the payload of a [CREATE] request.
+pub fn encode_create(tags: &[&str], value: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![];
+    for tag in tags {
+        buffer.extend(tag.as_bytes());
+        buffer.push(00);
+    }
+
+    buffer.push(00);
+    buffer.extend(value);
+    buffer
+}
+
+/// Splits the payload of a [CREATE] request into the tags and the value of
+/// the record.
+///
+/// # Returns
+///
+/// [None] if the tags are not followed by an empty one.
+pub(crate) fn decode_create(payload: &[u8]) -> Option<(Vec<String>, &[u8])> {
+    let mut tags = vec![];
+    let mut rest = payload;
+    loop {
+        let end = rest.iter().position(|c| *c == 00)?;
+        let (tag, next) = (&rest[..end], &rest[end + 1..]);
+        if tag.is_empty() {
+            return Some((tags, next));
+        }
+
+        tags.push(String::from_utf8_lossy(tag).to_string());
+        rest = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::settings::Settings;
+    use std::time::Duration;
+
+    #[test]
+    fn test_decode_create() {
+        let payload = encode_create(&["news", "rust"], b"value\x00with\x00nulls");
+        let (tags, value) = decode_create(&payload).unwrap();
+        assert_eq!(tags, vec!["news", "rust"]);
+        assert_eq!(value, b"value\x00with\x00nulls");
+        assert_eq!(decode_create(b"\x00value"), Some((vec![], &b"value"[..])));
+        assert_eq!(decode_create(b"news\x00value"), None);
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut settings = Settings::new("memory://".into()).unwrap();
+        settings.addr = "127.0.0.1:0".parse().unwrap();
+        let node = Node::new(settings);
+        let local = node.local();
+        std::thread::spawn(move || node.start(Some(2)));
+        while !local.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut client = local.client().unwrap();
+        let both = client.create_tagged(&["news", "rust"], b"both").unwrap().id;
+        let news = client.create_tagged(&["news"], b"news").unwrap().id;
+        let rust = client.create_tagged(&["rust"], b"rust").unwrap().id;
+        assert_eq!(client.aggregate(&both).unwrap().records[0].value, b"both");
+
+        let mut any = vec![both.clone(), news.clone(), rust.clone()];
+        any.sort();
+        assert_eq!(
+            client.tagged(&["news", "rust"], true).unwrap(),
+            vec![both.clone()]
+        );
+        assert_eq!(client.tagged(&["news", "rust"], false).unwrap(), any);
+        assert!(client.tagged(&["other"], false).unwrap().is_empty());
+        assert!(client.create_tagged(&["not a tag"], b"value").is_err());
+
+        // Removed records are no longer found by their tags.
+        client.remove(&[&both]).unwrap();
+        assert!(client.tagged(&["news", "rust"], true).unwrap().is_empty());
+        let mut left = vec![news, rust];
+        left.sort();
+        assert_eq!(client.tagged(&["news", "rust"], false).unwrap(), left);
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::protocol::codec;
+use crate::{FrameBuffer, Tcp};
+
+/// What a [FakePeer] does when it receives a request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// Replies with a well-formed response frame.
+    Respond { status: u8, payload: Vec<u8> },
+    /// Writes the bytes as they are, which do not have to form a valid frame,
+    /// and closes the connection.
+    Raw(Vec<u8>),
+    /// Closes the connection without replying.
+    Disconnect,
+    /// Waits for the given duration before performing the inner reply.
+    Delay(Duration, Box<Reply>),
+}
+
+impl Reply {
+    /// A successful response with the given payload.
+    pub fn ok(payload: impl Into<Vec<u8>>) -> Self {
+        Self::Respond {
+            status: 0,
+            payload: payload.into(),
+        }
+    }
+
+    /// An empty response with the given status.
+    pub fn status(status: u8) -> Self {
+        Self::Respond {
+            status,
+            payload: vec![],
+        }
+    }
+
+    /// Delays the reply by the given duration.
+    pub fn delayed(self, duration: Duration) -> Self {
+        Self::Delay(duration, Box::new(self))
+    }
+}
+
+/// The code and payload of a request received by a [FakePeer].
+type Request = (u16, Vec<u8>);
+
+/// A remote node which speaks the wire protocol, but replies from a script
+/// instead of a storage backend. Used for reproducing the failures of remote
+/// nodes in tests. Connections are served one at a time, and dropping the peer
+/// closes the current connection, since nodes keep their connections to peers
+/// open.
+///
+/// # Example
+///
+/// ```rs
+/// let peer = FakePeer::bind([
+///     (0x0003, Reply::ok("01GQ...:value\x00")),
+///     (0x0003, Reply::Disconnect),
+/// ])?;
+///
+/// let mut client = sdk::Client::connect(peer.addr())?;
+/// ```
+pub struct FakePeer {
+    addr: SocketAddr,
+    /// The code and payload of every request received so far.
+    requests: Arc<Mutex<Vec<Request>>>,
+    /// The connection being served, if any.
+    current: Arc<Mutex<Option<TcpStream>>>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakePeer {
+    /// Binds the peer to a free local port.
+    ///
+    /// # Arguments
+    ///
+    /// * `script` - The replies for each request code. Replies for the same code
+    ///   are used in the order they are listed, and the last one is repeated once
+    ///   the others are used up. Requests with a code which is not in the script
+    ///   are replied to with status `1`.
+    pub fn bind(script: impl IntoIterator<Item = (u16, Reply)>) -> io::Result<Self> {
+        let mut table: HashMap<u16, VecDeque<Reply>> = HashMap::new();
+        for (code, reply) in script {
+            table.entry(code).or_default().push_back(reply);
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(vec![]));
+        let current = Arc::new(Mutex::new(None));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let requests = Arc::clone(&requests);
+            let current = Arc::clone(&current);
+            let stopped = Arc::clone(&stopped);
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    if let Ok(stream) = stream {
+                        *current.lock().unwrap() = stream.try_clone().ok();
+                        // The peer may have been dropped before the connection
+                        // could be closed by it.
+                        if stopped.load(Ordering::SeqCst) {
+                            break;
+                        }
+
+                        // Errors are part of what is being simulated, so they are ignored.
+                        let _ = serve(stream, &mut table, &requests);
+                        *current.lock().unwrap() = None;
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            addr,
+            requests,
+            current,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the address the peer is listening on.
+    #[inline(always)]
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the code and payload of every request received so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FakePeer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Waking up the listener, which is blocked on accepting a connection.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(stream) = self.current.lock().unwrap().take() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Serves the requests of a single connection until a reply closes it, or
+/// until the client disconnects.
+fn serve(
+    stream: TcpStream,
+    table: &mut HashMap<u16, VecDeque<Reply>>,
+    requests: &Mutex<Vec<Request>>,
+) -> io::Result<()> {
+    let mut frames = FrameBuffer::new(codec::Kind::Request);
+    while let Some(frame) = Tcp::read_frame(&stream, &mut frames)? {
+        let (request, _) = codec::decode_request(frame)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        requests
+            .lock()
+            .unwrap()
+            .push((request.code, request.payload.to_vec()));
+
+        let reply = match table.get_mut(&request.code) {
+            Some(replies) if replies.len() > 1 => replies.pop_front().unwrap(),
+            Some(replies) => replies.front().cloned().unwrap(),
+            None => Reply::status(1),
+        };
+
+        if !perform(&stream, reply)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Performs the reply. Returns whether the connection stays open.
+fn perform(stream: &TcpStream, reply: Reply) -> io::Result<bool> {
+    match reply {
+        Reply::Respond { status, payload } => {
+            let buffer = codec::encode_response(status, &payload)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Tcp::write(stream, &buffer)?;
+            Ok(true)
+        }
+
+        Reply::Raw(buffer) => {
+            Tcp::write(stream, &buffer)?;
+            Ok(false)
+        }
+
+        Reply::Disconnect => Ok(false),
+
+        Reply::Delay(duration, reply) => {
+            std::thread::sleep(duration);
+            perform(stream, *reply)
+        }
+    }
+}
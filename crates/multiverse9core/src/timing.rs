@@ -0,0 +1,227 @@
+//! Timed requests let client developers tell the latency of the network apart
+//! from the time a node spends on a request. A [TIMED] request carries another
+//! request frame as its payload, and its response is the response frame of
+//! that request followed by a [Trailer] encoded as JSON:
+//!
+//! ```text
+//! +----------------+------------------+
+//! | response frame | trailer (JSON)   |
+//! +----------------+------------------+
+//! ```
+//!
+//! Requests which change the state of the connection cannot be timed, and
+//! timed requests cannot be journaled, since the timings of a replayed
+//! response would be stale. Requests with a deadline cannot be timed either,
+//! and neither can be journaled, so that wrapped requests are never nested.
+
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::protocol::codec;
+use crate::storage::{
+    Append, Claim, Connection, Entry, Error, Interactions, Keyspace, NewRecord, Relation,
+};
+
+/// Request code of a timed request.
+pub const TIMED: u16 = 0x001A;
+
+/// Where a node spent the time it took to handle a request, in microseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Trailer {
+    /// Time the request waited before it was handled, including the time the
+    /// connection waited for a thread if it is the first request on it.
+    pub queue: u64,
+    /// Time spent on requests to the storage backend.
+    pub storage: u64,
+    /// Time spent on requests to remote nodes.
+    pub remote: u64,
+    /// Time from when the request was received until its response was ready,
+    /// including the queue.
+    pub total: u64,
+}
+
+/// The time the request the current thread is handling has spent so far.
+#[derive(Clone, Copy)]
+struct Spent {
+    /// When the request was received, and how long it waited before that.
+    received: Option<(Instant, Duration)>,
+    storage: Duration,
+    remote: Duration,
+}
+
+thread_local! {
+    static SPENT: Cell<Spent> = const { Cell::new(Spent {
+        received: None,
+        storage: Duration::ZERO,
+        remote: Duration::ZERO,
+    }) };
+}
+
+/// Records that the current thread received a request which had already
+/// waited for the given time.
+pub(crate) fn received(waited: Duration) {
+    SPENT.with(|spent| {
+        spent.set(Spent {
+            received: Some((Instant::now(), waited)),
+            storage: Duration::ZERO,
+            remote: Duration::ZERO,
+        })
+    });
+}
+
+/// Records time spent on a request to the storage backend.
+pub(crate) fn storage(elapsed: Duration) {
+    SPENT.with(|spent| {
+        let mut current = spent.get();
+        current.storage += elapsed;
+        spent.set(current);
+    });
+}
+
+/// Records time spent on requests to a remote node.
+pub(crate) fn remote(elapsed: Duration) {
+    SPENT.with(|spent| {
+        let mut current = spent.get();
+        current.remote += elapsed;
+        spent.set(current);
+    });
+}
+
+/// Returns the time since the request the current thread is handling was
+/// received, including the time it waited before.
+pub(crate) fn waited() -> Duration {
+    SPENT.with(|spent| match spent.get().received {
+        Some((received, waited)) => waited + received.elapsed(),
+        None => Duration::ZERO,
+    })
+}
+
+/// Starts timing a request, and returns the time it waited before.
+pub(crate) fn start() -> Duration {
+    SPENT.with(|spent| {
+        let mut current = spent.get();
+        current.storage = Duration::ZERO;
+        current.remote = Duration::ZERO;
+        spent.set(current);
+        match current.received {
+            Some((received, waited)) => waited + received.elapsed(),
+            None => Duration::ZERO,
+        }
+    })
+}
+
+/// Returns the trailer of the request whose timing was started at `started`,
+/// after waiting for `queue`.
+pub(crate) fn finish(started: Instant, queue: Duration) -> Trailer {
+    let spent = SPENT.with(|spent| spent.get());
+    Trailer {
+        queue: queue.as_micros() as u64,
+        storage: spent.storage.as_micros() as u64,
+        remote: spent.remote.as_micros() as u64,
+        total: (queue + started.elapsed()).as_micros() as u64,
+    }
+}
+
+/// Decodes the payload of the response to a [TIMED] request into the response
+/// to the inner request and the trailer.
+pub fn decode(payload: &[u8]) -> Result<(codec::Response<'_>, Trailer), codec::Error> {
+    let (response, len) = codec::decode_response(payload)?;
+    let trailer = serde_json::from_slice(&payload[len..]).unwrap_or_default();
+    Ok((response, trailer))
+}
+
+/// A connection to the storage backend which records the time spent on every
+/// call, for the trailers of timed requests.
+pub(crate) struct Timed<'a>(pub(crate) &'a mut dyn Connection);
+
+/// Implements [Connection] for [Timed] by timing every call of the inner
+/// connection.
+macro_rules! timed {
+    ($(fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        impl Connection for Timed<'_> {
+            $(
+                fn $name(&mut self, $($arg: $ty),*) -> $ret {
+                    let started = Instant::now();
+                    let result = self.0.$name($($arg),*);
+                    storage(started.elapsed());
+                    result
+                }
+            )*
+        }
+    };
+}
+
+timed! {
+    fn get(keyspace: &Keyspace, key: &str) -> Result<Option<Vec<u8>>, Error>;
+    fn signature(keyspace: &Keyspace, key: &str) -> Result<Option<Vec<u8>>, Error>;
+    fn owner(keyspace: &Keyspace, key: &str) -> Result<Option<String>, Error>;
+    fn create(keyspace: &Keyspace, id: &ulid::Ulid, value: &[u8], signature: Option<&[u8]>, owner: Option<&str>) -> Result<(), Error>;
+    fn create_many(keyspace: &Keyspace, records: &[NewRecord]) -> Result<(), Error>;
+    fn create_content(keyspace: &Keyspace, key: &str, value: &[u8], signature: Option<&[u8]>, owner: Option<&str>, referrer: &str) -> Result<bool, Error>;
+    fn references(keyspace: &Keyspace, key: &str) -> Result<Vec<String>, Error>;
+    fn dereference(keyspace: &Keyspace, key: &str, referrer: &str) -> Result<Option<usize>, Error>;
+    fn del(keyspace: &Keyspace, keys: &[String]) -> Result<(), Error>;
+    fn tag(keyspace: &Keyspace, key: &str, tags: &[String]) -> Result<(), Error>;
+    fn tagged(keyspace: &Keyspace, tags: &[String], all: bool) -> Result<Vec<String>, Error>;
+    fn index(keyspace: &Keyspace, key: &str, terms: &[String]) -> Result<(), Error>;
+    fn search(keyspace: &Keyspace, terms: &[String]) -> Result<Vec<String>, Error>;
+    fn post(keyspace: &Keyspace, feed: &str, ids: &[ulid::Ulid]) -> Result<(), Error>;
+    fn latest(keyspace: &Keyspace, feed: &str, count: usize) -> Result<Vec<String>, Error>;
+    fn latest_before(keyspace: &Keyspace, feed: &str, before: &str, count: usize) -> Result<Vec<String>, Error>;
+    fn register(keyspace: &Keyspace, handle: &str, public: &str, profile: &[u8]) -> Result<bool, Error>;
+    fn profile(keyspace: &Keyspace, handle: &str) -> Result<Option<Vec<u8>>, Error>;
+    fn handle(keyspace: &Keyspace, public: &str) -> Result<Option<String>, Error>;
+    fn link(keyspace: &Keyspace, relation: Relation, handle: &str, actor: &str) -> Result<bool, Error>;
+    fn unlink(keyspace: &Keyspace, relation: Relation, handle: &str, actor: &str) -> Result<bool, Error>;
+    fn members(keyspace: &Keyspace, relation: Relation, handle: &str) -> Result<Vec<String>, Error>;
+    fn like(keyspace: &Keyspace, id: &str, identity: &str) -> Result<bool, Error>;
+    fn reply(keyspace: &Keyspace, parent: &str, id: &ulid::Ulid) -> Result<(), Error>;
+    fn replies(keyspace: &Keyspace, parent: &str, count: usize) -> Result<Vec<String>, Error>;
+    fn parent(keyspace: &Keyspace, id: &str) -> Result<Option<String>, Error>;
+    fn interactions(keyspace: &Keyspace, id: &str) -> Result<Interactions, Error>;
+    fn push_sample(keyspace: &Keyspace, addr: &str, sample: &[u8], window: usize) -> Result<(), Error>;
+    fn samples(keyspace: &Keyspace, addr: &str) -> Result<Vec<Vec<u8>>, Error>;
+    fn claim(keyspace: &Keyspace, client: &str, id: &str, ttl: Duration) -> Result<Claim, Error>;
+    fn complete(keyspace: &Keyspace, client: &str, id: &str, response: &[u8], ttl: Duration) -> Result<(), Error>;
+    fn release(keyspace: &Keyspace, client: &str, id: &str) -> Result<(), Error>;
+    fn begin_upload(keyspace: &Keyspace, client: &str, id: &str, ttl: Duration) -> Result<(), Error>;
+    fn append_upload(keyspace: &Keyspace, client: &str, id: &str, chunk: &[u8], max: usize, ttl: Duration) -> Result<Append, Error>;
+    fn read_upload(keyspace: &Keyspace, client: &str, id: &str) -> Result<Option<Vec<u8>>, Error>;
+    fn commit_upload(keyspace: &Keyspace, client: &str, id: &str, record: &ulid::Ulid, owner: Option<&str>) -> Result<bool, Error>;
+    fn discard_upload(keyspace: &Keyspace, client: &str, id: &str) -> Result<(), Error>;
+    fn buckets(keyspace: &Keyspace) -> Result<Vec<String>, Error>;
+    fn bucket(keyspace: &Keyspace, bucket: &str) -> Result<Vec<String>, Error>;
+    fn size(keyspace: &Keyspace, key: &str) -> Result<usize, Error>;
+    fn keys(keyspace: &Keyspace) -> Result<Vec<String>, Error>;
+    fn scan_keys(keyspace: &Keyspace, prefix: &str) -> Result<Vec<String>, Error>;
+    fn export(keyspace: &Keyspace, key: &str) -> Result<Option<Entry>, Error>;
+    fn exists(keyspace: &Keyspace, key: &str) -> Result<bool, Error>;
+    fn import(keyspace: &Keyspace, entry: &Entry) -> Result<(), Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trailer() {
+        received(Duration::from_millis(2));
+        let queue = start();
+        assert!(queue >= Duration::from_millis(2));
+
+        let started = Instant::now();
+        storage(Duration::from_millis(3));
+        remote(Duration::from_millis(5));
+        storage(Duration::from_millis(1));
+        let trailer = finish(started, queue);
+        assert_eq!((trailer.storage, trailer.remote), (4000, 5000));
+        assert!(trailer.total >= trailer.queue);
+
+        let mut payload = codec::encode_response(0, b"value").unwrap();
+        payload.extend(serde_json::to_vec(&trailer).unwrap());
+        let (response, decoded) = decode(&payload).unwrap();
+        assert_eq!(response.payload, b"value");
+        assert_eq!(decoded, trailer);
+    }
+}
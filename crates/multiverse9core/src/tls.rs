@@ -0,0 +1,319 @@
+use log::*;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier};
+use rustls::{Certificate, DistinguishedName, PrivateKey, ServerName};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::protocol::Identity;
+use crate::settings::{Peer, Tls};
+use crate::transport::Transport;
+
+crate::enum_with_impl_error! {
+    pub Error,
+    .Io(std::io::Error) [source]
+    .Tls(rustls::Error) [source]
+    .InvalidPem(String)
+    ~Debug
+}
+
+/// Accepts incoming connections over TLS, requiring the clients to present a
+/// certificate.
+pub(crate) struct Acceptor {
+    config: Arc<rustls::ServerConfig>,
+    /// Fingerprints of the pinned certificates of acknowledged nodes.
+    pins: Vec<(String, SocketAddr)>,
+    /// Whether certificates which are not pinned, but have been verified
+    /// against the configured CA, are accepted.
+    ca: bool,
+}
+
+impl Acceptor {
+    pub(crate) fn new(tls: &Tls, peers: &[Peer]) -> Result<Self, Error> {
+        let verifier: Arc<dyn ClientCertVerifier> = match &tls.ca {
+            Some(ca) => Arc::new(AllowAnyAuthenticatedClient::new(roots(ca)?)),
+            None => Arc::new(PinnedClients),
+        };
+
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certificates(&tls.certificate)?, private_key(&tls.key)?)
+            .map_err(Error::Tls)?;
+
+        Ok(Self {
+            config: Arc::new(config),
+            pins: pins(peers),
+            ca: tls.ca.is_some(),
+        })
+    }
+
+    /// Performs the TLS handshake on an accepted connection, and maps the
+    /// certificate of the client onto an identity. Certificates pinned for an
+    /// acknowledged node identify that node, while other certificates signed
+    /// by the CA are identified by their fingerprint.
+    pub(crate) fn accept(&self, stream: TcpStream) -> io::Result<ServerStream> {
+        let addr = stream.peer_addr()?;
+        let connection = rustls::ServerConnection::new(Arc::clone(&self.config))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut stream = rustls::StreamOwned::new(connection, stream);
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+
+        let fingerprint = stream
+            .conn
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .map(|certificate| fingerprint(&certificate.0))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::PermissionDenied, "No client certificate")
+            })?;
+
+        let identity = match self.pins.iter().find(|(pin, _)| *pin == fingerprint) {
+            Some((_, node)) => Identity::Node(node.to_string()),
+            None if self.ca => Identity::Subject(format!("cert:{}", fingerprint)),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Certificate {} of {} is not pinned", fingerprint, addr),
+                ))
+            }
+        };
+
+        debug!("{} presented a certificate for {}", addr, identity);
+        Ok(ServerStream {
+            inner: stream,
+            identity,
+        })
+    }
+}
+
+/// Creates the configuration for outgoing TLS connections to other nodes.
+/// With a CA, the certificates of the nodes are verified against it, and
+/// otherwise they must be pinned for an acknowledged node.
+pub fn connector(tls: &Tls, peers: &[Peer]) -> Result<Arc<rustls::ClientConfig>, Error> {
+    let (certificates, key) = (certificates(&tls.certificate)?, private_key(&tls.key)?);
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let config = match &tls.ca {
+        Some(ca) => builder
+            .with_root_certificates(roots(ca)?)
+            .with_client_auth_cert(certificates, key),
+        None => builder
+            .with_custom_certificate_verifier(Arc::new(PinnedServers(pins(peers))))
+            .with_client_auth_cert(certificates, key),
+    };
+
+    Ok(Arc::new(config.map_err(Error::Tls)?))
+}
+
+/// Performs the TLS handshake on an outgoing connection.
+pub(crate) fn connect(
+    stream: TcpStream,
+    config: Arc<rustls::ClientConfig>,
+) -> io::Result<ClientStream> {
+    let name = ServerName::IpAddress(stream.peer_addr()?.ip());
+    let connection = rustls::ClientConnection::new(config, name)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut stream = rustls::StreamOwned::new(connection, stream);
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+
+    Ok(ClientStream { inner: stream })
+}
+
+/// An accepted connection over TLS.
+pub(crate) struct ServerStream {
+    inner: rustls::StreamOwned<rustls::ServerConnection, TcpStream>,
+    /// The identity established from the certificate of the client.
+    identity: Identity,
+}
+
+/// An outgoing connection over TLS.
+pub(crate) struct ClientStream {
+    inner: rustls::StreamOwned<rustls::ClientConnection, TcpStream>,
+}
+
+impl Read for ServerStream {
+    #[inline(always)]
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buffer)
+    }
+}
+
+impl Write for ServerStream {
+    #[inline(always)]
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.inner.write(buffer)
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for ServerStream {
+    #[inline(always)]
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.sock.peer_addr()
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.inner.conn.send_close_notify();
+        self.inner.flush()?;
+        self.inner.sock.shutdown(Shutdown::Write)
+    }
+
+    #[inline(always)]
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.sock.set_read_timeout(timeout)
+    }
+
+    fn identity(&self) -> Option<Identity> {
+        Some(self.identity.clone())
+    }
+}
+
+impl Read for ClientStream {
+    #[inline(always)]
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buffer)
+    }
+}
+
+impl Write for ClientStream {
+    #[inline(always)]
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.inner.write(buffer)
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for ClientStream {
+    #[inline(always)]
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.sock.peer_addr()
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.inner.conn.send_close_notify();
+        self.inner.flush()?;
+        self.inner.sock.shutdown(Shutdown::Write)
+    }
+
+    #[inline(always)]
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.sock.set_read_timeout(timeout)
+    }
+}
+
+/// Accepts any client certificate during the handshake. Whether the
+/// certificate is pinned is checked by [Acceptor::accept] once the handshake
+/// has completed, since that is where it is mapped onto an identity.
+struct PinnedClients;
+
+impl ClientCertVerifier for PinnedClients {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// Accepts server certificates which are pinned for an acknowledged node.
+struct PinnedServers(Vec<(String, SocketAddr)>);
+
+impl ServerCertVerifier for PinnedServers {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(&end_entity.0);
+        match self.0.iter().any(|(pin, _)| *pin == fingerprint) {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General(format!(
+                "Certificate {} is not pinned",
+                fingerprint
+            ))),
+        }
+    }
+}
+
+/// Returns the hex-encoded SHA-256 fingerprint of a DER-encoded certificate,
+/// which is the format certificates are pinned in.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn pins(peers: &[Peer]) -> Vec<(String, SocketAddr)> {
+    peers
+        .iter()
+        .filter_map(|peer| {
+            let pin = peer.certificate.as_ref()?;
+            Some((pin.to_lowercase(), peer.addr))
+        })
+        .collect()
+}
+
+fn roots(path: &Path) -> Result<rustls::RootCertStore, Error> {
+    let mut roots = rustls::RootCertStore::empty();
+    let certificates: Vec<Vec<u8>> = certificates(path)?.into_iter().map(|c| c.0).collect();
+    let (valid, _) = roots.add_parsable_certificates(&certificates);
+    if valid == 0 {
+        return Err(Error::InvalidPem(format!(
+            "No CA certificates in {:?}",
+            path
+        )));
+    }
+
+    Ok(roots)
+}
+
+fn certificates(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let mut reader = io::BufReader::new(std::fs::File::open(path).map_err(Error::Io)?);
+    let certificates = rustls_pemfile::certs(&mut reader).map_err(Error::Io)?;
+    if certificates.is_empty() {
+        return Err(Error::InvalidPem(format!("No certificates in {:?}", path)));
+    }
+
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn private_key(path: &Path) -> Result<PrivateKey, Error> {
+    let mut reader = io::BufReader::new(std::fs::File::open(path).map_err(Error::Io)?);
+    for item in rustls_pemfile::read_all(&mut reader).map_err(Error::Io)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+
+    Err(Error::InvalidPem(format!("No private key in {:?}", path)))
+}
@@ -0,0 +1,181 @@
+//! Transfers copy every key of a node from one storage backend to another, for
+//! moving a node onto a new backend. The keys are copied in the order of their
+//! names, one after another, the same way [crate::backup] archives them.
+//!
+//! Every [CHECKPOINT_EVERY] keys, the name of the key copied last is saved as a
+//! checkpoint in the target backend. The checkpoint is kept next to the
+//! namespace of the node rather than within it, so that it never collides with
+//! a key being copied. A transfer which was interrupted resumes after the
+//! checkpoint the next time it is run, and the checkpoint is cleared once every
+//! key has been copied. Since keys which are changed in the source
+//! behind the checkpoint are not copied again, the node should be stopped
+//! while its keys are transferred.
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{self, Connection, Entry, Keyspace, Value};
+
+/// The name of the key holding the checkpoint of a transfer, relative to the
+/// namespace of the checkpoints, see [checkpoints].
+pub const CHECKPOINT: &str = "transfer";
+
+/// Appended to the namespace of the node to make up the namespace of the
+/// checkpoints, which keys of the node never fall into.
+pub const CHECKPOINTS_SUFFIX: &str = "#checkpoints";
+
+/// The number of keys copied between two checkpoints.
+pub const CHECKPOINT_EVERY: usize = 1000;
+
+/// The progress of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Report {
+    /// The number of keys in the source.
+    pub total: usize,
+    /// The number of keys copied so far.
+    pub copied: usize,
+    /// The number of keys left out, since an earlier run copied them already.
+    pub resumed: usize,
+    /// Whether every key has been copied.
+    pub done: bool,
+}
+
+/// Copies the keys of the namespace from the source into the target, resuming
+/// from the checkpoint in the target, and reports the progress after every
+/// checkpoint. Keys which went away since they were listed are left out.
+pub(crate) fn transfer(
+    source: &mut dyn Connection,
+    target: &mut dyn Connection,
+    keyspace: &Keyspace,
+    progress: &mut dyn FnMut(&Report),
+) -> Result<Report, storage::Error> {
+    let checkpoint = match target.export(&checkpoints(keyspace), CHECKPOINT)? {
+        Some(Entry {
+            value: Value::String(last),
+            ..
+        }) if !last.is_empty() => Some(String::from_utf8_lossy(&last).to_string()),
+        _ => None,
+    };
+
+    let mut keys = source.keys(keyspace)?;
+    keys.sort_unstable();
+
+    let mut report = Report {
+        total: keys.len(),
+        ..Default::default()
+    };
+    for key in keys {
+        if checkpoint.as_ref().is_some_and(|last| key <= *last) {
+            report.resumed += 1;
+            continue;
+        }
+
+        if let Some(entry) = source.export(keyspace, &key)? {
+            target.import(keyspace, &entry)?;
+        }
+
+        report.copied += 1;
+        if report.copied.is_multiple_of(CHECKPOINT_EVERY) {
+            save(target, keyspace, key.into_bytes())?;
+            progress(&report);
+        }
+    }
+
+    save(target, keyspace, vec![])?;
+    report.done = true;
+    progress(&report);
+    Ok(report)
+}
+
+/// Returns the layout of the keys holding the checkpoints of transfers of the
+/// node with the given layout.
+pub fn checkpoints(keyspace: &Keyspace) -> Keyspace {
+    Keyspace {
+        namespace: format!("{}{}", keyspace.namespace, CHECKPOINTS_SUFFIX),
+        partition: keyspace.partition,
+    }
+}
+
+fn save(
+    target: &mut dyn Connection,
+    keyspace: &Keyspace,
+    last: Vec<u8>,
+) -> Result<(), storage::Error> {
+    target.import(
+        &checkpoints(keyspace),
+        &Entry {
+            key: CHECKPOINT.into(),
+            value: Value::String(last),
+            ttl: None,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Partition;
+    use crate::storage::memory::Memory;
+    use crate::storage::Backend;
+
+    #[test]
+    fn test_transfer() {
+        let keyspace = Keyspace {
+            namespace: "multiverse9_test".into(),
+            partition: Partition::Hour,
+        };
+        let mut source = Memory::default().connection().unwrap();
+        let mut target = Memory::default().connection().unwrap();
+        let ids: Vec<_> = (0..3).map(|n| ulid::Ulid::from_parts(1, n)).collect();
+        for id in &ids {
+            source
+                .create(&keyspace, id, b"value", None, Some("alice"))
+                .unwrap();
+        }
+
+        // A key of the node named like the checkpoint is copied as any other.
+        let named = Entry {
+            key: CHECKPOINT.into(),
+            value: Value::String(b"record".to_vec()),
+            ttl: None,
+        };
+        source.import(&keyspace, &named).unwrap();
+
+        // Resuming after the first record, which is left out of the transfer.
+        let first = ids[0].to_string();
+        save(target.as_mut(), &keyspace, first.clone().into_bytes()).unwrap();
+        let mut reports = vec![];
+        let report = transfer(source.as_mut(), target.as_mut(), &keyspace, &mut |report| {
+            reports.push(*report)
+        })
+        .unwrap();
+
+        // The records, their owners, the index bucket and the named key.
+        assert_eq!(report.total, 8);
+        assert_eq!(report.resumed, 1);
+        assert_eq!(report.copied, 7);
+        assert!(report.done);
+        assert_eq!(reports, vec![report]);
+        assert_eq!(target.get(&keyspace, &first).unwrap(), None);
+        for id in &ids[1..] {
+            let id = id.to_string();
+            assert_eq!(target.get(&keyspace, &id).unwrap().unwrap(), b"value");
+            assert_eq!(target.owner(&keyspace, &id).unwrap().unwrap(), "alice");
+        }
+        assert_eq!(target.export(&keyspace, CHECKPOINT).unwrap(), Some(named));
+        assert_eq!(
+            target
+                .get(&checkpoints(&keyspace), CHECKPOINT)
+                .unwrap()
+                .unwrap(),
+            Vec::<u8>::new()
+        );
+
+        // A transfer which completed starts over.
+        let report = transfer(source.as_mut(), target.as_mut(), &keyspace, &mut |_| {}).unwrap();
+        assert_eq!((report.copied, report.resumed), (8, 0));
+        assert_eq!(
+            target.get(&keyspace, &first).unwrap().unwrap(),
+            b"value".to_vec()
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::loopback::Signal;
+use crate::protocol::Identity;
+
+/// What a parked stream is waited on to become readable, see [crate::reactor].
+pub(crate) enum Readiness {
+    /// Another handle to the socket the stream is exchanged over.
+    Socket(TcpStream),
+    /// The signal the other end of an in-process stream raises once it writes
+    /// or goes away.
+    Signal(Arc<Signal>),
+}
+
+/// A byte stream which frames are exchanged over. Implemented by plain TCP
+/// streams, and by TLS streams when the `tls` feature is enabled.
+pub(crate) trait Transport: io::Read + io::Write + Send {
+    /// Returns the address of the remote end of the stream.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Shuts down the write side of the stream after flushing it, so that the
+    /// remote end reads everything written so far followed by the end of the
+    /// stream.
+    fn shutdown_write(&mut self) -> io::Result<()>;
+
+    /// Sets how long reads wait for data before they fail with an error of
+    /// kind [io::ErrorKind::WouldBlock] or [io::ErrorKind::TimedOut]. Reads
+    /// wait forever with [None].
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Returns another handle to the same stream, so that the stream can be
+    /// read from one thread while it is written from another. Streams which
+    /// keep state on both directions, such as TLS, cannot be cloned.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The stream cannot be cloned",
+        ))
+    }
+
+    /// Returns the identity the remote end has proven while establishing the
+    /// stream, such as with a client certificate.
+    fn identity(&self) -> Option<Identity> {
+        None
+    }
+
+    /// Returns what is waited on for the stream to become readable while it is
+    /// parked, see [crate::reactor]. Streams which buffer what they read from
+    /// the socket, such as TLS, cannot be parked, since buffered data would
+    /// never wake them.
+    fn readiness(&self) -> io::Result<Readiness> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The stream cannot be parked",
+        ))
+    }
+}
+
+impl Transport for TcpStream {
+    #[inline(always)]
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.shutdown(Shutdown::Write)
+    }
+
+    #[inline(always)]
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn readiness(&self) -> io::Result<Readiness> {
+        TcpStream::try_clone(self).map(Readiness::Socket)
+    }
+}
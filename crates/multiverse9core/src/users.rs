@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+/// The longest handle a user may have.
+const MAX_HANDLE_LEN: usize = 32;
+
+/// The profile of an actor registered on an instance. Users prove that they
+/// are the owner of the profile by completing the signed handshake with its
+/// key, after which the records they create are attributed to them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    /// The name the user is known by on the instance, and `handle@addr` across
+    /// the federation.
+    pub handle: String,
+    /// Hex-encoded Ed25519 public key of the user.
+    pub key: String,
+    /// Time of the registration, in milliseconds since the Unix epoch.
+    pub registered: u64,
+}
+
+/// Returns whether the handle can be registered. Handles are limited to
+/// lowercase alphanumeric characters, `-` and `_`, so that they never collide
+/// with the delimiters of the payloads and keys they are part of.
+pub fn is_valid_handle(handle: &str) -> bool {
+    !handle.is_empty()
+        && handle.len() <= MAX_HANDLE_LEN
+        && handle
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Splits an actor into its handle and the address of the node it is
+/// registered on, i.e. `handle@addr`. Actors without an address are registered
+/// on current node.
+///
+/// # Returns
+///
+/// [None] if the handle is invalid.
+pub fn split_actor(actor: &str) -> Option<(&str, Option<&str>)> {
+    let (handle, addr) = match actor.split_once('@') {
+        Some((handle, addr)) => (handle, Some(addr)),
+        None => (actor, None),
+    };
+
+    is_valid_handle(handle).then_some((handle, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_handle() {
+        assert!(is_valid_handle("alice"));
+        assert!(is_valid_handle("bob_2-b"));
+        assert!(!is_valid_handle(""));
+        assert!(!is_valid_handle("Alice"));
+        assert!(!is_valid_handle("alice@127.0.0.1:8000"));
+        assert!(!is_valid_handle("alice~bob"));
+        assert!(!is_valid_handle(&"a".repeat(MAX_HANDLE_LEN + 1)));
+    }
+
+    #[test]
+    fn test_split_actor() {
+        assert_eq!(split_actor("alice"), Some(("alice", None)));
+        assert_eq!(
+            split_actor("alice@127.0.0.1:8000"),
+            Some(("alice", Some("127.0.0.1:8000")))
+        );
+        assert_eq!(split_actor("Alice@127.0.0.1:8000"), None);
+        assert_eq!(split_actor("@127.0.0.1:8000"), None);
+    }
+}
@@ -0,0 +1,139 @@
+//! Verification of the signatures nodes receive. Handshake proofs are checked
+//! on workers of their own, which the reactor hands the connection to until
+//! the proof is answered, so that the worker serving the connection moves on
+//! rather than waiting for it. See [crate::reactor].
+//!
+//! Signatures of records aggregated from remote nodes are checked by the
+//! handler which passes them on, since its reply depends on them. The entries
+//! verified on a connection to a remote node are remembered for as long as the
+//! connection is among the most recent sessions, so that records which are
+//! read repeatedly are verified once per peer and session. Sessions are never
+//! shared between connections, so a key pinned anew is never trusted with
+//! entries verified against the previous one.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::api::internal;
+
+/// The number of workers checking handshake proofs.
+pub(crate) const WORKERS: usize = 2;
+
+/// The most sessions whose verified entries are remembered, beyond which those
+/// of the oldest session are forgotten.
+const MAX_SESSIONS: usize = 256;
+
+/// The most verified entries remembered per session.
+const MAX_VERIFIED: usize = 4096;
+
+/// A session with a remote node, by the ID of the connection and the public
+/// key which was pinned for it.
+type Session = (u64, String);
+
+/// The digests of the intact entries verified on every session.
+type Verified = Mutex<BTreeMap<Session, HashSet<blake3::Hash>>>;
+
+/// Returns the ID of a new session, which is never handed out twice.
+pub(crate) fn session() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The entries verified on every session with a remote node.
+#[derive(Default)]
+pub(crate) struct Verifier {
+    verified: Verified,
+}
+
+impl Verifier {
+    /// Verifies the signatures of the entries aggregated on the session with the
+    /// node with the given public key. See [internal::verify_entries].
+    pub(crate) fn verify(&self, public: &str, session: u64, reply: &[u8]) -> Vec<u8> {
+        check(reply, (session, public.to_string()), &self.verified)
+    }
+}
+
+/// Verifies the entries, skipping those which were verified on the session
+/// before.
+fn check(entries: &[u8], session: Session, verified: &Verified) -> Vec<u8> {
+    internal::verify_entries(entries, |key, owner, value, signature| {
+        let digest = digest(key, owner, value, signature);
+        let known = verified
+            .lock()
+            .unwrap()
+            .get(&session)
+            .is_some_and(|digests| digests.contains(&digest));
+        if known {
+            return true;
+        }
+
+        let intact = crate::keys::verify_record(&session.1, key, owner, value, signature);
+        if intact {
+            let mut verified = verified.lock().unwrap();
+            let digests = verified.entry(session.clone()).or_default();
+            if digests.len() >= MAX_VERIFIED {
+                digests.clear();
+            }
+
+            digests.insert(digest);
+            if verified.len() > MAX_SESSIONS {
+                verified.pop_first();
+            }
+        }
+
+        intact
+    })
+}
+
+/// Digests everything the signature of an entry covers along with the
+/// signature, with the length of every part, so that no two entries share a
+/// digest.
+fn digest(key: &str, owner: Option<&str>, value: &[u8], signature: &[u8]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    for part in [
+        key.as_bytes(),
+        owner.unwrap_or_default().as_bytes(),
+        value,
+        signature,
+    ] {
+        hasher.update(&(part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+
+    hasher.update(&[owner.is_some() as u8]);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{hex, Keypair};
+
+    #[test]
+    fn test_verify() {
+        let keypair = Keypair::generate();
+        let signature = hex(&keypair.sign_record("key1", None, b"value1"));
+        let reply = format!(
+            "key1#{0}:value1\x00key2#{0}:value2\x00unsigned:value\x00",
+            signature
+        );
+
+        let verifier = Verifier::default();
+        let verified = verifier.verify(&keypair.public(), session(), reply.as_bytes());
+        assert_eq!(
+            verified,
+            b"key1:value1\x00key2:Invalid signature\x00unsigned:value\x00"
+        );
+
+        // Only the intact entry is remembered, and only for its session.
+        let sessions = verifier.verified.lock().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions.values().next().unwrap().len(), 1);
+        drop(sessions);
+
+        let other = Keypair::generate();
+        let verified = verifier.verify(&other.public(), session(), reply.as_bytes());
+        assert!(verified.starts_with(b"key1:Invalid signature\x00"));
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Prefix of the first target of an aggregate request which names the view
+/// the entries are returned in, i.e. `view=summary`.
+pub const PREFIX: &str = "view=";
+
+/// The view which returns values as they are stored.
+pub const RAW: &str = "raw";
+/// The view which shortens values to at most [SUMMARY_LEN] bytes.
+pub const SUMMARY: &str = "summary";
+/// The longest value returned by the [SUMMARY] view, before the ellipsis.
+pub const SUMMARY_LEN: usize = 280;
+
+/// Transforms the values of records as they are returned, such as to shorten
+/// them or to render a thumbnail of an image.
+pub trait View: Send + Sync {
+    /// Transforms the value of the record with the given key.
+    fn apply(&self, key: &str, value: &[u8]) -> Vec<u8>;
+}
+
+impl<F: Fn(&str, &[u8]) -> Vec<u8> + Send + Sync> View for F {
+    fn apply(&self, key: &str, value: &[u8]) -> Vec<u8> {
+        self(key, value)
+    }
+}
+
+/// The views of a node by their names. Besides [RAW] and [SUMMARY], which
+/// every node has, views are registered by the programs embedding the node
+/// with [crate::node::Node::with_view].
+#[derive(Clone)]
+pub struct Views(HashMap<String, Arc<dyn View>>);
+
+impl Views {
+    /// Registers the view under the given name, replacing the view which was
+    /// registered under it before. Views can only be requested by names which
+    /// are valid feed names, see [crate::feed::is_valid_name].
+    pub fn register(&mut self, name: impl Into<String>, view: impl View + 'static) {
+        self.0.insert(name.into(), Arc::new(view));
+    }
+
+    /// Returns the view registered under the given name.
+    pub fn get(&self, name: &str) -> Option<Arc<dyn View>> {
+        self.0.get(name).cloned()
+    }
+}
+
+impl Default for Views {
+    fn default() -> Self {
+        let mut views = Self(HashMap::new());
+        views.register(RAW, |_: &str, value: &[u8]| value.to_vec());
+        views.register(SUMMARY, |_: &str, value: &[u8]| summarize(value));
+        views
+    }
+}
+
+impl std::fmt::Debug for Views {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+        f.debug_tuple("Views").field(&names).finish()
+    }
+}
+
+/// Shortens the value to at most [SUMMARY_LEN] bytes followed by an ellipsis,
+/// without splitting UTF-8 characters.
+fn summarize(value: &[u8]) -> Vec<u8> {
+    if value.len() <= SUMMARY_LEN {
+        return value.to_vec();
+    }
+
+    let mut end = SUMMARY_LEN;
+    // Continuation bytes of UTF-8 characters start with `0b10`.
+    while end > 0 && value[end] & 0b1100_0000 == 0b1000_0000 {
+        end -= 1;
+    }
+
+    let mut summary = value[..end].to_vec();
+    summary.extend("…".as_bytes());
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let views = Views::default();
+        let summary = views.get(SUMMARY).unwrap();
+        assert_eq!(summary.apply("key", b"short"), b"short");
+
+        let long = "é".repeat(SUMMARY_LEN);
+        let summarized = String::from_utf8(summary.apply("key", long.as_bytes())).unwrap();
+        assert!(summarized.ends_with('…'));
+        assert_eq!(summarized.len(), SUMMARY_LEN + "…".len());
+    }
+
+    #[test]
+    fn test_register() {
+        let mut views = Views::default();
+        views.register("length", |_: &str, value: &[u8]| {
+            value.len().to_string().into_bytes()
+        });
+        assert_eq!(views.get("length").unwrap().apply("key", b"value"), b"5");
+        assert_eq!(views.get(RAW).unwrap().apply("key", b"value"), b"value");
+        assert!(views.get("thumbnail").is_none());
+    }
+}
@@ -1,8 +1,11 @@
 use std::process::ExitCode;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use log::error;
+use multiverse9core::backup;
+use multiverse9core::doctor;
 use multiverse9core::prelude::*;
+use multiverse9core::roles;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -10,16 +13,52 @@ struct Args {
     #[arg(short, long)]
     debug: bool,
 
+    /// Format of the log lines, `json` emitting one object per line
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    log_format: logger::Format,
+
     #[command(subcommand)]
     action: Action,
 }
 
 #[derive(clap::Subcommand, Debug)]
 enum Action {
-    /// One-time setup and configuration generation
+    /// One-time setup and configuration generation, asking for each setting
+    /// in turn unless `--non-interactive` is given
     Setup {
+        /// Connection string of the storage backend
+        #[arg(long)]
+        redis_uri: Option<String>,
+
+        /// Take the settings from the arguments only, without asking for them
+        #[arg(long, requires = "redis_uri")]
+        non_interactive: bool,
+
+        /// Address the node binds to
+        #[arg(long)]
+        addr: Option<std::net::SocketAddr>,
+
+        /// Name of the instance, generated unless given
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Allow anyone to request the metadata of the node
         #[arg(long)]
-        redis_uri: String,
+        open_metadata: bool,
+
+        /// Roles of connections which have not authenticated, reading,
+        /// writing and deleting unless given
+        #[arg(long = "anonymous-role", value_delimiter = ',')]
+        anonymous_roles: Vec<roles::Role>,
+
+        /// Address of a node to acknowledge from the start
+        #[arg(long = "peer")]
+        peers: Vec<std::net::SocketAddr>,
+
+        /// Path the settings are written to. Non-interactive setups print the
+        /// settings unless given
+        #[arg(short, long)]
+        output: Option<String>,
     },
 
     /// Start a TcpListener and bind to `127.0.0.1:<port>`
@@ -29,57 +68,1142 @@ enum Action {
 
         #[arg(short, long)]
         threads: Option<usize>,
+
+        /// Detach from the terminal and keep running in the background, for
+        /// hosts without a service manager
+        #[arg(long)]
+        daemon: bool,
+
+        /// Path the process ID is written to while the node is running
+        #[arg(long)]
+        pid_file: Option<std::path::PathBuf>,
+
+        /// Path the logs of a detached node are appended to, which are
+        /// discarded unless given
+        #[arg(long, requires = "daemon")]
+        log_file: Option<std::path::PathBuf>,
+    },
+
+    /// Move records stored under bare IDs into the namespace of the node,
+    /// resuming an interrupted migration, or copy every key of the node into
+    /// another storage backend
+    Migrate {
+        #[arg(short)]
+        settings: String,
+
+        /// Connection string of the backend to copy from, instead of the one in
+        /// the settings
+        #[arg(long, requires = "to")]
+        from: Option<String>,
+
+        /// Connection string of the backend to copy every key into, resuming an
+        /// interrupted copy, such as `sled://data/node`. The backend must keep
+        /// its data, so `memory://` is rejected
+        #[arg(long)]
+        to: Option<String>,
+    },
+
+    /// Archive every key of the node into a portable backup, which works while
+    /// the node is running
+    Backup {
+        #[arg(short)]
+        settings: String,
+
+        /// Path the archive is written to
+        #[arg(short, long)]
+        output: String,
+    },
+
+    /// Load the keys of a backup into the node
+    Restore {
+        #[arg(short)]
+        settings: String,
+
+        /// Path of the archive to load
+        #[arg(short, long)]
+        input: String,
+
+        /// Replace the keys which exist already
+        #[arg(long, conflicts_with = "skip_existing")]
+        overwrite: bool,
+
+        /// Keep the keys which exist already, which is the default
+        #[arg(long)]
+        skip_existing: bool,
+
+        /// Only read the archive and report what would be restored
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Check the settings of a node for common misconfigurations, and print
+    /// what was found, most serious first
+    Doctor {
+        #[arg(short)]
+        settings: String,
+    },
+
+    /// Manage the nodes acknowledged by a running node, or show their health
+    Peers {
+        #[command(subcommand)]
+        action: peers::Action,
+    },
+
+    /// Manage the tokens of a running node, such as for rotating a secret
+    Tokens {
+        #[command(subcommand)]
+        action: tokens::Action,
+    },
+
+    /// Drive load against a running node through the SDK, and report the
+    /// throughput and latency percentiles of every operation
+    Bench {
+        /// Address of the node to load
+        #[arg(short, long)]
+        addr: String,
+
+        /// Operations every connection issues in turn
+        #[arg(
+            long,
+            value_enum,
+            value_delimiter = ',',
+            default_value = "create,aggregate"
+        )]
+        ops: Vec<bench::Op>,
+
+        /// Number of connections issuing requests at the same time
+        #[arg(short, long, default_value_t = 16)]
+        concurrency: usize,
+
+        /// Seconds the load is driven for
+        #[arg(long, default_value_t = 10)]
+        duration: u64,
+
+        /// Size in bytes of the values of the created records
+        #[arg(long, default_value_t = 128)]
+        size: usize,
+
+        /// Secret of a token to authenticate every connection with
+        #[arg(long)]
+        token: Option<String>,
+    },
+
+    /// Reconcile the records of a running node with one of its acknowledged
+    /// nodes right away
+    SyncWith {
+        /// Address of the admin listener of the node
+        #[arg(short, long)]
+        addr: String,
+
+        /// Address of the acknowledged node to reconcile with
+        peer: String,
+    },
+
+    /// Print the completions of the subcommands and their flags for a shell,
+    /// e.g. `multiverse9ctl completions bash > /etc/bash_completion.d/multiverse9ctl`
+    Completions {
+        /// Shell to complete for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Ping a running node, and print the round trip along with its load
+    Status {
+        /// Address of the node to ping
+        #[arg(short, long)]
+        addr: String,
+
+        /// Milliseconds to wait for the node before giving up on it
+        #[arg(long, default_value_t = 1000)]
+        timeout: u64,
     },
 }
 
 impl Action {
-    pub fn execute(self) {
+    pub fn execute(self) -> Result<(), Box<dyn std::error::Error>> {
         match self {
-            Self::Setup { redis_uri } => {
-                match Settings::new(redis_uri) {
-                    Ok(settings) => {
-                        // We are only printing the generated settings as a JSON file. It is the
-                        // responsibility of the server maintainer to decide the directory where
-                        // it is going to be stored.
-                        println!("{}", settings.to_string());
-                    }
+            Self::Setup {
+                redis_uri,
+                non_interactive,
+                addr,
+                name,
+                open_metadata,
+                anonymous_roles,
+                peers,
+                output,
+            } => {
+                let answers = setup::Answers {
+                    redis_uri,
+                    addr,
+                    name,
+                    open_metadata,
+                    anonymous_roles,
+                    peers,
+                    output,
+                };
 
-                    Err(e) => error!("{:?}", e),
+                if non_interactive {
+                    setup::non_interactive(answers)?;
+                } else {
+                    setup::wizard(answers)?;
                 }
             }
 
-            Self::Run { settings, threads } => {
+            Self::Run {
+                settings,
+                threads,
+                daemon,
+                pid_file,
+                log_file,
+            } => {
                 let path = std::path::PathBuf::from(settings);
-                let settings = Settings::try_from(path).expect("Could not read settings");
-                Node::new(settings)
-                    .start(threads)
-                    .expect("Could not start the node");
+                let settings = Settings::try_from(path.clone())?;
+                // Detaching before any thread is spawned, since only the thread
+                // which forks is carried over into the detached process.
+                let pid_file = match daemon {
+                    true => daemon::detach(pid_file, log_file.as_deref())?,
+                    false => daemon::PidFile::create(pid_file)?,
+                };
+
+                let node = Node::new(settings).with_source(path);
+                // Draining the requests in flight on SIGINT and SIGTERM, rather
+                // than dropping the connections mid-write.
+                let shutdown = node.shutdown();
+                ctrlc::set_handler(move || shutdown.stop())?;
+                node.start(threads)?;
+                drop(pid_file);
+            }
+
+            Self::Migrate {
+                settings,
+                from,
+                to: Some(to),
+            } => {
+                let path = std::path::PathBuf::from(settings);
+                let mut settings = Settings::try_from(path)?;
+                if let Some(from) = from {
+                    settings.redis_uri = from;
+                }
+
+                let report = Node::new(settings).transfer(&to)?;
+                println!(
+                    "Copied {} keys into {}, {} of them by an earlier run",
+                    report.copied + report.resumed,
+                    to,
+                    report.resumed
+                );
+            }
+
+            Self::Migrate { settings, .. } => {
+                let path = std::path::PathBuf::from(settings);
+                let settings = Settings::try_from(path)?;
+                let report = Node::new(settings).migrate()?;
+                println!(
+                    "Migrated {} records, skipped {} shadowed by namespaced records",
+                    report.migrated, report.skipped
+                );
+            }
+
+            Self::Backup { settings, output } => {
+                let path = std::path::PathBuf::from(settings);
+                let settings = Settings::try_from(path)?;
+
+                // Writing next to the output first, so that an interrupted backup
+                // never leaves a truncated archive behind under its name.
+                let partial = format!("{}.partial", output);
+                let file = std::fs::File::create(&partial)?;
+                let report = match Node::new(settings).backup(std::io::BufWriter::new(file)) {
+                    Ok(report) => report,
+                    Err(e) => {
+                        let _ = std::fs::remove_file(&partial);
+                        return Err(e.into());
+                    }
+                };
+
+                std::fs::rename(&partial, &output)?;
+                println!(
+                    "Backed up {} keys into {} ({} bytes)",
+                    report.keys, output, report.bytes
+                );
+            }
+
+            Self::Restore {
+                settings,
+                input,
+                overwrite,
+                skip_existing: _,
+                dry_run,
+            } => {
+                let path = std::path::PathBuf::from(settings);
+                let settings = Settings::try_from(path)?;
+                let existing = match overwrite {
+                    true => backup::Existing::Overwrite,
+                    false => backup::Existing::Skip,
+                };
+
+                let file = std::io::BufReader::new(std::fs::File::open(&input)?);
+                let restored = Node::new(settings).restore(file, existing, dry_run)?;
+                println!(
+                    "{} {} of {} keys from {}, skipped {} which exist already",
+                    if dry_run { "Would restore" } else { "Restored" },
+                    restored.restored,
+                    restored.read,
+                    input,
+                    restored.skipped
+                );
+            }
+
+            Self::Doctor { settings } => {
+                let path = std::path::PathBuf::from(settings);
+                let settings = Settings::try_from(path)?;
+                let findings = doctor::diagnose(&settings);
+                for finding in &findings {
+                    println!(
+                        "{}\t{}\t{}\n\t\t{}",
+                        finding.severity, finding.check, finding.message, finding.hint
+                    );
+                }
+
+                let errors = findings
+                    .iter()
+                    .filter(|finding| finding.severity == doctor::Severity::Error)
+                    .count();
+                match findings.is_empty() {
+                    true => println!("No problems found"),
+                    false => println!(
+                        "Found {} problems, {} of them errors",
+                        findings.len(),
+                        errors
+                    ),
+                }
+
+                if errors > 0 {
+                    return Err("The node is misconfigured".into());
+                }
+            }
+
+            Self::Peers { action } => action.execute()?,
+
+            Self::Tokens { action } => action.execute()?,
+
+            Self::Bench {
+                addr,
+                ops,
+                concurrency,
+                duration,
+                size,
+                token,
+            } => {
+                let results = bench::run(&bench::Config {
+                    addr,
+                    ops,
+                    concurrency,
+                    duration: std::time::Duration::from_secs(duration),
+                    size,
+                    token,
+                })?;
+
+                bench::print(&results, duration);
+            }
+
+            Self::SyncWith { addr, peer } => {
+                let summary = sdk::Admin::connect(addr)?.sync_with(&peer)?;
+                println!(
+                    "Pulled {} and pushed {} records from and to {} ({} bytes in {}ms)",
+                    summary.pulled, summary.pushed, peer, summary.bytes, summary.duration
+                );
+            }
+
+            Self::Completions { shell } => {
+                let mut command = Args::command();
+                let name = command.get_name().to_string();
+                clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+            }
+
+            Self::Status { addr, timeout } => {
+                let timeout = std::time::Duration::from_millis(timeout);
+                let addr = std::net::ToSocketAddrs::to_socket_addrs(&addr)?
+                    .next()
+                    .ok_or("The address does not resolve")?;
+                let mut client = sdk::Connector::Plain.connect_timeout(&addr, timeout)?;
+                let pong = client.ping_timeout(Some(timeout))?;
+                println!(
+                    "{} is up, replied in {}ms with {} active and {} queued connections",
+                    addr,
+                    pong.rtt.as_millis(),
+                    pong.load.active,
+                    pong.load.queued
+                );
             }
         }
+
+        Ok(())
     }
 }
 
 fn main() -> ExitCode {
     let args = Args::parse();
-    if let Err(e) = logger::setup(args.debug) {
+    if let Err(e) = logger::setup(args.debug, args.log_format) {
         eprintln!("Logger failed to start: {:?}", e);
         return ExitCode::FAILURE;
     }
 
-    args.action.execute();
+    if let Err(e) = args.action.execute() {
+        error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+
     ExitCode::SUCCESS
 }
 
+mod peers {
+    use multiverse9core::routing::Tag;
+    use multiverse9core::sdk;
+    use multiverse9core::settings::Peer;
+
+    #[derive(clap::Subcommand, Debug)]
+    pub enum Action {
+        /// Show the health of the acknowledged nodes
+        Health {
+            /// Address of the node to ask
+            #[arg(short, long)]
+            addr: String,
+
+            /// Secret of a token to authenticate with
+            #[arg(long)]
+            token: Option<String>,
+
+            /// Only show the acknowledged node with this address
+            #[arg(long)]
+            peer: Option<String>,
+
+            /// List every sample in the window instead of only the summary
+            #[arg(long)]
+            history: bool,
+        },
+
+        /// List the acknowledged nodes
+        List {
+            /// Address of the admin listener of the node
+            #[arg(short, long)]
+            addr: String,
+
+            /// Print the nodes as JSON, as they are written in the settings
+            #[arg(long)]
+            json: bool,
+        },
+
+        /// Acknowledge a node until the node is restarted
+        Add {
+            /// Address of the admin listener of the node
+            #[arg(short, long)]
+            addr: String,
+
+            /// Hex-encoded public key to pin the node by
+            #[arg(long)]
+            key: Option<String>,
+
+            /// Secret to authenticate with when sending requests to the node
+            #[arg(long)]
+            token: Option<String>,
+
+            /// Tags describing the placement of the node, e.g. `region=eu`
+            #[arg(long = "tag")]
+            tags: Vec<Tag>,
+
+            /// Roles of the node, in place of those of every acknowledged node
+            #[arg(long = "role", value_delimiter = ',')]
+            roles: Vec<multiverse9core::roles::Role>,
+
+            /// Address of the node to acknowledge
+            peer: std::net::SocketAddr,
+        },
+
+        /// Stop acknowledging a node
+        Remove {
+            /// Address of the admin listener of the node
+            #[arg(short, long)]
+            addr: String,
+
+            /// Address of the node to stop acknowledging
+            peer: String,
+        },
+    }
+
+    impl Action {
+        pub fn execute(self) -> Result<(), Box<dyn std::error::Error>> {
+            match self {
+                Self::Health {
+                    addr,
+                    token,
+                    peer,
+                    history,
+                } => {
+                    let mut client = sdk::Client::connect(addr)?;
+                    if let Some(token) = token {
+                        client.authenticate(&token)?;
+                    }
+
+                    for report in client.health(peer.as_deref())? {
+                        crate::report::print(&report, history);
+                    }
+                }
+
+                Self::List { addr, json } => {
+                    let peers = sdk::Admin::connect(addr)?.peers()?;
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&peers)?);
+                        return Ok(());
+                    }
+
+                    for peer in &peers {
+                        let tags: Vec<String> = peer.tags.iter().map(Tag::to_string).collect();
+                        println!(
+                            "{}\t{}\t{}",
+                            peer.addr,
+                            peer.key.as_deref().unwrap_or("-"),
+                            tags.join(",")
+                        );
+                    }
+                }
+
+                Self::Add {
+                    addr,
+                    key,
+                    token,
+                    tags,
+                    roles,
+                    peer,
+                } => {
+                    sdk::Admin::connect(addr)?.add_peer(&Peer {
+                        addr: peer,
+                        tags,
+                        token,
+                        certificate: None,
+                        key,
+                        roles: (!roles.is_empty()).then_some(roles),
+                    })?;
+                    println!("Acknowledged {}", peer);
+                }
+
+                Self::Remove { addr, peer } => {
+                    sdk::Admin::connect(addr)?.remove_peer(&peer)?;
+                    println!("No longer acknowledging {}", peer);
+                }
+            }
+
+            Ok(())
+        }
+    }
+}
+
+mod tokens {
+    use multiverse9core::sdk;
+    use multiverse9core::settings::Token;
+
+    #[derive(clap::Subcommand, Debug)]
+    pub enum Action {
+        /// List the tokens, without their secrets
+        List {
+            /// Address of the admin listener of the node
+            #[arg(short, long)]
+            addr: String,
+        },
+
+        /// Add a token, which is valid right away
+        Add {
+            /// Address of the admin listener of the node
+            #[arg(short, long)]
+            addr: String,
+
+            /// ID to revoke the token by, generated unless given
+            #[arg(long)]
+            id: Option<String>,
+
+            /// Name requests issued with the token are attributed to
+            #[arg(long)]
+            subject: String,
+
+            /// Secret to authenticate with
+            #[arg(long)]
+            secret: String,
+        },
+
+        /// Revoke a token, which is no longer valid right away
+        Revoke {
+            /// Address of the admin listener of the node
+            #[arg(short, long)]
+            addr: String,
+
+            /// ID of the token to revoke
+            id: String,
+        },
+    }
+
+    impl Action {
+        pub fn execute(self) -> Result<(), Box<dyn std::error::Error>> {
+            match self {
+                Self::List { addr } => {
+                    for token in sdk::Admin::connect(addr)?.tokens()? {
+                        println!("{}\t{}", token.id.as_deref().unwrap_or("-"), token.subject);
+                    }
+                }
+
+                Self::Add {
+                    addr,
+                    id,
+                    subject,
+                    secret,
+                } => {
+                    let id = sdk::Admin::connect(addr)?.add_token(&Token {
+                        id,
+                        subject: subject.clone(),
+                        secret,
+                    })?;
+                    println!("Added token {} of {}", id, subject);
+                }
+
+                Self::Revoke { addr, id } => {
+                    sdk::Admin::connect(addr)?.revoke_token(&id)?;
+                    println!("Revoked token {}", id);
+                }
+            }
+
+            Ok(())
+        }
+    }
+}
+
+mod report {
+    use multiverse9core::health::Report;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Prints the summary of the health of a node, followed by its samples if
+    /// the history is requested.
+    pub fn print(report: &Report, history: bool) {
+        let state = match report.is_up() {
+            Some(true) => "up",
+            Some(false) => "down",
+            None => "unknown",
+        };
+
+        let rtt = match report.rtt() {
+            Some(rtt) => format!("{}ms", rtt),
+            None => "-".into(),
+        };
+
+        println!(
+            "{}\t{}\tuptime {:.1}%\terrors {:.1}%\trtt {}\t({} samples)",
+            report.addr,
+            state,
+            report.uptime() * 100.0,
+            report.error_rate() * 100.0,
+            rtt,
+            report.samples.len()
+        );
+
+        if !history {
+            return;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        for sample in &report.samples {
+            let rtt = match sample.rtt {
+                Some(rtt) => format!("{}ms", rtt),
+                None => "-".into(),
+            };
+
+            println!(
+                "  {}s ago\t{}\t{}\t{}",
+                now.saturating_sub(sample.at) / 1000,
+                if sample.up { "up" } else { "down" },
+                rtt,
+                sample.error.as_deref().unwrap_or_default()
+            );
+        }
+    }
+}
+
+mod bench {
+    use multiverse9core::sdk;
+    use std::time::{Duration, Instant};
+
+    /// An operation the benchmark issues.
+    #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Op {
+        /// Creates a record
+        Create,
+        /// Aggregates a record created before the load started
+        Aggregate,
+    }
+
+    pub struct Config {
+        pub addr: String,
+        pub ops: Vec<Op>,
+        pub concurrency: usize,
+        pub duration: Duration,
+        pub size: usize,
+        pub token: Option<String>,
+    }
+
+    /// The outcome of issuing one of the operations.
+    pub struct Results {
+        pub op: Op,
+        /// Latencies of the successful requests in microseconds.
+        pub latencies: Vec<u64>,
+        pub errors: usize,
+    }
+
+    /// Drives load against the node until the duration has passed, with every
+    /// connection issuing the operations one after another.
+    pub fn run(config: &Config) -> Result<Vec<Results>, sdk::Error> {
+        let value = vec![b'x'; config.size.max(1)];
+        let deadline = Instant::now() + config.duration;
+        let workers: Vec<_> = (0..config.concurrency.max(1))
+            .map(|_| {
+                let mut client = connect(config)?;
+                // Aggregating a record of its own, so that every connection
+                // reads the same amount of data.
+                let key = client.create(&value)?.id;
+                let ops = config.ops.clone();
+                let value = value.clone();
+                Ok(std::thread::spawn(move || {
+                    let mut results: Vec<_> = ops
+                        .iter()
+                        .map(|op| Results {
+                            op: *op,
+                            latencies: vec![],
+                            errors: 0,
+                        })
+                        .collect();
+
+                    while Instant::now() < deadline {
+                        for results in &mut results {
+                            let started = Instant::now();
+                            let result = match results.op {
+                                Op::Create => client.create(&value).map(|_| ()),
+                                Op::Aggregate => client.aggregate(&key).map(|_| ()),
+                            };
+
+                            match result {
+                                Ok(()) => {
+                                    results.latencies.push(started.elapsed().as_micros() as u64)
+                                }
+                                Err(_) => results.errors += 1,
+                            }
+                        }
+                    }
+
+                    results
+                }))
+            })
+            .collect::<Result<_, sdk::Error>>()?;
+
+        let mut merged: Vec<_> = config
+            .ops
+            .iter()
+            .map(|op| Results {
+                op: *op,
+                latencies: vec![],
+                errors: 0,
+            })
+            .collect();
+        for worker in workers {
+            let results = worker.join().expect("Benchmark worker panicked");
+            for (merged, results) in merged.iter_mut().zip(results) {
+                merged.latencies.extend(results.latencies);
+                merged.errors += results.errors;
+            }
+        }
+
+        for results in &mut merged {
+            results.latencies.sort_unstable();
+        }
+
+        Ok(merged)
+    }
+
+    fn connect(config: &Config) -> Result<sdk::Client, sdk::Error> {
+        let mut client = sdk::Client::connect(&config.addr)?;
+        if let Some(token) = &config.token {
+            client.authenticate(token)?;
+        }
+
+        Ok(client)
+    }
+
+    /// Returns the latency in milliseconds below which the given fraction of
+    /// the sorted latencies are.
+    fn percentile(latencies: &[u64], fraction: f64) -> f64 {
+        if latencies.is_empty() {
+            return 0.0;
+        }
+
+        let index = ((latencies.len() as f64 * fraction).ceil() as usize).clamp(1, latencies.len());
+        latencies[index - 1] as f64 / 1000.0
+    }
+
+    pub fn print(results: &[Results], duration: u64) {
+        println!("op\trequests\terrors\treq/s\tp50\tp90\tp99\tmax");
+        for results in results {
+            let latencies = &results.latencies;
+            println!(
+                "{:?}\t{}\t{}\t{:.1}\t{:.2}ms\t{:.2}ms\t{:.2}ms\t{:.2}ms",
+                results.op,
+                latencies.len(),
+                results.errors,
+                latencies.len() as f64 / duration.max(1) as f64,
+                percentile(latencies, 0.5),
+                percentile(latencies, 0.9),
+                percentile(latencies, 0.99),
+                percentile(latencies, 1.0),
+            );
+        }
+    }
+}
+
+mod setup {
+    use multiverse9core::prelude::*;
+    use multiverse9core::roles::Role;
+    use multiverse9core::settings::Peer;
+    use std::io::{self, BufRead, Write};
+    use std::net::SocketAddr;
+    use std::path::Path;
+
+    /// The settings given as arguments, which the wizard offers as defaults.
+    pub struct Answers {
+        pub redis_uri: Option<String>,
+        pub addr: Option<SocketAddr>,
+        pub name: Option<String>,
+        pub open_metadata: bool,
+        pub anonymous_roles: Vec<Role>,
+        pub peers: Vec<SocketAddr>,
+        pub output: Option<String>,
+    }
+
+    const DEFAULT_REDIS_URI: &str = "redis://127.0.0.1:6379";
+    const DEFAULT_OUTPUT: &str = "multiverse9.json";
+
+    /// Generates the settings from the arguments alone.
+    pub fn non_interactive(answers: Answers) -> Result<(), Box<dyn std::error::Error>> {
+        let redis_uri = answers.redis_uri.clone().unwrap_or_default();
+        let mut settings = Settings::new(redis_uri)?;
+        if let Some(addr) = answers.addr {
+            settings.addr = addr;
+        }
+        if let Some(name) = &answers.name {
+            settings.name = check_name(name)?;
+        }
+
+        settings.perms.open_metadata = answers.open_metadata;
+        if !answers.anonymous_roles.is_empty() {
+            settings.perms.anonymous = answers.anonymous_roles.clone();
+        }
+        settings.nodes = check_peers(&answers.peers, settings.addr)?;
+        match &answers.output {
+            Some(output) => write(&settings, Path::new(output)),
+            // It is the responsibility of the server maintainer to decide the
+            // directory where the settings are going to be stored.
+            None => {
+                println!("{}", settings);
+                Ok(())
+            }
+        }
+    }
+
+    /// Asks for each setting in turn, until its answer is valid, and writes the
+    /// settings to the chosen path.
+    pub fn wizard(answers: Answers) -> Result<(), Box<dyn std::error::Error>> {
+        let mut input = io::stdin().lock();
+        let default = answers.redis_uri.as_deref().unwrap_or(DEFAULT_REDIS_URI);
+        let mut settings = ask(&mut input, "Storage connection string", default, |answer| {
+            Settings::new(answer.into()).map_err(|e| format!("{:?}", e))
+        })?;
+
+        let default = answers.addr.unwrap_or(settings.addr).to_string();
+        settings.addr = ask(&mut input, "Address to bind to", &default, |answer| {
+            answer.parse().map_err(|e| format!("{}", e))
+        })?;
+
+        let default = answers
+            .name
+            .clone()
+            .unwrap_or_else(|| settings.name.clone());
+        settings.name = ask(&mut input, "Instance name", &default, check_name)?;
+
+        let default = yes_no(answers.open_metadata);
+        settings.perms.open_metadata = ask(
+            &mut input,
+            "Allow anyone to request the metadata of the node? (yes/no)",
+            default,
+            check_yes_no,
+        )?;
+
+        let default: Vec<_> = match answers.anonymous_roles.is_empty() {
+            true => settings
+                .perms
+                .anonymous
+                .iter()
+                .map(Role::to_string)
+                .collect(),
+            false => answers
+                .anonymous_roles
+                .iter()
+                .map(Role::to_string)
+                .collect(),
+        };
+        settings.perms.anonymous = ask(
+            &mut input,
+            "Roles of connections which have not authenticated, separated by commas",
+            &default.join(","),
+            |answer| {
+                answer
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|role| !role.is_empty())
+                    .map(str::parse)
+                    .collect::<Result<Vec<Role>, _>>()
+            },
+        )?;
+
+        let default: Vec<_> = answers.peers.iter().map(|addr| addr.to_string()).collect();
+        let addr = settings.addr;
+        settings.nodes = ask(
+            &mut input,
+            "Addresses of the nodes to acknowledge, separated by commas",
+            &default.join(","),
+            |answer| {
+                let peers = answer
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|peer| !peer.is_empty())
+                    .map(|peer| peer.parse().map_err(|e| format!("{:?}: {}", peer, e)))
+                    .collect::<Result<Vec<SocketAddr>, _>>()?;
+                check_peers(&peers, addr)
+            },
+        )?;
+
+        let default = answers.output.as_deref().unwrap_or(DEFAULT_OUTPUT);
+        let output: String = ask(
+            &mut input,
+            "Path to write the settings to",
+            default,
+            |answer| Ok(answer.to_string()),
+        )?;
+
+        let path = Path::new(&output);
+        if path.exists() {
+            let question = format!("{} exists already, replace it? (yes/no)", output);
+            if !ask(&mut input, &question, "no", check_yes_no)? {
+                return Err("Setup was cancelled".into());
+            }
+        }
+
+        write(&settings, path)
+    }
+
+    /// Asks the question until the answer passes the check, taking the default
+    /// for empty answers.
+    fn ask<T>(
+        input: &mut impl BufRead,
+        question: &str,
+        default: &str,
+        check: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        loop {
+            match default.is_empty() {
+                true => print!("{}: ", question),
+                false => print!("{} [{}]: ", question, default),
+            }
+            io::stdout().flush()?;
+
+            let mut answer = String::new();
+            if input.read_line(&mut answer)? == 0 {
+                return Err("Setup was cancelled".into());
+            }
+
+            let answer = match answer.trim() {
+                "" => default,
+                answer => answer,
+            };
+
+            match check(answer) {
+                Ok(value) => return Ok(value),
+                Err(e) => println!("Invalid answer, {}", e),
+            }
+        }
+    }
+
+    fn yes_no(value: bool) -> &'static str {
+        match value {
+            true => "yes",
+            false => "no",
+        }
+    }
+
+    fn check_yes_no(answer: &str) -> Result<bool, String> {
+        match answer.to_ascii_lowercase().as_str() {
+            "y" | "yes" => Ok(true),
+            "n" | "no" => Ok(false),
+            _ => Err("expected yes or no".into()),
+        }
+    }
+
+    fn check_name(answer: &str) -> Result<String, String> {
+        if answer.is_empty() || answer.contains(char::is_whitespace) {
+            return Err("names cannot be empty or contain whitespace".into());
+        }
+
+        Ok(answer.into())
+    }
+
+    /// Checks that the node does not acknowledge itself or a node twice.
+    fn check_peers(peers: &[SocketAddr], addr: SocketAddr) -> Result<Vec<Peer>, String> {
+        let mut nodes: Vec<Peer> = vec![];
+        for &peer in peers {
+            if peer == addr {
+                return Err(format!("{} is the address of the node itself", peer));
+            }
+            if nodes.iter().any(|node| node.addr == peer) {
+                return Err(format!("{} is given twice", peer));
+            }
+
+            nodes.push(Peer {
+                addr: peer,
+                tags: vec![],
+                token: None,
+                certificate: None,
+                key: None,
+                roles: None,
+            });
+        }
+
+        Ok(nodes)
+    }
+
+    fn write(settings: &Settings, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, settings.to_string())?;
+        println!(
+            "Wrote the settings of {} to {}",
+            settings.name,
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+mod daemon {
+    use std::path::{Path, PathBuf};
+
+    /// Removes the process ID file once the node stops.
+    pub struct PidFile(Option<PathBuf>);
+
+    impl PidFile {
+        /// Writes the ID of the current process to the file.
+        pub fn create(path: Option<PathBuf>) -> std::io::Result<Self> {
+            if let Some(path) = &path {
+                std::fs::write(path, format!("{}\n", std::process::id()))?;
+            }
+
+            Ok(Self(path))
+        }
+    }
+
+    impl Drop for PidFile {
+        fn drop(&mut self) {
+            if let Some(path) = &self.0 {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Detaches the current process from the terminal, continuing in a child
+    /// process of its own session. The detached process keeps the working
+    /// directory, so that relative paths in the settings resolve as before,
+    /// and its output is appended to the log file.
+    #[cfg(unix)]
+    pub fn detach(
+        pid_file: Option<PathBuf>,
+        log_file: Option<&Path>,
+    ) -> Result<PidFile, Box<dyn std::error::Error>> {
+        let output = |path: Option<&Path>| -> std::io::Result<daemonize::Stdio> {
+            Ok(match path {
+                Some(path) => std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?
+                    .into(),
+                None => daemonize::Stdio::devnull(),
+            })
+        };
+
+        let mut daemon = daemonize::Daemonize::new()
+            .working_directory(std::env::current_dir()?)
+            .stdout(output(log_file)?)
+            .stderr(output(log_file)?);
+        if let Some(path) = &pid_file {
+            daemon = daemon.pid_file(path);
+        }
+
+        daemon.start()?;
+        // The file is written by the detached process, which removes it again.
+        Ok(PidFile(pid_file))
+    }
+
+    #[cfg(not(unix))]
+    pub fn detach(
+        _: Option<PathBuf>,
+        _: Option<&Path>,
+    ) -> Result<PidFile, Box<dyn std::error::Error>> {
+        Err("Detaching requires Unix".into())
+    }
+}
+
 mod logger {
     use log::LevelFilter;
+    use multiverse9core::crash;
     use std::env;
     use std::str::FromStr;
+    use std::time::{SystemTime, UNIX_EPOCH};
 
-    pub fn setup(debug: bool) -> Result<(), fern::InitError> {
-        fern::Dispatch::new()
-            .format(|out, message, record| {
+    /// Format of the log lines.
+    #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Format {
+        /// Lines for reading, prefixed with their level
+        Text,
+        /// One JSON object per line, for ingestion by log aggregators
+        Json,
+    }
+
+    pub fn setup(debug: bool, format: Format) -> Result<(), fern::InitError> {
+        let dispatch = match format {
+            Format::Text => fern::Dispatch::new().format(|out, message, record| {
                 out.finish(format_args!("{}: {}", record.level(), message))
-            })
+            }),
+            // Annotating every line with the connection and the journaled request
+            // the logging thread is serving, if any.
+            Format::Json => fern::Dispatch::new().format(|out, message, record| {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_millis() as u64)
+                    .unwrap_or_default();
+                let context = crash::context();
+                let line = serde_json::json!({
+                    "level": record.level().as_str(),
+                    "timestamp": timestamp,
+                    "module": record.module_path().unwrap_or(record.target()),
+                    "message": message.to_string(),
+                    "request": context.request,
+                    "peer": context.peer,
+                });
+                out.finish(format_args!("{}", line))
+            }),
+        };
+
+        dispatch
             .level(if debug {
                 LevelFilter::Debug
             } else {